@@ -4,6 +4,10 @@ use criterion::{criterion_group, criterion_main, Criterion};
 
 use risc_v_emulator_lib::cpu::isa::RV32I;
 use risc_v_emulator_lib::cpu::Cpu;
+use risc_v_emulator_lib::memory::{Bus, Dram, Memory, SparseDram};
+
+const DRAM_BASE: u32 = 0x8000_0000;
+const DRAM_SIZE: u32 = 1024 * 1024 * 128;
 
 fn cycle_loop(c: &mut Criterion) {
     const CODE: &[u8] = include_bytes!("loop.bin");
@@ -20,19 +24,85 @@ fn cycle_fib(c: &mut Criterion) {
 
     let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(CODE);
     c.bench_function("fibonacci calculation", |b| {
-        b.iter(|| loop {
-            if let Err(e) = criterion::black_box(cpu.cycle()) {
-                criterion::black_box(e);
-                break;
-            }
+        b.iter(|| {
+            cpu.reset();
+            criterion::black_box(cpu.run());
         })
     });
+
+    let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(CODE);
+    c.bench_function("fibonacci calculation (single step)", |b| {
+        b.iter(|| {
+            cpu.reset();
+            criterion::black_box(cpu.run_while(|_| true));
+        })
+    });
+
+    let mut cpu: Cpu<RV32I, 32> = Cpu::new(
+        Bus::new(vec![(
+            DRAM_BASE..DRAM_BASE + DRAM_SIZE,
+            Box::new(Dram::with_code(CODE, DRAM_SIZE)),
+        )]),
+        DRAM_BASE..DRAM_BASE + DRAM_SIZE,
+    );
+    c.bench_function("fibonacci calculation (dram)", |b| {
+        b.iter(|| {
+            cpu.reset();
+            criterion::black_box(cpu.run());
+        })
+    });
+}
+
+fn bus_access(c: &mut Criterion) {
+    // a few small regions in front of dram, so dispatch has to skip them
+    let bus = |dram: Box<dyn Memory<u32>>| {
+        let mut mem_map: Vec<(_, Box<dyn Memory<u32>>)> = (0..8u32)
+            .map(|i| {
+                let base = 0x1000_0000 + i * 0x1000;
+                (
+                    base..base + 0x1000,
+                    Box::new(Dram::with_code(&[], 0x1000_u32)) as Box<dyn Memory<u32>>,
+                )
+            })
+            .collect();
+        mem_map.push((DRAM_BASE..DRAM_BASE + DRAM_SIZE, dram));
+        Bus::new(mem_map)
+    };
+
+    for (name, dram) in [
+        (
+            "dram",
+            Box::new(Dram::with_code(&[], DRAM_SIZE)) as Box<dyn Memory<u32>>,
+        ),
+        (
+            "sparse dram",
+            Box::new(SparseDram::with_code(&[], DRAM_SIZE)),
+        ),
+    ] {
+        let mut bus = bus(dram);
+
+        c.bench_function(&format!("bus load_u32 ({name})"), |b| {
+            b.iter(|| {
+                for addr in (DRAM_BASE..DRAM_BASE + 0x4000).step_by(4) {
+                    criterion::black_box(bus.load_u32(criterion::black_box(addr)).ok());
+                }
+            })
+        });
+
+        c.bench_function(&format!("bus store_u32 ({name})"), |b| {
+            b.iter(|| {
+                for addr in (DRAM_BASE..DRAM_BASE + 0x4000).step_by(4) {
+                    criterion::black_box(bus.store_u32(criterion::black_box(addr), addr).ok());
+                }
+            })
+        });
+    }
 }
 
 criterion_group!(
     name = benches;
     config = Criterion::default().measurement_time(Duration::from_secs(20));
-    targets = cycle_loop, cycle_fib
+    targets = cycle_loop, cycle_fib, bus_access
 );
 
 criterion_main!(benches);
@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::ops::Range;
+#[cfg(all(feature = "jit", target_arch = "x86_64"))]
+use std::rc::Rc;
+
+use crate::cpu::isa::{As, Op, Xlen};
+#[cfg(all(feature = "jit", target_arch = "x86_64"))]
+use crate::cpu::jit::{Jit, Segment, HOT_THRESHOLD};
+
+/// Granularity at which stores are checked against translated code.
+const CODE_PAGE_SIZE: usize = 4096;
+
+/// Number of entries of the direct-mapped cache in front of the block index.
+const JUMP_CACHE_ENTRIES: usize = 1024;
+
+/// Upper bound for the number of instructions in a block.
+pub(crate) const MAX_BLOCK_LEN: usize = 64;
+
+/// Number of operations of invalidated blocks after which the cache is flushed, if they also
+/// make up most of it. Blocks are never reused, so code that keeps rewriting itself would
+/// otherwise grow the cache without bound.
+const MAX_DEAD_OPS: usize = 1 << 16;
+
+/// A straight-line sequence of decoded instructions, ending at the first instruction that may
+/// change control flow or the translation state.
+struct Block<A> {
+    /// Position of the block's operations in [`BlockCache::ops`].
+    ops: Range<usize>,
+    start: A,
+    /// Address of the instruction following the last one in the block.
+    end: A,
+    /// Cleared when the memory the block was translated from is written to.
+    valid: bool,
+    /// Successor blocks as (pc, block index), for the fall-through and the taken path. Links to
+    /// invalidated blocks are ignored, block indices are not reused before a flush.
+    links: [Option<(A, usize)>; 2],
+    /// Number of executions, the block is compiled once it reaches [`HOT_THRESHOLD`].
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    heat: u32,
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    native: Option<Rc<[Segment]>>,
+}
+
+/// Cache of translated blocks, keyed by the address of their first instruction.
+pub(crate) struct BlockCache<A: Xlen> {
+    /// Operations of all blocks, stored back to back.
+    ops: Vec<Op>,
+    blocks: Vec<Block<A>>,
+    index: HashMap<A, usize>,
+    /// Recently looked up (pc, block index) pairs, mostly for indirect jumps.
+    jump_cache: Box<[(A, usize)]>,
+    /// Blocks translated from each page, to find the blocks a store has to invalidate.
+    code_pages: HashMap<usize, Vec<usize>>,
+    /// Number of operations of the invalidated blocks, reclaimed by flushing the cache.
+    dead_ops: usize,
+    /// Lowest and highest address of translated code, to skip the page lookup for most stores.
+    code_bounds: (usize, usize),
+    /// The block executed last, used to follow its links.
+    last: Option<usize>,
+    /// Incremented whenever blocks are invalidated, so a running block can notice it is stale.
+    generation: u64,
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    jit: Jit,
+}
+
+impl<A: Xlen> BlockCache<A> {
+    pub(crate) fn new() -> BlockCache<A> {
+        Self {
+            ops: Vec::new(),
+            blocks: Vec::new(),
+            index: HashMap::new(),
+            jump_cache: vec![(A::max_value(), 0); JUMP_CACHE_ENTRIES].into_boxed_slice(),
+            code_pages: HashMap::new(),
+            dead_ops: 0,
+            code_bounds: (usize::MAX, 0),
+            last: None,
+            generation: 0,
+            #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+            jit: Jit::new(),
+        }
+    }
+
+    /// Returns the range of `op` indices making up the block.
+    pub(crate) fn block(&self, index: usize) -> Range<usize> {
+        self.blocks[index].ops.clone()
+    }
+
+    pub(crate) fn op(&self, index: usize) -> Op {
+        self.ops[index]
+    }
+
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Returns the compiled form of the block, compiling it when it has become hot. `xlen` is
+    /// the register width in bytes.
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    pub(crate) fn native(
+        &mut self,
+        index: usize,
+        xlen: usize,
+        reg_count: usize,
+    ) -> Option<Rc<[Segment]>> {
+        let block = &mut self.blocks[index];
+        if block.native.is_none() {
+            block.heat = block.heat.saturating_add(1);
+            if block.heat != HOT_THRESHOLD {
+                return None;
+            }
+
+            let compiled = self.jit.compile(
+                &self.ops[block.ops.clone()],
+                block.ops.start,
+                block.start.as_t::<usize>() as u64,
+                xlen,
+                reg_count,
+            );
+            match compiled {
+                // blocks without anything to compile stay interpreted
+                Ok(segments) => {
+                    block.native = Some(segments).filter(|segments| {
+                        segments
+                            .iter()
+                            .any(|segment| matches!(segment, Segment::Native { .. }))
+                    });
+                }
+                // changing the protection of a chunk failed, which unmaps it together with the
+                // code of other blocks, so everything goes back to the interpreter
+                Err(_) => {
+                    for block in &mut self.blocks {
+                        block.native = None;
+                    }
+                    self.jit.clear();
+                }
+            }
+        }
+
+        self.blocks[index].native.clone()
+    }
+
+    #[cfg(all(test, feature = "jit", target_arch = "x86_64"))]
+    pub(crate) fn compiled_blocks(&self) -> usize {
+        self.blocks
+            .iter()
+            .filter(|block| block.valid && block.native.is_some())
+            .count()
+    }
+
+    /// Number of operations held by the cache, including those of invalidated blocks.
+    #[cfg(test)]
+    pub(crate) fn ops_len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Looks up the block starting at `pc`, following the links of the last executed block first.
+    #[inline]
+    pub(crate) fn get(&mut self, pc: A) -> Option<usize> {
+        let links = self.last.map_or([None; 2], |last| self.blocks[last].links);
+
+        let index = match links {
+            [Some((a, index)), _] | [_, Some((a, index))]
+                if a == pc && self.blocks[index].valid =>
+            {
+                index
+            }
+            _ => {
+                let slot = Self::jump_cache_slot(pc);
+                let index = match self.jump_cache[slot] {
+                    (tag, index) if tag == pc => index,
+                    _ => {
+                        let index = *self.index.get(&pc)?;
+                        self.jump_cache[slot] = (pc, index);
+                        index
+                    }
+                };
+
+                if let Some(last) = self.last {
+                    self.link(last, pc, index);
+                }
+                index
+            }
+        };
+
+        self.last = Some(index);
+        Some(index)
+    }
+
+    fn jump_cache_slot(pc: A) -> usize {
+        (pc.as_t::<usize>() >> 2) % JUMP_CACHE_ENTRIES
+    }
+
+    fn link(&mut self, from: usize, pc: A, to: usize) {
+        let block = &mut self.blocks[from];
+        let slot = usize::from(pc != block.end);
+        block.links[slot] = Some((pc, to));
+    }
+
+    /// Adds the block of `ops` starting at `start`, ending before `end`.
+    pub(crate) fn insert(&mut self, start: A, end: A, ops: Vec<Op>) -> usize {
+        // no block is running while a new one is translated, so all of them can be dropped
+        if self.dead_ops > MAX_DEAD_OPS && 2 * self.dead_ops > self.ops.len() {
+            self.flush();
+        }
+
+        let index = self.blocks.len();
+
+        let (first, last) = (start.as_t::<usize>(), end.as_t::<usize>().wrapping_sub(1));
+        for page in first / CODE_PAGE_SIZE..=last / CODE_PAGE_SIZE {
+            self.code_pages.entry(page).or_default().push(index);
+        }
+        self.code_bounds = (self.code_bounds.0.min(first), self.code_bounds.1.max(last));
+
+        let first = self.ops.len();
+        self.ops.extend_from_slice(&ops);
+
+        self.blocks.push(Block {
+            ops: first..self.ops.len(),
+            start,
+            end,
+            valid: true,
+            links: [None; 2],
+            #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+            heat: 0,
+            #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+            native: None,
+        });
+        self.index.insert(start, index);
+
+        if let Some(last) = self.last {
+            self.link(last, start, index);
+        }
+        self.last = Some(index);
+
+        index
+    }
+
+    /// Invalidates all blocks overlapping `size` bytes starting at `addr`.
+    pub(crate) fn invalidate(&mut self, addr: A, size: usize) {
+        let first = addr.as_t::<usize>();
+        let last = first.wrapping_add(size - 1);
+        if last < self.code_bounds.0 || first > self.code_bounds.1 {
+            return;
+        }
+
+        for page in first / CODE_PAGE_SIZE..=last / CODE_PAGE_SIZE {
+            let Some(indices) = self.code_pages.get_mut(&page) else {
+                continue;
+            };
+
+            indices.retain(|&index| {
+                let block = &mut self.blocks[index];
+                let overlaps = block.start.as_t::<usize>() <= last
+                    && first < block.end.as_t::<usize>()
+                    && block.valid;
+                if overlaps {
+                    block.valid = false;
+                    self.index.remove(&block.start);
+                    self.jump_cache[Self::jump_cache_slot(block.start)].0 = A::max_value();
+                    self.dead_ops += block.ops.len();
+                    self.generation += 1;
+                }
+                block.valid
+            });
+        }
+    }
+
+    pub(crate) fn flush(&mut self) {
+        self.ops.clear();
+        self.blocks.clear();
+        self.index.clear();
+        self.jump_cache.fill((A::max_value(), 0));
+        self.code_pages.clear();
+        self.dead_ops = 0;
+        self.code_bounds = (usize::MAX, 0);
+        self.last = None;
+        self.generation += 1;
+
+        #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+        self.jit.clear();
+    }
+}
@@ -0,0 +1,141 @@
+use num_traits::{NumCast, ToPrimitive, Zero};
+
+use crate::cpu::isa::Isa;
+use crate::cpu::pmp::PMP_ENTRIES;
+use crate::cpu::{Cpu, Privilege};
+
+pub const MSTATUS: u16 = 0x300;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+pub const PMPCFG0: u16 = 0x3A0;
+pub const PMPADDR0: u16 = 0x3B0;
+
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_MPP: u64 = 0b11 << 11;
+
+/// Machine software interrupt, bit in `mip` and `mie` as well as the interrupt cause.
+pub const MSI: u64 = 1 << 3;
+/// Machine timer interrupt
+pub const MTI: u64 = 1 << 7;
+/// Supervisor external interrupt, taken in M-mode since there is no delegation
+pub const SEI: u64 = 1 << 9;
+/// Machine external interrupt
+pub const MEI: u64 = 1 << 11;
+
+/// Machine-mode trap setup and handling registers.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct TrapCsrs {
+    pub(crate) mstatus: u64,
+    pub(crate) mie: u64,
+    pub(crate) mtvec: u64,
+    pub(crate) mscratch: u64,
+    pub(crate) mepc: u64,
+    pub(crate) mcause: u64,
+    pub(crate) mtval: u64,
+}
+
+impl<I: Isa<REG_COUNT>, const REG_COUNT: usize> Cpu<I, REG_COUNT> {
+    /// Returns whether `csr` may be accessed from the current privilege mode and, for writes,
+    /// is not read-only.
+    fn csr_accessible(&self, csr: u16, write: bool) -> bool {
+        let min_privilege = match (csr >> 8) & 0b11 {
+            0b00 => Privilege::User,
+            0b01 => Privilege::Supervisor,
+            _ => Privilege::Machine,
+        };
+        let read_only = (csr >> 10) & 0b11 == 0b11;
+
+        self.privilege >= min_privilege && !(write && read_only)
+    }
+
+    /// Reads a csr, returns `None` if it does not exist or is not accessible.
+    pub(crate) fn read_csr(&self, csr: u16) -> Option<I::XlenU> {
+        if !self.csr_accessible(csr, false) {
+            return None;
+        }
+
+        let trap = &self.trap_csrs;
+        let value = match csr {
+            MSTATUS => trap.mstatus,
+            MIE => trap.mie,
+            MTVEC => trap.mtvec,
+            MSCRATCH => trap.mscratch,
+            MEPC => trap.mepc,
+            MCAUSE => trap.mcause,
+            MTVAL => trap.mtval,
+            MIP => self.pending_interrupts(),
+            _ => return self.read_pmp_csr(csr),
+        };
+
+        // the registers are stored zero extended and never hold more than xlen bits
+        Some(NumCast::from(value).expect("csr value fits xlen"))
+    }
+
+    fn read_pmp_csr(&self, csr: u16) -> Option<I::XlenU> {
+        match csr {
+            PMPCFG0..=0x3AF => self.pmp.read_cfg_csr((csr - PMPCFG0) as usize),
+            PMPADDR0..=0x3EF if ((csr - PMPADDR0) as usize) < PMP_ENTRIES => {
+                Some(self.pmp.read_addr_csr((csr - PMPADDR0) as usize))
+            }
+            // unimplemented pmp entries are read-only zero
+            PMPADDR0..=0x3EF => Some(I::XlenU::zero()),
+            _ => None,
+        }
+    }
+
+    /// Writes a csr, returns `None` if it does not exist or is not writable.
+    pub(crate) fn write_csr(&mut self, csr: u16, value: I::XlenU) -> Option<()> {
+        if !self.csr_accessible(csr, true) {
+            return None;
+        }
+
+        // cached instructions were fetched under the old pmp configuration
+        if matches!(csr, PMPCFG0..=0x3EF) {
+            self.flush_translations();
+        }
+
+        let trap = &mut self.trap_csrs;
+        let new = value.to_u64().expect("xlen fits 64 bits");
+        match csr {
+            MSTATUS => {
+                // MPP only holds implemented privilege modes, 0b10 is reserved
+                let mpp = match new & MSTATUS_MPP {
+                    0x1000 => trap.mstatus & MSTATUS_MPP,
+                    mpp => mpp,
+                };
+                trap.mstatus = new & (MSTATUS_MIE | MSTATUS_MPIE) | mpp;
+            }
+            MIE => trap.mie = new & (MSI | MTI | SEI | MEI),
+            // vectored mode is supported, the reserved modes fall back to direct
+            MTVEC if new & 0b11 > 1 => trap.mtvec = new & !0b11,
+            MTVEC => trap.mtvec = new,
+            MSCRATCH => trap.mscratch = new,
+            MEPC => trap.mepc = new & !0b11,
+            MCAUSE => trap.mcause = new,
+            MTVAL => trap.mtval = new,
+            // the interrupt lines are driven by devices, no bit is writable
+            MIP => {}
+            _ => return self.write_pmp_csr(csr, value),
+        }
+
+        Some(())
+    }
+
+    fn write_pmp_csr(&mut self, csr: u16, value: I::XlenU) -> Option<()> {
+        match csr {
+            PMPCFG0..=0x3AF => self.pmp.write_cfg_csr((csr - PMPCFG0) as usize, value),
+            PMPADDR0..=0x3EF if ((csr - PMPADDR0) as usize) < PMP_ENTRIES => {
+                self.pmp.write_addr_csr((csr - PMPADDR0) as usize, value);
+                Some(())
+            }
+            PMPADDR0..=0x3EF => Some(()),
+            _ => None,
+        }
+    }
+}
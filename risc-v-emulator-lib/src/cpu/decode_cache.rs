@@ -0,0 +1,68 @@
+use crate::cpu::isa::{As, Op, Xlen};
+
+const ENTRIES: usize = 4096;
+
+/// Direct-mapped cache of decoded instructions, indexed by the (word aligned) address they were
+/// fetched from.
+///
+/// Entries have to be invalidated whenever the memory they were decoded from may have changed,
+/// or whenever a fetch from their address could now fail (pmp or privilege changes).
+pub(crate) struct DecodeCache<A: Xlen> {
+    entries: Box<[(A, Op)]>,
+}
+
+impl<A: Xlen> DecodeCache<A> {
+    /// Unused entry, its tag can never match since only aligned addresses are cached.
+    fn empty() -> (A, Op) {
+        (A::max_value(), Op::Illegal(0))
+    }
+
+    pub(crate) fn new() -> DecodeCache<A> {
+        Self {
+            entries: vec![Self::empty(); ENTRIES].into_boxed_slice(),
+        }
+    }
+
+    fn index(addr: usize) -> usize {
+        (addr >> 2) % ENTRIES
+    }
+
+    pub(crate) fn get(&self, addr: A) -> Option<Op> {
+        let (tag, op) = self.entries[Self::index(addr.as_t())];
+        (tag == addr).then_some(op)
+    }
+
+    pub(crate) fn insert(&mut self, addr: A, op: Op) {
+        if addr.as_t::<usize>() % 4 == 0 {
+            self.entries[Self::index(addr.as_t())] = (addr, op);
+        }
+    }
+
+    /// Invalidates all entries decoded from `size` bytes starting at `addr`.
+    pub(crate) fn invalidate(&mut self, addr: A, size: usize) {
+        // every entry would be visited anyway
+        if size >= 4 * ENTRIES {
+            return self.flush();
+        }
+
+        let start = addr.as_t::<usize>() & !0b11;
+        let end = addr.as_t::<usize>().wrapping_add(size - 1) & !0b11;
+
+        let mut word = start;
+        loop {
+            let entry = &mut self.entries[Self::index(word)];
+            if entry.0 != A::max_value() && entry.0.as_t::<usize>() == word {
+                *entry = Self::empty();
+            }
+
+            if word == end {
+                break;
+            }
+            word = word.wrapping_add(4);
+        }
+    }
+
+    pub(crate) fn flush(&mut self) {
+        self.entries.fill(Self::empty());
+    }
+}
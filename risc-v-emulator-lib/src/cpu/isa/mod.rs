@@ -1,14 +1,18 @@
-use std::fmt::{Debug, Display, UpperHex};
+use std::fmt::{Debug, Display, Formatter, UpperHex};
+use std::hash::Hash;
 
 use num_traits::ops::overflowing::OverflowingAdd;
 use num_traits::{AsPrimitive, NumAssign, PrimInt, Signed, Unsigned, WrappingAdd, WrappingSub};
 
+pub use op::Op;
 pub use rv32e::RV32E;
 pub use rv32i::RV32I;
 pub use rv64i::RV64I;
 
 use crate::cpu::{CPUError, Cpu};
 
+mod op;
+
 mod rv32i;
 
 mod rv32e;
@@ -25,6 +29,7 @@ pub trait Xlen:
     + UpperHex
     + Display
     + Debug
+    + Hash
     + AsPrimitive<usize>
 {
 }
@@ -39,6 +44,7 @@ impl<T> Xlen for T where
         + UpperHex
         + Display
         + Debug
+        + Hash
         + AsPrimitive<usize>
 {
 }
@@ -51,9 +57,13 @@ pub trait Isa<const REG_COUNT: usize> {
 
     const INSN_SIZE: Self::XlenU;
 
+    /// Decodes a raw instruction, encodings this isa does not implement decode to
+    /// [`Op::Illegal`].
+    fn decode(instruction: u32) -> Op;
+
     fn exec<const REG_COUNT_I: usize, I: Isa<REG_COUNT_I>>(
         cpu: &mut Cpu<I, REG_COUNT_I>,
-        instruction: u32,
+        op: Op,
     ) -> Result<(), CPUError<I::XlenU>>
     where
         bool: AsPrimitive<I::XlenU>,
@@ -71,6 +81,27 @@ pub trait Isa<const REG_COUNT: usize> {
         I::XlenU: AsPrimitive<u32>;
 }
 
+/// Describes an [`Isa`], returned by [`Cpu::isa_info`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct IsaInfo {
+    pub id: &'static str,
+    /// Register width in bits
+    pub xlen: u32,
+    /// Instruction size in bytes
+    pub insn_size: usize,
+    pub reg_count: usize,
+}
+
+impl Display for IsaInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ISA: {} bits={} insn_size={} reg_count={}",
+            self.id, self.xlen, self.insn_size, self.reg_count
+        )
+    }
+}
+
 pub(crate) trait As {
     fn as_t<T: Copy + 'static>(self) -> T
     where
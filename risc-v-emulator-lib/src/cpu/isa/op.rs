@@ -0,0 +1,145 @@
+/// A decoded instruction.
+///
+/// Register fields are indices into the register file, immediates are already extracted from
+/// the instruction and sign extended to 32 bits where the instruction requires it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Op {
+    Lui { rd: u8, imm: u32 },
+    Auipc { rd: u8, imm: u32 },
+    Jal { rd: u8, imm: i32 },
+    Jalr { rd: u8, rs1: u8, imm: i32 },
+
+    Beq { rs1: u8, rs2: u8, imm: i32 },
+    Bne { rs1: u8, rs2: u8, imm: i32 },
+    Blt { rs1: u8, rs2: u8, imm: i32 },
+    Bge { rs1: u8, rs2: u8, imm: i32 },
+    Bltu { rs1: u8, rs2: u8, imm: i32 },
+    Bgeu { rs1: u8, rs2: u8, imm: i32 },
+
+    Lb { rd: u8, rs1: u8, imm: i32 },
+    Lh { rd: u8, rs1: u8, imm: i32 },
+    Lw { rd: u8, rs1: u8, imm: i32 },
+    Lbu { rd: u8, rs1: u8, imm: i32 },
+    Lhu { rd: u8, rs1: u8, imm: i32 },
+
+    Sb { rs1: u8, rs2: u8, imm: i32 },
+    Sh { rs1: u8, rs2: u8, imm: i32 },
+    Sw { rs1: u8, rs2: u8, imm: i32 },
+
+    Addi { rd: u8, rs1: u8, imm: i32 },
+    Slti { rd: u8, rs1: u8, imm: i32 },
+    Sltiu { rd: u8, rs1: u8, imm: i32 },
+    Xori { rd: u8, rs1: u8, imm: i32 },
+    Ori { rd: u8, rs1: u8, imm: i32 },
+    Andi { rd: u8, rs1: u8, imm: i32 },
+    Slli { rd: u8, rs1: u8, shamt: u8 },
+    Srli { rd: u8, rs1: u8, shamt: u8 },
+    Srai { rd: u8, rs1: u8, shamt: u8 },
+
+    Add { rd: u8, rs1: u8, rs2: u8 },
+    Sub { rd: u8, rs1: u8, rs2: u8 },
+    Sll { rd: u8, rs1: u8, rs2: u8 },
+    Slt { rd: u8, rs1: u8, rs2: u8 },
+    Sltu { rd: u8, rs1: u8, rs2: u8 },
+    Xor { rd: u8, rs1: u8, rs2: u8 },
+    Srl { rd: u8, rs1: u8, rs2: u8 },
+    Sra { rd: u8, rs1: u8, rs2: u8 },
+    Or { rd: u8, rs1: u8, rs2: u8 },
+    And { rd: u8, rs1: u8, rs2: u8 },
+
+    Fence,
+    FenceI,
+    Ecall,
+    Ebreak,
+    /// Return from a machine-mode trap handler
+    Mret,
+    /// Wait for interrupt, executed as a no-op
+    Wfi,
+
+    /// CSRRW, CSRRS, CSRRC and their immediate forms, `funct3` selects the operation
+    Csr {
+        rd: u8,
+        rs1: u8,
+        csr: u16,
+        funct3: u8,
+        instruction: u32,
+    },
+
+    /// An encoding that is not implemented
+    Illegal(u32),
+}
+
+impl Op {
+    /// Returns the register numbers (rd, rs1, rs2) used by this operation, 0 for unused fields.
+    pub fn registers(&self) -> (u8, u8, u8) {
+        match *self {
+            Op::Lui { rd, .. } | Op::Auipc { rd, .. } | Op::Jal { rd, .. } => (rd, 0, 0),
+            Op::Jalr { rd, rs1, .. }
+            | Op::Lb { rd, rs1, .. }
+            | Op::Lh { rd, rs1, .. }
+            | Op::Lw { rd, rs1, .. }
+            | Op::Lbu { rd, rs1, .. }
+            | Op::Lhu { rd, rs1, .. }
+            | Op::Addi { rd, rs1, .. }
+            | Op::Slti { rd, rs1, .. }
+            | Op::Sltiu { rd, rs1, .. }
+            | Op::Xori { rd, rs1, .. }
+            | Op::Ori { rd, rs1, .. }
+            | Op::Andi { rd, rs1, .. }
+            | Op::Slli { rd, rs1, .. }
+            | Op::Srli { rd, rs1, .. }
+            | Op::Srai { rd, rs1, .. } => (rd, rs1, 0),
+            Op::Beq { rs1, rs2, .. }
+            | Op::Bne { rs1, rs2, .. }
+            | Op::Blt { rs1, rs2, .. }
+            | Op::Bge { rs1, rs2, .. }
+            | Op::Bltu { rs1, rs2, .. }
+            | Op::Bgeu { rs1, rs2, .. }
+            | Op::Sb { rs1, rs2, .. }
+            | Op::Sh { rs1, rs2, .. }
+            | Op::Sw { rs1, rs2, .. } => (0, rs1, rs2),
+            Op::Add { rd, rs1, rs2 }
+            | Op::Sub { rd, rs1, rs2 }
+            | Op::Sll { rd, rs1, rs2 }
+            | Op::Slt { rd, rs1, rs2 }
+            | Op::Sltu { rd, rs1, rs2 }
+            | Op::Xor { rd, rs1, rs2 }
+            | Op::Srl { rd, rs1, rs2 }
+            | Op::Sra { rd, rs1, rs2 }
+            | Op::Or { rd, rs1, rs2 }
+            | Op::And { rd, rs1, rs2 } => (rd, rs1, rs2),
+            // the immediate forms encode the immediate in the rs1 field
+            Op::Csr { rd, rs1, funct3, .. } if funct3 & 0b100 == 0 => (rd, rs1, 0),
+            Op::Csr { rd, .. } => (rd, 0, 0),
+            Op::Fence
+            | Op::FenceI
+            | Op::Ecall
+            | Op::Ebreak
+            | Op::Mret
+            | Op::Wfi
+            | Op::Illegal(_) => (0, 0, 0),
+        }
+    }
+
+    /// Returns whether a translated block has to end after this operation, because it may
+    /// change control flow or invalidate translated code.
+    pub(crate) fn ends_block(&self) -> bool {
+        matches!(
+            self,
+            Op::Jal { .. }
+                | Op::Jalr { .. }
+                | Op::Beq { .. }
+                | Op::Bne { .. }
+                | Op::Blt { .. }
+                | Op::Bge { .. }
+                | Op::Bltu { .. }
+                | Op::Bgeu { .. }
+                | Op::FenceI
+                | Op::Ecall
+                | Op::Ebreak
+                | Op::Mret
+                | Op::Csr { .. }
+                | Op::Illegal(_)
+        )
+    }
+}
@@ -1,7 +1,7 @@
 use num_traits::AsPrimitive;
 
 use crate::cpu::isa::rv32i::RV32I;
-use crate::cpu::isa::Isa;
+use crate::cpu::isa::{Isa, Op};
 use crate::cpu::{CPUError, Cpu};
 
 pub struct RV32E(());
@@ -13,9 +13,21 @@ impl Isa<16> for RV32E {
     const ISA_ID: &'static str = "RV32E";
     const INSN_SIZE: Self::XlenU = 4;
 
+    fn decode(instruction: u32) -> Op {
+        let op = RV32I::decode(instruction);
+
+        // RV32E only has the registers x0 - x15
+        let (rd, rs1, rs2) = op.registers();
+        if rd.max(rs1).max(rs2) >= 16 {
+            Op::Illegal(instruction)
+        } else {
+            op
+        }
+    }
+
     fn exec<const REG_COUNT: usize, I: Isa<REG_COUNT>>(
         cpu: &mut Cpu<I, REG_COUNT>,
-        instruction: u32,
+        op: Op,
     ) -> Result<(), CPUError<I::XlenU>>
     where
         bool: AsPrimitive<I::XlenU>,
@@ -32,6 +44,6 @@ impl Isa<16> for RV32E {
         I::XlenU: AsPrimitive<u16>,
         I::XlenU: AsPrimitive<u32>,
     {
-        RV32I::exec(cpu, instruction)
+        RV32I::exec(cpu, op)
     }
 }
@@ -1,12 +1,11 @@
 use std::ops::{BitAnd, BitOr, BitXor, Shl, Shr};
 
 use num_traits::ops::overflowing::OverflowingAdd;
-use num_traits::{AsPrimitive, Bounded, PrimInt, WrappingAdd, WrappingSub, Zero};
+use num_traits::{AsPrimitive, Bounded, WrappingAdd, WrappingSub, Zero};
 
 use crate::cpu::isa::As;
-use crate::cpu::isa::Isa;
+use crate::cpu::isa::{Isa, Op};
 use crate::cpu::{CPUError, Cpu};
-use crate::memory::Memory;
 
 #[derive(PartialEq)]
 pub struct RV32I(());
@@ -18,274 +17,394 @@ impl Isa<32> for RV32I {
 
     const INSN_SIZE: Self::XlenU = 4;
 
-    fn exec<const REG_COUNT: usize, I: Isa<REG_COUNT>>(
-        cpu: &mut Cpu<I, REG_COUNT>,
-        instruction: u32,
-    ) -> Result<(), CPUError<I::XlenU>>
-    where
-        bool: AsPrimitive<I::XlenU>,
-        u8: AsPrimitive<I::XlenU>,
-        i8: AsPrimitive<I::XlenU>,
-        u16: AsPrimitive<I::XlenU>,
-        u32: AsPrimitive<I::XlenU>,
-        i32: AsPrimitive<I::XlenU>,
-        i8: AsPrimitive<I::XlenI>,
-        i16: AsPrimitive<I::XlenI>,
-        u32: AsPrimitive<I::XlenI>,
-        i32: AsPrimitive<I::XlenI>,
-        I::XlenU: AsPrimitive<u8>,
-        I::XlenU: AsPrimitive<u16>,
-        I::XlenU: AsPrimitive<u32>,
-    {
-        let rd = ((instruction >> 7) & 0x1F) as usize;
-        let rs1 = ((instruction >> 15) & 0x1F) as usize;
-        let rs2 = ((instruction >> 20) & 0x1F) as usize;
+    fn decode(instruction: u32) -> Op {
+        let rd = ((instruction >> 7) & 0x1F) as u8;
+        let rs1 = ((instruction >> 15) & 0x1F) as u8;
+        let rs2 = ((instruction >> 20) & 0x1F) as u8;
 
         let opcode = instruction & 0x7F; // opcode [6:0]
 
-        let funct3 = ((instruction >> 12) & 0x7) as usize; // [14:12]
-        let funct7 = ((instruction >> 25) & 0x7F) as usize; // [31:25]
+        let funct3 = (instruction >> 12) & 0x7; // [14:12]
+        let funct7 = (instruction >> 25) & 0x7F; // [31:25]
 
-        // set x0 to 0 to emulate x0 hardwired to all zeroes
-        cpu.registers[0] = I::XlenU::zero();
+        // sign extended immediate [31:20]
+        let imm_i = (instruction & 0xFFF0_0000) as i32 >> 20;
 
-        // decode and execute instruction
         match opcode {
-            _ if instruction == 0xFFFF_FFFF => {
-                println!(
-                    "ISA: {} bits={} insn_size={} reg_count={}",
-                    I::ISA_ID,
-                    I::XlenU::max_value().count_ones(),
-                    I::INSN_SIZE.as_t::<usize>(),
-                    REG_COUNT
-                )
-            }
             // LUI
-            0b011_0111 => {
-                let imm = (instruction & 0xFFFF_F000).as_t::<I::XlenU>(); // TODO sign extend for 64bit?
-                cpu.registers[rd] = imm;
-            }
+            0b011_0111 => Op::Lui {
+                rd,
+                imm: instruction & 0xFFFF_F000, // TODO sign extend for 64bit?
+            },
             // AUIPC
-            0b001_0111 => {
-                let imm = (instruction & 0xFFFF_F000).as_t::<I::XlenU>(); // TODO sign extend for 64bit?
-                cpu.registers[rd] = cpu.pc + imm - I::INSN_SIZE;
-            }
+            0b001_0111 => Op::Auipc {
+                rd,
+                imm: instruction & 0xFFFF_F000, // TODO sign extend for 64bit?
+            },
             // JAL
             0b110_1111 => {
                 // [31][19:12][20][30:21]0  ins
                 //  20  19 12  11  10  1    target
-                let imm = ((instruction & 0x8000_0000) as i32 >> 11).as_t::<I::XlenI>().as_t::<I::XlenU>() // [31] -> [20]
-                    | (instruction & 0xF_F000).as_t::<I::XlenU>() // [12:19] -> [12:19]
-                    | (instruction & 0x10_0000).as_t::<I::XlenU>() >> 9
-                    | (instruction & 0x7FE0_0000).as_t::<I::XlenU>() >> 20;
+                let imm = (instruction & 0x8000_0000) as i32 >> 11 // [31] -> [20]
+                    | (instruction & 0xF_F000) as i32 // [12:19] -> [12:19]
+                    | ((instruction & 0x10_0000) >> 9) as i32
+                    | ((instruction & 0x7FE0_0000) >> 20) as i32;
 
-                cpu.registers[rd] = cpu.pc;
-                cpu.pc = (cpu.pc - I::INSN_SIZE).overflowing_add(&imm).0;
+                Op::Jal { rd, imm }
             }
             // JALR
-            0b110_0111 if funct3 == 0b000 => {
-                let imm = ((instruction & 0xFFF0_0000) as i32 >> 20)
-                    .as_t::<I::XlenI>()
-                    .as_t::<I::XlenU>(); // sign extended immediate [31:20]
-
-                cpu.registers[rd] = cpu.pc;
-                cpu.pc = cpu.registers[rs1].overflowing_add(&imm).0 & (I::XlenU::max_value() << 1);
-            }
+            0b110_0111 if funct3 == 0b000 => Op::Jalr { rd, rs1, imm: imm_i },
             // BRANCH
             0b110_0011 => {
                 // [31][7][30:25][11:8]0  ins
                 //  12  11 10  5  4  1    target
-                let imm = ((instruction & 0x8000_0000) as i32 >> 19)
-                    .as_t::<I::XlenI>()
-                    .as_t::<I::XlenU>()
-                    | ((instruction & 0x80).as_t::<I::XlenU>()) << 4
-                    | (instruction & 0x7E00_0000).as_t::<I::XlenU>() >> 20
-                    | (instruction & 0xF00).as_t::<I::XlenU>() >> 7;
+                let imm = (instruction & 0x8000_0000) as i32 >> 19
+                    | ((instruction & 0x80) << 4) as i32
+                    | ((instruction & 0x7E00_0000) >> 20) as i32
+                    | ((instruction & 0xF00) >> 7) as i32;
 
-                let funct3 = ((instruction >> 12) & 0x7) as usize; // [14:12]
                 match funct3 {
-                    // BEQ
-                    0b000 => {
-                        if cpu.registers[rs1] == cpu.registers[rs2] {
-                            cpu.pc = (cpu.pc - I::INSN_SIZE).overflowing_add(&imm).0
-                        }
-                    }
-                    // BNE
-                    0b001 => {
-                        if cpu.registers[rs1] != cpu.registers[rs2] {
-                            cpu.pc = (cpu.pc - I::INSN_SIZE).overflowing_add(&imm).0
-                        }
-                    }
-                    // BLT
-                    0b100 => {
-                        if cpu.registers[rs1].as_t::<I::XlenI>()
-                            < cpu.registers[rs2].as_t::<I::XlenI>()
-                        {
-                            cpu.pc = (cpu.pc - I::INSN_SIZE).overflowing_add(&imm).0
-                        }
-                    }
-                    // BGE
-                    0b101 => {
-                        if cpu.registers[rs1].as_t::<I::XlenI>()
-                            >= cpu.registers[rs2].as_t::<I::XlenI>()
-                        {
-                            cpu.pc = (cpu.pc - I::INSN_SIZE).overflowing_add(&imm).0
-                        }
-                    }
-                    // BLTU
-                    0b110 => {
-                        if cpu.registers[rs1] < cpu.registers[rs2] {
-                            cpu.pc = (cpu.pc - I::INSN_SIZE).overflowing_add(&imm).0
-                        }
-                    }
-                    // BGEU
-                    0b111 => {
-                        if cpu.registers[rs1] >= cpu.registers[rs2] {
-                            cpu.pc = (cpu.pc - I::INSN_SIZE).overflowing_add(&imm).0
-                        }
-                    }
-                    _ => return Err(CPUError::InstructionNotImplemented(instruction)),
+                    0b000 => Op::Beq { rs1, rs2, imm },
+                    0b001 => Op::Bne { rs1, rs2, imm },
+                    0b100 => Op::Blt { rs1, rs2, imm },
+                    0b101 => Op::Bge { rs1, rs2, imm },
+                    0b110 => Op::Bltu { rs1, rs2, imm },
+                    0b111 => Op::Bgeu { rs1, rs2, imm },
+                    _ => Op::Illegal(instruction),
                 }
             }
             // LOAD
-            0b000_0011 => {
-                let imm = ((instruction & 0xFFF0_0000) as i32 >> 20)
-                    .as_t::<I::XlenI>()
-                    .as_t::<I::XlenU>(); // sign extended immediate [31:20]
-                let address = cpu.registers[rs1].overflowing_add(&imm).0;
-
-                cpu.registers[rd] = match funct3 {
-                    // LB
-                    0b000 => cpu
-                        .bus
-                        .load_i8(address)?
-                        .as_t::<I::XlenI>()
-                        .as_t::<I::XlenU>(),
-                    // LH
-                    0b001 => cpu
-                        .bus
-                        .load_i16(address)?
-                        .as_t::<I::XlenI>()
-                        .as_t::<I::XlenU>(),
-                    // LW
-                    0b010 => cpu
-                        .bus
-                        .load_i32(address)?
-                        .as_t::<I::XlenI>()
-                        .as_t::<I::XlenU>(),
-                    // LBU
-                    0b100 => cpu.bus.load_u8(address)?.as_t::<I::XlenU>(),
-                    // LHU
-                    0b101 => cpu.bus.load_u16(address)?.as_t::<I::XlenU>(),
-                    _ => return Err(CPUError::InstructionNotImplemented(instruction)),
-                }
-            }
+            0b000_0011 => match funct3 {
+                0b000 => Op::Lb { rd, rs1, imm: imm_i },
+                0b001 => Op::Lh { rd, rs1, imm: imm_i },
+                0b010 => Op::Lw { rd, rs1, imm: imm_i },
+                0b100 => Op::Lbu { rd, rs1, imm: imm_i },
+                0b101 => Op::Lhu { rd, rs1, imm: imm_i },
+                _ => Op::Illegal(instruction),
+            },
             // STORE
             0b010_0011 => {
-                let imm = ((instruction & 0xFE00_0000) as i32 >> 20)
-                    .as_t::<I::XlenI>()
-                    .as_t::<I::XlenU>()
-                    | (instruction & 0xF80).as_t::<I::XlenU>() >> 7; // sign extended immediate [31:25][11:7]
-                let address = cpu.registers[rs1].overflowing_add(&imm).0;
+                // sign extended immediate [31:25][11:7]
+                let imm = (instruction & 0xFE00_0000) as i32 >> 20
+                    | ((instruction & 0xF80) >> 7) as i32;
 
                 match funct3 {
-                    // SB
-                    0b000 => cpu.bus.store_u8(address, cpu.registers[rs2].as_t::<u8>())?,
-                    // SH
-                    0b001 => cpu
-                        .bus
-                        .store_u16(address, cpu.registers[rs2].as_t::<u16>())?,
-                    // SW
-                    0b010 => cpu
-                        .bus
-                        .store_u32(address, cpu.registers[rs2].as_t::<u32>())?,
-                    _ => return Err(CPUError::InstructionNotImplemented(instruction)),
+                    0b000 => Op::Sb { rs1, rs2, imm },
+                    0b001 => Op::Sh { rs1, rs2, imm },
+                    0b010 => Op::Sw { rs1, rs2, imm },
+                    _ => Op::Illegal(instruction),
                 }
             }
             // OP-IMM
-            0b001_0011 => {
-                let imm = ((instruction & 0xFFF0_0000) as i32 >> 20)
-                    .as_t::<I::XlenI>()
-                    .as_t::<I::XlenU>(); // sign extended immediate [31:20]
+            0b001_0011 => match (funct7, funct3) {
+                (_, 0b000) => Op::Addi { rd, rs1, imm: imm_i },
+                (_, 0b010) => Op::Slti { rd, rs1, imm: imm_i },
+                (_, 0b011) => Op::Sltiu { rd, rs1, imm: imm_i },
+                (_, 0b100) => Op::Xori { rd, rs1, imm: imm_i },
+                (_, 0b110) => Op::Ori { rd, rs1, imm: imm_i },
+                (_, 0b111) => Op::Andi { rd, rs1, imm: imm_i },
+                (0b000_0000, 0b001) => Op::Slli { rd, rs1, shamt: rs2 },
+                (0b000_0000, 0b101) => Op::Srli { rd, rs1, shamt: rs2 },
+                (0b010_0000, 0b101) => Op::Srai { rd, rs1, shamt: rs2 },
+                _ => Op::Illegal(instruction),
+            },
+            // OP
+            0b011_0011 => match (funct7, funct3) {
+                (0b000_0000, 0b000) => Op::Add { rd, rs1, rs2 },
+                (0b010_0000, 0b000) => Op::Sub { rd, rs1, rs2 },
+                (0b000_0000, 0b001) => Op::Sll { rd, rs1, rs2 },
+                (0b000_0000, 0b010) => Op::Slt { rd, rs1, rs2 },
+                (0b000_0000, 0b011) => Op::Sltu { rd, rs1, rs2 },
+                (0b000_0000, 0b100) => Op::Xor { rd, rs1, rs2 },
+                (0b000_0000, 0b101) => Op::Srl { rd, rs1, rs2 },
+                (0b010_0000, 0b101) => Op::Sra { rd, rs1, rs2 },
+                (0b000_0000, 0b110) => Op::Or { rd, rs1, rs2 },
+                (0b000_0000, 0b111) => Op::And { rd, rs1, rs2 },
+                _ => Op::Illegal(instruction),
+            },
+            // MISC_MEM
+            0b000_1111 => match funct3 {
+                0b000 => Op::Fence,
+                // FENCE.I (Zifencei)
+                0b001 => Op::FenceI,
+                _ => Op::Illegal(instruction),
+            },
+            // SYSTEM
+            0b111_0011 => {
+                let bits_31_20 = (instruction >> 20) & 0xFFF;
+                match (bits_31_20, rs1, funct3, rd) {
+                    (0b0000_0000_0000, 0b0_0000, 0b000, 0b0_0000) => Op::Ecall,
+                    (0b0000_0000_0001, 0b0_0000, 0b000, 0b0_0000) => Op::Ebreak,
+                    (0b0011_0000_0010, 0b0_0000, 0b000, 0b0_0000) => Op::Mret,
+                    (0b0001_0000_0101, 0b0_0000, 0b000, 0b0_0000) => Op::Wfi,
+                    // CSRRW, CSRRS, CSRRC, CSRRWI, CSRRSI, CSRRCI (Zicsr)
+                    (csr, _, 0b001..=0b011 | 0b101..=0b111, _) => Op::Csr {
+                        rd,
+                        rs1,
+                        csr: csr as u16,
+                        funct3: funct3 as u8,
+                        instruction,
+                    },
+                    _ => Op::Illegal(instruction),
+                }
+            }
+            _ => Op::Illegal(instruction),
+        }
+    }
 
-                cpu.registers[rd] = match (funct7, funct3) {
-                    // ADDI
-                    (_, 0b000) => cpu.registers[rs1].wrapping_add(&imm),
-                    // SLTI
-                    (_, 0b010) => cpu.registers[rs1]
-                        .as_t::<I::XlenI>()
-                        .lt(&imm.as_t::<I::XlenI>())
-                        .as_t::<I::XlenU>(),
-                    // SLTIU
-                    (_, 0b011) => cpu.registers[rs1].lt(&imm).as_t::<I::XlenU>(),
-                    // XORI
-                    (_, 0b100) => cpu.registers[rs1].bitxor(imm),
-                    // ORI
-                    (_, 0b110) => cpu.registers[rs1].bitor(imm),
-                    // ANDI
-                    (_, 0b111) => cpu.registers[rs1].bitand(imm),
-                    // SLLI (logical left shift)
-                    (0b000_0000, 0b001) => cpu.registers[rs1].shl(rs2),
-                    // SRLI (logical right shift)
-                    (0b000_0000, 0b101) => cpu.registers[rs1].shr(rs2),
-                    // SRAI (arithmetic right shift)
-                    (0b010_0000, 0b101) => cpu.registers[rs1]
-                        .as_t::<I::XlenI>()
-                        .shr(rs2)
-                        .as_t::<I::XlenU>(),
-                    _ => return Err(CPUError::InstructionNotImplemented(instruction)),
+    #[inline(always)]
+    fn exec<const REG_COUNT: usize, I: Isa<REG_COUNT>>(
+        cpu: &mut Cpu<I, REG_COUNT>,
+        op: Op,
+    ) -> Result<(), CPUError<I::XlenU>>
+    where
+        bool: AsPrimitive<I::XlenU>,
+        u8: AsPrimitive<I::XlenU>,
+        i8: AsPrimitive<I::XlenU>,
+        u16: AsPrimitive<I::XlenU>,
+        u32: AsPrimitive<I::XlenU>,
+        i32: AsPrimitive<I::XlenU>,
+        i8: AsPrimitive<I::XlenI>,
+        i16: AsPrimitive<I::XlenI>,
+        u32: AsPrimitive<I::XlenI>,
+        i32: AsPrimitive<I::XlenI>,
+        I::XlenU: AsPrimitive<u8>,
+        I::XlenU: AsPrimitive<u16>,
+        I::XlenU: AsPrimitive<u32>,
+    {
+        let sext = |imm: i32| imm.as_t::<I::XlenI>().as_t::<I::XlenU>();
+        let r = |reg: u8| reg as usize;
+
+        // pc already points to the next instruction
+        let insn_pc = cpu.pc - I::INSN_SIZE;
+
+        match op {
+            Op::Lui { rd, imm } => cpu.set_register(rd, imm.as_t::<I::XlenU>()),
+            Op::Auipc { rd, imm } => cpu.set_register(rd, insn_pc + imm.as_t::<I::XlenU>()),
+            Op::Jal { rd, imm } => {
+                cpu.set_register(rd, cpu.pc);
+                cpu.pc = insn_pc.overflowing_add(&sext(imm)).0;
+            }
+            Op::Jalr { rd, rs1, imm } => {
+                let target = cpu.registers[r(rs1)].overflowing_add(&sext(imm)).0;
+
+                cpu.set_register(rd, cpu.pc);
+                cpu.pc = target & (I::XlenU::max_value() << 1);
+            }
+            Op::Beq { rs1, rs2, imm } => {
+                if cpu.registers[r(rs1)] == cpu.registers[r(rs2)] {
+                    cpu.pc = insn_pc.overflowing_add(&sext(imm)).0
                 }
             }
-            // OP
-            0b011_0011 => {
-                cpu.registers[rd] = match (funct7, funct3) {
-                    // ADD
-                    (0b000_0000, 0b000) => cpu.registers[rs1].wrapping_add(&cpu.registers[rs2]),
-                    // SUB
-                    (0b010_0000, 0b000) => cpu.registers[rs1].wrapping_sub(&cpu.registers[rs2]),
-                    // SLL (logical left shift)
-                    (0b000_0000, 0b001) => cpu.registers[rs1]
-                        .shl((cpu.registers[rs2] & 0b1_1111.as_t::<I::XlenU>()).as_t::<usize>()),
-                    // SLT (rs1 < rs2 signed)
-                    (0b000_0000, 0b010) => cpu.registers[rs1]
+            Op::Bne { rs1, rs2, imm } => {
+                if cpu.registers[r(rs1)] != cpu.registers[r(rs2)] {
+                    cpu.pc = insn_pc.overflowing_add(&sext(imm)).0
+                }
+            }
+            Op::Blt { rs1, rs2, imm } => {
+                if cpu.registers[r(rs1)].as_t::<I::XlenI>()
+                    < cpu.registers[r(rs2)].as_t::<I::XlenI>()
+                {
+                    cpu.pc = insn_pc.overflowing_add(&sext(imm)).0
+                }
+            }
+            Op::Bge { rs1, rs2, imm } => {
+                if cpu.registers[r(rs1)].as_t::<I::XlenI>()
+                    >= cpu.registers[r(rs2)].as_t::<I::XlenI>()
+                {
+                    cpu.pc = insn_pc.overflowing_add(&sext(imm)).0
+                }
+            }
+            Op::Bltu { rs1, rs2, imm } => {
+                if cpu.registers[r(rs1)] < cpu.registers[r(rs2)] {
+                    cpu.pc = insn_pc.overflowing_add(&sext(imm)).0
+                }
+            }
+            Op::Bgeu { rs1, rs2, imm } => {
+                if cpu.registers[r(rs1)] >= cpu.registers[r(rs2)] {
+                    cpu.pc = insn_pc.overflowing_add(&sext(imm)).0
+                }
+            }
+            Op::Lb { rd, rs1, imm } => {
+                let address = cpu.registers[r(rs1)].overflowing_add(&sext(imm)).0;
+                cpu.set_register(
+                    rd,
+                    cpu.load::<i8>(address)?
                         .as_t::<I::XlenI>()
-                        .lt(&cpu.registers[rs2].as_t::<I::XlenI>())
                         .as_t::<I::XlenU>(),
-                    // SLTU (rs1 < rs2 unsigned)
-                    (0b000_0000, 0b011) => (cpu.registers[rs1])
-                        .lt(&(cpu.registers[rs2]))
+                );
+            }
+            Op::Lh { rd, rs1, imm } => {
+                let address = cpu.registers[r(rs1)].overflowing_add(&sext(imm)).0;
+                cpu.set_register(
+                    rd,
+                    cpu.load::<i16>(address)?
+                        .as_t::<I::XlenI>()
                         .as_t::<I::XlenU>(),
-                    // XOR
-                    (0b000_0000, 0b100) => cpu.registers[rs1].bitxor(cpu.registers[rs2]),
-                    // SRL (logical right shift)
-                    (0b000_0000, 0b101) => cpu.registers[rs1]
-                        .shr((cpu.registers[rs2] & 0b1_1111.as_t::<I::XlenU>()).as_t::<usize>()),
-                    // SRA (arithmetic right shift)
-                    (0b010_0000, 0b101) => cpu.registers[rs1]
+                );
+            }
+            Op::Lw { rd, rs1, imm } => {
+                let address = cpu.registers[r(rs1)].overflowing_add(&sext(imm)).0;
+                cpu.set_register(
+                    rd,
+                    cpu.load::<i32>(address)?
                         .as_t::<I::XlenI>()
-                        .shr((cpu.registers[rs2] & 0b1_1111.as_t::<I::XlenU>()).as_t::<usize>())
                         .as_t::<I::XlenU>(),
-                    // OR
-                    (0b000_0000, 0b110) => cpu.registers[rs1].bitor(cpu.registers[rs2]),
-                    // AND
-                    (0b000_0000, 0b111) => cpu.registers[rs1].bitand(cpu.registers[rs2]),
-                    _ => return Err(CPUError::InstructionNotImplemented(instruction)),
-                }
+                );
             }
-            // MISC_MEM
-            0b000_1111 if funct3 == 0b000 => todo!("FENCE (RV32I"),
-            // SYSTEM
-            0b111_0011 => {
-                let bits_31_20 = ((instruction >> 20) & 0xFFF) as usize;
-                match (bits_31_20, rs1, funct3, rd) {
-                    // ECALL
-                    (0b0000_0000_0000, 0b0_0000, 0b000, 0b0_0000) => todo!("ECALL (RV32I"),
-                    // EBREAK
-                    (0b0000_0000_0001, 0b0_0000, 0b000, 0b0_0000) => todo!("EBREAK (RV32I"),
-                    _ => return Err(CPUError::InstructionNotImplemented(instruction)),
+            Op::Lbu { rd, rs1, imm } => {
+                let address = cpu.registers[r(rs1)].overflowing_add(&sext(imm)).0;
+                cpu.set_register(rd, cpu.load::<u8>(address)?.as_t::<I::XlenU>());
+            }
+            Op::Lhu { rd, rs1, imm } => {
+                let address = cpu.registers[r(rs1)].overflowing_add(&sext(imm)).0;
+                cpu.set_register(rd, cpu.load::<u16>(address)?.as_t::<I::XlenU>());
+            }
+            Op::Sb { rs1, rs2, imm } => {
+                let address = cpu.registers[r(rs1)].overflowing_add(&sext(imm)).0;
+                cpu.store::<u8>(address, cpu.registers[r(rs2)].as_t::<u8>())?
+            }
+            Op::Sh { rs1, rs2, imm } => {
+                let address = cpu.registers[r(rs1)].overflowing_add(&sext(imm)).0;
+                cpu.store::<u16>(address, cpu.registers[r(rs2)].as_t::<u16>())?
+            }
+            Op::Sw { rs1, rs2, imm } => {
+                let address = cpu.registers[r(rs1)].overflowing_add(&sext(imm)).0;
+                cpu.store::<u32>(address, cpu.registers[r(rs2)].as_t::<u32>())?
+            }
+            Op::Addi { rd, rs1, imm } => {
+                cpu.set_register(rd, cpu.registers[r(rs1)].wrapping_add(&sext(imm)))
+            }
+            Op::Slti { rd, rs1, imm } => cpu.set_register(
+                rd,
+                cpu.registers[r(rs1)]
+                    .as_t::<I::XlenI>()
+                    .lt(&imm.as_t::<I::XlenI>())
+                    .as_t::<I::XlenU>(),
+            ),
+            Op::Sltiu { rd, rs1, imm } => {
+                cpu.set_register(rd, cpu.registers[r(rs1)].lt(&sext(imm)).as_t::<I::XlenU>())
+            }
+            Op::Xori { rd, rs1, imm } => {
+                cpu.set_register(rd, cpu.registers[r(rs1)].bitxor(sext(imm)))
+            }
+            Op::Ori { rd, rs1, imm } => {
+                cpu.set_register(rd, cpu.registers[r(rs1)].bitor(sext(imm)))
+            }
+            Op::Andi { rd, rs1, imm } => {
+                cpu.set_register(rd, cpu.registers[r(rs1)].bitand(sext(imm)))
+            }
+            // logical left shift
+            Op::Slli { rd, rs1, shamt } => {
+                cpu.set_register(rd, cpu.registers[r(rs1)].shl(shamt as usize))
+            }
+            // logical right shift
+            Op::Srli { rd, rs1, shamt } => {
+                cpu.set_register(rd, cpu.registers[r(rs1)].shr(shamt as usize))
+            }
+            // arithmetic right shift
+            Op::Srai { rd, rs1, shamt } => cpu.set_register(
+                rd,
+                cpu.registers[r(rs1)]
+                    .as_t::<I::XlenI>()
+                    .shr(shamt as usize)
+                    .as_t::<I::XlenU>(),
+            ),
+            Op::Add { rd, rs1, rs2 } => cpu.set_register(
+                rd,
+                cpu.registers[r(rs1)].wrapping_add(&cpu.registers[r(rs2)]),
+            ),
+            Op::Sub { rd, rs1, rs2 } => cpu.set_register(
+                rd,
+                cpu.registers[r(rs1)].wrapping_sub(&cpu.registers[r(rs2)]),
+            ),
+            Op::Sll { rd, rs1, rs2 } => cpu.set_register(
+                rd,
+                cpu.registers[r(rs1)]
+                    .shl((cpu.registers[r(rs2)] & 0b1_1111.as_t::<I::XlenU>()).as_t::<usize>()),
+            ),
+            // rs1 < rs2 signed
+            Op::Slt { rd, rs1, rs2 } => cpu.set_register(
+                rd,
+                cpu.registers[r(rs1)]
+                    .as_t::<I::XlenI>()
+                    .lt(&cpu.registers[r(rs2)].as_t::<I::XlenI>())
+                    .as_t::<I::XlenU>(),
+            ),
+            // rs1 < rs2 unsigned
+            Op::Sltu { rd, rs1, rs2 } => cpu.set_register(
+                rd,
+                cpu.registers[r(rs1)]
+                    .lt(&cpu.registers[r(rs2)])
+                    .as_t::<I::XlenU>(),
+            ),
+            Op::Xor { rd, rs1, rs2 } => {
+                cpu.set_register(rd, cpu.registers[r(rs1)].bitxor(cpu.registers[r(rs2)]))
+            }
+            Op::Srl { rd, rs1, rs2 } => cpu.set_register(
+                rd,
+                cpu.registers[r(rs1)]
+                    .shr((cpu.registers[r(rs2)] & 0b1_1111.as_t::<I::XlenU>()).as_t::<usize>()),
+            ),
+            Op::Sra { rd, rs1, rs2 } => cpu.set_register(
+                rd,
+                cpu.registers[r(rs1)]
+                    .as_t::<I::XlenI>()
+                    .shr((cpu.registers[r(rs2)] & 0b1_1111.as_t::<I::XlenU>()).as_t::<usize>())
+                    .as_t::<I::XlenU>(),
+            ),
+            Op::Or { rd, rs1, rs2 } => {
+                cpu.set_register(rd, cpu.registers[r(rs1)].bitor(cpu.registers[r(rs2)]))
+            }
+            Op::And { rd, rs1, rs2 } => {
+                cpu.set_register(rd, cpu.registers[r(rs1)].bitand(cpu.registers[r(rs2)]))
+            }
+            // memory accesses are performed in program order, nothing to do
+            Op::Fence => {}
+            // stores already invalidate decoded instructions, flush anyway to be safe
+            Op::FenceI => cpu.flush_translations(),
+            Op::Ecall => cpu.ecall(insn_pc),
+            Op::Ebreak => return Err(CPUError::Breakpoint(insn_pc)),
+            Op::Mret => cpu.mret()?,
+            // interrupts are checked between blocks anyway, waiting for one is not required
+            Op::Wfi => {}
+            Op::Csr {
+                rd,
+                rs1,
+                csr,
+                funct3,
+                instruction,
+            } => {
+                let operand = if funct3 & 0b100 == 0 {
+                    cpu.registers[r(rs1)]
+                } else {
+                    (rs1 as u32).as_t::<I::XlenU>() // zero extended uimm [19:15]
+                };
+
+                // CSRRW(I) does not read if rd is x0, CSRRS(I)/CSRRC(I) do not write if rs1 is x0
+                let swap = funct3 & 0b11 == 0b01;
+                let old = if !swap || rd != 0 {
+                    cpu.read_csr(csr)
+                        .ok_or(CPUError::InstructionNotImplemented(instruction))?
+                } else {
+                    I::XlenU::zero()
+                };
+
+                if swap || rs1 != 0 {
+                    let new = match funct3 & 0b11 {
+                        0b01 => operand,
+                        0b10 => old | operand,
+                        _ => old & !operand,
+                    };
+                    cpu.write_csr(csr, new)
+                        .ok_or(CPUError::InstructionNotImplemented(instruction))?;
                 }
+
+                cpu.set_register(rd, old);
+            }
+            Op::Illegal(instruction) => {
+                return Err(CPUError::InstructionNotImplemented(instruction))
             }
-            _ => return Err(CPUError::InstructionNotImplemented(instruction)),
         }
 
         Ok(())
@@ -1,7 +1,7 @@
 use num_traits::AsPrimitive;
 
 use crate::cpu::isa::rv32i::RV32I;
-use crate::cpu::isa::Isa;
+use crate::cpu::isa::{Isa, Op};
 use crate::cpu::{CPUError, Cpu};
 
 pub struct RV64I(());
@@ -12,9 +12,13 @@ impl Isa<32> for RV64I {
     const ISA_ID: &'static str = "RV64I";
     const INSN_SIZE: Self::XlenU = 4;
 
+    fn decode(instruction: u32) -> Op {
+        RV32I::decode(instruction)
+    }
+
     fn exec<const REG_COUNT: usize, I: Isa<REG_COUNT>>(
         cpu: &mut Cpu<I, REG_COUNT>,
-        instruction: u32,
+        op: Op,
     ) -> Result<(), CPUError<I::XlenU>>
     where
         bool: AsPrimitive<I::XlenU>,
@@ -31,7 +35,7 @@ impl Isa<32> for RV64I {
         I::XlenU: AsPrimitive<u16>,
         I::XlenU: AsPrimitive<u32>,
     {
-        let res = RV32I::exec(cpu, instruction);
+        let res = RV32I::exec(cpu, op);
 
         if let Err(CPUError::InstructionNotImplemented(_)) = res {
             todo!("")
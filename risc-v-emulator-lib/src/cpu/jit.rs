@@ -0,0 +1,312 @@
+//! Compiles translated blocks to x86-64 machine code.
+//!
+//! Only instructions that can not trap are compiled: runs of integer register operations are
+//! turned into native functions operating directly on the register file, everything else is
+//! left to the interpreter. Compiled code is owned by the [`BlockCache`](super::block_cache),
+//! so it is dropped together with the blocks it was compiled from.
+
+use std::io;
+use std::rc::Rc;
+
+use memmap2::{Mmap, MmapMut};
+
+use crate::cpu::isa::Op;
+
+/// Number of times a block has to be executed before it is compiled.
+pub(crate) const HOT_THRESHOLD: u32 = 32;
+
+/// Size of the executable memory chunks compiled code is placed in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Compiled code, called with a pointer to the register file.
+pub(crate) type NativeFn = unsafe extern "sysv64" fn(registers: *mut u8);
+
+/// A part of a compiled block.
+#[derive(Copy, Clone)]
+pub(crate) enum Segment {
+    /// `len` instructions compiled to native code.
+    Native { code: NativeFn, len: usize },
+    /// A single instruction left to the interpreter, index into the block cache's ops.
+    Interpreted { op: usize },
+}
+
+pub(crate) struct Jit {
+    /// Chunk code is currently appended to.
+    current: Option<Mmap>,
+    used: usize,
+    /// Chunks that are full but may still be referenced.
+    full: Vec<Mmap>,
+}
+
+impl Jit {
+    pub(crate) fn new() -> Jit {
+        Self {
+            current: None,
+            used: 0,
+            full: Vec::new(),
+        }
+    }
+
+    /// Compiles the block made of `ops`, whose first instruction is at `pc` and whose first op
+    /// has index `first_op` in the block cache.
+    ///
+    /// `xlen` is the register width in bytes, registers outside of `reg_count` are never
+    /// accessed by compiled code.
+    ///
+    /// On error, code compiled before may have been unmapped and must not be called anymore.
+    pub(crate) fn compile(
+        &mut self,
+        ops: &[Op],
+        first_op: usize,
+        pc: u64,
+        xlen: usize,
+        reg_count: usize,
+    ) -> io::Result<Rc<[Segment]>> {
+        let mut segments = Vec::new();
+        let mut i = 0;
+
+        while i < ops.len() {
+            let mut emitter = Emitter::new(xlen == 8);
+            let mut len = 0;
+            while i + len < ops.len() {
+                let insn_pc = pc.wrapping_add(4 * (i + len) as u64);
+                if !emitter.emit(ops[i + len], insn_pc, reg_count) {
+                    break;
+                }
+                len += 1;
+            }
+
+            // a call is only worth it for more than a single instruction
+            if len >= 2 {
+                emitter.ret();
+                let code = self.install(&emitter.code)?;
+                segments.push(Segment::Native { code, len });
+                i += len;
+            } else {
+                segments.push(Segment::Interpreted { op: first_op + i });
+                i += 1;
+            }
+        }
+
+        Ok(segments.into())
+    }
+
+    /// Copies `code` to executable memory.
+    ///
+    /// On error the current chunk is unmapped, so all code compiled into it must be dropped.
+    fn install(&mut self, code: &[u8]) -> io::Result<NativeFn> {
+        assert!(code.len() <= CHUNK_SIZE);
+
+        if self.current.is_none() || self.used + code.len() > CHUNK_SIZE {
+            if let Some(full) = self.current.take() {
+                self.full.push(full);
+            }
+            self.current = Some(MmapMut::map_anon(CHUNK_SIZE)?.make_exec()?);
+            self.used = 0;
+        }
+
+        // the chunk is only writable while code is copied into it
+        let mut chunk = self.current.take().expect("chunk allocated above").make_mut()?;
+        chunk[self.used..self.used + code.len()].copy_from_slice(code);
+        let chunk = chunk.make_exec()?;
+
+        // SAFETY: the code was generated by `Emitter` and is a complete function following the
+        // sysv64 calling convention. The mapping is kept alive until `clear` is called, which
+        // only happens together with dropping all segments referring to it.
+        let native = unsafe {
+            std::mem::transmute::<*const u8, NativeFn>(chunk.as_ptr().add(self.used))
+        };
+
+        // keep functions 16 byte aligned
+        self.used = (self.used + code.len()).next_multiple_of(16);
+        self.current = Some(chunk);
+
+        Ok(native)
+    }
+
+    /// Drops all compiled code.
+    pub(crate) fn clear(&mut self) {
+        self.current = None;
+        self.used = 0;
+        self.full.clear();
+    }
+}
+
+/// Host register holding the first operand and the result.
+const EAX: u8 = 0;
+/// Host register holding the second operand.
+const ECX: u8 = 1;
+/// Host register holding the pointer to the register file.
+const RDI: u8 = 7;
+
+/// Emits x86-64 code for register operations, every operation loads its operands from the
+/// register file into eax/ecx (rax/rcx on RV64) and stores the result back.
+struct Emitter {
+    code: Vec<u8>,
+    /// 64 bit registers
+    wide: bool,
+}
+
+impl Emitter {
+    fn new(wide: bool) -> Emitter {
+        Self {
+            code: Vec::new(),
+            wide,
+        }
+    }
+
+    /// Emits `op`, returns false if it can not be compiled.
+    fn emit(&mut self, op: Op, pc: u64, reg_count: usize) -> bool {
+        let (rd, rs1, rs2) = op.registers();
+        if [rd, rs1, rs2].iter().any(|&r| r as usize >= reg_count) {
+            return false;
+        }
+
+        match op {
+            // writes to x0 are discarded
+            Op::Lui { rd: 0, .. }
+            | Op::Auipc { rd: 0, .. }
+            | Op::Addi { rd: 0, .. }
+            | Op::Slti { rd: 0, .. }
+            | Op::Sltiu { rd: 0, .. }
+            | Op::Xori { rd: 0, .. }
+            | Op::Ori { rd: 0, .. }
+            | Op::Andi { rd: 0, .. }
+            | Op::Slli { rd: 0, .. }
+            | Op::Srli { rd: 0, .. }
+            | Op::Srai { rd: 0, .. }
+            | Op::Add { rd: 0, .. }
+            | Op::Sub { rd: 0, .. }
+            | Op::Sll { rd: 0, .. }
+            | Op::Slt { rd: 0, .. }
+            | Op::Sltu { rd: 0, .. }
+            | Op::Xor { rd: 0, .. }
+            | Op::Srl { rd: 0, .. }
+            | Op::Sra { rd: 0, .. }
+            | Op::Or { rd: 0, .. }
+            | Op::And { rd: 0, .. }
+            | Op::Fence => return true,
+            // the immediate is zero extended
+            Op::Lui { imm, .. } => self.mov_imm(imm as u64),
+            Op::Auipc { imm, .. } => self.mov_imm(pc.wrapping_add(imm as u64)),
+            Op::Addi { rs1, imm, .. } => self.alu_imm(0x05, rs1, imm),
+            Op::Xori { rs1, imm, .. } => self.alu_imm(0x35, rs1, imm),
+            Op::Ori { rs1, imm, .. } => self.alu_imm(0x0D, rs1, imm),
+            Op::Andi { rs1, imm, .. } => self.alu_imm(0x25, rs1, imm),
+            // setl, setb
+            Op::Slti { rs1, imm, .. } => self.set_imm(0x9C, rs1, imm),
+            Op::Sltiu { rs1, imm, .. } => self.set_imm(0x92, rs1, imm),
+            // shl, shr, sar
+            Op::Slli { rs1, shamt, .. } => self.shift_imm(0xE0, rs1, shamt),
+            Op::Srli { rs1, shamt, .. } => self.shift_imm(0xE8, rs1, shamt),
+            Op::Srai { rs1, shamt, .. } => self.shift_imm(0xF8, rs1, shamt),
+            Op::Add { rs1, rs2, .. } => self.alu(0x01, rs1, rs2),
+            Op::Sub { rs1, rs2, .. } => self.alu(0x29, rs1, rs2),
+            Op::Xor { rs1, rs2, .. } => self.alu(0x31, rs1, rs2),
+            Op::Or { rs1, rs2, .. } => self.alu(0x09, rs1, rs2),
+            Op::And { rs1, rs2, .. } => self.alu(0x21, rs1, rs2),
+            Op::Slt { rs1, rs2, .. } => self.set(0x9C, rs1, rs2),
+            Op::Sltu { rs1, rs2, .. } => self.set(0x92, rs1, rs2),
+            Op::Sll { rs1, rs2, .. } => self.shift(0xE0, rs1, rs2),
+            Op::Srl { rs1, rs2, .. } => self.shift(0xE8, rs1, rs2),
+            Op::Sra { rs1, rs2, .. } => self.shift(0xF8, rs1, rs2),
+            _ => return false,
+        }
+
+        self.store(rd);
+        true
+    }
+
+    fn rex_w(&mut self) {
+        if self.wide {
+            self.code.push(0x48);
+        }
+    }
+
+    fn disp(reg: u8, wide: bool) -> [u8; 4] {
+        (reg as u32 * if wide { 8 } else { 4 }).to_le_bytes()
+    }
+
+    /// mov host, [rdi + reg], x0 reads as zero regardless of the register file.
+    fn load(&mut self, host: u8, reg: u8) {
+        if reg == 0 {
+            // xor host, host
+            self.code.extend([0x31, 0xC0 | host << 3 | host]);
+        } else {
+            self.rex_w();
+            self.code.extend([0x8B, 0x80 | host << 3 | RDI]);
+            self.code.extend(Self::disp(reg, self.wide));
+        }
+    }
+
+    /// mov [rdi + reg], eax
+    fn store(&mut self, reg: u8) {
+        self.rex_w();
+        self.code.extend([0x89, 0x80 | EAX << 3 | RDI]);
+        self.code.extend(Self::disp(reg, self.wide));
+    }
+
+    /// mov eax, imm
+    fn mov_imm(&mut self, imm: u64) {
+        if self.wide {
+            self.code.extend([0x48, 0xB8]);
+            self.code.extend(imm.to_le_bytes());
+        } else {
+            self.code.push(0xB8);
+            self.code.extend((imm as u32).to_le_bytes());
+        }
+    }
+
+    /// `opcode` eax, imm32 with the immediate sign extended to the register width
+    fn alu_imm(&mut self, opcode: u8, rs1: u8, imm: i32) {
+        self.load(EAX, rs1);
+        self.rex_w();
+        self.code.push(opcode);
+        self.code.extend(imm.to_le_bytes());
+    }
+
+    /// `opcode` eax, ecx
+    fn alu(&mut self, opcode: u8, rs1: u8, rs2: u8) {
+        self.load(EAX, rs1);
+        self.load(ECX, rs2);
+        self.rex_w();
+        self.code.extend([opcode, 0xC0 | ECX << 3 | EAX]);
+    }
+
+    /// cmp eax, imm32; set`cc` al; movzx eax, al
+    fn set_imm(&mut self, cc: u8, rs1: u8, imm: i32) {
+        self.alu_imm(0x3D, rs1, imm);
+        self.setcc(cc);
+    }
+
+    /// cmp eax, ecx; set`cc` al; movzx eax, al
+    fn set(&mut self, cc: u8, rs1: u8, rs2: u8) {
+        self.alu(0x39, rs1, rs2);
+        self.setcc(cc);
+    }
+
+    fn setcc(&mut self, cc: u8) {
+        self.code.extend([0x0F, cc, 0xC0, 0x0F, 0xB6, 0xC0]);
+    }
+
+    /// shl/shr/sar eax, imm8
+    fn shift_imm(&mut self, modrm: u8, rs1: u8, shamt: u8) {
+        self.load(EAX, rs1);
+        self.rex_w();
+        self.code.extend([0xC1, modrm, shamt]);
+    }
+
+    /// shl/shr/sar eax, cl with the shift amount masked to 5 bits like the interpreter does
+    fn shift(&mut self, modrm: u8, rs1: u8, rs2: u8) {
+        self.load(EAX, rs1);
+        self.load(ECX, rs2);
+        // and ecx, 0x1F
+        self.code.extend([0x83, 0xE1, 0x1F]);
+        self.rex_w();
+        self.code.extend([0xD3, modrm]);
+    }
+
+    fn ret(&mut self) {
+        self.code.push(0xC3);
+    }
+}
@@ -1,21 +1,78 @@
 use std::fmt::{Debug, Display, Formatter};
 use std::ops::Range;
 
-use num_traits::{AsPrimitive, Zero};
+use num_traits::{AsPrimitive, NumCast, WrappingAdd, Zero};
 
-use crate::cpu::isa::{As, Isa, Xlen};
-use crate::memory::{Bus, Dram, Memory};
+use crate::cpu::block_cache::{BlockCache, MAX_BLOCK_LEN};
+use crate::cpu::csr::TrapCsrs;
+use crate::cpu::decode_cache::DecodeCache;
+use crate::cpu::isa::{As, Isa, IsaInfo, Op, Xlen};
+use crate::cpu::pmp::Pmp;
+use crate::devices::virtio::{VirtioDevice, VirtioMmio};
+use crate::devices::{
+    Clint, Framebuffer, GoldfishRtc, HartIrqs, Htif, IrqLine, Plic, TestFinisher, Timebase, Uart,
+    WallClock,
+};
+use crate::elf::{Elf, ElfError};
+use crate::memory::{
+    Access, AccessType, Bus, Memory, MisalignedAccess, Permissions, Rom, SparseDram,
+};
 
+mod block_cache;
+mod csr;
+mod decode_cache;
 pub mod isa;
+#[cfg(all(feature = "jit", target_arch = "x86_64"))]
+mod jit;
+pub mod pmp;
 #[cfg(test)]
 mod test;
+mod trap;
+mod virt;
+
+// memory map of the machine built by `Cpu::with_code`, as on qemu's virt machine
+const ROM_BASE: usize = 0x1000;
+/// Granularity of the boot rom, whose size follows the image.
+const ROM_ALIGN: usize = 0x1000;
+const FINISHER_BASE: usize = 0x10_0000;
+const FINISHER_SIZE: usize = 0x1000;
+const RTC_BASE: usize = 0x10_1000;
+const RTC_SIZE: usize = 0x1000;
+const RTC_IRQ: usize = 11;
+const CLINT_BASE: usize = 0x200_0000;
+const CLINT_SIZE: usize = 0x1_0000;
+const PLIC_BASE: usize = 0xC00_0000;
+const PLIC_SIZE: usize = 0x400_0000;
+const PLIC_SOURCES: usize = 96;
+const UART_BASE: usize = 0x1000_0000;
+const UART_SIZE: usize = 0x100;
+const UART_IRQ: usize = 10;
+const VIRTIO_BASE: usize = 0x1000_1000;
+const VIRTIO_SIZE: usize = 0x1000;
+const VIRTIO_SLOTS: usize = 8;
+const FRAMEBUFFER_BASE: usize = 0x5000_0000;
+const FRAMEBUFFER_SIZE: usize = 0x1000_0000;
+const DRAM_BASE: usize = 0x8000_0000;
+const DRAM_SIZE: usize = 1024 * 1024 * 128;
 
 #[derive(Debug)]
 pub enum CPUError<A> {
     // TODO handle these errors the way they are supposed to be handled according to RISC-V Spec
     InstructionNotImplemented(u32),
     AddressNotMapped(A),
+    InstructionAddressMisaligned(A),
+    InstructionAccessFault(A),
+    LoadAddressMisaligned(A),
+    LoadAccessFault(A),
+    StoreAddressMisaligned(A),
+    StoreAccessFault(A),
     InvalidAccessSize(u64),
+    /// An `EBREAK` at the given address was executed
+    Breakpoint(A),
+    /// The guest powered the machine off with the given exit code
+    PowerOff(u32),
+    /// The guest requested a reset of the machine
+    ResetRequested,
 }
 
 impl<A: Xlen> Display for CPUError<A> {
@@ -27,13 +84,79 @@ impl<A: Xlen> Display for CPUError<A> {
             CPUError::AddressNotMapped(address) => {
                 write!(f, "Nothing is mapped to address {address:#018X}!")
             }
+            CPUError::InstructionAddressMisaligned(address) => {
+                write!(f, "Instruction address {address:#018X} is misaligned!")
+            }
+            CPUError::InstructionAccessFault(address) => {
+                write!(f, "Instruction access fault at address {address:#018X}!")
+            }
+            CPUError::LoadAddressMisaligned(address) => {
+                write!(f, "Load address {address:#018X} is misaligned!")
+            }
+            CPUError::LoadAccessFault(address) => {
+                write!(f, "Load access fault at address {address:#018X}!")
+            }
+            CPUError::StoreAddressMisaligned(address) => {
+                write!(f, "Store address {address:#018X} is misaligned!")
+            }
+            CPUError::StoreAccessFault(address) => {
+                write!(f, "Store access fault at address {address:#018X}!")
+            }
             CPUError::InvalidAccessSize(size) => {
                 write!(f, "Can not read {size} bits!")
             }
+            CPUError::Breakpoint(address) => {
+                write!(f, "Breakpoint at address {address:#018X}!")
+            }
+            CPUError::PowerOff(code) => {
+                write!(f, "Powered off with exit code {code}!")
+            }
+            CPUError::ResetRequested => {
+                write!(f, "Reset requested!")
+            }
         }
     }
 }
 
+/// Why one of the `run` methods of [`Cpu`] returned.
+#[derive(Debug)]
+pub enum StopReason<A> {
+    /// The cpu is stuck in a jump or branch to itself.
+    Halted,
+    /// The guest powered the machine off with the given exit code, e.g. through a
+    /// [`TestFinisher`](crate::devices::TestFinisher).
+    PowerOff(u32),
+    /// An `EBREAK` at the given address was executed, or the address passed to
+    /// [`Cpu::run_until`] was reached.
+    Breakpoint(A),
+    /// Executing an instruction failed, pc points after it.
+    Error(CPUError<A>),
+    /// The number of instructions passed to [`Cpu::run_for`] has been executed.
+    BudgetExhausted,
+    /// The predicate passed to [`Cpu::run_while`] returned false.
+    HostRequest,
+}
+
+impl<A: Xlen> Display for StopReason<A> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StopReason::Halted => write!(f, "Halted!"),
+            StopReason::PowerOff(code) => write!(f, "Powered off with exit code {code}!"),
+            StopReason::Breakpoint(address) => write!(f, "Breakpoint at {address:#018X}!"),
+            StopReason::Error(e) => write!(f, "Error: {e}"),
+            StopReason::BudgetExhausted => write!(f, "Instruction budget exhausted!"),
+            StopReason::HostRequest => write!(f, "Stopped by host!"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
 #[derive(PartialEq)]
 pub struct RegisterDump<I: Isa<REG_COUNT>, const REG_COUNT: usize> {
     pc: Option<I::XlenU>,
@@ -104,13 +227,150 @@ pub struct Cpu<I: Isa<REG_COUNT>, const REG_COUNT: usize> {
     pub(crate) pc: I::XlenU,
     pub(crate) bus: Bus<I::XlenU>,
     pub(crate) registers: [I::XlenU; REG_COUNT],
+    pub(crate) privilege: Privilege,
+    pub(crate) pmp: Pmp<I::XlenU>,
+    pub(crate) trap_csrs: TrapCsrs,
+    pub(crate) decode_cache: DecodeCache<I::XlenU>,
+    block_cache: BlockCache<I::XlenU>,
+    retired: u64,
+    htif: Option<Htif<I::XlenU>>,
+    irqs: HartIrqs,
+    /// Unused virtio-mmio slots of the machine and their interrupt lines
+    virtio_slots: Vec<(Range<I::XlenU>, IrqLine)>,
+    /// Region of the machine reserved for a framebuffer, while none is attached
+    framebuffer_slot: Option<Range<I::XlenU>>,
     dram_mapping: Range<I::XlenU>,
+    reset_vector: I::XlenU,
+    /// Boot layout of a machine built by `Cpu::virt`, whose device tree follows the devices
+    boot: Option<virt::Boot>,
 }
 
 impl<I: Isa<REG_COUNT>, const REG_COUNT: usize> Cpu<I, REG_COUNT> {
     pub fn get_isa_id(&self) -> &'static str {
         I::ISA_ID
     }
+
+    /// Describes the isa this cpu implements.
+    pub fn isa_info(&self) -> IsaInfo {
+        IsaInfo {
+            id: I::ISA_ID,
+            xlen: (std::mem::size_of::<I::XlenU>() * 8) as u32,
+            insn_size: I::INSN_SIZE.as_t(),
+            reg_count: REG_COUNT,
+        }
+    }
+
+    pub fn privilege(&self) -> Privilege {
+        self.privilege
+    }
+
+    pub fn pmp(&self) -> &Pmp<I::XlenU> {
+        &self.pmp
+    }
+
+    /// Writes `value` to register `reg`, writes to x0 are discarded since it is hardwired to
+    /// zero.
+    #[inline(always)]
+    pub(crate) fn set_register(&mut self, reg: u8, value: I::XlenU) {
+        if reg != 0 {
+            self.registers[reg as usize] = value;
+        }
+    }
+
+    pub(crate) fn load<T: Access>(&self, addr: I::XlenU) -> Result<T, CPUError<I::XlenU>> {
+        self.check_pmp(addr, T::SIZE, AccessType::Load)?;
+        T::load(&self.bus, addr)
+    }
+
+    pub(crate) fn store<T: Access>(
+        &mut self,
+        addr: I::XlenU,
+        value: T,
+    ) -> Result<(), CPUError<I::XlenU>> {
+        self.check_pmp(addr, T::SIZE, AccessType::Store)?;
+        value.store(&mut self.bus, addr)?;
+
+        // self-modifying code has to be decoded again
+        self.decode_cache.invalidate(addr, T::SIZE);
+        self.block_cache.invalidate(addr, T::SIZE);
+
+        match &mut self.htif {
+            Some(htif) if htif.is_command(addr, T::SIZE) => htif.handle(&mut self.bus),
+            _ => Ok(()),
+        }
+    }
+
+    /// Attaches a host-target interface, replacing the current one.
+    pub fn set_htif(&mut self, htif: Htif<I::XlenU>) {
+        self.htif = Some(htif);
+    }
+
+    /// Replaces the interrupt lines of the cpu, devices on the bus raise interrupts through
+    /// lines handed out by `irqs`.
+    pub fn set_irqs(&mut self, irqs: HartIrqs) {
+        self.irqs = irqs;
+    }
+
+    /// Maps `device` on the next free virtio-mmio slot of the machine, returns its base address
+    /// or `None` if there is no free slot.
+    pub fn attach_virtio(
+        &mut self,
+        device: impl VirtioDevice<I::XlenU> + 'static,
+    ) -> Option<I::XlenU> {
+        if self.virtio_slots.is_empty() {
+            return None;
+        }
+
+        let (mapping, irq) = self.virtio_slots.remove(0);
+        let transport = VirtioMmio::new(mapping.end - mapping.start, Box::new(device), irq);
+        self.bus
+            .insert(mapping.clone(), Box::new(transport), Permissions::RW);
+        self.update_device_tree();
+
+        Some(mapping.start)
+    }
+
+    /// Maps `framebuffer` at the start of the framebuffer slot of the machine, returns its base
+    /// address or `None` if there is no free slot or the framebuffer does not fit.
+    pub fn attach_framebuffer(
+        &mut self,
+        framebuffer: Framebuffer<I::XlenU>,
+    ) -> Option<I::XlenU> {
+        let slot = self
+            .framebuffer_slot
+            .take_if(|slot| framebuffer.size() <= slot.end - slot.start)?;
+        self.bus.insert(
+            slot.start..slot.start + framebuffer.size(),
+            Box::new(framebuffer),
+            Permissions::RW,
+        );
+
+        Some(slot.start)
+    }
+
+    /// Drops all decoded instructions and translated blocks.
+    pub(crate) fn flush_translations(&mut self) {
+        self.decode_cache.flush();
+        self.block_cache.flush();
+    }
+
+    /// Number of instructions executed successfully since the last reset.
+    pub fn instructions_retired(&self) -> u64 {
+        self.retired
+    }
+
+    fn check_pmp(
+        &self,
+        addr: I::XlenU,
+        size: usize,
+        access: AccessType,
+    ) -> Result<(), CPUError<I::XlenU>> {
+        if self.pmp.check(addr, size, access, self.privilege) {
+            Ok(())
+        } else {
+            Err(access.access_fault(addr))
+        }
+    }
 }
 
 impl<I: Isa<REG_COUNT>, const REG_COUNT: usize> Cpu<I, REG_COUNT>
@@ -135,6 +395,18 @@ where
             pc: I::XlenU::zero(),
             bus,
             registers: [I::XlenU::zero(); REG_COUNT],
+            privilege: Privilege::Machine,
+            pmp: Pmp::new(),
+            trap_csrs: TrapCsrs::default(),
+            decode_cache: DecodeCache::new(),
+            block_cache: BlockCache::new(),
+            retired: 0,
+            htif: None,
+            irqs: HartIrqs::new(),
+            virtio_slots: Vec::new(),
+            framebuffer_slot: None,
+            reset_vector: dram_mapping.start,
+            boot: None,
             dram_mapping,
         };
 
@@ -143,33 +415,394 @@ where
         cpu
     }
 
+    /// Creates a cpu starting execution at `reset_vector` instead of the start of dram.
+    pub fn with_reset_vector(
+        bus: Bus<I::XlenU>,
+        dram_mapping: Range<I::XlenU>,
+        reset_vector: I::XlenU,
+    ) -> Cpu<I, REG_COUNT> {
+        let mut cpu = Self::new(bus, dram_mapping);
+        cpu.reset_vector = reset_vector;
+        cpu.reset();
+
+        cpu
+    }
+
+    /// Creates a cpu with `code` loaded to the start of dram, a [`TestFinisher`] at `0x10_0000`,
+    /// a [`GoldfishRtc`] at `0x10_1000` using interrupt 11, a [`Clint`] counting retired
+    /// instructions at `0x200_0000`, a [`Plic`] at `0xC00_0000` and a [`Uart`] connected to
+    /// stdio at `0x1000_0000` using interrupt 10, as on qemu's virt machine. Eight virtio-mmio
+    /// slots follow the uart in steps of `0x1000`, see [`attach_virtio`](Self::attach_virtio),
+    /// and `0x5000_0000` is reserved for up to 256 MiB of framebuffer, see
+    /// [`attach_framebuffer`](Self::attach_framebuffer).
     pub fn with_code(code: &[u8]) -> Cpu<I, REG_COUNT> {
-        const DRAM_BASE: usize = 0x8000_0000;
-        const DRAM_SIZE: usize = 1024 * 1024 * 128;
+        Self::machine(None, code)
+    }
+
+    /// Creates a cpu like [`with_code`](Self::with_code) running the ELF executable `data`,
+    /// see [`load_elf`](Self::load_elf).
+    pub fn with_elf(data: &[u8]) -> Result<Cpu<I, REG_COUNT>, ElfError> {
+        let elf = Elf::parse(data)?;
+        if elf.xlen as usize != 8 * std::mem::size_of::<I::XlenU>() {
+            return Err(ElfError::Unsupported(
+                "register width does not match the isa",
+            ));
+        }
 
-        Cpu::new(
-            Bus::new(vec![(
+        let mut cpu = Self::with_code(&[]);
+        cpu.load_elf(&elf)?;
+
+        Ok(cpu)
+    }
+
+    /// Loads the segments of `elf` and resets the cpu to start at its entry point. An [`Htif`]
+    /// is attached if the executable defines `tohost`.
+    pub fn load_elf(&mut self, elf: &Elf) -> Result<(), ElfError> {
+        for segment in &elf.segments {
+            let zeros = std::iter::repeat_n(0, (segment.mem_size - segment.data.len() as u64) as usize);
+            for (i, byte) in segment.data.iter().copied().chain(zeros).enumerate() {
+                let addr = segment.address.wrapping_add(i as u64);
+                <I::XlenU as NumCast>::from(addr)
+                    .and_then(|addr| self.bus.store_u8(addr, byte).ok())
+                    .ok_or(ElfError::SegmentNotMapped(addr))?;
+            }
+        }
+        self.flush_translations();
+
+        self.reset_vector = <I::XlenU as NumCast>::from(elf.entry)
+            .ok_or(ElfError::Unsupported("entry point out of range"))?;
+        if let Some(htif) = Htif::from_elf(elf) {
+            self.htif = Some(htif);
+        }
+        self.reset();
+
+        Ok(())
+    }
+
+    /// Creates a cpu booting from a read-only `rom` image at `0x1000`, with `code` loaded into
+    /// dram and the devices of [`with_code`](Self::with_code). Returns `None` if the rom does not
+    /// fit below the test finisher, i.e. is larger than 1020 KiB.
+    pub fn with_boot_rom(rom: &[u8], code: &[u8]) -> Option<Cpu<I, REG_COUNT>> {
+        (rom.len() <= FINISHER_BASE - ROM_BASE).then(|| Self::machine(Some(rom), code))
+    }
+
+    /// Builds the machine of [`with_code`](Self::with_code), resetting to the start of `rom`
+    /// mapped at [`ROM_BASE`] if there is one and to the start of dram otherwise.
+    fn machine(rom: Option<&[u8]>, code: &[u8]) -> Cpu<I, REG_COUNT> {
+        // the plic drives the M- and S-mode external interrupts of hart 0
+        let irqs = HartIrqs::new();
+        let clint = Clint::new(
+            CLINT_SIZE.as_t(),
+            Timebase::Instructions(1),
+            irqs.line(3),
+            irqs.line(7),
+        );
+        let plic = Plic::new(
+            PLIC_SIZE.as_t(),
+            PLIC_SOURCES,
+            vec![irqs.line(11), irqs.line(9)],
+        );
+        let uart = Uart::new(UART_SIZE.as_t()).with_irq(plic.irq_line(UART_IRQ));
+        let rtc = GoldfishRtc::new(RTC_SIZE.as_t(), plic.irq_line(RTC_IRQ));
+        // devices are attached later, using interrupts 1 to 8
+        let virtio_slots = (0..VIRTIO_SLOTS)
+            .map(|i| {
+                let base = VIRTIO_BASE + i * VIRTIO_SIZE;
+                (base.as_t()..(base + VIRTIO_SIZE).as_t(), plic.irq_line(1 + i))
+            })
+            .collect();
+
+        let mut mem_map: Vec<(_, Box<dyn Memory<I::XlenU>>, _)> = Vec::new();
+        if let Some(rom) = rom {
+            // whole pages, the rom is not empty even for an empty image
+            let size = rom.len().max(1).next_multiple_of(ROM_ALIGN);
+            mem_map.push((
+                ROM_BASE.as_t()..(ROM_BASE + size).as_t(),
+                Box::new(Rom::with_data(rom, size.as_t())),
+                Permissions::RX,
+            ));
+        }
+        mem_map.extend([
+            (
+                FINISHER_BASE.as_t()..(FINISHER_BASE + FINISHER_SIZE).as_t(),
+                Box::new(TestFinisher::new(FINISHER_SIZE.as_t())) as Box<dyn Memory<_>>,
+                Permissions::RW,
+            ),
+            (
+                RTC_BASE.as_t()..(RTC_BASE + RTC_SIZE).as_t(),
+                Box::new(rtc),
+                Permissions::RW,
+            ),
+            (
+                CLINT_BASE.as_t()..(CLINT_BASE + CLINT_SIZE).as_t(),
+                Box::new(clint),
+                Permissions::RW,
+            ),
+            (
+                PLIC_BASE.as_t()..(PLIC_BASE + PLIC_SIZE).as_t(),
+                Box::new(plic),
+                Permissions::RW,
+            ),
+            (
+                UART_BASE.as_t()..(UART_BASE + UART_SIZE).as_t(),
+                Box::new(uart),
+                Permissions::RW,
+            ),
+            (
                 DRAM_BASE.as_t()..(DRAM_BASE + DRAM_SIZE).as_t(),
-                Box::new(Dram::with_code(code, DRAM_SIZE.as_t())),
-            )]),
+                Box::new(SparseDram::with_code(code, DRAM_SIZE.as_t())),
+                Permissions::RWX,
+            ),
+        ]);
+
+        let reset_vector = if rom.is_some() { ROM_BASE } else { DRAM_BASE };
+        let mut cpu = Cpu::with_reset_vector(
+            Bus::with_permissions(mem_map),
             DRAM_BASE.as_t()..(DRAM_BASE + DRAM_SIZE).as_t(),
-        )
+            reset_vector.as_t(),
+        );
+        cpu.set_irqs(irqs);
+        cpu.virtio_slots = virtio_slots;
+        cpu.framebuffer_slot =
+            Some(FRAMEBUFFER_BASE.as_t()..(FRAMEBUFFER_BASE + FRAMEBUFFER_SIZE).as_t());
+
+        cpu
     }
 
     pub fn cycle(&mut self) -> Result<(), CPUError<I::XlenU>> {
-        // fetch
-        let instruction = self.fetch()?;
+        self.poll_interrupts();
+        let pc = self.pc;
+
+        // fetch and decode, unless the instruction has been decoded before
+        let op = match self.decode(pc) {
+            Ok(op) => op,
+            Err(e) => return self.exception(e, pc),
+        };
 
         // increment pc
         self.pc += I::INSN_SIZE;
 
-        // decode and execute
-        self.execute(instruction)
+        // execute
+        if let Err(e) = self.execute(op) {
+            return self.exception(e, pc);
+        }
+        self.retired += 1;
+
+        Ok(())
+    }
+
+    /// Executes the block of instructions starting at pc, up to and including the next
+    /// instruction that may change control flow.
+    ///
+    /// Blocks are translated on first execution and linked to their successors, so running
+    /// code in a loop of `run_block` skips fetch and decode entirely.
+    pub fn run_block(&mut self) -> Result<(), CPUError<I::XlenU>> {
+        self.poll_interrupts();
+        let index = match self.lookup_block() {
+            Ok(index) => index,
+            Err(e) => return self.exception(e, self.pc),
+        };
+        self.execute_block(index)
+    }
+
+    /// Runs until the cpu halts, hits a breakpoint or fails.
+    pub fn run(&mut self) -> StopReason<I::XlenU> {
+        self.run_with(u64::MAX, None, None)
+    }
+
+    /// Like [`run`](Self::run), but stops once `n` instructions have been retired.
+    pub fn run_for(&mut self, n: u64) -> StopReason<I::XlenU> {
+        self.run_with(n, None, None)
+    }
+
+    /// Like [`run`](Self::run), but stops with [`StopReason::Breakpoint`] as soon as pc equals
+    /// `pc`, before executing the instruction there. Returns immediately if pc already equals
+    /// `pc`.
+    pub fn run_until(&mut self, pc: I::XlenU) -> StopReason<I::XlenU> {
+        self.run_with(u64::MAX, Some(pc), None)
+    }
+
+    /// Like [`run`](Self::run), but evaluates `predicate` before every instruction and stops
+    /// with [`StopReason::HostRequest`] once it returns false.
+    ///
+    /// Instructions are executed one at a time, so this is considerably slower than the other
+    /// run methods.
+    pub fn run_while(&mut self, mut predicate: impl FnMut(&Self) -> bool) -> StopReason<I::XlenU> {
+        self.run_with(u64::MAX, None, Some(&mut predicate))
+    }
+
+    fn run_with(
+        &mut self,
+        mut budget: u64,
+        stop: Option<I::XlenU>,
+        mut predicate: Option<&mut dyn FnMut(&Self) -> bool>,
+    ) -> StopReason<I::XlenU> {
+        loop {
+            if stop == Some(self.pc) {
+                return StopReason::Breakpoint(self.pc);
+            }
+            if budget == 0 {
+                return StopReason::BudgetExhausted;
+            }
+
+            let (pc, retired) = (self.pc, self.retired);
+            let result = match predicate.as_mut() {
+                Some(predicate) => {
+                    if !predicate(self) {
+                        return StopReason::HostRequest;
+                    }
+                    self.cycle()
+                }
+                None => self.step(budget, stop),
+            };
+
+            match result {
+                Err(CPUError::Breakpoint(pc)) => return StopReason::Breakpoint(pc),
+                Err(CPUError::PowerOff(code)) => return StopReason::PowerOff(code),
+                Err(CPUError::ResetRequested) => {
+                    self.reset();
+                    continue;
+                }
+                Err(e) => return StopReason::Error(e),
+                Ok(()) => {}
+            }
+            budget -= self.retired - retired;
+
+            // a jump or branch to itself can only be left through an interrupt
+            if self.pc == pc
+                && self.retired == retired + 1
+                && !self.interrupts_enabled()
+                && self.is_idle_loop(pc)
+            {
+                return StopReason::Halted;
+            }
+        }
+    }
+
+    /// Executes the block at pc, or only its first instruction if the block is longer than
+    /// `budget`, would run past `stop` or past the next event of a device.
+    #[inline]
+    fn step(&mut self, budget: u64, stop: Option<I::XlenU>) -> Result<(), CPUError<I::XlenU>> {
+        self.poll_interrupts();
+        // devices are ticked exactly when their event is due
+        let budget = budget.min(self.bus.next_event().saturating_sub(self.retired).max(1));
+        let index = match self.lookup_block() {
+            Ok(index) => index,
+            Err(e) => return self.exception(e, self.pc),
+        };
+        let len = self.block_cache.block(index).len();
+        let ends_after = |stop: I::XlenU| {
+            let end = self
+                .pc
+                .wrapping_add(&(I::INSN_SIZE * (len as u32).as_t::<I::XlenU>()));
+            self.pc < stop && stop < end
+        };
+
+        if len as u64 > budget || stop.is_some_and(ends_after) {
+            self.cycle()
+        } else {
+            self.execute_block(index)
+        }
+    }
+
+    fn is_idle_loop(&mut self, pc: I::XlenU) -> bool {
+        matches!(
+            self.decode(pc),
+            Ok(Op::Jal { .. }
+                | Op::Beq { .. }
+                | Op::Bne { .. }
+                | Op::Blt { .. }
+                | Op::Bge { .. }
+                | Op::Bltu { .. }
+                | Op::Bgeu { .. })
+        )
+    }
+
+    #[inline]
+    fn lookup_block(&mut self) -> Result<usize, CPUError<I::XlenU>> {
+        match self.block_cache.get(self.pc) {
+            Some(index) => Ok(index),
+            None => self.translate(self.pc),
+        }
+    }
+
+    #[inline]
+    fn execute_block(&mut self, index: usize) -> Result<(), CPUError<I::XlenU>> {
+        #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+        if let Some(segments) =
+            self.block_cache
+                .native(index, std::mem::size_of::<I::XlenU>(), REG_COUNT)
+        {
+            return self.run_native(&segments);
+        }
+
+        let generation = self.block_cache.generation();
+        let ops = self.block_cache.block(index);
+        let len = ops.len() as u64;
+
+        for (i, op) in ops.enumerate() {
+            self.pc += I::INSN_SIZE;
+            if let Err(e) = self.execute(self.block_cache.op(op)) {
+                self.retired += i as u64;
+                return self.exception(e, self.pc - I::INSN_SIZE);
+            }
+
+            // a store has overwritten translated code, the rest of the block may be stale
+            if self.block_cache.generation() != generation {
+                self.retired += i as u64 + 1;
+                return Ok(());
+            }
+        }
+
+        self.retired += len;
+
+        Ok(())
+    }
+
+    /// Executes a compiled block, interpreting the instructions that were not compiled.
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    fn run_native(&mut self, segments: &[jit::Segment]) -> Result<(), CPUError<I::XlenU>> {
+        let generation = self.block_cache.generation();
+
+        for segment in segments {
+            match *segment {
+                jit::Segment::Native { code, len } => {
+                    // SAFETY: compiled code only accesses the first REG_COUNT registers of
+                    // width XlenU through the pointer, which is exactly the register file
+                    unsafe { code(self.registers.as_mut_ptr().cast()) };
+
+                    self.pc += I::INSN_SIZE * (len as u32).as_t::<I::XlenU>();
+                    self.retired += len as u64;
+                }
+                jit::Segment::Interpreted { op } => {
+                    self.pc += I::INSN_SIZE;
+                    if let Err(e) = self.execute(self.block_cache.op(op)) {
+                        return self.exception(e, self.pc - I::INSN_SIZE);
+                    }
+                    self.retired += 1;
+
+                    // a store has overwritten translated code, the rest of the block may be stale
+                    if self.block_cache.generation() != generation {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
+        Ok(())
     }
 
     pub fn reset(&mut self) {
-        self.pc = self.dram_mapping.start;
+        self.pc = self.reset_vector;
         self.registers[2] = self.dram_mapping.end;
+        self.privilege = Privilege::Machine;
+        self.pmp = Pmp::new();
+        self.trap_csrs = TrapCsrs::default();
+        self.retired = 0;
+        self.bus.reset_devices();
+
+        // translated code stays valid: memory is unchanged and with pmp reset machine mode may
+        // fetch from everywhere instructions could be fetched before
     }
 
     pub fn dump_registers(&self) -> RegisterDump<I, REG_COUNT> {
@@ -180,11 +813,55 @@ where
         self.bus.get_data(self.dram_mapping.clone()).unwrap()
     }
 
-    fn fetch(&self) -> Result<u32, CPUError<I::XlenU>> {
-        self.bus.load_u32(self.pc)
+    pub fn set_misaligned_access(&mut self, policy: MisalignedAccess) {
+        self.bus.set_misaligned_access(policy);
+    }
+
+    /// Sets the source of the time of day, e.g. of the real-time clock, which is the host's
+    /// clock by default.
+    pub fn set_wall_clock(&mut self, clock: WallClock) {
+        self.bus.set_wall_clock(clock);
+    }
+
+    /// Number of misaligned fetches, loads and stores executed so far.
+    pub fn misaligned_accesses(&self) -> u64 {
+        self.bus.misaligned_accesses()
+    }
+
+    fn fetch(&self, pc: I::XlenU) -> Result<u32, CPUError<I::XlenU>> {
+        self.check_pmp(pc, 4, AccessType::Fetch)?;
+        self.bus.fetch_u32(pc)
+    }
+
+    fn decode(&mut self, pc: I::XlenU) -> Result<Op, CPUError<I::XlenU>> {
+        if let Some(op) = self.decode_cache.get(pc) {
+            return Ok(op);
+        }
+
+        let op = I::decode(self.fetch(pc)?);
+        self.decode_cache.insert(pc, op);
+
+        Ok(op)
+    }
+
+    /// Translates the block starting at `start`. The block ends early before an instruction
+    /// that can not be fetched, the error is reported once execution reaches it.
+    fn translate(&mut self, start: I::XlenU) -> Result<usize, CPUError<I::XlenU>> {
+        let mut ops = vec![self.decode(start)?];
+        let mut pc = start + I::INSN_SIZE;
+
+        while !ops[ops.len() - 1].ends_block() && ops.len() < MAX_BLOCK_LEN {
+            match self.decode(pc) {
+                Ok(op) => ops.push(op),
+                Err(_) => break,
+            }
+            pc += I::INSN_SIZE;
+        }
+
+        Ok(self.block_cache.insert(start, pc, ops))
     }
 
-    fn execute(&mut self, instruction: u32) -> Result<(), CPUError<I::XlenU>> {
-        I::exec(self, instruction)
+    fn execute(&mut self, op: Op) -> Result<(), CPUError<I::XlenU>> {
+        I::exec(self, op)
     }
 }
@@ -0,0 +1,193 @@
+use num_traits::Unsigned;
+
+use crate::cpu::isa::{As, Xlen};
+use crate::cpu::Privilege;
+use crate::memory::AccessType;
+
+pub const PMP_ENTRIES: usize = 16;
+
+const PMP_R: u8 = 1 << 0;
+const PMP_W: u8 = 1 << 1;
+const PMP_X: u8 = 1 << 2;
+const PMP_A: u8 = 0b11 << 3;
+const PMP_L: u8 = 1 << 7;
+
+const A_OFF: u8 = 0b00 << 3;
+const A_TOR: u8 = 0b01 << 3;
+const A_NA4: u8 = 0b10 << 3;
+const A_NAPOT: u8 = 0b11 << 3;
+
+/// Physical memory protection unit with [`PMP_ENTRIES`] entries.
+pub struct Pmp<A: Xlen + Unsigned> {
+    cfg: [u8; PMP_ENTRIES],
+    addr: [A; PMP_ENTRIES],
+    /// Whether any entry is locked, which means accesses from M-mode have to be checked too.
+    locked: bool,
+}
+
+impl<A: Xlen + Unsigned> Pmp<A> {
+    pub fn new() -> Self {
+        Self {
+            cfg: [0; PMP_ENTRIES],
+            addr: [A::zero(); PMP_ENTRIES],
+            locked: false,
+        }
+    }
+
+    pub fn cfg(&self, index: usize) -> u8 {
+        self.cfg[index]
+    }
+
+    pub fn addr(&self, index: usize) -> A {
+        self.addr[index]
+    }
+
+    /// Number of entries packed into one `pmpcfg` register.
+    fn entries_per_cfg() -> usize {
+        A::zero().count_zeros() as usize / 8
+    }
+
+    /// Maps a `pmpcfg` register number to the first entry it holds, which may be past the
+    /// implemented entries. On RV64 only the even registers exist.
+    fn cfg_base(reg: usize) -> Option<usize> {
+        let base = reg * 4;
+
+        base.is_multiple_of(Self::entries_per_cfg()).then_some(base)
+    }
+
+    pub(crate) fn read_cfg_csr(&self, reg: usize) -> Option<A> {
+        let base = Self::cfg_base(reg)?;
+        // registers of unimplemented entries are read-only zero
+        if base >= PMP_ENTRIES {
+            return Some(A::zero());
+        }
+
+        Some(
+            self.cfg[base..base + Self::entries_per_cfg()]
+                .iter()
+                .rev()
+                .fold(A::zero(), |acc, cfg| (acc << 8) | A::from(*cfg).unwrap()),
+        )
+    }
+
+    pub(crate) fn write_cfg_csr(&mut self, reg: usize, value: A) -> Option<()> {
+        let base = Self::cfg_base(reg)?;
+        if base >= PMP_ENTRIES {
+            return Some(());
+        }
+
+        for i in 0..Self::entries_per_cfg() {
+            let index = base + i;
+            if self.is_locked(index) {
+                continue;
+            }
+
+            let mut cfg =
+                (value >> (i * 8)).as_t::<usize>() as u8 & (PMP_L | PMP_A | PMP_X | PMP_W | PMP_R);
+            // R=0 W=1 is reserved
+            if cfg & (PMP_R | PMP_W) == PMP_W {
+                cfg &= !PMP_W;
+            }
+            self.cfg[index] = cfg;
+        }
+
+        self.locked = self.cfg.iter().any(|cfg| cfg & PMP_L != 0);
+
+        Some(())
+    }
+
+    pub(crate) fn read_addr_csr(&self, index: usize) -> A {
+        self.addr[index]
+    }
+
+    pub(crate) fn write_addr_csr(&mut self, index: usize, value: A) {
+        let next_is_locked_tor = index + 1 < PMP_ENTRIES
+            && self.is_locked(index + 1)
+            && self.cfg[index + 1] & PMP_A == A_TOR;
+
+        if self.is_locked(index) || next_is_locked_tor {
+            return;
+        }
+
+        // pmpaddr holds bits [55:2] of the physical address on RV64
+        self.addr[index] = if A::zero().count_zeros() == 64 {
+            value & (A::max_value() >> 10)
+        } else {
+            value
+        };
+    }
+
+    fn is_locked(&self, index: usize) -> bool {
+        self.cfg[index] & PMP_L != 0
+    }
+
+    /// Returns the physical address range matched by entry `index`, if it is enabled.
+    fn range(&self, index: usize) -> Option<(u128, u128)> {
+        let addr = self.addr[index].as_t::<usize>() as u128;
+
+        match self.cfg[index] & PMP_A {
+            A_OFF => None,
+            A_TOR => {
+                let start = match index {
+                    0 => 0,
+                    _ => (self.addr[index - 1].as_t::<usize>() as u128) << 2,
+                };
+                Some((start, addr << 2))
+            }
+            A_NA4 => Some((addr << 2, (addr << 2) + 4)),
+            A_NAPOT => {
+                let ones = addr.trailing_ones();
+                let base = (addr & !((1 << ones) - 1)) << 2;
+                Some((base, base + (8 << ones)))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Checks whether an access of `size` bytes at `addr` from `privilege` mode is permitted.
+    ///
+    /// The lowest-numbered entry matching any byte of the access decides; it has to match all
+    /// bytes. M-mode accesses are only restricted by locked entries, S- and U-mode accesses fail
+    /// if no entry matches.
+    pub fn check(&self, addr: A, size: usize, access: AccessType, privilege: Privilege) -> bool {
+        if privilege == Privilege::Machine && !self.locked {
+            return true;
+        }
+
+        let start = addr.as_t::<usize>() as u128;
+        let end = start + size as u128;
+
+        for index in 0..PMP_ENTRIES {
+            let Some((entry_start, entry_end)) = self.range(index) else {
+                continue;
+            };
+
+            if start >= entry_end || end <= entry_start {
+                continue;
+            }
+
+            if start < entry_start || end > entry_end {
+                return false;
+            }
+
+            let cfg = self.cfg[index];
+            if privilege == Privilege::Machine && cfg & PMP_L == 0 {
+                return true;
+            }
+
+            return match access {
+                AccessType::Fetch => cfg & PMP_X != 0,
+                AccessType::Load => cfg & PMP_R != 0,
+                AccessType::Store => cfg & PMP_W != 0,
+            };
+        }
+
+        privilege == Privilege::Machine
+    }
+}
+
+impl<A: Xlen + Unsigned> Default for Pmp<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
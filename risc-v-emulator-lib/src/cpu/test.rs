@@ -20,7 +20,7 @@ mod instructions {
     use num_traits::Num;
 
     use crate::cpu::isa::{As, Isa, RV32I};
-    use crate::cpu::{Cpu, RegisterDump};
+    use crate::cpu::{Cpu, RegisterDump, StopReason};
 
     // TODO parse at compile time
     fn parse_testcase<const REG_COUNT: usize, I: Isa<REG_COUNT>>(
@@ -86,12 +86,18 @@ mod instructions {
     fn execute_insn_test(name: &str, testcase: &str, binary: &[u8]) {
         let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(binary);
 
-        loop {
-            // were currently just waiting for the cpu to run into empty memory
-            if cpu.cycle().is_err() {
-                break;
-            }
-        }
+        // tests either power off through the test finisher or run into empty memory
+        let reason = cpu.run_while(|_| true);
+        assert!(
+            !matches!(reason, StopReason::PowerOff(code) if code != 0),
+            "{name} failed: {reason}"
+        );
+
+        // translated blocks have to behave exactly like single stepping
+        let mut block_cpu: Cpu<RV32I, 32> = Cpu::with_code(binary);
+        block_cpu.run();
+        assert_eq!(cpu.dump_registers(), block_cpu.dump_registers());
+        assert_eq!(cpu.instructions_retired(), block_cpu.instructions_retired());
 
         let mut actual_regs = cpu.dump_registers();
 
@@ -122,3 +128,1075 @@ mod instructions {
     // include tests generated via build.rs
     include!(concat!(env!("OUT_DIR"), "/tests_insn.rs"));
 }
+
+#[test]
+fn test_misaligned_load_trap() {
+    use crate::cpu::isa::RV32I;
+    use crate::cpu::{CPUError, Cpu};
+    use crate::memory::MisalignedAccess;
+
+    // lw x1, -7(x2)
+    const CODE: [u8; 4] = 0xFF91_2083_u32.to_le_bytes();
+
+    let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(&CODE);
+    assert!(cpu.cycle().is_ok());
+    assert_eq!(1, cpu.misaligned_accesses());
+
+    let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(&CODE);
+    cpu.set_misaligned_access(MisalignedAccess::Trap);
+    assert!(matches!(
+        cpu.cycle(),
+        Err(CPUError::LoadAddressMisaligned(0x87FF_FFF9))
+    ));
+}
+
+#[test]
+fn test_boot_rom() {
+    use crate::cpu::isa::RV32I;
+    use crate::cpu::Cpu;
+
+    // lui x1, 0x80000
+    // jalr x0, 0(x1)
+    const ROM: [u8; 8] = [0xB7, 0x00, 0x00, 0x80, 0x67, 0x80, 0x00, 0x00];
+    // addi x3, x0, 7
+    const CODE: [u8; 4] = 0x0070_0193_u32.to_le_bytes();
+
+    let mut cpu: Cpu<RV32I, 32> = Cpu::with_boot_rom(&ROM, &CODE).unwrap();
+    assert_eq!(0x1000, cpu.pc);
+
+    for _ in 0..3 {
+        assert!(cpu.cycle().is_ok());
+    }
+
+    assert_eq!(0x8000_0004, cpu.pc);
+    assert_eq!(7, cpu.registers[3]);
+
+    // the rom is as large as the image, which jumps to its second page
+    let mut rom = vec![0; 0x1008];
+    rom[..4].copy_from_slice(&0x0000_106F_u32.to_le_bytes()); // j .+0x1000
+    rom[0x1000..].copy_from_slice(&ROM);
+    let mut cpu: Cpu<RV32I, 32> = Cpu::with_boot_rom(&rom, &CODE).unwrap();
+    for _ in 0..4 {
+        assert!(cpu.cycle().is_ok());
+    }
+    assert_eq!(0x8000_0004, cpu.pc);
+
+    // up to the test finisher
+    assert!(Cpu::<RV32I, 32>::with_boot_rom(&vec![0; 0xF_F000], &CODE).is_some());
+    assert!(Cpu::<RV32I, 32>::with_boot_rom(&vec![0; 0xF_F001], &CODE).is_none());
+}
+
+#[test]
+fn test_virt() {
+    use crate::cpu::isa::{RV32I, RV64I};
+    use crate::cpu::Cpu;
+    use crate::devices::virtio::VirtioRng;
+    use crate::memory::Memory;
+
+    // returns the node, name and value of every property in a device tree blob
+    fn properties(blob: &[u8]) -> Vec<(String, String, Vec<u8>)> {
+        let word = |offset: usize| u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap());
+        let string = |offset: usize| {
+            let len = blob[offset..].iter().position(|&b| b == 0).unwrap();
+            String::from_utf8(blob[offset..offset + len].to_vec()).unwrap()
+        };
+
+        assert_eq!(0xD00D_FEED, word(0));
+        assert_eq!(blob.len(), word(4) as usize);
+        let (mut offset, strings) = (word(8) as usize, word(12) as usize);
+        let (mut nodes, mut properties) = (Vec::new(), Vec::new());
+        loop {
+            offset += 4;
+            match word(offset - 4) {
+                1 => {
+                    let name = string(offset);
+                    offset = (offset + name.len() + 1).next_multiple_of(4);
+                    nodes.push(name);
+                }
+                2 => assert!(nodes.pop().is_some()),
+                3 => {
+                    let len = word(offset) as usize;
+                    let name = string(strings + word(offset + 4) as usize);
+                    let value = blob[offset + 8..offset + 8 + len].to_vec();
+                    properties.push((nodes.last().unwrap().clone(), name, value));
+                    offset = (offset + 8 + len).next_multiple_of(4);
+                }
+                9 => break,
+                token => panic!("unexpected token {token}"),
+            }
+        }
+        assert!(nodes.is_empty());
+
+        properties
+    }
+
+    // j .
+    const FIRMWARE: [u8; 4] = 0x0000_006F_u32.to_le_bytes();
+    const KERNEL: [u8; 4] = [1, 2, 3, 4];
+    let initrd = vec![5; 0x1800];
+
+    let mut cpu: Cpu<RV32I, 32> =
+        Cpu::virt(&FIRMWARE, &KERNEL, Some(&initrd), "console=ttyS0").unwrap();
+    assert_eq!(0x1000, cpu.pc);
+    for _ in 0..6 {
+        assert!(cpu.cycle().is_ok());
+    }
+    assert_eq!(0x8000_0000, cpu.pc);
+    // hart id, device tree and fw_dynamic_info
+    assert_eq!(0, cpu.registers[10]);
+    assert_eq!(0x87E0_0000, cpu.registers[11]);
+    assert_eq!(0x1028, cpu.registers[12]);
+    assert_eq!(0x4942_534F, cpu.bus.load_u32(0x1028).unwrap());
+    assert_eq!(0x8040_0000, cpu.bus.load_u32(0x1030).unwrap());
+    assert_eq!(1, cpu.bus.load_u32(0x1034).unwrap());
+
+    // the kernel is at the first 4 MiB boundary, the initrd right below the device tree
+    assert_eq!(0x0403_0201, cpu.bus.load_u32(0x8040_0000).unwrap());
+    assert_eq!(0x0505_0505, cpu.bus.load_u32(0x87DF_E000).unwrap());
+    assert_eq!(0x0505_0505, cpu.bus.load_u32(0x87DF_F7FC).unwrap());
+
+    let dump = |cpu: &Cpu<RV32I, 32>| {
+        let size = u32::from_be(cpu.bus.load_u32(0x87E0_0004).unwrap()) as usize;
+        let blob: Vec<u8> = (0..size)
+            .map(|i| cpu.bus.load_u8(0x87E0_0000 + i as u32).unwrap())
+            .collect();
+        properties(&blob)
+    };
+    let find = |properties: &[(String, String, Vec<u8>)], node: &str, name: &str| {
+        properties
+            .iter()
+            .find(|(n, p, _)| n == node && p == name)
+            .map(|(_, _, value)| value.clone())
+    };
+
+    let tree = dump(&cpu);
+    assert_eq!(Some(b"console=ttyS0\0".to_vec()), find(&tree, "chosen", "bootargs"));
+    assert_eq!(
+        Some(0x87DF_E000_u64.to_be_bytes().to_vec()),
+        find(&tree, "chosen", "linux,initrd-start")
+    );
+    assert_eq!(
+        Some(0x87DF_F800_u64.to_be_bytes().to_vec()),
+        find(&tree, "chosen", "linux,initrd-end")
+    );
+    assert_eq!(Some(b"rv32i\0".to_vec()), find(&tree, "cpu@0", "riscv,isa"));
+    assert_eq!(None, find(&tree, "virtio_mmio@10001000", "compatible"));
+
+    // attached devices are added to the device tree
+    assert_eq!(Some(0x1000_1000), cpu.attach_virtio(VirtioRng::new(1)));
+    let tree = dump(&cpu);
+    assert_eq!(
+        Some(b"virtio,mmio\0".to_vec()),
+        find(&tree, "virtio_mmio@10001000", "compatible")
+    );
+    assert_eq!(
+        Some(1_u32.to_be_bytes().to_vec()),
+        find(&tree, "virtio_mmio@10001000", "interrupts")
+    );
+    assert_eq!(None, find(&tree, "virtio_mmio@10002000", "compatible"));
+
+    // on RV64 the rom loads doublewords and the kernel is at the first 2 MiB boundary
+    let cpu: Cpu<RV64I, 32> = Cpu::virt(&FIRMWARE, &KERNEL, None, "").unwrap();
+    assert_eq!(0x0202_B583, cpu.bus.load_u32(0x100C).unwrap());
+    assert_eq!(0x8020_0000, cpu.bus.load_u32(0x1038).unwrap());
+    assert_eq!(0x0403_0201, cpu.bus.load_u32(0x8020_0000).unwrap());
+}
+
+#[test]
+fn test_self_modifying_code() {
+    use crate::cpu::isa::RV32I;
+    use crate::cpu::{CPUError, Cpu, StopReason};
+
+    //     auipc t0, 0
+    //     lui t1, 0x200
+    //     addi t1, t1, 0x513  # t1 = addi a0, zero, 2
+    // l:  addi a0, zero, 1
+    //     bnez a1, e
+    //     addi a1, zero, 1
+    //     sw t1, 12(t0)       # overwrite the instruction at l
+    //     j l
+    // e:
+    const CODE: [u8; 32] = [
+        0x97, 0x02, 0x00, 0x00, 0x37, 0x03, 0x20, 0x00, 0x13, 0x03, 0x33, 0x51, 0x13, 0x05, 0x10,
+        0x00, 0x63, 0x98, 0x05, 0x00, 0x93, 0x05, 0x10, 0x00, 0x23, 0xA6, 0x62, 0x00, 0x6F, 0xF0,
+        0x1F, 0xFF,
+    ];
+
+    let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(&CODE);
+    assert!(matches!(
+        cpu.run_while(|_| true),
+        StopReason::Error(CPUError::InstructionNotImplemented(0))
+    ));
+    assert_eq!(0x8000_0024, cpu.pc);
+    assert_eq!(2, cpu.registers[10]);
+
+    let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(&CODE);
+    assert!(matches!(
+        cpu.run(),
+        StopReason::Error(CPUError::InstructionNotImplemented(0))
+    ));
+    assert_eq!(0x8000_0024, cpu.pc);
+    assert_eq!(2, cpu.registers[10]);
+    assert_eq!(10, cpu.instructions_retired());
+}
+
+#[test]
+fn test_block_cache_invalidate() {
+    use crate::cpu::block_cache::{BlockCache, MAX_BLOCK_LEN};
+    use crate::cpu::isa::Op;
+
+    let mut cache: BlockCache<u32> = BlockCache::new();
+    let first = cache.insert(0x1000, 0x1004, vec![Op::Ecall]);
+    let second = cache.insert(0x1004, 0x1008, vec![Op::Ecall]);
+    assert_eq!(Some(first), cache.get(0x1000));
+    assert_eq!(Some(second), cache.get(0x1004));
+
+    // the link from the first block must not lead to the invalidated one
+    cache.invalidate(0x1004, 4);
+    assert_eq!(Some(first), cache.get(0x1000));
+    assert_eq!(None, cache.get(0x1004));
+    let third = cache.insert(0x1004, 0x1008, vec![Op::Ecall]);
+    assert_eq!(Some(first), cache.get(0x1000));
+    assert_eq!(Some(third), cache.get(0x1004));
+
+    // code rewriting itself over and over does not grow the cache forever
+    for _ in 0..100_000 {
+        cache.insert(0x2000, 0x2100, vec![Op::Ecall; MAX_BLOCK_LEN]);
+        cache.invalidate(0x2000, 4);
+    }
+    assert!(cache.ops_len() < 1 << 18);
+}
+
+#[test]
+fn test_run() {
+    use crate::cpu::isa::RV32I;
+    use crate::cpu::{Cpu, StopReason};
+
+    //     addi t0, zero, 10
+    // l:  addi a0, a0, 1
+    //     addi a1, a1, 2
+    //     bne a0, t0, l
+    //     ebreak
+    //     addi a2, zero, 3
+    //     j .
+    const CODE: [u8; 28] = [
+        0x93, 0x02, 0xA0, 0x00, 0x13, 0x05, 0x15, 0x00, 0x93, 0x85, 0x25, 0x00, 0xE3, 0x1C, 0x55,
+        0xFE, 0x73, 0x00, 0x10, 0x00, 0x13, 0x06, 0x30, 0x00, 0x6F, 0x00, 0x00, 0x00,
+    ];
+
+    let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(&CODE);
+    assert!(matches!(cpu.run_for(5), StopReason::BudgetExhausted));
+    assert_eq!(5, cpu.instructions_retired());
+    assert_eq!(0x8000_0008, cpu.pc);
+    assert_eq!(2, cpu.registers[10]);
+
+    // stops in the middle of a block
+    let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(&CODE);
+    assert!(matches!(
+        cpu.run_until(0x8000_0008),
+        StopReason::Breakpoint(0x8000_0008)
+    ));
+    assert_eq!(2, cpu.instructions_retired());
+    assert_eq!(0, cpu.registers[11]);
+
+    let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(&CODE);
+    assert!(matches!(
+        cpu.run_while(|cpu| cpu.registers[10] < 3),
+        StopReason::HostRequest
+    ));
+    assert_eq!(3, cpu.registers[10]);
+
+    let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(&CODE);
+    assert!(matches!(cpu.run(), StopReason::Breakpoint(0x8000_0010)));
+    assert_eq!(0x8000_0014, cpu.pc);
+    assert_eq!((10, 20), (cpu.registers[10], cpu.registers[11]));
+
+    assert!(matches!(cpu.run(), StopReason::Halted));
+    assert_eq!(0x8000_0018, cpu.pc);
+    assert_eq!(3, cpu.registers[12]);
+}
+
+#[test]
+fn test_device_writes_code() {
+    use std::ops::Range;
+
+    use crate::cpu::isa::RV32I;
+    use crate::cpu::{CPUError, Cpu, StopReason};
+    use crate::devices::{Device, Timer};
+    use crate::memory::{impl_memory, Memory, Permissions};
+
+    // named like the address type `impl_memory!` expects
+    type A = u32;
+
+    /// Overwrites the code at 0x8000_0004 with `addi a0, zero, 2` at time 50.
+    struct Dma(Option<Timer>);
+
+    impl Memory<A> for Dma {
+        fn size(&self) -> A {
+            0x1000
+        }
+
+        impl_memory!(
+            self,
+            addr,
+            value,
+            { Err(CPUError::AddressNotMapped(addr)) },
+            {
+                let _ = value;
+                Err(CPUError::AddressNotMapped(addr))
+            }
+        );
+
+        fn get_data(&self, range: Range<u32>) -> Result<Vec<u8>, CPUError<u32>> {
+            Err(CPUError::AddressNotMapped(range.start))
+        }
+
+        fn as_device(&mut self) -> Option<&mut dyn Device<u32>> {
+            Some(self)
+        }
+    }
+
+    impl Device<u32> for Dma {
+        fn tick(&mut self, _now: u64, bus: &mut dyn Memory<u32>) {
+            bus.store_u32(0x8000_0004, 0x0020_0513).unwrap();
+        }
+
+        fn set_timer(&mut self, timer: Timer) {
+            timer.schedule_at(50);
+            self.0 = Some(timer);
+        }
+    }
+
+    //     addi t0, zero, 100
+    // l:  addi a0, zero, 1
+    //     addi a1, a1, 1
+    //     bne a1, t0, l
+    //     j .
+    const CODE: [u8; 20] = [
+        0x93, 0x02, 0x40, 0x06, 0x13, 0x05, 0x10, 0x00, 0x93, 0x85, 0x15, 0x00, 0xE3, 0x9C, 0x55,
+        0xFE, 0x6F, 0x00, 0x00, 0x00,
+    ];
+
+    let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(&CODE);
+    cpu.bus
+        .insert(0x2000_0000..0x2000_1000, Box::new(Dma(None)), Permissions::RW);
+    assert!(matches!(cpu.run(), StopReason::Halted));
+    assert_eq!(100, cpu.registers[11]);
+    // the loop ran the new code after the dma
+    assert_eq!(2, cpu.registers[10]);
+}
+
+#[test]
+fn test_finisher_power_off() {
+    use crate::cpu::isa::RV32I;
+    use crate::cpu::{Cpu, StopReason};
+
+    // lui t0, 0x100
+    // lui t1, 0x73
+    // addi t1, t1, 0x333  # fail with exit code 7
+    // sw t1, 0(t0)
+    const CODE: [u8; 16] = [
+        0xB7, 0x02, 0x10, 0x00, 0x37, 0x33, 0x07, 0x00, 0x13, 0x03, 0x33, 0x33, 0x23, 0xA0, 0x62,
+        0x00,
+    ];
+
+    let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(&CODE);
+    assert!(matches!(cpu.run(), StopReason::PowerOff(7)));
+}
+
+#[test]
+fn test_elf_htif() {
+    use crate::cpu::isa::RV32I;
+    use crate::cpu::{Cpu, StopReason};
+    use crate::devices::Htif;
+    use crate::elf::Elf;
+    use crate::memory::Memory;
+    use crate::test_util::Capture;
+
+    /// Builds an executable with a single segment loaded to `base`, followed by zeroes up to
+    /// `mem_size`.
+    fn elf32(code: &[u8], base: u32, mem_size: u32, symbols: &[(&str, u32)]) -> Vec<u8> {
+        let u16 = |elf: &mut Vec<u8>, v: u16| elf.extend(v.to_le_bytes());
+        let u32 = |elf: &mut Vec<u8>, v: u32| elf.extend(v.to_le_bytes());
+
+        let code_off = 52 + 32;
+        let symtab_off = code_off + code.len() as u32;
+        let strtab_off = symtab_off + 16 * (symbols.len() as u32 + 1);
+        let mut strtab = vec![0];
+        let mut symtab = vec![0; 16];
+        for (name, value) in symbols {
+            u32(&mut symtab, strtab.len() as u32);
+            u32(&mut symtab, *value);
+            symtab.extend([0; 8]);
+            strtab.extend(name.bytes().chain([0]));
+        }
+        let sh_off = strtab_off + strtab.len() as u32;
+
+        let mut elf = vec![0x7F, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        u16(&mut elf, 2); // executable
+        u16(&mut elf, 243);
+        u32(&mut elf, 1);
+        u32(&mut elf, base); // entry
+        u32(&mut elf, 52); // program headers
+        u32(&mut elf, sh_off);
+        u32(&mut elf, 0);
+        for v in [52, 32, 1, 40, 3, 0] {
+            u16(&mut elf, v);
+        }
+
+        for v in [1, code_off, base, base, code.len() as u32, mem_size, 7, 4] {
+            u32(&mut elf, v);
+        }
+        elf.extend(code);
+        elf.extend(symtab);
+        elf.extend(strtab.iter());
+
+        // null, symtab linked to strtab, strtab
+        elf.extend([0; 40]);
+        for v in [0, 2, 0, 0, symtab_off, strtab_off - symtab_off, 2, 0, 4, 16] {
+            u32(&mut elf, v);
+        }
+        for v in [0, 3, 0, 0, strtab_off, strtab.len() as u32, 0, 0, 1, 0] {
+            u32(&mut elf, v);
+        }
+
+        elf
+    }
+
+    //     auipc t0, 0
+    //     addi t0, t0, 0x100  # tohost
+    //     lui t1, 0x1010      # console putchar
+    //     addi t2, zero, 104
+    //     sw t2, 0(t0)
+    //     sw t1, 4(t0)
+    //     addi t2, zero, 105
+    //     sw t2, 0(t0)
+    //     sw t1, 4(t0)
+    //     addi t2, zero, 11   # exit with code 5
+    //     sw t2, 0(t0)
+    //     sw zero, 4(t0)
+    //     j .
+    const CODE: [u8; 52] = [
+        0x97, 0x02, 0x00, 0x00, 0x93, 0x82, 0x02, 0x10, 0x37, 0x03, 0x01, 0x01, 0x93, 0x03, 0x80,
+        0x06, 0x23, 0xA0, 0x72, 0x00, 0x23, 0xA2, 0x62, 0x00, 0x93, 0x03, 0x90, 0x06, 0x23, 0xA0,
+        0x72, 0x00, 0x23, 0xA2, 0x62, 0x00, 0x93, 0x03, 0xB0, 0x00, 0x23, 0xA0, 0x72, 0x00, 0x23,
+        0xA2, 0x02, 0x00, 0x6F, 0x00, 0x00, 0x00,
+    ];
+
+    let data = elf32(
+        &CODE,
+        0x8000_1000,
+        0x200,
+        &[("tohost", 0x8000_1100), ("fromhost", 0x8000_1108)],
+    );
+    let elf = Elf::parse(&data).unwrap();
+    assert_eq!(0x8000_1000, elf.entry);
+    assert_eq!(Some(0x8000_1100), elf.symbol("tohost"));
+
+    let console = Capture::default();
+    let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(&[]);
+    cpu.load_elf(&elf).unwrap();
+    cpu.set_htif(Htif::from_elf(&elf).unwrap().with_console(console.clone()));
+    assert_eq!(0x8000_1000, cpu.pc);
+
+    assert!(matches!(cpu.run(), StopReason::PowerOff(5)));
+    assert_eq!(b"hi".to_vec(), console.contents());
+    assert_eq!(Ok(1 << 56 | 1 << 48), cpu.bus.load_u64(0x8000_1108).map_err(|_| ()));
+
+    // the register width has to match
+    assert!(Cpu::<crate::cpu::isa::RV64I, 32>::with_elf(&data).is_err());
+}
+
+#[test]
+fn test_timer_interrupt() {
+    use crate::cpu::isa::{RV32I, RV64I};
+    use crate::cpu::{Cpu, StopReason};
+
+    //     la t0, handler
+    //     csrw mtvec, t0
+    //     lui t0, 0x2004      # mtimecmp
+    //     addi t1, zero, 100
+    //     sw t1, 0(t0)
+    //     sw zero, 4(t0)
+    //     addi t1, zero, 0x80 # MTIE
+    //     csrw mie, t1
+    //     csrsi mstatus, 8    # MIE
+    // idle:
+    //     j idle
+    // handler:
+    //     csrr a1, mcause
+    //     csrr a2, mepc
+    //     lui t0, 0x200c
+    //     lw a0, -8(t0)       # mtime
+    //     lui t0, 0x100
+    //     lui t1, 0x5
+    //     addi t1, t1, 0x555
+    //     sw t1, 0(t0)
+    const CODE: [u8; 76] = [
+        0x97, 0x02, 0x00, 0x00, 0x93, 0x82, 0xC2, 0x02, 0x73, 0x90, 0x52, 0x30, 0xB7, 0x42, 0x00,
+        0x02, 0x13, 0x03, 0x40, 0x06, 0x23, 0xA0, 0x62, 0x00, 0x23, 0xA2, 0x02, 0x00, 0x13, 0x03,
+        0x00, 0x08, 0x73, 0x10, 0x43, 0x30, 0x73, 0x60, 0x04, 0x30, 0x6F, 0x00, 0x00, 0x00, 0xF3,
+        0x25, 0x20, 0x34, 0x73, 0x26, 0x10, 0x34, 0xB7, 0xC2, 0x00, 0x02, 0x03, 0xA5, 0x82, 0xFF,
+        0xB7, 0x02, 0x10, 0x00, 0x37, 0x53, 0x00, 0x00, 0x13, 0x03, 0x53, 0x55, 0x23, 0xA0, 0x62,
+        0x00,
+    ];
+
+    // the idle loop is left through the interrupt instead of halting
+    let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(&CODE);
+    assert!(matches!(cpu.run_for(50), StopReason::BudgetExhausted));
+    assert!(matches!(cpu.run(), StopReason::PowerOff(0)));
+    assert_eq!(0x8000_0007, cpu.registers[11]);
+    assert_eq!(0x8000_0028, cpu.registers[12]);
+    // blocks stop at the event of the clint, so the interrupt is taken right when it is due
+    // and mtime is read two instructions later
+    assert_eq!(102, cpu.registers[10]);
+
+    let mut cpu: Cpu<RV64I, 32> = Cpu::with_code(&CODE);
+    assert!(matches!(cpu.run_while(|_| true), StopReason::PowerOff(0)));
+    assert_eq!(0x8000_0000_0000_0007, cpu.registers[11]);
+    assert_eq!(0x8000_0028, cpu.registers[12]);
+}
+
+#[test]
+fn test_ecall() {
+    use crate::cpu::isa::{RV32I, RV64I};
+    use crate::cpu::{Cpu, StopReason};
+
+    //     la t0, handler
+    //     csrw mtvec, t0
+    //     ecall
+    // handler:
+    //     csrr a1, mcause
+    //     csrr a2, mepc
+    //     j handler + 8
+    const CODE: [u8; 28] = [
+        0x97, 0x02, 0x00, 0x00, 0x93, 0x82, 0x02, 0x01, 0x73, 0x90, 0x52, 0x30, 0x73, 0x00, 0x00,
+        0x00, 0xF3, 0x25, 0x20, 0x34, 0x73, 0x26, 0x10, 0x34, 0x6F, 0x00, 0x00, 0x00,
+    ];
+
+    // an environment call from machine mode, returning to the ecall itself
+    let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(&CODE);
+    assert!(matches!(cpu.run(), StopReason::Halted));
+    assert_eq!(11, cpu.registers[11]);
+    assert_eq!(0x8000_000C, cpu.registers[12]);
+
+    let mut cpu: Cpu<RV64I, 32> = Cpu::with_code(&CODE);
+    assert!(matches!(cpu.run_while(|_| true), StopReason::Halted));
+    assert_eq!(11, cpu.registers[11]);
+    assert_eq!(0x8000_000C, cpu.registers[12]);
+}
+
+#[test]
+fn test_exceptions() {
+    use crate::cpu::isa::RV32I;
+    use crate::cpu::{Cpu, StopReason};
+    use crate::memory::MisalignedAccess;
+
+    // lui t1, 0x20000
+    // addi t1, t1, 0x5FF
+    // csrw pmpaddr0, t1
+    // li t1, 0x98         # L | NAPOT, 0x8000_1000 to 0x8000_1FFF is inaccessible
+    // csrw pmpcfg0, t1
+    // lui t2, 0x80001
+    const PMP: [u32; 6] = [
+        0x2000_0337,
+        0x5FF3_0313,
+        0x3B03_1073,
+        0x0980_0313,
+        0x3A03_1073,
+        0x8000_13B7,
+    ];
+    // lui t2, 0x80001
+    const NO_PMP: [u32; 1] = [0x8000_13B7];
+
+    // address of the faulting instruction
+    let pc = |setup: &[u32]| 0x8000_000C + 4 * setup.len() as u32;
+
+    // Runs `setup` and `fault` with a handler installed, returns mcause, mepc and mtval.
+    let run = |setup: &[u32], fault: u32, policy: MisalignedAccess| {
+        //     auipc t0, 0
+        //     addi t0, t0, 16
+        //     csrw mtvec, t0
+        //     <fault>
+        // handler:
+        //     csrr a1, mcause
+        //     csrr a2, mepc
+        //     csrr a3, mtval
+        //     j .
+        let code: Vec<u8> = setup
+            .iter()
+            .chain(&[0x0000_0297, 0x0102_8293, 0x3052_9073, fault])
+            .chain(&[0x3420_25F3, 0x3410_2673, 0x3430_26F3, 0x0000_006F])
+            .flat_map(|instruction| instruction.to_le_bytes())
+            .collect();
+
+        let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(&code);
+        cpu.set_misaligned_access(policy);
+        assert!(matches!(cpu.run(), StopReason::Halted));
+        assert_eq!(pc(setup) + 16, cpu.pc);
+
+        (cpu.registers[11], cpu.registers[12], cpu.registers[13])
+    };
+
+    // jalr zero, 0(t2)
+    assert_eq!(
+        (1, 0x8000_1000, 0x8000_1000),
+        run(&PMP, 0x0003_8067, MisalignedAccess::Emulate)
+    );
+    // all ones
+    assert_eq!(
+        (2, pc(&NO_PMP), 0xFFFF_FFFF),
+        run(&NO_PMP, 0xFFFF_FFFF, MisalignedAccess::Emulate)
+    );
+    // lw t3, 1(t2)
+    assert_eq!(
+        (4, pc(&NO_PMP), 0x8000_1001),
+        run(&NO_PMP, 0x0013_AE03, MisalignedAccess::Trap)
+    );
+    // lw t3, 0(t2)
+    assert_eq!(
+        (5, pc(&PMP), 0x8000_1000),
+        run(&PMP, 0x0003_AE03, MisalignedAccess::Emulate)
+    );
+    // sw t3, 2(t2)
+    assert_eq!(
+        (6, pc(&NO_PMP), 0x8000_1002),
+        run(&NO_PMP, 0x01C3_A123, MisalignedAccess::Trap)
+    );
+    // sw t3, 0(t2)
+    assert_eq!(
+        (7, pc(&PMP), 0x8000_1000),
+        run(&PMP, 0x01C3_A023, MisalignedAccess::Emulate)
+    );
+}
+
+#[test]
+fn test_virtual_wall_clock() {
+    use std::time::Duration;
+
+    use crate::cpu::isa::RV32I;
+    use crate::cpu::{Cpu, StopReason};
+    use crate::devices::WallClock;
+    use crate::memory::Memory;
+
+    // loop: addi x1, x1, 1
+    //       j loop
+    const CODE: [u8; 8] = [0x93, 0x80, 0x10, 0x00, 0x6F, 0xF0, 0xDF, 0xFF];
+
+    // the rtc reads the same time in every run, one second plus 10ns per instruction
+    let time = || {
+        let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(&CODE);
+        cpu.set_wall_clock(WallClock::Virtual(Duration::from_secs(1)));
+        assert!(matches!(cpu.run_for(1000), StopReason::BudgetExhausted));
+        cpu.bus.load_u32(0x10_1000).map_err(|_| ()).unwrap()
+    };
+    let first = time();
+    assert_eq!(first, time());
+    assert!((1_000_000_000..=1_000_010_000).contains(&first));
+}
+
+#[test]
+fn test_attach_framebuffer() {
+    use crate::cpu::isa::RV32I;
+    use crate::cpu::{Cpu, StopReason};
+    use crate::devices::{Framebuffer, PixelFormat};
+
+    // lui x1, 0x50000
+    // lui x2, 0xFF0
+    // sw x2, 0(x1)
+    // j .
+    const CODE: [u8; 16] = [
+        0xB7, 0x00, 0x00, 0x50, 0x37, 0x01, 0xFF, 0x00, 0x23, 0xA0, 0x20, 0x00, 0x6F, 0x00, 0x00,
+        0x00,
+    ];
+
+    let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(&CODE);
+    let framebuffer = Framebuffer::new(2, 1, PixelFormat::Xrgb8888).unwrap();
+    let screen = framebuffer.screen();
+    assert_eq!(Some(0x5000_0000), cpu.attach_framebuffer(framebuffer));
+    // there is a single slot
+    let framebuffer = Framebuffer::new(2, 1, PixelFormat::Xrgb8888).unwrap();
+    assert_eq!(None, cpu.attach_framebuffer(framebuffer));
+
+    // the guest paints the first pixel red
+    assert!(matches!(cpu.run(), StopReason::Halted));
+    assert_eq!(vec![255, 0, 0, 0, 0, 0], screen.to_rgb());
+}
+
+#[test]
+fn test_software_interrupt() {
+    use crate::cpu::isa::RV32I;
+    use crate::cpu::{Cpu, StopReason};
+
+    //     la t0, handler
+    //     csrw mtvec, t0
+    //     addi t1, zero, 8    # MSIE
+    //     csrw mie, t1
+    //     csrsi mstatus, 8    # MIE
+    //     lui t0, 0x2000      # msip
+    //     addi t1, zero, 1
+    //     sw t1, 0(t0)
+    //     addi a0, zero, 1
+    //     csrr a4, mstatus
+    //     lui t0, 0x100
+    //     lui t1, 0x5
+    //     addi t1, t1, 0x555
+    //     sw t1, 0(t0)
+    // handler:
+    //     sw zero, 0(t0)
+    //     addi a3, zero, 42
+    //     csrr a1, mcause
+    //     csrr a2, mepc
+    //     mret
+    const CODE: [u8; 80] = [
+        0x97, 0x02, 0x00, 0x00, 0x93, 0x82, 0xC2, 0x03, 0x73, 0x90, 0x52, 0x30, 0x13, 0x03, 0x80,
+        0x00, 0x73, 0x10, 0x43, 0x30, 0x73, 0x60, 0x04, 0x30, 0xB7, 0x02, 0x00, 0x02, 0x13, 0x03,
+        0x10, 0x00, 0x23, 0xA0, 0x62, 0x00, 0x13, 0x05, 0x10, 0x00, 0x73, 0x27, 0x00, 0x30, 0xB7,
+        0x02, 0x10, 0x00, 0x37, 0x53, 0x00, 0x00, 0x13, 0x03, 0x53, 0x55, 0x23, 0xA0, 0x62, 0x00,
+        0x23, 0xA0, 0x02, 0x00, 0x93, 0x06, 0xA0, 0x02, 0xF3, 0x25, 0x20, 0x34, 0x73, 0x26, 0x10,
+        0x34, 0x73, 0x00, 0x20, 0x30,
+    ];
+
+    let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(&CODE);
+    assert!(matches!(cpu.run(), StopReason::PowerOff(0)));
+
+    // taken once, at the latest after the block containing the store, and returned from
+    assert_eq!(42, cpu.registers[13]);
+    assert_eq!(0x8000_0003, cpu.registers[11]);
+    assert!((0x8000_0024..=0x8000_002C).contains(&cpu.registers[12]));
+    assert_eq!(1, cpu.registers[10]);
+    // MRET restores MIE and sets MPIE
+    assert_eq!(0x8, cpu.registers[14]);
+    assert_eq!(0x88, cpu.trap_csrs.mstatus);
+}
+
+#[test]
+fn test_external_interrupt() {
+    use crate::cpu::isa::RV32I;
+    use crate::cpu::{Cpu, StopReason};
+
+    //     la t0, handler
+    //     csrw mtvec, t0
+    //     lui t0, 0xc000      # plic
+    //     addi t1, zero, 1
+    //     sw t1, 40(t0)       # priority of source 10
+    //     lui t2, 0xc002
+    //     addi t1, zero, 1024
+    //     sw t1, 0(t2)        # enable source 10 for context 0
+    //     li t1, 0x800        # MEIE
+    //     csrw mie, t1
+    //     csrsi mstatus, 8    # MIE
+    //     lui t0, 0x10000     # uart
+    //     addi t1, zero, 2
+    //     sb t1, 1(t0)        # enable the transmitter interrupt
+    // idle:
+    //     j idle
+    // handler:
+    //     csrr a2, mcause
+    //     lui t0, 0xc200
+    //     lw a1, 4(t0)        # claim
+    //     lw a3, 4(t0)
+    //     sw a1, 4(t0)        # complete
+    //     lui t0, 0x100
+    //     lui t1, 0x5
+    //     addi t1, t1, 0x555
+    //     sw t1, 0(t0)
+    const CODE: [u8; 104] = [
+        0x97, 0x02, 0x00, 0x00, 0x93, 0x82, 0x42, 0x04, 0x73, 0x90, 0x52, 0x30, 0xB7, 0x02, 0x00,
+        0x0C, 0x13, 0x03, 0x10, 0x00, 0x23, 0xA4, 0x62, 0x02, 0xB7, 0x23, 0x00, 0x0C, 0x13, 0x03,
+        0x00, 0x40, 0x23, 0xA0, 0x63, 0x00, 0x37, 0x13, 0x00, 0x00, 0x13, 0x03, 0x03, 0x80, 0x73,
+        0x10, 0x43, 0x30, 0x73, 0x60, 0x04, 0x30, 0xB7, 0x02, 0x00, 0x10, 0x13, 0x03, 0x20, 0x00,
+        0xA3, 0x80, 0x62, 0x00, 0x6F, 0x00, 0x00, 0x00, 0x73, 0x26, 0x20, 0x34, 0xB7, 0x02, 0x20,
+        0x0C, 0x83, 0xA5, 0x42, 0x00, 0x83, 0xA6, 0x42, 0x00, 0x23, 0xA2, 0xB2, 0x00, 0xB7, 0x02,
+        0x10, 0x00, 0x37, 0x53, 0x00, 0x00, 0x13, 0x03, 0x53, 0x55, 0x23, 0xA0, 0x62, 0x00,
+    ];
+
+    let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(&CODE);
+    assert!(matches!(cpu.run(), StopReason::PowerOff(0)));
+    assert_eq!(0x8000_000B, cpu.registers[12]);
+    assert_eq!(10, cpu.registers[11]);
+    // claimed interrupts are not forwarded again until completed
+    assert_eq!(0, cpu.registers[13]);
+}
+
+#[test]
+fn test_x0_hardwired() {
+    use crate::cpu::isa::RV32I;
+    use crate::cpu::Cpu;
+
+    // addi x0, x0, 5
+    // add x1, x0, x0
+    // lui x0, 0x12345
+    // jal x0, 4
+    // auipc x2, 0
+    const CODE: [u8; 20] = [
+        0x13, 0x00, 0x50, 0x00, 0xB3, 0x00, 0x00, 0x00, 0x37, 0x50, 0x34, 0x12, 0x6F, 0x00, 0x40,
+        0x00, 0x17, 0x01, 0x00, 0x00,
+    ];
+
+    let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(&CODE);
+    for _ in 0..5 {
+        cpu.cycle().unwrap();
+        assert_eq!(0, cpu.registers[0]);
+    }
+
+    assert_eq!(0, cpu.registers[1]);
+    assert_eq!(0x8000_0010, cpu.registers[2]);
+}
+
+#[test]
+fn test_all_ones_is_illegal() {
+    use crate::cpu::isa::{Isa, Op, RV32I};
+    use crate::cpu::{CPUError, Cpu};
+
+    assert_eq!(Op::Illegal(0xFFFF_FFFF), RV32I::decode(0xFFFF_FFFF));
+
+    let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(&[0xFF; 4]);
+    assert!(matches!(
+        cpu.cycle(),
+        Err(CPUError::InstructionNotImplemented(0xFFFF_FFFF))
+    ));
+}
+
+#[test]
+fn test_rv32e_registers() {
+    use crate::cpu::isa::{Isa, Op, RV32E, RV32I};
+
+    // addi x15, x0, 1
+    assert_eq!(
+        Op::Addi {
+            rd: 15,
+            rs1: 0,
+            imm: 1
+        },
+        RV32E::decode(0x0010_0793)
+    );
+    // addi x16, x0, 1
+    assert_eq!(Op::Illegal(0x0010_0813), RV32E::decode(0x0010_0813));
+    assert!(matches!(RV32I::decode(0x0010_0813), Op::Addi { rd: 16, .. }));
+}
+
+#[cfg(all(feature = "jit", target_arch = "x86_64"))]
+mod jit {
+    use crate::cpu::isa::{As, RV32I, RV64I};
+    use crate::cpu::Cpu;
+
+    /// Runs `$code` until pc reaches the end of it, either single stepping or running blocks.
+    macro_rules! run {
+        ($isa:ty, $code:expr, $step:ident) => {{
+            let mut cpu: Cpu<$isa, 32> = Cpu::with_code(&$code);
+            while cpu.pc.as_t::<u64>() != 0x8000_0000 + $code.len() as u64 {
+                cpu.$step().unwrap();
+            }
+            cpu
+        }};
+    }
+
+    //     addi t0, zero, 100
+    //     lui  a0, 0x12345
+    //     addi a1, zero, -7
+    // l:  add  a2, a2, a0
+    //     sub  a3, a3, a1
+    //     xor  a4, a2, a3
+    //     or   a5, a4, a1
+    //     and  a6, a5, a2
+    //     sll  a7, a2, a1
+    //     srl  s2, a3, t0
+    //     sra  s3, a3, a2
+    //     slt  s4, a3, a2
+    //     sltu s5, a3, a2
+    //     slli s6, a2, 3
+    //     srli s7, a3, 5
+    //     srai s8, a3, 7
+    //     slti s9, a3, -5
+    //     sltiu s10, a4, 2047
+    //     xori s11, a5, -1
+    //     ori  t3, a6, 0x55
+    //     andi t4, a7, -256
+    //     auipc t5, 0x1
+    //     add  a2, a2, s8
+    //     add  a3, a3, s7
+    //     addi a0, a0, 0x3F1
+    //     addi zero, a0, 1
+    //     addi t0, t0, -1
+    //     bnez t0, l
+    const ALU: [u8; 112] = [
+        0x93, 0x02, 0x40, 0x06, 0x37, 0x55, 0x34, 0x12, 0x93, 0x05, 0x90, 0xFF, 0x33, 0x06, 0xA6,
+        0x00, 0xB3, 0x86, 0xB6, 0x40, 0x33, 0x47, 0xD6, 0x00, 0xB3, 0x67, 0xB7, 0x00, 0x33, 0xF8,
+        0xC7, 0x00, 0xB3, 0x18, 0xB6, 0x00, 0x33, 0xD9, 0x56, 0x00, 0xB3, 0xD9, 0xC6, 0x40, 0x33,
+        0xAA, 0xC6, 0x00, 0xB3, 0xBA, 0xC6, 0x00, 0x13, 0x1B, 0x36, 0x00, 0x93, 0xDB, 0x56, 0x00,
+        0x13, 0xDC, 0x76, 0x40, 0x93, 0xAC, 0xB6, 0xFF, 0x13, 0x3D, 0xF7, 0x7F, 0x93, 0xCD, 0xF7,
+        0xFF, 0x13, 0x6E, 0x58, 0x05, 0x93, 0xFE, 0x08, 0xF0, 0x17, 0x1F, 0x00, 0x00, 0x33, 0x06,
+        0x86, 0x01, 0xB3, 0x86, 0x76, 0x01, 0x13, 0x05, 0x15, 0x3F, 0x13, 0x00, 0x15, 0x00, 0x93,
+        0x82, 0xF2, 0xFF, 0xE3, 0x90, 0x02, 0xFA,
+    ];
+
+    #[test]
+    fn test_alu_rv32() {
+        let interpreted = run!(RV32I, ALU, cycle);
+        let compiled = run!(RV32I, ALU, run_block);
+
+        assert_eq!(1, compiled.block_cache.compiled_blocks());
+        assert_eq!(interpreted.registers, compiled.registers);
+        assert_eq!(
+            interpreted.instructions_retired(),
+            compiled.instructions_retired()
+        );
+    }
+
+    #[test]
+    fn test_alu_rv64() {
+        let interpreted = run!(RV64I, ALU, cycle);
+        let compiled = run!(RV64I, ALU, run_block);
+
+        assert_eq!(1, compiled.block_cache.compiled_blocks());
+        assert_eq!(interpreted.registers, compiled.registers);
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        //     auipc s0, 0
+        //     lui t1, 0x250
+        //     addi t1, t1, 0x513  # t1 = addi a0, a0, 2
+        //     addi t0, zero, 100
+        // l:  addi a0, a0, 1
+        //     addi a1, a1, 3
+        //     addi t0, t0, -1
+        //     addi t2, zero, 50
+        //     bne t0, t2, s
+        //     sw t1, 16(s0)       # overwrite the instruction at l after it has been compiled
+        // s:  bnez t0, l
+        const CODE: [u8; 44] = [
+            0x17, 0x04, 0x00, 0x00, 0x37, 0x03, 0x25, 0x00, 0x13, 0x03, 0x33, 0x51, 0x93, 0x02,
+            0x40, 0x06, 0x13, 0x05, 0x15, 0x00, 0x93, 0x85, 0x35, 0x00, 0x93, 0x82, 0xF2, 0xFF,
+            0x93, 0x03, 0x20, 0x03, 0x63, 0x94, 0x72, 0x00, 0x23, 0x28, 0x64, 0x00, 0xE3, 0x94,
+            0x02, 0xFE,
+        ];
+
+        let interpreted = run!(RV32I, CODE, cycle);
+        let compiled = run!(RV32I, CODE, run_block);
+
+        assert_eq!(150, compiled.registers[10]);
+        assert_eq!(interpreted.registers, compiled.registers);
+    }
+}
+
+mod pmp {
+    use crate::cpu::isa::RV32I;
+    use crate::cpu::pmp::{Pmp, PMP_ENTRIES};
+    use crate::cpu::{CPUError, Cpu, Privilege, StopReason};
+    use crate::memory::AccessType::{Fetch, Load, Store};
+
+    const R: u32 = 0x01;
+    const RWX: u32 = 0x07;
+    const TOR: u32 = 0x08;
+    const NA4: u32 = 0x10;
+    const NAPOT: u32 = 0x18;
+    const L: u32 = 0x80;
+
+    #[test]
+    fn test_napot() {
+        let mut pmp: Pmp<u32> = Pmp::new();
+        // 0x8000_0000..0x8000_1000
+        pmp.write_addr_csr(0, (0x8000_0000 >> 2) | 0x1FF);
+        pmp.write_cfg_csr(0, NAPOT | R);
+
+        assert!(pmp.check(0x8000_0FFC, 4, Load, Privilege::User));
+        assert!(!pmp.check(0x8000_0FFC, 4, Store, Privilege::User));
+        assert!(!pmp.check(0x8000_0FFC, 4, Fetch, Privilege::Supervisor));
+        assert!(!pmp.check(0x8000_0FFE, 4, Load, Privilege::User));
+        assert!(!pmp.check(0x8000_1000, 4, Load, Privilege::User));
+        assert!(pmp.check(0x8000_0FFC, 4, Store, Privilege::Machine));
+    }
+
+    #[test]
+    fn test_tor_and_na4() {
+        let mut pmp: Pmp<u32> = Pmp::new();
+        pmp.write_addr_csr(0, 0x1000 >> 2);
+        pmp.write_addr_csr(1, 0x2000 >> 2);
+        pmp.write_addr_csr(2, 0x3000 >> 2);
+        pmp.write_cfg_csr(0, (NA4 | RWX) << 16 | (TOR | R) << 8);
+
+        assert!(pmp.check(0x1000, 4, Load, Privilege::User));
+        assert!(pmp.check(0x1FFC, 4, Load, Privilege::User));
+        assert!(!pmp.check(0x1FFC, 4, Store, Privilege::User));
+        assert!(!pmp.check(0x0FFC, 4, Load, Privilege::User));
+        assert!(pmp.check(0x3000, 4, Store, Privilege::User));
+        assert!(!pmp.check(0x3002, 4, Store, Privilege::User));
+        assert_eq!(
+            Some((NA4 | RWX) << 16 | (TOR | R) << 8),
+            pmp.read_cfg_csr(0)
+        );
+    }
+
+    #[test]
+    fn test_priority() {
+        let mut pmp: Pmp<u32> = Pmp::new();
+        pmp.write_addr_csr(0, 0x1000 >> 2);
+        // everything
+        pmp.write_addr_csr(1, u32::MAX);
+        pmp.write_cfg_csr(0, (NAPOT | RWX) << 8 | NA4);
+
+        assert!(!pmp.check(0x1000, 4, Load, Privilege::User));
+        assert!(pmp.check(0x1004, 4, Load, Privilege::User));
+    }
+
+    #[test]
+    fn test_lock() {
+        let mut pmp: Pmp<u32> = Pmp::new();
+        pmp.write_addr_csr(0, 0x1000 >> 2);
+        pmp.write_addr_csr(1, 0x2000 >> 2);
+        pmp.write_cfg_csr(0, (L | TOR | R) << 8);
+
+        assert!(!pmp.check(0x1000, 4, Store, Privilege::Machine));
+        assert!(pmp.check(0x1000, 4, Load, Privilege::Machine));
+        assert!(pmp.check(0x3000, 4, Store, Privilege::Machine));
+
+        // locked entries and the address of a locked TOR entry's lower bound ignore writes
+        pmp.write_cfg_csr(0, (TOR | RWX) << 8);
+        pmp.write_addr_csr(0, 0);
+        pmp.write_addr_csr(1, 0);
+        assert_eq!((L | TOR | R) as u8, pmp.cfg(1));
+        assert_eq!(0x1000 >> 2, pmp.addr(0));
+        assert_eq!(0x2000 >> 2, pmp.addr(1));
+    }
+
+    #[test]
+    fn test_unimplemented_cfg() {
+        // pmpcfg4 to pmpcfg15 hold no implemented entries
+        let mut pmp: Pmp<u32> = Pmp::new();
+        assert_eq!(Some(()), pmp.write_cfg_csr(15, u32::MAX));
+        assert_eq!(Some(0), pmp.read_cfg_csr(15));
+        assert!((0..PMP_ENTRIES).all(|index| pmp.cfg(index) == 0));
+
+        // the odd registers do not exist on RV64
+        let mut pmp: Pmp<u64> = Pmp::new();
+        assert_eq!(Some(()), pmp.write_cfg_csr(14, u64::MAX));
+        assert_eq!(Some(0), pmp.read_cfg_csr(14));
+        assert_eq!(None, pmp.read_cfg_csr(3));
+    }
+
+    #[test]
+    fn test_csr_instructions() {
+        const CODE: [u8; 36] = [
+            0xb7, 0x12, 0x00, 0x80, // lui t0, 0x80001
+            0x37, 0x03, 0x00, 0x20, // lui t1, 0x20000
+            0x13, 0x03, 0xf3, 0x5f, // addi t1, t1, 0x5FF
+            0x73, 0x10, 0x03, 0x3b, // csrw pmpaddr0, t1
+            0x13, 0x03, 0x90, 0x09, // li t1, 0x99 (L | NAPOT | R)
+            0x73, 0x10, 0x03, 0x3a, // csrw pmpcfg0, t1
+            0xf3, 0x23, 0x00, 0x3a, // csrr t2, pmpcfg0
+            0x03, 0xae, 0x02, 0x00, // lw t3, 0(t0)
+            0x23, 0xa0, 0xc2, 0x01, // sw t3, 0(t0)
+        ];
+
+        let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(&CODE);
+
+        assert!(matches!(
+            cpu.run(),
+            StopReason::Error(CPUError::StoreAccessFault(0x8000_1000))
+        ));
+        assert_eq!(0x99, cpu.registers[7]);
+        assert_eq!(0x200005FF, cpu.pmp().addr(0));
+    }
+}
@@ -0,0 +1,151 @@
+//! Delivery of interrupts and exceptions, and returning from trap handlers. Every trap is taken
+//! in machine mode. Exceptions whose handler can not be fetched, e.g. because `mtvec` was never
+//! set up, are reported to the host as [`CPUError`] instead.
+
+use num_traits::{NumCast, ToPrimitive};
+
+use crate::cpu::csr::{MEI, MSI, MSTATUS_MIE, MSTATUS_MPIE, MSTATUS_MPP, MTI, SEI};
+use crate::cpu::isa::Isa;
+use crate::cpu::{CPUError, Cpu, Privilege};
+use crate::memory::AccessType;
+
+const MRET: u32 = 0x3020_0073;
+
+/// Interrupt causes in the order they are taken if several are pending.
+const PRIORITY: [u64; 4] = [11, 3, 7, 9];
+
+/// Exception cause of an `ECALL` from user mode, the privilege mode is added to it.
+const ECALL_FROM_U: u64 = 8;
+
+impl<I: Isa<REG_COUNT>, const REG_COUNT: usize> Cpu<I, REG_COUNT> {
+    /// Returns the levels of the interrupt lines as reported in `mip`.
+    #[inline]
+    pub(crate) fn pending_interrupts(&self) -> u64 {
+        self.irqs.pending()
+    }
+
+    /// Returns whether an interrupt would be taken if one of the lines enabled in `mie` was
+    /// raised.
+    pub(crate) fn interrupts_enabled(&self) -> bool {
+        let trap = &self.trap_csrs;
+        trap.mie & (MSI | MTI | SEI | MEI) != 0
+            && (self.privilege < Privilege::Machine || trap.mstatus & MSTATUS_MIE != 0)
+    }
+
+    /// Takes the highest priority interrupt that is pending and enabled, if any. Called before
+    /// each instruction or block.
+    #[inline]
+    pub(crate) fn poll_interrupts(&mut self) {
+        self.bus.tick(self.retired);
+        // devices may have written over translated code
+        for (addr, size) in self.bus.take_device_writes() {
+            self.decode_cache.invalidate(addr, size);
+            self.block_cache.invalidate(addr, size);
+        }
+        if !self.interrupts_enabled() {
+            return;
+        }
+
+        let pending = self.pending_interrupts() & self.trap_csrs.mie;
+        if let Some(cause) = PRIORITY.into_iter().find(|cause| pending & 1 << cause != 0) {
+            self.take_interrupt(cause);
+        }
+    }
+
+    /// Enters the machine-mode trap handler for interrupt `cause`, pc is the instruction to
+    /// return to.
+    fn take_interrupt(&mut self, cause: u64) {
+        let xlen = 8 * std::mem::size_of::<I::XlenU>();
+        // vectored mode jumps to base + 4 * cause
+        let offset = if self.trap_csrs.mtvec & 0b1 != 0 { 4 * cause } else { 0 };
+        self.enter_trap(1 << (xlen - 1) | cause, self.pc, offset, 0);
+    }
+
+    /// Enters the machine-mode trap handler for an `ECALL` at `pc`, which is returned to.
+    pub(crate) fn ecall(&mut self, pc: I::XlenU) {
+        let cause = ECALL_FROM_U + self.privilege as u64;
+        self.enter_trap(cause, pc, 0, 0);
+    }
+
+    /// Enters the machine-mode trap handler for the exception `e` raised by the instruction at
+    /// `pc`, which is returned to. Errors that are not exceptions are passed through, as are
+    /// exceptions whose handler can not be fetched, which would trap again forever.
+    pub(crate) fn exception(
+        &mut self,
+        e: CPUError<I::XlenU>,
+        pc: I::XlenU,
+    ) -> Result<(), CPUError<I::XlenU>> {
+        let address = |addr: I::XlenU| addr.to_u64().expect("xlen fits 64 bits");
+        let (cause, tval) = match e {
+            CPUError::InstructionAddressMisaligned(addr) => (0, address(addr)),
+            CPUError::InstructionAccessFault(addr) => (1, address(addr)),
+            CPUError::InstructionNotImplemented(instruction) => (2, instruction as u64),
+            CPUError::LoadAddressMisaligned(addr) => (4, address(addr)),
+            CPUError::LoadAccessFault(addr) => (5, address(addr)),
+            CPUError::StoreAddressMisaligned(addr) => (6, address(addr)),
+            CPUError::StoreAccessFault(addr) => (7, address(addr)),
+            _ => return Err(e),
+        };
+
+        let handler = NumCast::from(self.trap_csrs.mtvec & !0b11).expect("mtvec is xlen wide");
+        if !self
+            .pmp
+            .check(handler, 4, AccessType::Fetch, Privilege::Machine)
+            || self.bus.fetch_u32(handler).is_err()
+        {
+            return Err(e);
+        }
+
+        self.enter_trap(cause, pc, 0, tval);
+        Ok(())
+    }
+
+    /// Saves the state for returning to `epc` and jumps to `offset` past the base in `mtvec`.
+    fn enter_trap(&mut self, cause: u64, epc: I::XlenU, offset: u64, tval: u64) {
+        let xlen = 8 * std::mem::size_of::<I::XlenU>();
+        let trap = &mut self.trap_csrs;
+
+        trap.mepc = epc.to_u64().expect("xlen fits 64 bits");
+        trap.mcause = cause;
+        trap.mtval = tval;
+
+        // MPIE = MIE, MIE = 0, MPP = privilege
+        let mie = trap.mstatus & MSTATUS_MIE;
+        trap.mstatus &= !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP);
+        trap.mstatus |= mie << 4 | (self.privilege as u64) << 11;
+        self.privilege = Privilege::Machine;
+
+        let target = (trap.mtvec & !0b11).wrapping_add(offset);
+        self.pc = NumCast::from(target & (u64::MAX >> (64 - xlen))).expect("masked to xlen");
+    }
+
+    /// Returns from a machine-mode trap handler to `mepc` and the privilege mode in MPP.
+    pub(crate) fn mret(&mut self) -> Result<(), CPUError<I::XlenU>> {
+        if self.privilege != Privilege::Machine {
+            return Err(CPUError::InstructionNotImplemented(MRET));
+        }
+
+        // MIE = MPIE, MPIE = 1, MPP = user
+        let trap = &mut self.trap_csrs;
+        let (mpie, mpp) = (
+            trap.mstatus & MSTATUS_MPIE,
+            (trap.mstatus & MSTATUS_MPP) >> 11,
+        );
+        trap.mstatus &= !(MSTATUS_MIE | MSTATUS_MPP);
+        trap.mstatus |= mpie >> 4 | MSTATUS_MPIE;
+
+        self.pc = NumCast::from(trap.mepc).expect("mepc holds an xlen wide address");
+        self.privilege = match mpp {
+            0 => Privilege::User,
+            1 => Privilege::Supervisor,
+            _ => Privilege::Machine,
+        };
+
+        // cached instructions were fetched with machine-mode permissions
+        if self.privilege != Privilege::Machine {
+            self.flush_translations();
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,300 @@
+//! Boot layout of qemu's virt machine: firmware, kernel, initrd and a generated device tree.
+
+use std::ops::Range;
+
+use num_traits::{AsPrimitive, NumCast};
+
+use crate::cpu::isa::{As, Isa};
+use crate::cpu::{
+    Cpu, CLINT_BASE, CLINT_SIZE, DRAM_BASE, DRAM_SIZE, FINISHER_BASE, FINISHER_SIZE, PLIC_BASE,
+    PLIC_SIZE, PLIC_SOURCES, RTC_BASE, RTC_IRQ, RTC_SIZE, UART_BASE, UART_IRQ, UART_SIZE,
+    VIRTIO_BASE, VIRTIO_SIZE, VIRTIO_SLOTS,
+};
+use crate::devices::{FINISHER_PASS, FINISHER_RESET, INSTRUCTIONS_PER_SECOND};
+use crate::fdt::Fdt;
+use crate::memory::Memory;
+
+/// The device tree is placed at the last multiple of this that leaves room for it in dram.
+const FDT_ALIGN: usize = 0x20_0000;
+const FDT_MAX_SIZE: usize = 0x1_0000;
+const INITRD_ALIGN: usize = 0x1000;
+
+// phandles of the nodes other nodes refer to
+const CPU_INTC: u32 = 1;
+const PLIC: u32 = 2;
+const FINISHER: u32 = 3;
+
+/// `fw_dynamic_info` magic, "OSBI".
+const FW_DYNAMIC_MAGIC: u64 = 0x4942_534F;
+const FW_DYNAMIC_VERSION: u64 = 2;
+/// The kernel is entered in supervisor mode.
+const FW_DYNAMIC_NEXT_MODE: u64 = 1;
+
+/// Where [`Cpu::virt`] placed the images the device tree points to.
+pub(super) struct Boot {
+    fdt: usize,
+    initrd: Option<Range<usize>>,
+    bootargs: String,
+}
+
+impl<I: Isa<REG_COUNT>, const REG_COUNT: usize> Cpu<I, REG_COUNT> {
+    /// Writes the device tree describing the machine as it is now, if it was built by
+    /// [`virt`](Self::virt).
+    pub(super) fn update_device_tree(&mut self) {
+        let Some(boot) = &self.boot else {
+            return;
+        };
+
+        let fdt = boot.fdt;
+        let blob = self.device_tree(boot);
+        assert!(
+            blob.len() <= FDT_MAX_SIZE,
+            "the device tree outgrew its space"
+        );
+        self.write_image(fdt, &blob);
+    }
+
+    /// Copies `data` to `addr` in dram.
+    fn write_image(&mut self, addr: usize, data: &[u8]) {
+        for (i, &byte) in data.iter().enumerate() {
+            let stored = <I::XlenU as NumCast>::from(addr + i)
+                .and_then(|addr| self.bus.store_u8(addr, byte).ok());
+            assert!(stored.is_some(), "images are placed in dram");
+        }
+    }
+
+    fn device_tree(&self, boot: &Boot) -> Vec<u8> {
+        let mut fdt = Fdt::new();
+        fdt.begin_node("");
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_u32("#size-cells", 2);
+        fdt.property_string("compatible", "riscv-virtio");
+        fdt.property_string("model", "riscv-virtio,qemu");
+
+        fdt.begin_node("chosen");
+        fdt.property_string("bootargs", &boot.bootargs);
+        fdt.property_string("stdout-path", &format!("/soc/serial@{UART_BASE:x}"));
+        if let Some(initrd) = &boot.initrd {
+            fdt.property_u64("linux,initrd-start", initrd.start as u64);
+            fdt.property_u64("linux,initrd-end", initrd.end as u64);
+        }
+        fdt.end_node();
+
+        fdt.begin_node(&format!("memory@{DRAM_BASE:x}"));
+        fdt.property_string("device_type", "memory");
+        fdt.property_cells("reg", &reg(DRAM_BASE, DRAM_SIZE));
+        fdt.end_node();
+
+        fdt.begin_node("cpus");
+        fdt.property_u32("#address-cells", 1);
+        fdt.property_u32("#size-cells", 0);
+        fdt.property_u32("timebase-frequency", INSTRUCTIONS_PER_SECOND as u32);
+        fdt.begin_node("cpu@0");
+        fdt.property_string("device_type", "cpu");
+        fdt.property_u32("reg", 0);
+        fdt.property_string("status", "okay");
+        fdt.property_string("compatible", "riscv");
+        fdt.property_string("riscv,isa", &I::ISA_ID.to_lowercase());
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property("interrupt-controller", &[]);
+        fdt.property_string("compatible", "riscv,cpu-intc");
+        fdt.property_u32("phandle", CPU_INTC);
+        fdt.end_node();
+        fdt.end_node();
+        fdt.end_node();
+
+        // the test finisher doubles as syscon for powering off and rebooting
+        for (name, value) in [("poweroff", FINISHER_PASS), ("reboot", FINISHER_RESET)] {
+            fdt.begin_node(name);
+            fdt.property_string("compatible", &format!("syscon-{name}"));
+            fdt.property_u32("regmap", FINISHER);
+            fdt.property_u32("offset", 0);
+            fdt.property_u32("value", value);
+            fdt.end_node();
+        }
+
+        fdt.begin_node("soc");
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_u32("#size-cells", 2);
+        fdt.property_string("compatible", "simple-bus");
+        fdt.property("ranges", &[]);
+
+        fdt.begin_node(&format!("test@{FINISHER_BASE:x}"));
+        fdt.property_strings("compatible", &["sifive,test1", "sifive,test0", "syscon"]);
+        fdt.property_cells("reg", &reg(FINISHER_BASE, FINISHER_SIZE));
+        fdt.property_u32("phandle", FINISHER);
+        fdt.end_node();
+
+        fdt.begin_node(&format!("rtc@{RTC_BASE:x}"));
+        fdt.property_string("compatible", "google,goldfish-rtc");
+        fdt.property_cells("reg", &reg(RTC_BASE, RTC_SIZE));
+        fdt.property_u32("interrupts", RTC_IRQ as u32);
+        fdt.property_u32("interrupt-parent", PLIC);
+        fdt.end_node();
+
+        fdt.begin_node(&format!("clint@{CLINT_BASE:x}"));
+        fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+        fdt.property_cells("reg", &reg(CLINT_BASE, CLINT_SIZE));
+        fdt.property_cells("interrupts-extended", &[CPU_INTC, 3, CPU_INTC, 7]);
+        fdt.end_node();
+
+        fdt.begin_node(&format!("plic@{PLIC_BASE:x}"));
+        fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+        fdt.property_cells("reg", &reg(PLIC_BASE, PLIC_SIZE));
+        fdt.property_u32("#address-cells", 0);
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property("interrupt-controller", &[]);
+        fdt.property_u32("riscv,ndev", PLIC_SOURCES as u32 - 1);
+        fdt.property_cells("interrupts-extended", &[CPU_INTC, 11, CPU_INTC, 9]);
+        fdt.property_u32("phandle", PLIC);
+        fdt.end_node();
+
+        fdt.begin_node(&format!("serial@{UART_BASE:x}"));
+        fdt.property_string("compatible", "ns16550a");
+        fdt.property_cells("reg", &reg(UART_BASE, UART_SIZE));
+        fdt.property_u32("clock-frequency", 0x38_4000);
+        fdt.property_u32("interrupts", UART_IRQ as u32);
+        fdt.property_u32("interrupt-parent", PLIC);
+        fdt.end_node();
+
+        // only the slots with a device are mapped, probing the others would fault
+        for i in 0..VIRTIO_SLOTS {
+            let base = VIRTIO_BASE + i * VIRTIO_SIZE;
+            let free = self
+                .virtio_slots
+                .iter()
+                .any(|(mapping, _)| mapping.start.as_t::<usize>() == base);
+            if free {
+                continue;
+            }
+
+            fdt.begin_node(&format!("virtio_mmio@{base:x}"));
+            fdt.property_string("compatible", "virtio,mmio");
+            fdt.property_cells("reg", &reg(base, VIRTIO_SIZE));
+            fdt.property_u32("interrupts", 1 + i as u32);
+            fdt.property_u32("interrupt-parent", PLIC);
+            fdt.end_node();
+        }
+
+        fdt.end_node();
+        fdt.end_node();
+
+        fdt.finish()
+    }
+}
+
+impl<I: Isa<REG_COUNT>, const REG_COUNT: usize> Cpu<I, REG_COUNT>
+where
+    bool: AsPrimitive<I::XlenU>,
+    u8: AsPrimitive<I::XlenU>,
+    i8: AsPrimitive<I::XlenU>,
+    u16: AsPrimitive<I::XlenU>,
+    u32: AsPrimitive<I::XlenU>,
+    i32: AsPrimitive<I::XlenU>,
+    i8: AsPrimitive<I::XlenI>,
+    i16: AsPrimitive<I::XlenI>,
+    u32: AsPrimitive<I::XlenI>,
+    i32: AsPrimitive<I::XlenI>,
+    I::XlenU: AsPrimitive<u8>,
+    I::XlenU: AsPrimitive<u16>,
+    I::XlenU: AsPrimitive<u32>,
+    usize: AsPrimitive<I::XlenU>,
+{
+    /// Creates a cpu with the devices of [`with_code`](Self::with_code) that boots like qemu's
+    /// virt machine.
+    ///
+    /// A boot rom at the reset vector enters `firmware` at the start of dram with the hart id in
+    /// `a0`, the address of the device tree in `a1` and that of a `fw_dynamic_info` in `a2`,
+    /// which tells OpenSBI to continue with `kernel` in supervisor mode. The kernel is placed at
+    /// the first 2 MiB boundary past the firmware on RV64 and 4 MiB boundary on RV32, the
+    /// `initrd` right below the device tree at the end of dram. The device tree records the
+    /// `bootargs`, the initrd and the uart as console, and follows the devices attached with
+    /// [`attach_virtio`](Self::attach_virtio).
+    ///
+    /// Returns `None` if the images do not fit in dram.
+    ///
+    /// Only the boot layout exists so far: a stock kernel and OpenSBI also need RV64 execution,
+    /// the M, A and C extensions and supervisor mode with Sv39, which are not implemented yet.
+    pub fn virt(
+        firmware: &[u8],
+        kernel: &[u8],
+        initrd: Option<&[u8]>,
+        bootargs: &str,
+    ) -> Option<Cpu<I, REG_COUNT>> {
+        let xlen = 8 * std::mem::size_of::<I::XlenU>();
+        let fdt = (DRAM_BASE + DRAM_SIZE - FDT_MAX_SIZE) & !(FDT_ALIGN - 1);
+        let kernel_align = if xlen == 64 { 0x20_0000 } else { 0x40_0000 };
+        let kernel_start = (DRAM_BASE + firmware.len()).next_multiple_of(kernel_align);
+        let kernel_end = kernel_start.checked_add(kernel.len())?;
+
+        let initrd = match initrd {
+            Some(initrd) => {
+                let start = fdt.checked_sub(initrd.len())? & !(INITRD_ALIGN - 1);
+                Some((start..start + initrd.len(), initrd))
+            }
+            None => None,
+        };
+        let free = initrd.as_ref().map_or(fdt, |(range, _)| range.start);
+        if kernel_end > free {
+            return None;
+        }
+
+        let mut cpu = Self::machine(Some(&boot_rom(xlen, fdt, kernel_start)), firmware);
+        cpu.write_image(kernel_start, kernel);
+        if let Some((range, data)) = &initrd {
+            cpu.write_image(range.start, data);
+        }
+        cpu.boot = Some(Boot {
+            fdt,
+            initrd: initrd.map(|(range, _)| range),
+            bootargs: bootargs.to_owned(),
+        });
+        cpu.update_device_tree();
+
+        Some(cpu)
+    }
+}
+
+/// Returns the cells of a `reg` property with two cells each for address and size.
+fn reg(base: usize, size: usize) -> [u32; 4] {
+    let (base, size) = (base as u64, size as u64);
+    [
+        (base >> 32) as u32,
+        base as u32,
+        (size >> 32) as u32,
+        size as u32,
+    ]
+}
+
+/// Returns the boot rom of [`Cpu::virt`], which enters the firmware at the start of dram with
+/// the device tree at `fdt` and the kernel at `kernel`.
+fn boot_rom(xlen: usize, fdt: usize, kernel: usize) -> Vec<u8> {
+    // funct3 of lw or ld
+    let load = if xlen == 64 { 0b011 << 12 } else { 0b010 << 12 };
+    let code: [u32; 6] = [
+        0x0000_0297,        // auipc t0, 0
+        0x0282_8613,        // addi a2, t0, 40      # fw_dynamic_info
+        0x0000_0513,        // li a0, 0             # hart id
+        0x0202_8583 | load, // l[wd] a1, 32(t0)     # device tree
+        0x0182_8283 | load, // l[wd] t0, 24(t0)     # firmware
+        0x0002_8067,        // jr t0
+    ];
+
+    let mut rom: Vec<u8> = code.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+    rom.extend_from_slice(&(DRAM_BASE as u64).to_le_bytes());
+    rom.extend_from_slice(&(fdt as u64).to_le_bytes());
+    // fw_dynamic_info: magic, version, next address, next mode, options and boot hart
+    for field in [
+        FW_DYNAMIC_MAGIC,
+        FW_DYNAMIC_VERSION,
+        kernel as u64,
+        FW_DYNAMIC_NEXT_MODE,
+        0,
+        0,
+    ] {
+        rom.extend_from_slice(&field.to_le_bytes()[..xlen / 8]);
+    }
+
+    rom
+}
@@ -0,0 +1,201 @@
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use num_traits::Unsigned;
+
+use crate::cpu::isa::{As, Xlen};
+use crate::cpu::CPUError;
+use crate::devices::{Device, IrqLine, Timer};
+use crate::memory::{impl_memory, Access, Memory};
+
+// register offsets of hart 0, the layout used by SiFive and qemu
+const MSIP: usize = 0x0000;
+const MTIMECMP: usize = 0x4000;
+const MTIME: usize = 0xBFF8;
+
+/// How often host time is checked against `mtimecmp`, in virtual time.
+const WALL_CLOCK_POLL: Duration = Duration::from_micros(100);
+
+/// What `mtime` counts.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Timebase {
+    /// One tick per the given number of retired instructions, so runs are reproducible.
+    Instructions(u64),
+    /// Host wall-clock time at the given frequency in Hz.
+    WallClock(u64),
+}
+
+/// Core-local interruptor of a single hart, providing the machine timer and software interrupt.
+///
+/// The device schedules a tick for when `mtime` reaches `mtimecmp`, so timer interrupts are
+/// only noticed between translated blocks, and `mtime` read within a block is the value at its
+/// start. Until the device is mapped on a bus, and given a [`Timer`], `mtime` does not count.
+pub struct Clint<A: Xlen + Unsigned> {
+    size: A,
+    timebase: Timebase,
+    msip: bool,
+    mtimecmp: u64,
+    /// Added to the ticks counted by the timebase, so `mtime` can be written
+    offset: u64,
+    start: Instant,
+    timer: Option<Timer>,
+    /// Machine software and timer interrupt lines of the hart
+    msi: IrqLine,
+    mti: IrqLine,
+}
+
+impl<A: Xlen + Unsigned> Clint<A> {
+    pub fn new(size: A, timebase: Timebase, msi: IrqLine, mti: IrqLine) -> Clint<A> {
+        Self {
+            size,
+            timebase,
+            msip: false,
+            // no timer interrupt until one is programmed
+            mtimecmp: u64::MAX,
+            offset: 0,
+            start: Instant::now(),
+            timer: None,
+            msi,
+            mti,
+        }
+    }
+
+    pub fn mtime(&self) -> u64 {
+        let ticks = match self.timebase {
+            Timebase::Instructions(n) => self.timer.as_ref().map_or(0, Timer::now) / n.max(1),
+            Timebase::WallClock(hz) => {
+                let nanos = self.start.elapsed().as_nanos();
+                (nanos * hz as u128 / 1_000_000_000) as u64
+            }
+        };
+        ticks.wrapping_add(self.offset)
+    }
+
+    pub fn mtimecmp(&self) -> u64 {
+        self.mtimecmp
+    }
+
+    /// Level of the machine software interrupt line, MSIP in `mip`.
+    pub fn software_interrupt(&self) -> bool {
+        self.msip
+    }
+
+    /// Level of the machine timer interrupt line, MTIP in `mip`.
+    pub fn timer_interrupt(&self) -> bool {
+        self.mtime() >= self.mtimecmp
+    }
+
+    fn update_irqs(&self) {
+        self.msi.set(self.msip);
+        self.update_timer();
+    }
+
+    /// Drives the timer line and schedules a tick for when it is raised.
+    fn update_timer(&self) {
+        let level = self.timer_interrupt();
+        self.mti.set(level);
+
+        let Some(timer) = &self.timer else {
+            return;
+        };
+        if level {
+            // the line stays raised until mtimecmp or mtime is written
+            timer.cancel();
+            return;
+        }
+        match self.timebase {
+            Timebase::Instructions(n) => {
+                let (n, now) = (n.max(1), timer.now());
+                let ticks = self.mtimecmp - self.mtime();
+                timer.schedule_at((now - now % n).saturating_add(ticks.saturating_mul(n)));
+            }
+            // host time passes independently of the hart
+            Timebase::WallClock(_) => timer.schedule_after(WALL_CLOCK_POLL),
+        }
+    }
+
+    /// Returns the register containing `offset` with its address and width in bytes.
+    fn register(&self, offset: usize) -> Option<(usize, usize, u64)> {
+        match offset {
+            MSIP..=0x0003 => Some((MSIP, 4, self.software_interrupt() as u64)),
+            MTIMECMP..=0x4007 => Some((MTIMECMP, 8, self.mtimecmp())),
+            MTIME..=0xBFFF => Some((MTIME, 8, self.mtime())),
+            _ => None,
+        }
+    }
+
+    fn read<T: Access>(&self, addr: A) -> Result<T, CPUError<A>> {
+        let offset = addr.as_t::<usize>();
+        let Some((base, width, value)) = self.register(offset) else {
+            // reserved space, e.g. the registers of other harts, reads as zero
+            return Ok(T::from_le_bytes(&[0; 16][..T::SIZE]));
+        };
+
+        value.to_le_bytes()[..width]
+            .get(offset - base..offset - base + T::SIZE)
+            .map(T::from_le_bytes)
+            .ok_or(CPUError::LoadAccessFault(addr))
+    }
+
+    fn write<T: Access>(&mut self, addr: A, value: T) -> Result<(), CPUError<A>> {
+        let offset = addr.as_t::<usize>();
+        let Some((base, width, old)) = self.register(offset) else {
+            return Ok(());
+        };
+
+        // partial writes, e.g. of one half of mtimecmp on 32 bit harts, keep the other bytes
+        let mut bytes = old.to_le_bytes();
+        let part = bytes[..width]
+            .get_mut(offset - base..offset - base + T::SIZE)
+            .ok_or(CPUError::StoreAccessFault(addr))?;
+        value.write_le_bytes(part);
+        let new = u64::from_le_bytes(bytes);
+
+        match base {
+            MSIP => self.msip = new & 1 != 0,
+            MTIMECMP => self.mtimecmp = new,
+            _ => self.offset = new.wrapping_sub(self.mtime().wrapping_sub(self.offset)),
+        }
+        self.update_irqs();
+
+        Ok(())
+    }
+}
+
+impl<A: Xlen + Unsigned> Device<A> for Clint<A> {
+    /// `mtime` has reached `mtimecmp`.
+    fn tick(&mut self, _now: u64, _bus: &mut dyn Memory<A>) {
+        self.update_timer();
+    }
+
+    fn set_timer(&mut self, timer: Timer) {
+        self.timer = Some(timer);
+        self.update_timer();
+    }
+
+    /// Restarts `mtime` from zero and clears the software interrupt, `mtimecmp` is kept.
+    fn reset(&mut self) {
+        self.msip = false;
+        self.offset = 0;
+        self.start = Instant::now();
+        self.update_irqs();
+    }
+}
+
+impl<A: Xlen + Unsigned> Memory<A> for Clint<A> {
+    fn size(&self) -> A {
+        self.size
+    }
+
+    impl_memory!(self, addr, value, { self.read(addr) }, {
+        self.write(addr, value)
+    });
+
+    fn get_data(&self, range: Range<A>) -> Result<Vec<u8>, CPUError<A>> {
+        Ok(vec![0; (range.end - range.start).as_t::<usize>()])
+    }
+
+    fn as_device(&mut self) -> Option<&mut dyn Device<A>> {
+        Some(self)
+    }
+}
@@ -0,0 +1,261 @@
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::rc::Rc;
+
+use num_traits::{NumCast, Unsigned};
+
+use crate::cpu::isa::{As, Xlen};
+use crate::cpu::CPUError;
+use crate::memory::{impl_memory, load_from_slice, store_to_slice, Access, Memory};
+
+/// Largest amount of pixel data in a stored deflate block.
+const STORED_BLOCK_SIZE: usize = 0xFFFF;
+
+/// Layout of a pixel in memory, named like the formats of the `simple-framebuffer` device tree
+/// binding. The components are listed from the most to the least significant bits of the
+/// little-endian pixel.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// 16 bits, `r5g6b5`
+    Rgb565,
+    /// 24 bits, `r8g8b8`
+    Rgb888,
+    /// 32 bits with the top byte unused, `x8r8g8b8`
+    Xrgb8888,
+    /// 32 bits with the top byte unused, `x8b8g8r8`
+    Xbgr8888,
+}
+
+impl PixelFormat {
+    /// Returns the format called `name` in the device tree binding.
+    pub fn from_name(name: &str) -> Option<PixelFormat> {
+        match name {
+            "r5g6b5" => Some(PixelFormat::Rgb565),
+            "r8g8b8" => Some(PixelFormat::Rgb888),
+            "x8r8g8b8" => Some(PixelFormat::Xrgb8888),
+            "x8b8g8r8" => Some(PixelFormat::Xbgr8888),
+            _ => None,
+        }
+    }
+
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Rgb888 => 3,
+            PixelFormat::Xrgb8888 | PixelFormat::Xbgr8888 => 4,
+        }
+    }
+
+    /// Converts the pixel in `bytes` to 8 bit red, green and blue.
+    fn to_rgb(self, bytes: &[u8]) -> [u8; 3] {
+        match self {
+            PixelFormat::Rgb565 => {
+                let pixel = u16::from_le_bytes([bytes[0], bytes[1]]);
+                let (r, g, b) = (pixel >> 11, pixel >> 5 & 0x3F, pixel & 0x1F);
+                // the top bits are repeated, so white stays white
+                [
+                    (r << 3 | r >> 2) as u8,
+                    (g << 2 | g >> 4) as u8,
+                    (b << 3 | b >> 2) as u8,
+                ]
+            }
+            PixelFormat::Rgb888 | PixelFormat::Xrgb8888 => [bytes[2], bytes[1], bytes[0]],
+            PixelFormat::Xbgr8888 => [bytes[0], bytes[1], bytes[2]],
+        }
+    }
+}
+
+/// The picture shown by a [`Framebuffer`], for taking snapshots while the guest runs. Clones
+/// show the same picture.
+#[derive(Clone)]
+pub struct Screen {
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+    pixels: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Screen {
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    /// Returns the current frame with 8 bit red, green and blue per pixel, row by row from the
+    /// top left corner.
+    pub fn to_rgb(&self) -> Vec<u8> {
+        self.pixels
+            .borrow()
+            .chunks_exact(self.format.bytes_per_pixel())
+            .flat_map(|pixel| self.format.to_rgb(pixel))
+            .collect()
+    }
+
+    /// Writes the current frame as a binary PPM image.
+    pub fn write_ppm(&self, mut out: impl Write) -> io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        out.write_all(&self.to_rgb())?;
+        out.flush()
+    }
+
+    /// Writes the current frame as a PNG image, which is not compressed.
+    pub fn write_png(&self, mut out: impl Write) -> io::Result<()> {
+        let rgb = self.to_rgb();
+
+        // every row starts with its filter type, none
+        let mut data = Vec::with_capacity(rgb.len() + self.height);
+        for row in rgb.chunks_exact(3 * self.width) {
+            data.push(0);
+            data.extend(row);
+        }
+
+        // zlib stream with the default window size and the data in stored blocks
+        let mut zlib = Vec::with_capacity(data.len() + data.len() / STORED_BLOCK_SIZE * 5 + 11);
+        zlib.extend([0x78, 0x01]);
+        let mut blocks = data.chunks(STORED_BLOCK_SIZE).peekable();
+        while let Some(block) = blocks.next() {
+            let len = block.len() as u16;
+            zlib.push(blocks.peek().is_none() as u8);
+            zlib.extend(len.to_le_bytes());
+            zlib.extend((!len).to_le_bytes());
+            zlib.extend(block);
+        }
+        zlib.extend(adler32(&data).to_be_bytes());
+
+        let mut header = Vec::with_capacity(13);
+        header.extend((self.width as u32).to_be_bytes());
+        header.extend((self.height as u32).to_be_bytes());
+        // 8 bit truecolor, default compression and filters, not interlaced
+        header.extend([8, 2, 0, 0, 0]);
+
+        out.write_all(b"\x89PNG\r\n\x1a\n")?;
+        write_chunk(&mut out, b"IHDR", &header)?;
+        write_chunk(&mut out, b"IDAT", &zlib)?;
+        write_chunk(&mut out, b"IEND", &[])?;
+        out.flush()
+    }
+
+    /// Writes the current frame to the file at `path`, as a PPM image if its extension is
+    /// `ppm` and as a PNG image otherwise.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let out = BufWriter::new(File::create(path)?);
+        match path.extension() {
+            Some(extension) if extension.eq_ignore_ascii_case("ppm") => self.write_ppm(out),
+            _ => self.write_png(out),
+        }
+    }
+}
+
+fn write_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    out.write_all(&crc32(kind.iter().chain(data)).to_be_bytes())
+}
+
+/// The checksum of png chunks.
+fn crc32<'a>(data: impl IntoIterator<Item = &'a u8>) -> u32 {
+    let crc = data.into_iter().fold(!0u32, |mut crc, &byte| {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+        crc
+    });
+
+    !crc
+}
+
+/// The checksum of zlib streams.
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // the sums can not overflow within a chunk
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+
+    b << 16 | a
+}
+
+/// Linear framebuffer without any registers, like the `simple-framebuffer` of the device tree:
+/// the rows of pixels follow each other without padding, starting at the top left corner.
+///
+/// Nothing is displayed, the picture is read through the [`Screen`] of the framebuffer.
+pub struct Framebuffer<A: Xlen + Unsigned> {
+    size: A,
+    screen: Screen,
+}
+
+impl<A: Xlen + Unsigned> Framebuffer<A> {
+    /// Creates a black framebuffer of `width` times `height` pixels, or returns `None` if it has
+    /// no pixels or does not fit in the address space.
+    pub fn new(width: usize, height: usize, format: PixelFormat) -> Option<Framebuffer<A>> {
+        if width == 0 || height == 0 {
+            return None;
+        }
+        let len = width
+            .checked_mul(height)?
+            .checked_mul(format.bytes_per_pixel())?;
+        let size = <A as NumCast>::from(len)?;
+
+        Some(Self {
+            size,
+            screen: Screen {
+                width,
+                height,
+                format,
+                pixels: Rc::new(RefCell::new(vec![0; len])),
+            },
+        })
+    }
+
+    pub fn screen(&self) -> Screen {
+        self.screen.clone()
+    }
+
+    fn load<T: Access>(&self, addr: A) -> Result<T, CPUError<A>> {
+        load_from_slice(&self.screen.pixels.borrow(), addr)
+    }
+
+    fn store<T: Access>(&mut self, addr: A, value: T) -> Result<(), CPUError<A>> {
+        store_to_slice(&mut self.screen.pixels.borrow_mut(), addr, value)
+    }
+}
+
+impl<A: Xlen + Unsigned> Memory<A> for Framebuffer<A> {
+    fn size(&self) -> A {
+        self.size
+    }
+
+    impl_memory!(self, addr, value, { self.load(addr) }, {
+        self.store(addr, value)
+    });
+
+    fn get_data(&self, range: Range<A>) -> Result<Vec<u8>, CPUError<A>> {
+        if range.start <= range.end && range.end <= self.size {
+            Ok(self.screen.pixels.borrow()[range.start.as_t()..range.end.as_t()].to_vec())
+        } else {
+            Err(CPUError::AddressNotMapped(range.end))
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use std::cell::Cell;
+use std::ops::Range;
+use std::time::Duration;
+
+use num_traits::Unsigned;
+
+use crate::cpu::isa::{As, Xlen};
+use crate::cpu::CPUError;
+use crate::devices::{Device, IrqLine, Timer, WallClock, INSTRUCTIONS_PER_SECOND};
+use crate::memory::{impl_memory, Access, Memory};
+
+// register offsets, all registers are 32 bits wide
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+const ALARM_LOW: usize = 0x08;
+const ALARM_HIGH: usize = 0x0C;
+const IRQ_ENABLED: usize = 0x10;
+const CLEAR_ALARM: usize = 0x14;
+const ALARM_STATUS: usize = 0x18;
+const CLEAR_INTERRUPT: usize = 0x1C;
+
+/// How often the host clock is checked against the alarm, in virtual time.
+const HOST_CLOCK_POLL: Duration = Duration::from_micros(100);
+
+/// Real-time clock of the goldfish platform, as found on qemu's virt machine, counting
+/// nanoseconds since the Unix epoch.
+///
+/// The time comes from the [`WallClock`] of the machine, so it only advances once the device
+/// is mapped on a bus and given a [`Timer`]. Writing the time keeps the difference to that
+/// clock, which is forgotten on reset.
+pub struct GoldfishRtc<A: Xlen + Unsigned> {
+    size: A,
+    irq: IrqLine,
+    timer: Option<Timer>,
+    /// Added to the wall clock, so the time can be written
+    offset: u64,
+    /// Upper half of the time, latched when the lower half is read
+    time_high: Cell<u32>,
+    alarm: u64,
+    alarm_running: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+impl<A: Xlen + Unsigned> GoldfishRtc<A> {
+    pub fn new(size: A, irq: IrqLine) -> GoldfishRtc<A> {
+        Self {
+            size,
+            irq,
+            timer: None,
+            offset: 0,
+            time_high: Cell::new(0),
+            alarm: 0,
+            alarm_running: false,
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+
+    /// Nanoseconds since the Unix epoch.
+    pub fn time(&self) -> u64 {
+        let wall_time = self.timer.as_ref().map_or(Duration::ZERO, Timer::wall_time);
+        (wall_time.as_nanos() as u64).wrapping_add(self.offset)
+    }
+
+    /// Fires the alarm if it is due, otherwise schedules a tick for when it is, and drives the
+    /// interrupt line.
+    fn update(&mut self) {
+        let now = self.time();
+        if self.alarm_running && self.alarm <= now {
+            self.alarm_running = false;
+            self.irq_pending = true;
+        }
+        self.irq.set(self.irq_pending && self.irq_enabled);
+
+        let Some(timer) = &self.timer else {
+            return;
+        };
+        if !self.alarm_running {
+            timer.cancel();
+            return;
+        }
+        match timer.wall_clock() {
+            WallClock::Virtual(_) => {
+                // rounded up, the tick must not come before the alarm
+                let nanos = (self.alarm - now) as u128;
+                let instructions =
+                    (nanos * INSTRUCTIONS_PER_SECOND as u128).div_ceil(1_000_000_000);
+                timer.schedule_in(instructions.try_into().unwrap_or(u64::MAX));
+            }
+            // host time passes independently of the hart
+            WallClock::Host => timer.schedule_after(HOST_CLOCK_POLL),
+        }
+    }
+
+    fn read_register(&self, offset: usize) -> u32 {
+        match offset {
+            TIME_LOW => {
+                let time = self.time();
+                self.time_high.set((time >> 32) as u32);
+                time as u32
+            }
+            TIME_HIGH => self.time_high.get(),
+            ALARM_LOW => self.alarm as u32,
+            ALARM_HIGH => (self.alarm >> 32) as u32,
+            IRQ_ENABLED => self.irq_enabled as u32,
+            ALARM_STATUS => self.alarm_running as u32,
+            // write-only and reserved registers
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: usize, value: u32) {
+        match offset {
+            // the time is set by writing the upper half first
+            TIME_LOW => {
+                let time = (self.time_high.get() as u64) << 32 | value as u64;
+                self.offset = time.wrapping_sub(self.time().wrapping_sub(self.offset));
+            }
+            TIME_HIGH => self.time_high.set(value),
+            // the alarm is armed by writing the lower half, after the upper one
+            ALARM_LOW => {
+                self.alarm = self.alarm & !0xFFFF_FFFF | value as u64;
+                self.alarm_running = true;
+            }
+            ALARM_HIGH => self.alarm = self.alarm & 0xFFFF_FFFF | (value as u64) << 32,
+            IRQ_ENABLED => self.irq_enabled = value & 1 != 0,
+            CLEAR_ALARM => self.alarm_running = false,
+            CLEAR_INTERRUPT => self.irq_pending = false,
+            // read-only and reserved registers
+            _ => return,
+        }
+        self.update();
+    }
+
+    fn read<T: Access>(&self, addr: A) -> Result<T, CPUError<A>> {
+        let offset = addr.as_t::<usize>();
+        if T::SIZE != 4 || offset % 4 != 0 {
+            return Err(CPUError::LoadAccessFault(addr));
+        }
+        Ok(T::from_le_bytes(&self.read_register(offset).to_le_bytes()))
+    }
+
+    fn write<T: Access>(&mut self, addr: A, value: T) -> Result<(), CPUError<A>> {
+        let offset = addr.as_t::<usize>();
+        if T::SIZE != 4 || offset % 4 != 0 {
+            return Err(CPUError::StoreAccessFault(addr));
+        }
+        let mut bytes = [0; 16];
+        value.write_le_bytes(&mut bytes[..T::SIZE]);
+        self.write_register(
+            offset,
+            u32::from_le_bytes(bytes[..4].try_into().expect("4 bytes")),
+        );
+
+        Ok(())
+    }
+}
+
+impl<A: Xlen + Unsigned> Device<A> for GoldfishRtc<A> {
+    /// The alarm may be due.
+    fn tick(&mut self, _now: u64, _bus: &mut dyn Memory<A>) {
+        self.update();
+    }
+
+    fn set_timer(&mut self, timer: Timer) {
+        self.timer = Some(timer);
+        self.update();
+    }
+
+    /// Returns to the time of the wall clock and disarms the alarm and interrupt.
+    fn reset(&mut self) {
+        self.offset = 0;
+        self.time_high.set(0);
+        self.alarm = 0;
+        self.alarm_running = false;
+        self.irq_enabled = false;
+        self.irq_pending = false;
+        self.update();
+    }
+}
+
+impl<A: Xlen + Unsigned> Memory<A> for GoldfishRtc<A> {
+    fn size(&self) -> A {
+        self.size
+    }
+
+    impl_memory!(self, addr, value, { self.read(addr) }, {
+        self.write(addr, value)
+    });
+
+    fn get_data(&self, range: Range<A>) -> Result<Vec<u8>, CPUError<A>> {
+        Ok(vec![0; (range.end - range.start).as_t::<usize>()])
+    }
+
+    fn as_device(&mut self) -> Option<&mut dyn Device<A>> {
+        Some(self)
+    }
+}
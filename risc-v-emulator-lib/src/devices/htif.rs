@@ -0,0 +1,150 @@
+use std::io::{self, Write};
+
+use num_traits::Unsigned;
+
+use crate::cpu::isa::{As, Xlen};
+use crate::cpu::CPUError;
+use crate::elf::Elf;
+use crate::memory::Memory;
+
+/// Syscall numbers of the proxied syscalls, as used by riscv-tests.
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+const EFAULT: i64 = 14;
+const ENOSYS: i64 = 38;
+
+/// Longest `write` executed at once, longer ones write only part of the buffer.
+const MAX_WRITE: u64 = 0x1_0000;
+
+/// Berkeley host-target interface, as used by riscv-tests and spike.
+///
+/// Unlike other devices HTIF is not mapped on the bus: `tohost` and `fromhost` are plain words
+/// in dram, usually located through the symbols of the same name. A command is issued once a
+/// store reaches the upper half of `tohost`, so 32 bit guests writing the lower half first are
+/// seen with the full command. Commands encode the device in bits 63:56, the command in 55:48
+/// and the payload in the lower 48 bits:
+///
+/// * device 0, command 0: with bit 0 set the program exits with code `payload >> 1`, otherwise
+///   the payload points to a syscall block of which `write` and `exit` are supported.
+/// * device 1, command 1: writes the character in the lowest byte to the console.
+///
+/// Console input, device 1 command 0, is not supported: the read is never answered, which
+/// guests see as no character being available.
+pub struct Htif<A: Xlen + Unsigned> {
+    tohost: A,
+    fromhost: Option<A>,
+    console: Box<dyn Write>,
+}
+
+impl<A: Xlen + Unsigned> Htif<A> {
+    /// Creates an interface at the configured addresses, writing to stdout.
+    pub fn new(tohost: A, fromhost: Option<A>) -> Htif<A> {
+        Self {
+            tohost,
+            fromhost,
+            console: Box::new(io::stdout()),
+        }
+    }
+
+    /// Locates `tohost` and `fromhost` through the symbols of `elf`, returns `None` if it does
+    /// not define `tohost`.
+    pub fn from_elf(elf: &Elf) -> Option<Htif<A>> {
+        let tohost = A::from(elf.symbol("tohost")?)?;
+        let fromhost = elf.symbol("fromhost").and_then(A::from);
+
+        Some(Self::new(tohost, fromhost))
+    }
+
+    /// Replaces the console output, stdout by default.
+    pub fn with_console(mut self, console: impl Write + 'static) -> Htif<A> {
+        self.console = Box::new(console);
+        self
+    }
+
+    /// Returns whether a store of `size` bytes to `addr` issues a command.
+    #[inline]
+    pub(crate) fn is_command(&self, addr: A, size: usize) -> bool {
+        let last = self.tohost.as_t::<usize>().wrapping_add(7);
+        let addr = addr.as_t::<usize>();
+        addr <= last && last < addr.wrapping_add(size)
+    }
+
+    /// Executes the command written to `tohost`.
+    ///
+    /// Exiting is reported as [`CPUError::PowerOff`].
+    pub(crate) fn handle(&mut self, mem: &mut impl Memory<A>) -> Result<(), CPUError<A>> {
+        let command = mem.load_u64(self.tohost)?;
+        if command == 0 {
+            return Ok(());
+        }
+        mem.store_u64(self.tohost, 0)?;
+
+        let (device, cmd, payload) = (command >> 56, (command >> 48) & 0xFF, command << 16 >> 16);
+        match (device, cmd) {
+            (0, 0) if payload & 1 == 1 => Err(CPUError::PowerOff(exit_code(payload >> 1))),
+            (0, 0) => {
+                let Some(block) = A::from(payload) else {
+                    return Ok(());
+                };
+                self.syscall(mem, block)?;
+                self.respond(mem, 1)
+            }
+            // getchar, see above
+            (1, 0) => Ok(()),
+            (1, 1) => {
+                // the console is best effort, the guest can not do anything about errors
+                let _ = self.console.write_all(&[payload as u8]);
+                let _ = self.console.flush();
+                self.respond(mem, 1 << 56 | 1 << 48)
+            }
+            // unknown commands are dropped
+            _ => Ok(()),
+        }
+    }
+
+    /// Executes the syscall described by the 8 words at `block`, the result replaces the
+    /// syscall number.
+    fn syscall(&mut self, mem: &mut impl Memory<A>, block: A) -> Result<(), CPUError<A>> {
+        let word = |i: u8| block.wrapping_add(&A::from(8 * i).expect("fits every address width"));
+        let args = [
+            mem.load_u64(word(0))?,
+            mem.load_u64(word(1))?,
+            mem.load_u64(word(2))?,
+            mem.load_u64(word(3))?,
+        ];
+
+        let result = match args {
+            [SYS_EXIT, code, ..] => return Err(CPUError::PowerOff(exit_code(code))),
+            // stdout and stderr
+            [SYS_WRITE, 1 | 2, buf, len] => {
+                let len = len.min(MAX_WRITE);
+                let range = buf
+                    .checked_add(len)
+                    .and_then(|end| Some(A::from(buf)?..A::from(end)?));
+                match range.map(|range| mem.get_data(range)) {
+                    Some(Ok(data)) => {
+                        let _ = self.console.write_all(&data);
+                        let _ = self.console.flush();
+                        len
+                    }
+                    _ => -EFAULT as u64,
+                }
+            }
+            _ => -ENOSYS as u64,
+        };
+
+        mem.store_u64(word(0), result)
+    }
+
+    fn respond(&mut self, mem: &mut impl Memory<A>, value: u64) -> Result<(), CPUError<A>> {
+        match self.fromhost {
+            Some(fromhost) => mem.store_u64(fromhost, value),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Exit codes wider than the stop reason can represent are reported as failure.
+fn exit_code(code: u64) -> u32 {
+    u32::try_from(code).unwrap_or(u32::MAX)
+}
@@ -0,0 +1,82 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// Receives the levels of numbered interrupt lines, e.g. an interrupt controller or a hart.
+pub trait IrqSink {
+    fn set_level(&self, line: usize, level: bool);
+}
+
+/// A level-triggered interrupt line, driven by a device and connected to an [`IrqSink`].
+///
+/// Setting the level takes effect immediately, the sink does not have to poll the device.
+#[derive(Clone)]
+pub struct IrqLine {
+    sink: Rc<dyn IrqSink>,
+    line: usize,
+}
+
+impl IrqLine {
+    pub fn new(sink: Rc<dyn IrqSink>, line: usize) -> IrqLine {
+        Self { sink, line }
+    }
+
+    pub fn set(&self, level: bool) {
+        self.sink.set_level(self.line, level);
+    }
+
+    pub fn raise(&self) {
+        self.set(true);
+    }
+
+    pub fn lower(&self) {
+        self.set(false);
+    }
+
+    /// Number of the line at the sink, e.g. the interrupt source of a [`Plic`](super::Plic).
+    pub fn line(&self) -> usize {
+        self.line
+    }
+}
+
+struct Pending(Cell<u64>);
+
+impl IrqSink for Pending {
+    fn set_level(&self, line: usize, level: bool) {
+        let pending = self.0.get() & !(1 << line);
+        self.0.set(pending | (level as u64) << line);
+    }
+}
+
+/// Interrupt lines of a hart, line `n` drives bit `n` of `mip`, i.e. interrupt cause `n`.
+///
+/// Clones share the lines, see [`Cpu::set_irqs`](crate::cpu::Cpu::set_irqs).
+#[derive(Clone)]
+pub struct HartIrqs {
+    pending: Rc<Pending>,
+}
+
+impl HartIrqs {
+    pub fn new() -> HartIrqs {
+        Self {
+            pending: Rc::new(Pending(Cell::new(0))),
+        }
+    }
+
+    /// Returns the line driving interrupt `cause`.
+    pub fn line(&self, cause: usize) -> IrqLine {
+        assert!(cause < 64, "no interrupt cause {cause}");
+        IrqLine::new(self.pending.clone(), cause)
+    }
+
+    /// Levels of all lines, as read from `mip`.
+    #[inline]
+    pub fn pending(&self) -> u64 {
+        self.pending.0.get()
+    }
+}
+
+impl Default for HartIrqs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
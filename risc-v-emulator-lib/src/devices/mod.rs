@@ -0,0 +1,48 @@
+//! Memory mapped peripherals, mapped on the [`Bus`](crate::memory::Bus) like any other memory.
+
+pub use clint::{Clint, Timebase};
+pub use framebuffer::{Framebuffer, PixelFormat, Screen};
+pub use goldfish_rtc::GoldfishRtc;
+pub use htif::Htif;
+pub use irq::{HartIrqs, IrqLine, IrqSink};
+pub use plic::Plic;
+pub use scheduler::{Scheduler, Timer, WallClock, INSTRUCTIONS_PER_SECOND};
+pub use test_finisher::{TestFinisher, FINISHER_FAIL, FINISHER_PASS, FINISHER_RESET};
+pub use uart::Uart;
+
+use crate::memory::Memory;
+
+mod clint;
+mod framebuffer;
+mod goldfish_rtc;
+mod htif;
+mod irq;
+mod plic;
+mod scheduler;
+mod test_finisher;
+mod uart;
+pub mod virtio;
+#[cfg(test)]
+mod test;
+
+/// A peripheral that does work over time, e.g. a timer or a dma engine, besides reacting to
+/// loads and stores. Devices signal the cpu through [`IrqLine`]s.
+///
+/// Every region whose [`Memory::as_device`] returns the device gets a [`Timer`] from the
+/// [`Bus`](crate::memory::Bus), which ticks the device when the event scheduled through it is
+/// due.
+pub trait Device<A>: Memory<A> {
+    /// Called when the scheduled event is due, `now` is the number of instructions the hart has
+    /// retired since the last reset.
+    ///
+    /// `bus` is the bus the device is mapped on, for accessing guest memory. The region of the
+    /// device itself is not mapped during the call.
+    fn tick(&mut self, now: u64, bus: &mut dyn Memory<A>);
+
+    /// Hands the device the timer for scheduling its ticks, once it is mapped on a bus.
+    fn set_timer(&mut self, _timer: Timer) {}
+
+    /// Restores the state after power-on, the machine is being reset. Time restarts from zero
+    /// and pending events have been dropped.
+    fn reset(&mut self) {}
+}
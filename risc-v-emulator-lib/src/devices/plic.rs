@@ -0,0 +1,293 @@
+use std::cell::RefCell;
+use std::ops::Range;
+use std::rc::Rc;
+
+use num_traits::Unsigned;
+
+use crate::cpu::isa::{As, Xlen};
+use crate::cpu::CPUError;
+use crate::devices::{Device, IrqLine, IrqSink};
+use crate::memory::{impl_memory, Access, Memory};
+
+// register offsets, the layout used by SiFive and qemu
+const PRIORITY: usize = 0x00_0000;
+const PENDING: usize = 0x00_1000;
+const ENABLE: usize = 0x00_2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+
+/// Priorities are 3 bits wide, as on qemu.
+const PRIORITY_MASK: u32 = 0b111;
+
+struct State {
+    /// Per source, source 0 does not exist
+    priority: Vec<u32>,
+    /// Level of the interrupt line of each source
+    level: Vec<bool>,
+    pending: Vec<bool>,
+    /// Claimed by a context and not completed yet, so the source is not forwarded again
+    claimed: Vec<bool>,
+    /// Per context, one bit per source
+    enable: Vec<Vec<u32>>,
+    threshold: Vec<u32>,
+    /// External interrupt line driven by each context
+    outputs: Vec<IrqLine>,
+}
+
+impl State {
+    fn enabled(&self, context: usize, source: usize) -> bool {
+        self.enable[context][source / 32] & 1 << (source % 32) != 0
+    }
+
+    /// Returns the pending source with the highest priority enabled for `context` and above
+    /// its threshold, the lowest id wins ties.
+    fn best(&self, context: usize) -> Option<usize> {
+        (1..self.priority.len())
+            .filter(|&source| self.pending[source] && self.enabled(context, source))
+            .filter(|&source| self.priority[source] > self.threshold[context])
+            .min_by_key(|&source| (u32::MAX - self.priority[source], source))
+    }
+
+    /// Forwards the line of `source` to the pending bits unless it is being handled.
+    fn gateway(&mut self, source: usize) {
+        if !self.claimed[source] {
+            self.pending[source] = self.level[source];
+        }
+    }
+
+    /// Returns the context whose claim/complete register is at `offset`.
+    fn claim_context(&self, offset: usize) -> Option<usize> {
+        let context = offset.checked_sub(CONTEXT)? / CONTEXT_STRIDE;
+        (context < self.threshold.len() && offset % CONTEXT_STRIDE == 4).then_some(context)
+    }
+
+    /// Drives the output of each context high while it has an interrupt to claim.
+    fn update(&self) {
+        for (context, output) in self.outputs.iter().enumerate() {
+            output.set(self.best(context).is_some());
+        }
+    }
+
+    fn read(&mut self, offset: usize) -> u32 {
+        match self.claim_context(offset) {
+            Some(context) => self.claim(context),
+            None => self.peek(offset),
+        }
+    }
+
+    /// Reads the register at `offset` without claiming an interrupt.
+    fn peek(&self, offset: usize) -> u32 {
+        let sources = self.priority.len();
+        let contexts = self.threshold.len();
+        match offset {
+            PRIORITY..PENDING if offset / 4 < sources => self.priority[offset / 4],
+            PENDING..ENABLE => (0..32)
+                .map(|bit| (offset - PENDING) / 4 * 32 + bit)
+                .filter(|&source| source < sources && self.pending[source])
+                .fold(0, |word, source| word | 1 << (source % 32)),
+            ENABLE..CONTEXT => {
+                let (context, word) = (
+                    (offset - ENABLE) / ENABLE_STRIDE,
+                    offset % ENABLE_STRIDE / 4,
+                );
+                self.enable
+                    .get(context)
+                    .and_then(|enable| enable.get(word))
+                    .copied()
+                    .unwrap_or(0)
+            }
+            CONTEXT.. if (offset - CONTEXT) / CONTEXT_STRIDE < contexts => {
+                let context = (offset - CONTEXT) / CONTEXT_STRIDE;
+                match offset % CONTEXT_STRIDE {
+                    0 => self.threshold[context],
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: usize, value: u32) {
+        let sources = self.priority.len();
+        let contexts = self.threshold.len();
+        match offset {
+            // source 0 does not exist, its priority stays zero
+            PRIORITY..PENDING if (1..sources).contains(&(offset / 4)) => {
+                self.priority[offset / 4] = value & PRIORITY_MASK
+            }
+            ENABLE..CONTEXT => {
+                let (context, word) = (
+                    (offset - ENABLE) / ENABLE_STRIDE,
+                    offset % ENABLE_STRIDE / 4,
+                );
+                if let Some(enable) = self.enable.get_mut(context).and_then(|e| e.get_mut(word)) {
+                    // bits of sources that do not exist, including source 0, are hardwired to
+                    // zero
+                    let existing = sources.saturating_sub(word * 32).min(32);
+                    let mut mask = ((1u64 << existing) - 1) as u32;
+                    if word == 0 {
+                        mask &= !1;
+                    }
+                    *enable = value & mask;
+                }
+            }
+            CONTEXT.. if (offset - CONTEXT) / CONTEXT_STRIDE < contexts => {
+                let context = (offset - CONTEXT) / CONTEXT_STRIDE;
+                match offset % CONTEXT_STRIDE {
+                    0 => self.threshold[context] = value & PRIORITY_MASK,
+                    4 => self.complete(context, value as usize),
+                    _ => {}
+                }
+            }
+            // the pending bits are read-only
+            _ => {}
+        }
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        let Some(source) = self.best(context) else {
+            return 0;
+        };
+
+        self.pending[source] = false;
+        self.claimed[source] = true;
+        source as u32
+    }
+
+    fn complete(&mut self, context: usize, source: usize) {
+        // completions for sources the context can not have claimed are ignored
+        if source == 0 || source >= self.priority.len() || !self.enabled(context, source) {
+            return;
+        }
+
+        self.claimed[source] = false;
+        self.gateway(source);
+    }
+}
+
+impl IrqSink for RefCell<State> {
+    fn set_level(&self, source: usize, level: bool) {
+        let mut state = self.borrow_mut();
+        state.level[source] = level;
+        state.gateway(source);
+        state.update();
+    }
+}
+
+/// Platform-level interrupt controller, collecting the interrupt lines of devices and routing
+/// them to the external interrupt lines of the harts.
+///
+/// Each context drives one output line, by convention `2 * hart` is the M-mode context of a
+/// hart wired to MEIP and `2 * hart + 1` the S-mode context wired to SEIP. Devices raise and
+/// lower their line through an [`IrqLine`] handed out by [`irq_line`](Self::irq_line).
+pub struct Plic<A: Xlen + Unsigned> {
+    size: A,
+    state: Rc<RefCell<State>>,
+}
+
+impl<A: Xlen + Unsigned> Plic<A> {
+    /// Creates a controller with sources `1..sources` and one context per output line.
+    pub fn new(size: A, sources: usize, contexts: Vec<IrqLine>) -> Plic<A> {
+        assert!(sources <= 1024, "the plic supports at most 1023 sources");
+
+        let count = contexts.len();
+        Self {
+            size,
+            state: Rc::new(RefCell::new(State {
+                priority: vec![0; sources],
+                level: vec![false; sources],
+                pending: vec![false; sources],
+                claimed: vec![false; sources],
+                enable: vec![vec![0; sources.div_ceil(32)]; count],
+                threshold: vec![0; count],
+                outputs: contexts,
+            })),
+        }
+    }
+
+    /// Returns the line a device raises to request interrupt `source`.
+    pub fn irq_line(&self, source: usize) -> IrqLine {
+        assert!(
+            (1..self.state.borrow().priority.len()).contains(&source),
+            "no interrupt source {source}"
+        );
+
+        IrqLine::new(self.state.clone(), source)
+    }
+
+    fn read<T: Access>(&self, addr: A) -> Result<T, CPUError<A>> {
+        let offset = addr.as_t::<usize>();
+        let mut state = self.state.borrow_mut();
+        // only whole reads claim, the bytes of a split misaligned read must not claim one
+        // interrupt each
+        let word = if T::SIZE == 4 && offset.is_multiple_of(4) {
+            state.read(offset)
+        } else {
+            state.peek(offset & !0b11)
+        };
+        state.update();
+
+        // every register is 32 bits wide
+        word.to_le_bytes()
+            .get(offset % 4..offset % 4 + T::SIZE)
+            .map(T::from_le_bytes)
+            .ok_or(CPUError::LoadAccessFault(addr))
+    }
+
+    fn write<T: Access>(&mut self, addr: A, value: T) -> Result<(), CPUError<A>> {
+        let offset = addr.as_t::<usize>();
+        if offset % 4 + T::SIZE > 4 {
+            return Err(CPUError::StoreAccessFault(addr));
+        }
+
+        // narrower writes keep the other bytes of the register
+        let mut state = self.state.borrow_mut();
+        let mut bytes = match state.claim_context(offset & !0b11) {
+            Some(_) => [0; 4],
+            None => state.peek(offset & !0b11).to_le_bytes(),
+        };
+        value.write_le_bytes(&mut bytes[offset % 4..offset % 4 + T::SIZE]);
+        state.write(offset & !0b11, u32::from_le_bytes(bytes));
+        state.update();
+
+        Ok(())
+    }
+}
+
+impl<A: Xlen + Unsigned> Device<A> for Plic<A> {
+    /// Interrupts are routed as soon as a line changes, there is nothing to do over time.
+    fn tick(&mut self, _now: u64, _bus: &mut dyn Memory<A>) {}
+
+    /// Restores the reset state: every source is disabled with priority 0 and no interrupt is
+    /// claimed. The levels of the lines are kept, they belong to the devices.
+    fn reset(&mut self) {
+        let mut state = self.state.borrow_mut();
+        let state = &mut *state;
+        state.priority.fill(0);
+        state.claimed.fill(false);
+        state.enable.iter_mut().for_each(|enable| enable.fill(0));
+        state.threshold.fill(0);
+        state.pending.clone_from(&state.level);
+        state.update();
+    }
+}
+
+impl<A: Xlen + Unsigned> Memory<A> for Plic<A> {
+    fn size(&self) -> A {
+        self.size
+    }
+
+    impl_memory!(self, addr, value, { self.read(addr) }, {
+        self.write(addr, value)
+    });
+
+    fn get_data(&self, range: Range<A>) -> Result<Vec<u8>, CPUError<A>> {
+        // dumping memory must not claim interrupts
+        Ok(vec![0; (range.end - range.start).as_t::<usize>()])
+    }
+
+    fn as_device(&mut self) -> Option<&mut dyn Device<A>> {
+        Some(self)
+    }
+}
@@ -0,0 +1,180 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+
+/// Rate of the virtual clock, i.e. how many instructions the hart retires per second of
+/// virtual time.
+pub const INSTRUCTIONS_PER_SECOND: u64 = 100_000_000;
+
+/// Source of the time of day of a machine, see [`Timer::wall_time`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum WallClock {
+    /// The clock of the host.
+    #[default]
+    Host,
+    /// Starts at the given time since the Unix epoch and advances with virtual time, so runs
+    /// are reproducible.
+    Virtual(Duration),
+}
+
+struct Queue {
+    /// Instructions retired by the hart, as of the last tick of the bus
+    now: Cell<u64>,
+    /// Time of the pending event of each timer, `u64::MAX` if there is none
+    deadlines: RefCell<Vec<u64>>,
+    /// No event is due before this time, it may be earlier than the next event after a cancel
+    next: Cell<u64>,
+    wall_clock: Cell<WallClock>,
+}
+
+/// Event queue of a machine, ordering the callbacks devices schedule through their [`Timer`].
+///
+/// Time is the number of instructions retired by the hart since the last reset, so runs are
+/// reproducible. Between events the bus only compares the time against the next event, see
+/// [`Bus::tick`](crate::memory::Bus::tick).
+#[derive(Clone)]
+pub struct Scheduler {
+    queue: Rc<Queue>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Self {
+            queue: Rc::new(Queue {
+                now: Cell::new(0),
+                deadlines: RefCell::new(Vec::new()),
+                next: Cell::new(u64::MAX),
+                wall_clock: Cell::new(WallClock::default()),
+            }),
+        }
+    }
+
+    /// Returns a new timer without a pending event.
+    pub fn timer(&self) -> Timer {
+        let mut deadlines = self.queue.deadlines.borrow_mut();
+        deadlines.push(u64::MAX);
+
+        Timer {
+            queue: self.queue.clone(),
+            id: deadlines.len() - 1,
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.queue.now.get()
+    }
+
+    pub fn wall_clock(&self) -> WallClock {
+        self.queue.wall_clock.get()
+    }
+
+    pub fn set_wall_clock(&self, clock: WallClock) {
+        self.queue.wall_clock.set(clock);
+    }
+
+    /// Time of the next event, `u64::MAX` if none is pending.
+    #[inline]
+    pub fn next_event(&self) -> u64 {
+        self.queue.next.get()
+    }
+
+    #[inline]
+    pub(crate) fn set_now(&self, now: u64) {
+        self.queue.now.set(now);
+    }
+
+    /// Removes the event of timer `id` if it is due at `now`, returns whether it was.
+    pub(crate) fn take_due(&self, id: usize, now: u64) -> bool {
+        let mut deadlines = self.queue.deadlines.borrow_mut();
+        let due = deadlines[id] <= now;
+        if due {
+            deadlines[id] = u64::MAX;
+        }
+
+        due
+    }
+
+    /// Recomputes the time of the next event after events have been taken or cancelled.
+    pub(crate) fn update_next(&self) {
+        let next = self.queue.deadlines.borrow().iter().copied().min();
+        self.queue.next.set(next.unwrap_or(u64::MAX));
+    }
+
+    /// Drops all pending events and restarts time from zero.
+    pub(crate) fn reset(&self) {
+        self.queue.deadlines.borrow_mut().fill(u64::MAX);
+        self.queue.next.set(u64::MAX);
+        self.queue.now.set(0);
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Schedules the ticks of one device, each timer has at most one pending event.
+///
+/// When the event is due, the bus calls [`Device::tick`](super::Device::tick) and drops the
+/// event, so periodic work has to be rescheduled from `tick`.
+pub struct Timer {
+    queue: Rc<Queue>,
+    id: usize,
+}
+
+impl Timer {
+    /// Current time in instructions, as of the start of the running block.
+    pub fn now(&self) -> u64 {
+        self.queue.now.get()
+    }
+
+    pub fn wall_clock(&self) -> WallClock {
+        self.queue.wall_clock.get()
+    }
+
+    /// Time of day as a duration since the Unix epoch, from the clock of the machine.
+    pub fn wall_time(&self) -> Duration {
+        match self.wall_clock() {
+            WallClock::Host => SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default(),
+            WallClock::Virtual(start) => {
+                let nanos = self.now() as u128 * 1_000_000_000 / INSTRUCTIONS_PER_SECOND as u128;
+                start.saturating_add(Duration::from_nanos(nanos as u64))
+            }
+        }
+    }
+
+    /// Schedules a tick once `time` instructions have been retired, replacing the pending one.
+    /// Times in the past tick at the next opportunity.
+    pub fn schedule_at(&self, time: u64) {
+        self.queue.deadlines.borrow_mut()[self.id] = time;
+        if time < self.queue.next.get() {
+            self.queue.next.set(time);
+        }
+    }
+
+    /// Schedules a tick after `instructions` more instructions have been retired.
+    pub fn schedule_in(&self, instructions: u64) {
+        self.schedule_at(self.now().saturating_add(instructions));
+    }
+
+    /// Schedules a tick after `delay` of virtual time, see [`INSTRUCTIONS_PER_SECOND`].
+    pub fn schedule_after(&self, delay: Duration) {
+        let instructions = delay.as_nanos() * INSTRUCTIONS_PER_SECOND as u128 / 1_000_000_000;
+        self.schedule_in(instructions.try_into().unwrap_or(u64::MAX));
+    }
+
+    /// Drops the pending tick, if any.
+    pub fn cancel(&self) {
+        // the next event of the queue is recomputed lazily, once its time has come
+        self.queue.deadlines.borrow_mut()[self.id] = u64::MAX;
+    }
+
+    /// Returns the time of the pending tick.
+    pub fn scheduled(&self) -> Option<u64> {
+        let deadline = self.queue.deadlines.borrow()[self.id];
+        (deadline != u64::MAX).then_some(deadline)
+    }
+}
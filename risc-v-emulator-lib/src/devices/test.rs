@@ -0,0 +1,1004 @@
+use crate::cpu::CPUError;
+use crate::devices::{TestFinisher, FINISHER_FAIL, FINISHER_PASS, FINISHER_RESET};
+use crate::memory::Memory;
+
+#[test]
+fn test_finisher() {
+    let mut finisher: TestFinisher<u32> = TestFinisher::new(0x1000);
+
+    assert!(matches!(finisher.load_u32(0), Ok(0)));
+    assert!(finisher.store_u32(0, 0x1234).is_ok());
+    assert!(finisher.store_u32(4, FINISHER_PASS).is_ok());
+
+    assert!(matches!(
+        finisher.store_u32(0, FINISHER_PASS),
+        Err(CPUError::PowerOff(0))
+    ));
+    assert!(matches!(
+        finisher.store_u32(0, 42 << 16 | FINISHER_FAIL),
+        Err(CPUError::PowerOff(42))
+    ));
+    assert!(matches!(
+        finisher.store_u16(0, FINISHER_RESET as u16),
+        Err(CPUError::ResetRequested)
+    ));
+}
+
+#[test]
+fn test_htif() {
+    use crate::devices::Htif;
+    use crate::memory::Dram;
+    use crate::test_util::Capture;
+
+    const TOHOST: u32 = 0x100;
+    const FROMHOST: u32 = 0x108;
+
+    let output = Capture::default();
+    let mut htif: Htif<u32> = Htif::new(TOHOST, Some(FROMHOST)).with_console(output.clone());
+    let mut mem: Dram<u32> = Dram::with_code(&[], 0x2_0000);
+
+    // only stores reaching the upper word issue commands
+    assert!(!htif.is_command(TOHOST, 4));
+    assert!(htif.is_command(TOHOST + 4, 4));
+    assert!(htif.is_command(TOHOST, 8));
+    assert!(!htif.is_command(FROMHOST, 8));
+
+    // putchar
+    mem.store_u64(TOHOST, 1 << 56 | 1 << 48 | b'x' as u64).unwrap();
+    assert!(htif.handle(&mut mem).is_ok());
+    assert_eq!(b"x".to_vec(), output.contents());
+    assert!(matches!(mem.load_u64(TOHOST), Ok(0)));
+    assert!(matches!(mem.load_u64(FROMHOST), Ok(0x0101_0000_0000_0000)));
+
+    // write(1, "hello", 5) through a syscall block at 0x200
+    mem.store_u64(0x200, 64).unwrap();
+    mem.store_u64(0x208, 1).unwrap();
+    mem.store_u64(0x210, 0x300).unwrap();
+    mem.store_u64(0x218, 5).unwrap();
+    for (i, b) in b"hello".iter().enumerate() {
+        mem.store_u8(0x300 + i as u32, *b).unwrap();
+    }
+    mem.store_u64(FROMHOST, 0).unwrap();
+    mem.store_u64(TOHOST, 0x200).unwrap();
+    assert!(htif.handle(&mut mem).is_ok());
+    assert_eq!(b"xhello".to_vec(), output.contents());
+    assert!(matches!(mem.load_u64(0x200), Ok(5)));
+    assert!(matches!(mem.load_u64(FROMHOST), Ok(1)));
+
+    // long writes are cut short, buffers that are not mapped fail with EFAULT
+    mem.store_u64(0x200, 64).unwrap();
+    mem.store_u64(0x218, u64::MAX).unwrap();
+    mem.store_u64(TOHOST, 0x200).unwrap();
+    assert!(htif.handle(&mut mem).is_ok());
+    assert!(matches!(mem.load_u64(0x200), Ok(0x1_0000)));
+    assert_eq!(6 + 0x1_0000, output.contents().len());
+    mem.store_u64(0x200, 64).unwrap();
+    mem.store_u64(0x210, 0x1_F000).unwrap();
+    mem.store_u64(0x218, 0x2000).unwrap();
+    mem.store_u64(TOHOST, 0x200).unwrap();
+    assert!(htif.handle(&mut mem).is_ok());
+    assert!(matches!(mem.load_u64(0x200), Ok(v) if v as i64 == -14));
+
+    // console input is not answered
+    mem.store_u64(FROMHOST, 0).unwrap();
+    mem.store_u64(TOHOST, 1 << 56).unwrap();
+    assert!(htif.handle(&mut mem).is_ok());
+    assert!(matches!(mem.load_u64(FROMHOST), Ok(0)));
+
+    // unsupported syscalls fail with ENOSYS
+    mem.store_u64(0x200, 1234).unwrap();
+    mem.store_u64(TOHOST, 0x200).unwrap();
+    assert!(htif.handle(&mut mem).is_ok());
+    assert!(matches!(mem.load_u64(0x200), Ok(v) if v as i64 == -38));
+
+    // exit(3) through a syscall and directly
+    mem.store_u64(0x200, 93).unwrap();
+    mem.store_u64(0x208, 3).unwrap();
+    mem.store_u64(TOHOST, 0x200).unwrap();
+    assert!(matches!(htif.handle(&mut mem), Err(CPUError::PowerOff(3))));
+
+    mem.store_u64(TOHOST, 3 << 1 | 1).unwrap();
+    assert!(matches!(htif.handle(&mut mem), Err(CPUError::PowerOff(3))));
+}
+
+#[test]
+fn test_uart() {
+    use std::sync::mpsc;
+
+    use crate::devices::Uart;
+    use crate::test_util::Capture;
+
+    let output = Capture::default();
+    let (tx, rx) = mpsc::channel();
+    let mut uart: Uart<u32> = Uart::with_channel(0x100, rx, output.clone());
+
+    // transmitter empty, nothing received, no interrupts
+    assert!(matches!(uart.load_u8(5), Ok(0x60)));
+    assert!(matches!(uart.load_u8(2), Ok(0x01)));
+    assert!(!uart.interrupt_pending());
+
+    assert!(uart.store_u8(0, b'o').is_ok());
+    assert!(uart.store_u8(0, b'k').is_ok());
+    assert_eq!(b"ok".to_vec(), output.contents());
+
+    // the divisor latch shadows the data and interrupt enable registers
+    assert!(uart.store_u8(3, 0x83).is_ok());
+    assert!(uart.store_u8(0, 0x01).is_ok());
+    assert!(uart.store_u8(1, 0x02).is_ok());
+    assert!(matches!(uart.load_u8(0), Ok(0x01)));
+    assert!(matches!(uart.load_u8(1), Ok(0x02)));
+    assert!(uart.store_u8(3, 0x03).is_ok());
+    assert!(matches!(uart.load_u8(1), Ok(0x00)));
+    assert_eq!(b"ok".to_vec(), output.contents());
+
+    // received data is reported in the line status and raises an interrupt once enabled
+    tx.send(b'a').unwrap();
+    tx.send(b'b').unwrap();
+    assert!(matches!(uart.load_u8(5), Ok(0x61)));
+    assert!(!uart.interrupt_pending());
+    assert!(uart.store_u8(1, 0x01).is_ok());
+    assert!(uart.interrupt_pending());
+    assert!(uart.store_u8(2, 0x01).is_ok());
+    assert!(matches!(uart.load_u8(2), Ok(0xC4)));
+    assert!(matches!(uart.load_u8(0), Ok(b'a')));
+    assert!(matches!(uart.load_u8(0), Ok(b'b')));
+    assert!(matches!(uart.load_u8(5), Ok(0x60)));
+    assert!(!uart.interrupt_pending());
+
+    // the transmitter interrupt is raised when enabled and acknowledged by reading IIR
+    assert!(uart.store_u8(1, 0x03).is_ok());
+    assert!(uart.interrupt_pending());
+    assert!(matches!(uart.load_u8(2), Ok(0xC2)));
+    assert!(!uart.interrupt_pending());
+    assert!(uart.store_u8(0, b'!').is_ok());
+    assert!(uart.interrupt_pending());
+    assert!(matches!(uart.load_u8(2), Ok(0xC2)));
+    assert!(matches!(uart.load_u8(2), Ok(0xC1)));
+
+    // loopback
+    assert!(uart.store_u8(4, 0x1B).is_ok());
+    assert!(matches!(uart.load_u8(6), Ok(0xB0)));
+    assert!(uart.store_u8(0, b'x').is_ok());
+    assert!(matches!(uart.load_u8(0), Ok(b'x')));
+    assert_eq!(b"ok!".to_vec(), output.contents());
+
+    assert!(uart.store_u8(7, 0x5A).is_ok());
+    assert!(matches!(uart.load_u8(7), Ok(0x5A)));
+}
+
+#[test]
+fn test_clint() {
+    use crate::devices::{Clint, Device, HartIrqs, Scheduler, Timebase};
+    use crate::memory::Dram;
+
+    let irqs = HartIrqs::new();
+    let scheduler = Scheduler::new();
+    let mut clint: Clint<u32> = Clint::new(
+        0x1_0000,
+        Timebase::Instructions(10),
+        irqs.line(3),
+        irqs.line(7),
+    );
+    let mut bus: Dram<u32> = Dram::with_code(&[], 0);
+    clint.set_timer(scheduler.timer());
+    assert_eq!(u64::MAX, scheduler.next_event());
+
+    assert_eq!(0, irqs.pending());
+    assert!(clint.store_u32(0, 1).is_ok());
+    assert_eq!(0x8, irqs.pending());
+    assert!(matches!(clint.load_u32(0), Ok(1)));
+    assert!(clint.store_u32(0, 0).is_ok());
+    assert_eq!(0, irqs.pending());
+
+    // mtime counts retired instructions
+    scheduler.set_now(25);
+    assert_eq!(2, clint.mtime());
+    assert!(matches!(clint.load_u64(0xBFF8), Ok(2)));
+
+    // mtimecmp written in halves by 32 bit harts
+    assert!(!clint.timer_interrupt());
+    assert!(clint.store_u32(0x4004, 0).is_ok());
+    assert!(!clint.timer_interrupt());
+    assert!(clint.store_u32(0x4000, 3).is_ok());
+    assert!(matches!(clint.load_u64(0x4000), Ok(3)));
+    assert_eq!(0, irqs.pending());
+    // the tick is scheduled for when mtime reaches mtimecmp
+    assert_eq!(30, scheduler.next_event());
+    scheduler.set_now(30);
+    clint.tick(30, &mut bus);
+    assert_eq!(0x80, irqs.pending());
+
+    // writing mtime keeps it counting from the new value
+    assert!(clint.store_u32(0xBFFC, 1).is_ok());
+    assert_eq!(1 << 32 | 3, clint.mtime());
+    scheduler.set_now(40);
+    assert!(matches!(clint.load_u32(0xBFF8), Ok(4)));
+    assert!(matches!(clint.load_u32(0xBFFC), Ok(1)));
+
+    // registers of other harts do not exist
+    assert!(matches!(clint.load_u32(0x4), Ok(0)));
+    assert!(clint.store_u32(0x4008, 0).is_ok());
+
+    scheduler.reset();
+    clint.reset();
+    assert_eq!(0, clint.mtime());
+    assert_eq!(3, clint.mtimecmp());
+    assert_eq!(0, irqs.pending());
+}
+
+#[test]
+fn test_plic() {
+    use crate::devices::{Device, HartIrqs, Plic};
+
+    const MEI: u64 = 1 << 11;
+    const SEI: u64 = 1 << 9;
+
+    const ENABLE_1: u32 = 0x2080;
+    const THRESHOLD_0: u32 = 0x20_0000;
+    const CLAIM_0: u32 = 0x20_0004;
+    const CLAIM_1: u32 = 0x20_1004;
+
+    let irqs = HartIrqs::new();
+    let mut plic: Plic<u32> = Plic::new(0x400_0000, 40, vec![irqs.line(11), irqs.line(9)]);
+    let uart = plic.irq_line(10);
+    let disk = plic.irq_line(33);
+
+    // pending but neither prioritized nor enabled
+    uart.raise();
+    disk.raise();
+    assert!(matches!(plic.load_u32(0x1000), Ok(0x400)));
+    assert!(matches!(plic.load_u32(0x1004), Ok(0x2)));
+    assert_eq!(0, irqs.pending() & MEI);
+
+    assert!(plic.store_u32(10 * 4, 1).is_ok());
+    assert!(plic.store_u32(33 * 4, 0xFF).is_ok());
+    assert!(matches!(plic.load_u32(33 * 4), Ok(7)));
+    assert!(plic.store_u32(0x2000, 0xFFFF_FFFF).is_ok());
+    assert!(plic.store_u32(0x2004, 0xFFFF_FFFF).is_ok());
+    // source 0 and sources past the last do not exist
+    assert!(matches!(plic.load_u32(0x2000), Ok(0xFFFF_FFFE)));
+    assert!(matches!(plic.load_u32(0x2004), Ok(0xFF)));
+    assert_eq!(MEI, irqs.pending() & MEI);
+    assert_eq!(0, irqs.pending() & SEI);
+
+    // reads narrower than the register, e.g. split from a misaligned one, do not claim
+    assert!(matches!(plic.load_u8(CLAIM_0), Ok(0)));
+    assert!(matches!(plic.load_u16(CLAIM_0 + 2), Ok(0)));
+    assert_eq!(MEI, irqs.pending() & MEI);
+
+    // the higher priority is claimed first, then the source waits for completion
+    assert!(matches!(plic.load_u32(CLAIM_0), Ok(33)));
+    assert!(matches!(plic.load_u32(0x1004), Ok(0)));
+    assert!(matches!(plic.load_u32(CLAIM_0), Ok(10)));
+    assert!(matches!(plic.load_u32(CLAIM_0), Ok(0)));
+    assert_eq!(0, irqs.pending() & MEI);
+
+    // completing a source whose line is still raised makes it pending again
+    disk.lower();
+    assert!(plic.store_u32(CLAIM_0, 33).is_ok());
+    assert!(plic.store_u32(CLAIM_0, 10).is_ok());
+    assert!(matches!(plic.load_u32(0x1000), Ok(0x400)));
+    assert!(matches!(plic.load_u32(0x1004), Ok(0)));
+
+    // the threshold masks priorities up to and including it
+    assert!(plic.store_u32(THRESHOLD_0, 1).is_ok());
+    assert_eq!(0, irqs.pending() & MEI);
+    assert!(matches!(plic.load_u32(CLAIM_0), Ok(0)));
+
+    // the S-mode context has its own enables
+    assert!(plic.store_u8(ENABLE_1 + 1, 0x04).is_ok());
+    assert!(matches!(plic.load_u32(ENABLE_1), Ok(0x400)));
+    assert_eq!(SEI, irqs.pending() & SEI);
+    assert!(matches!(plic.load_u32(CLAIM_1), Ok(10)));
+    uart.lower();
+    assert!(plic.store_u32(CLAIM_1, 10).is_ok());
+    assert_eq!(0, irqs.pending() & SEI);
+
+    plic.reset();
+    assert!(matches!(plic.load_u32(10 * 4), Ok(0)));
+    assert!(matches!(plic.load_u32(ENABLE_1), Ok(0)));
+    assert_eq!(0, irqs.pending());
+}
+
+/// Plays the driver of the virtio device at `base`, setting up queue `i` with 8 descriptors and
+/// the rings at `rings[i]`.
+fn virtio_init(bus: &mut crate::memory::Bus<u32>, base: u32, rings: &[(u32, u32, u32)]) {
+    assert!(matches!(bus.load_u32(base), Ok(0x7472_6976)));
+    assert!(bus.store_u32(base + 0x70, 0x3).is_ok());
+    assert!(bus.store_u32(base + 0x24, 1).is_ok());
+    assert!(bus.store_u32(base + 0x20, 1).is_ok());
+    assert!(bus.store_u32(base + 0x70, 0xB).is_ok());
+    for (i, &(desc, avail, used)) in rings.iter().enumerate() {
+        assert!(bus.store_u32(base + 0x30, i as u32).is_ok());
+        assert!(bus.store_u32(base + 0x38, 8).is_ok());
+        assert!(bus.store_u32(base + 0x80, desc).is_ok());
+        assert!(bus.store_u32(base + 0x90, avail).is_ok());
+        assert!(bus.store_u32(base + 0xA0, used).is_ok());
+        assert!(bus.store_u32(base + 0x44, 1).is_ok());
+    }
+    assert!(bus.store_u32(base + 0x70, 0xF).is_ok());
+}
+
+/// Makes the chain of `buffers`, each an address, a length and whether it is writable, available
+/// on the queue with `rings`. Returns the index of the request in the rings.
+fn virtio_submit(
+    bus: &mut crate::memory::Bus<u32>,
+    (desc, avail, _): (u32, u32, u32),
+    buffers: &[(u32, u32, bool)],
+) -> u16 {
+    let idx = bus.load_u16(avail + 2).unwrap();
+    // chains of up to two buffers, up to four of them in flight
+    let head = (idx as u32 % 4) * 2;
+    for (i, &(addr, len, writable)) in buffers.iter().enumerate() {
+        let entry = desc + 16 * (head + i as u32);
+        let next = if i + 1 < buffers.len() { 1 } else { 0 };
+        assert!(bus.store_u64(entry, addr as u64).is_ok());
+        assert!(bus.store_u32(entry + 8, len).is_ok());
+        assert!(bus.store_u16(entry + 12, next | if writable { 2 } else { 0 }).is_ok());
+        assert!(bus.store_u16(entry + 14, (head + i as u32 + 1) as u16).is_ok());
+    }
+    assert!(bus.store_u16(avail + 4 + 2 * (idx as u32 % 8), head as u16).is_ok());
+    assert!(bus.store_u16(avail + 2, idx + 1).is_ok());
+    idx
+}
+
+/// Returns the length written to the request `idx` of the queue with `rings`, once it is used.
+fn virtio_used(
+    bus: &crate::memory::Bus<u32>,
+    (_, _, used): (u32, u32, u32),
+    idx: u16,
+) -> Option<u32> {
+    let used_idx = bus.load_u16(used + 2).unwrap();
+    (used_idx > idx).then(|| bus.load_u32(used + 8 + 8 * (idx as u32 % 8)).unwrap())
+}
+
+#[test]
+fn test_virtio_blk() {
+    use crate::devices::virtio::{DiskMode, VirtioBlk, VirtioMmio};
+    use crate::devices::HartIrqs;
+    use crate::memory::{Bus, Dram, Permissions};
+    use crate::test_util::temp_file;
+
+    const VIRTIO: u32 = 0x1000_0000;
+    const DESC: u32 = 0x1000;
+    const AVAIL: u32 = 0x2000;
+    const USED: u32 = 0x3000;
+    const HEADER: u32 = 0x4000;
+    const DATA: u32 = 0x5000;
+    const STATUS: u32 = 0x6000;
+
+    fn machine(path: &std::path::Path, mode: DiskMode) -> (Bus<u32>, HartIrqs) {
+        let irqs = HartIrqs::new();
+        let disk = VirtioBlk::open(path, mode).unwrap();
+        let mut bus: Bus<u32> =
+            Bus::new(vec![(0..0x1_0000, Box::new(Dram::with_code(&[], 0x1_0000)))]);
+        bus.insert(
+            VIRTIO..VIRTIO + 0x1000,
+            Box::new(VirtioMmio::new(0x1000, Box::new(disk), irqs.line(1))),
+            Permissions::RW,
+        );
+
+        assert!(matches!(bus.load_u32(VIRTIO + 0x8), Ok(2)));
+        assert!(matches!(bus.load_u32(VIRTIO + 0x100), Ok(4)));
+        virtio_init(&mut bus, VIRTIO, &[(DESC, AVAIL, USED)]);
+
+        (bus, irqs)
+    }
+
+    /// Submits a request of three descriptors: header, `len` bytes of data and status. Returns
+    /// the status and the length of the used element.
+    fn request(
+        bus: &mut Bus<u32>,
+        now: u64,
+        kind: u32,
+        sector: u64,
+        write: bool,
+        len: u32,
+    ) -> (u8, u32) {
+        assert!(bus.store_u32(HEADER, kind).is_ok());
+        assert!(bus.store_u64(HEADER + 8, sector).is_ok());
+        let descriptors = [
+            (HEADER, 16, 1),
+            (DATA, len, if write { 1 } else { 3 }),
+            (STATUS, 1, 2),
+        ];
+        for (i, (addr, len, flags)) in descriptors.into_iter().enumerate() {
+            let entry = DESC + 16 * i as u32;
+            assert!(bus.store_u64(entry, addr as u64).is_ok());
+            assert!(bus.store_u32(entry + 8, len).is_ok());
+            assert!(bus.store_u16(entry + 12, flags).is_ok());
+            assert!(bus.store_u16(entry + 14, i as u16 + 1).is_ok());
+        }
+
+        let idx = bus.load_u16(AVAIL + 2).unwrap();
+        assert!(bus.store_u16(AVAIL + 4 + 2 * (idx as u32 % 8), 0).is_ok());
+        assert!(bus.store_u16(AVAIL + 2, idx + 1).is_ok());
+        assert!(bus.store_u32(VIRTIO + 0x50, 0).is_ok());
+        // nothing happens until the bus ticks the device
+        assert!(matches!(bus.load_u16(USED + 2), Ok(used) if used == idx));
+        bus.tick(now);
+        assert!(matches!(bus.load_u16(USED + 2), Ok(used) if used == idx + 1));
+
+        let len = bus.load_u32(USED + 4 + 8 * (idx as u32 % 8) + 4).unwrap();
+        (bus.load_u8(STATUS).unwrap(), len)
+    }
+
+    let image: Vec<u8> = (0..4).flat_map(|sector| [sector as u8 + 1; 512]).collect();
+    let path = temp_file("virtio_blk", &image);
+
+    // reads and writes go to the image
+    let (mut bus, irqs) = machine(&path, DiskMode::ReadWrite);
+    assert_eq!((0, 513), request(&mut bus, 0, 0, 2, false, 512));
+    assert!(matches!(bus.load_u32(DATA + 508), Ok(0x0303_0303)));
+    assert_eq!(0x2, irqs.pending());
+    assert!(matches!(bus.load_u32(VIRTIO + 0x60), Ok(1)));
+    assert!(bus.store_u32(VIRTIO + 0x64, 1).is_ok());
+    assert_eq!(0, irqs.pending());
+
+    assert!(bus.store_u32(DATA, 0xDEAD_BEEF).is_ok());
+    assert_eq!((0, 1), request(&mut bus, 10, 1, 1, true, 512));
+    assert_eq!([0xEF, 0xBE, 0xAD, 0xDE, 3], std::fs::read(&path).unwrap()[512..517]);
+    // past the end of the disk and unknown requests
+    assert_eq!((1, 1), request(&mut bus, 20, 0, 4, false, 512));
+    assert_eq!((2, 1), request(&mut bus, 30, 0xFF, 0, false, 512));
+    assert_eq!((0, 21), request(&mut bus, 40, 8, 0, false, 512));
+    assert!(matches!(bus.load_u32(DATA), Ok(0x6373_6972)));
+    // huge buffers only get the reply, the status still follows them
+    assert_eq!((2, 1), request(&mut bus, 50, 0xFF, 0, false, u32::MAX));
+    assert_eq!((0, 21), request(&mut bus, 60, 8, 0, false, u32::MAX));
+    assert_eq!((1, 1), request(&mut bus, 70, 0, 0, false, u32::MAX));
+
+    // writes of a snapshot are only visible to the guest
+    let (mut bus, _) = machine(&path, DiskMode::CopyOnWrite);
+    assert!(bus.store_u32(DATA, 0x1234_5678).is_ok());
+    assert_eq!((0, 1), request(&mut bus, 0, 1, 0, true, 512));
+    assert!(bus.store_u32(DATA, 0).is_ok());
+    assert_eq!((0, 513), request(&mut bus, 10, 0, 0, false, 512));
+    assert!(matches!(bus.load_u32(DATA), Ok(0x1234_5678)));
+    assert_eq!(image[..512], std::fs::read(&path).unwrap()[..512]);
+
+    // the driver is told about read-only disks, which fail writes
+    let (mut bus, _) = machine(&path, DiskMode::ReadOnly);
+    assert!(matches!(bus.load_u32(VIRTIO + 0x10), Ok(0x220)));
+    assert_eq!((1, 1), request(&mut bus, 0, 1, 0, true, 512));
+
+    // resetting the device forgets the queue
+    assert!(bus.store_u32(VIRTIO + 0x70, 0).is_ok());
+    assert!(matches!(bus.load_u32(VIRTIO + 0x70), Ok(0)));
+    assert!(matches!(bus.load_u32(VIRTIO + 0x44), Ok(0)));
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_virtio_net() {
+    use crate::devices::virtio::{Loopback, NetBackend, Pcap, UnixSocket, VirtioMmio, VirtioNet};
+    use crate::devices::HartIrqs;
+    use crate::memory::{Bus, Dram, Permissions};
+    use crate::test_util::{temp_file, temp_path};
+
+    const VIRTIO: u32 = 0x1000_0000;
+    const RX: (u32, u32, u32) = (0x1000, 0x2000, 0x3000);
+    const TX: (u32, u32, u32) = (0x1800, 0x2800, 0x3800);
+    const BUFFER: u32 = 0x4000;
+    const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    let irqs = HartIrqs::new();
+    let network = Loopback::new();
+    let mut bus: Bus<u32> =
+        Bus::new(vec![(0..0x1_0000, Box::new(Dram::with_code(&[], 0x1_0000)))]);
+    let device = VirtioNet::new(network.clone(), MAC);
+    bus.insert(
+        VIRTIO..VIRTIO + 0x1000,
+        Box::new(VirtioMmio::new(0x1000, Box::new(device), irqs.line(1))),
+        Permissions::RW,
+    );
+
+    // the mac address followed by the link status
+    assert!(matches!(bus.load_u32(VIRTIO + 0x8), Ok(1)));
+    assert!(matches!(bus.load_u32(VIRTIO + 0x100), Ok(0x1200_5452)));
+    assert!(matches!(bus.load_u32(VIRTIO + 0x104), Ok(0x0001_5634)));
+    virtio_init(&mut bus, VIRTIO, &[RX, TX]);
+
+    // frames are sent without the header
+    let frame = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 1, 2, 3, 4];
+    for (i, byte) in frame.iter().enumerate() {
+        assert!(bus.store_u8(BUFFER + 12 + i as u32, *byte).is_ok());
+    }
+    let idx = virtio_submit(&mut bus, TX, &[(BUFFER, 22, false)]);
+    assert!(bus.store_u32(VIRTIO + 0x50, 1).is_ok());
+    bus.tick(0);
+    assert_eq!(Some(0), virtio_used(&bus, TX, idx));
+    assert_eq!(vec![frame.to_vec()], network.take_sent());
+    assert_eq!(0x2, irqs.pending());
+    assert!(bus.store_u32(VIRTIO + 0x64, 1).is_ok());
+
+    // frames wait for a receive buffer
+    network.inject(&frame[..8]);
+    let next = bus.next_event();
+    assert!(next > 0 && next < u64::MAX);
+    bus.tick(next);
+    assert_eq!(0, irqs.pending());
+    let idx = virtio_submit(&mut bus, RX, &[(BUFFER, 64, true)]);
+    assert!(bus.store_u32(VIRTIO + 0x50, 0).is_ok());
+    bus.tick(next + 1);
+    assert_eq!(Some(20), virtio_used(&bus, RX, idx));
+    assert!(matches!(bus.load_u16(BUFFER + 10), Ok(1)));
+    assert!(matches!(bus.load_u32(BUFFER + 16), Ok(0x0201_FFFF)));
+    assert_eq!(0x2, irqs.pending());
+    assert!(bus.store_u32(VIRTIO + 0x64, 1).is_ok());
+
+    // received frames are polled for, frames too large for the buffer are dropped
+    let idx = virtio_submit(&mut bus, RX, &[(BUFFER, 16, true)]);
+    network.inject(&frame);
+    let next = bus.next_event();
+    bus.tick(next - 1);
+    assert_eq!(None, virtio_used(&bus, RX, idx));
+    bus.tick(next);
+    assert_eq!(Some(0), virtio_used(&bus, RX, idx));
+
+    // the capture has a record of 16 bytes plus the frame for each direction
+    let path = temp_path("virtio_net.pcap");
+    let mut pcap = Pcap::new(Loopback::new().with_echo(), std::fs::File::create(&path).unwrap())
+        .unwrap();
+    assert!(pcap.send(&frame).is_ok());
+    assert_eq!(Some(frame.to_vec()), pcap.recv().unwrap());
+    assert_eq!(None, pcap.recv().unwrap());
+    let capture = std::fs::read(&path).unwrap();
+    assert_eq!(24 + 2 * (16 + frame.len()), capture.len());
+    assert_eq!([0xD4, 0xC3, 0xB2, 0xA1], capture[..4]);
+    assert_eq!(frame, capture[24 + 16..24 + 16 + frame.len()]);
+    std::fs::remove_file(path).unwrap();
+
+    // two sockets exchange frames, without a peer they are lost
+    let socket = |name: &str| temp_path(&format!("virtio_net-{name}"));
+    let mut a = UnixSocket::bind(socket("a"), socket("b")).unwrap();
+    assert!(a.send(&frame).is_ok());
+    let mut b = UnixSocket::bind(socket("b"), socket("a")).unwrap();
+    assert_eq!(None, b.recv().unwrap());
+    assert!(a.send(&frame).is_ok());
+    assert_eq!(Some(frame.to_vec()), b.recv().unwrap());
+    assert!(b.send(&frame[..6]).is_ok());
+    assert_eq!(Some(frame[..6].to_vec()), a.recv().unwrap());
+    std::fs::remove_file(socket("a")).unwrap();
+    std::fs::remove_file(socket("b")).unwrap();
+
+    // only stale sockets are replaced
+    let path = temp_file("virtio_net-file", b"data");
+    assert!(UnixSocket::bind(&path, socket("b")).is_err());
+    assert_eq!(b"data".to_vec(), std::fs::read(&path).unwrap());
+    std::fs::remove_file(path).unwrap();
+}
+
+/// Returns a bus with 64 KiB of memory and `device` at 0x1000_0000, whose interrupt is line 1
+/// of the returned hart.
+fn virtio_machine(
+    device: impl crate::devices::virtio::VirtioDevice<u32> + 'static,
+) -> (crate::memory::Bus<u32>, crate::devices::HartIrqs) {
+    use crate::devices::virtio::VirtioMmio;
+    use crate::devices::HartIrqs;
+    use crate::memory::{Bus, Dram, Permissions};
+
+    let irqs = HartIrqs::new();
+    let mut bus: Bus<u32> =
+        Bus::new(vec![(0..0x1_0000, Box::new(Dram::with_code(&[], 0x1_0000)))]);
+    bus.insert(
+        0x1000_0000..0x1000_1000,
+        Box::new(VirtioMmio::new(0x1000, Box::new(device), irqs.line(1))),
+        Permissions::RW,
+    );
+    (bus, irqs)
+}
+
+#[test]
+fn test_virtio_console() {
+    use std::sync::mpsc;
+
+    use crate::devices::virtio::VirtioConsole;
+    use crate::test_util::Capture;
+
+    const VIRTIO: u32 = 0x1000_0000;
+    const CONTROL: u32 = 0x8000;
+    const BUFFER: u32 = 0x9000;
+
+    fn control(id: u32, event: u16, value: u16) -> Vec<u8> {
+        let mut message = id.to_le_bytes().to_vec();
+        message.extend(event.to_le_bytes());
+        message.extend(value.to_le_bytes());
+        message
+    }
+
+    let (console_tx, console_rx) = mpsc::channel();
+    let (_port_tx, port_rx) = mpsc::channel();
+    let (console, port) = (Capture::default(), Capture::default());
+    let device = VirtioConsole::new()
+        .with_console(console_rx, console.clone())
+        .with_port("data", port_rx, port.clone());
+    let (mut bus, irqs) = virtio_machine(device);
+
+    // two ports, with the queues of the console, the control queues and those of the port
+    assert!(matches!(bus.load_u32(VIRTIO + 0x8), Ok(3)));
+    assert!(matches!(bus.load_u32(VIRTIO + 0x104), Ok(2)));
+    let rings: Vec<_> = (0..6)
+        .map(|i| (0x1000 + 0x100 * i, 0x2000 + 0x100 * i, 0x3000 + 0x100 * i))
+        .collect();
+    virtio_init(&mut bus, VIRTIO, &rings);
+    assert!(bus.store_u32(VIRTIO + 0x30, 6).is_ok());
+    assert!(matches!(bus.load_u32(VIRTIO + 0x34), Ok(0)));
+
+    // the ports are added once the driver is ready, their messages wait for buffers
+    for (i, byte) in control(0, 0, 1).into_iter().enumerate() {
+        assert!(bus.store_u8(BUFFER + i as u32, byte).is_ok());
+    }
+    let idx = virtio_submit(&mut bus, rings[3], &[(BUFFER, 8, false)]);
+    assert!(bus.store_u32(VIRTIO + 0x50, 3).is_ok());
+    bus.tick(0);
+    assert_eq!(Some(0), virtio_used(&bus, rings[3], idx));
+    for id in 0..2 {
+        let idx = virtio_submit(&mut bus, rings[2], &[(CONTROL, 64, true)]);
+        assert!(bus.store_u32(VIRTIO + 0x50, 2).is_ok());
+        bus.tick(1 + id as u64);
+        assert_eq!(Some(8), virtio_used(&bus, rings[2], idx));
+        assert!(matches!(bus.load_u64(CONTROL), Ok(v) if v.to_le_bytes()[..] == control(id, 1, 0)));
+    }
+
+    // ready ports are named or made the console, and opened
+    for (i, byte) in control(1, 3, 1).into_iter().enumerate() {
+        assert!(bus.store_u8(BUFFER + i as u32, byte).is_ok());
+    }
+    virtio_submit(&mut bus, rings[3], &[(BUFFER, 8, false)]);
+    let idx = virtio_submit(&mut bus, rings[2], &[(CONTROL, 64, true)]);
+    virtio_submit(&mut bus, rings[2], &[(CONTROL + 64, 64, true)]);
+    assert!(bus.store_u32(VIRTIO + 0x50, 3).is_ok());
+    bus.tick(10);
+    assert_eq!(Some(12), virtio_used(&bus, rings[2], idx));
+    assert!(matches!(bus.load_u32(CONTROL + 4), Ok(0x0001_0007)));
+    assert!(matches!(bus.load_u32(CONTROL + 8), Ok(0x6174_6164)));
+    assert!(matches!(bus.load_u32(CONTROL + 68), Ok(0x0001_0006)));
+    assert_eq!(0x2, irqs.pending());
+
+    // output goes to the port it was written to
+    for (i, byte) in b"hello".iter().enumerate() {
+        assert!(bus.store_u8(BUFFER + i as u32, *byte).is_ok());
+    }
+    let idx = virtio_submit(&mut bus, rings[5], &[(BUFFER, 5, false)]);
+    assert!(bus.store_u32(VIRTIO + 0x50, 5).is_ok());
+    bus.tick(20);
+    assert_eq!(Some(0), virtio_used(&bus, rings[5], idx));
+    assert_eq!(b"hello".to_vec(), port.contents());
+    assert!(console.contents().is_empty());
+
+    // input is polled for and split over the buffers
+    for byte in b"abcdef" {
+        console_tx.send(*byte).unwrap();
+    }
+    let first = virtio_submit(&mut bus, rings[0], &[(BUFFER, 4, true)]);
+    let next = bus.next_event();
+    bus.tick(next);
+    assert_eq!(Some(4), virtio_used(&bus, rings[0], first));
+    assert!(matches!(bus.load_u32(BUFFER), Ok(0x6463_6261)));
+    let second = virtio_submit(&mut bus, rings[0], &[(BUFFER + 4, 4, true)]);
+    assert!(bus.store_u32(VIRTIO + 0x50, 0).is_ok());
+    bus.tick(next + 1);
+    assert_eq!(Some(2), virtio_used(&bus, rings[0], second));
+    assert!(matches!(bus.load_u16(BUFFER + 4), Ok(0x6665)));
+}
+
+#[test]
+fn test_virtio_rng() {
+    use crate::devices::virtio::VirtioRng;
+
+    const RINGS: (u32, u32, u32) = (0x1000, 0x2000, 0x3000);
+    const BUFFER: u32 = 0x4000;
+
+    fn entropy(seed: u64) -> Vec<u8> {
+        let (mut bus, _) = virtio_machine(VirtioRng::new(seed));
+        assert!(matches!(bus.load_u32(0x1000_0008), Ok(4)));
+        virtio_init(&mut bus, 0x1000_0000, &[RINGS]);
+
+        let idx = virtio_submit(&mut bus, RINGS, &[(BUFFER, 13, true)]);
+        assert!(bus.store_u32(0x1000_0050, 0).is_ok());
+        bus.tick(0);
+        assert_eq!(Some(13), virtio_used(&bus, RINGS, idx));
+        (0..16).map(|i| bus.load_u8(BUFFER + i).unwrap()).collect()
+    }
+
+    // the same seed gives the same bytes, the buffer is filled exactly
+    let bytes = entropy(42);
+    assert_eq!(bytes, entropy(42));
+    assert_ne!(bytes, entropy(43));
+    assert_eq!([0; 3], bytes[13..]);
+    assert!(bytes[..13].iter().any(|&byte| byte != 0));
+
+    // huge buffers are only filled partly
+    let (mut bus, _) = virtio_machine(VirtioRng::new(42));
+    virtio_init(&mut bus, 0x1000_0000, &[RINGS]);
+    let idx = virtio_submit(&mut bus, RINGS, &[(BUFFER, u32::MAX, true)]);
+    assert!(bus.store_u32(0x1000_0050, 0).is_ok());
+    bus.tick(0);
+    assert_eq!(Some(4096), virtio_used(&bus, RINGS, idx));
+    assert_eq!(bytes[..13], bus.get_data(BUFFER..BUFFER + 13).unwrap());
+}
+
+#[test]
+fn test_virtio_queue_overflow() {
+    use crate::devices::virtio::VirtioRng;
+
+    const RINGS: (u32, u32, u32) = (0x1000, 0x2000, 0x3000);
+    const BUFFER: u32 = 0x4000;
+
+    // each ring in turn ends past the top of the guest address space
+    for register in [0x80, 0x90, 0xA0] {
+        let (mut bus, _) = virtio_machine(VirtioRng::new(42));
+        virtio_init(&mut bus, 0x1000_0000, &[RINGS]);
+        virtio_submit(&mut bus, RINGS, &[(BUFFER, 16, true)]);
+        assert!(bus.store_u32(0x1000_0000 + register, u32::MAX).is_ok());
+        assert!(bus.store_u32(0x1000_0000 + register + 4, u32::MAX).is_ok());
+
+        // the device needs a reset instead of panicking
+        assert!(bus.store_u32(0x1000_0050, 0).is_ok());
+        bus.tick(0);
+        assert!(matches!(bus.load_u32(0x1000_0070), Ok(0x4F)));
+    }
+}
+
+#[test]
+fn test_virtio_9p() {
+    use crate::devices::virtio::Virtio9p;
+    use crate::memory::Bus;
+    use crate::test_util::temp_path;
+
+    const RINGS: (u32, u32, u32) = (0x1000, 0x2000, 0x3000);
+    const REQUEST: u32 = 0x4000;
+    const REPLY: u32 = 0x8000;
+
+    /// Fields of a request, with their size.
+    #[derive(Clone, Copy)]
+    enum Field<'a> {
+        U8(u8),
+        U16(u16),
+        U32(u32),
+        U64(u64),
+        Str(&'a str),
+    }
+    use Field::*;
+
+    /// Sends a request of type `kind` with tag 1, returns the type and body of the reply.
+    fn call(bus: &mut Bus<u32>, kind: u8, fields: &[Field]) -> (u8, Vec<u8>) {
+        let mut body = Vec::new();
+        for field in fields {
+            match field {
+                U8(value) => body.push(*value),
+                U16(value) => body.extend(value.to_le_bytes()),
+                U32(value) => body.extend(value.to_le_bytes()),
+                U64(value) => body.extend(value.to_le_bytes()),
+                Str(value) => {
+                    body.extend((value.len() as u16).to_le_bytes());
+                    body.extend(value.as_bytes());
+                }
+            }
+        }
+        let mut request = (7 + body.len() as u32).to_le_bytes().to_vec();
+        request.push(kind);
+        request.extend(1u16.to_le_bytes());
+        request.extend(body);
+        for (i, byte) in request.iter().enumerate() {
+            assert!(bus.store_u8(REQUEST + i as u32, *byte).is_ok());
+        }
+
+        let len = request.len() as u32;
+        let idx = virtio_submit(bus, RINGS, &[(REQUEST, len, false), (REPLY, 0x4000, true)]);
+        assert!(bus.store_u32(0x1000_0050, 0).is_ok());
+        bus.tick(idx as u64);
+        let len = virtio_used(bus, RINGS, idx).unwrap();
+        let reply: Vec<_> = (0..len).map(|i| bus.load_u8(REPLY + i).unwrap()).collect();
+        assert_eq!(len, u32::from_le_bytes(reply[..4].try_into().unwrap()));
+        assert_eq!([1, 0], reply[5..7]);
+        (reply[4], reply[7..].to_vec())
+    }
+
+    let root = temp_path("virtio_9p");
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(root.join("sub")).unwrap();
+    std::fs::write(root.join("hello.txt"), "hi there").unwrap();
+    std::os::unix::fs::symlink("/etc", root.join("escape")).unwrap();
+
+    let (mut bus, _) = virtio_machine(Virtio9p::new(&root, "host").unwrap());
+    assert!(matches!(bus.load_u32(0x1000_0008), Ok(9)));
+    assert!(matches!(bus.load_u32(0x1000_0100), Ok(0x6F68_0004)));
+    assert!(matches!(bus.load_u16(0x1000_0104), Ok(0x7473)));
+    virtio_init(&mut bus, 0x1000_0000, &[RINGS]);
+
+    let (kind, body) = call(&mut bus, 100, &[U32(8192), Str("9P2000.L")]);
+    assert_eq!(101, kind);
+    assert_eq!(8192u32.to_le_bytes(), body[..4]);
+    let (kind, root_qid) = call(&mut bus, 104, &[U32(0), U32(!0), Str("root"), Str(""), U32(0)]);
+    assert_eq!((105, 0x80), (kind, root_qid[0]));
+
+    // files are read at any offset
+    let (kind, body) = call(&mut bus, 110, &[U32(0), U32(1), U16(1), Str("hello.txt")]);
+    assert_eq!((111, [1, 0]), (kind, [body[0], body[1]]));
+    assert_eq!(13, call(&mut bus, 12, &[U32(1), U32(0)]).0);
+    let (kind, body) = call(&mut bus, 116, &[U32(1), U64(3), U32(100)]);
+    assert_eq!((117, &b"\x05\x00\x00\x00there"[..]), (kind, &body[..]));
+    let (_, body) = call(&mut bus, 24, &[U32(1), U64(0x7FF)]);
+    assert_eq!(8u64.to_le_bytes(), body[49..57]);
+
+    // walks stop at the root and do not follow symlinks
+    let (_, body) = call(&mut bus, 110, &[U32(0), U32(2), U16(2), Str(".."), Str("sub")]);
+    assert_eq!(2, body[0]);
+    assert_eq!(root_qid[..], body[2..15]);
+    let (kind, body) = call(&mut bus, 110, &[U32(0), U32(3), U16(2), Str("escape"), Str("passwd")]);
+    assert_eq!((111, 1, 0x02), (kind, body[0], body[2]));
+    assert_eq!((7, 9u32.to_le_bytes().to_vec()), call(&mut bus, 120, &[U32(3)]));
+    let (kind, body) = call(&mut bus, 22, &[U32(1)]);
+    assert_eq!((7, 22u32.to_le_bytes().to_vec()), (kind, body));
+
+    // files and directories are created and removed
+    call(&mut bus, 110, &[U32(0), U32(4), U16(0)]);
+    let (kind, _) = call(&mut bus, 14, &[U32(4), Str("new.txt"), U32(0o101), U32(0o644), U32(0)]);
+    assert_eq!(15, kind);
+    let data = [U8(b'd'), U8(b'a'), U8(b't'), U8(b'a')];
+    let (kind, body) = call(&mut bus, 118, &[&[U32(4), U64(0), U32(4)][..], &data].concat());
+    assert_eq!((119, 4u32.to_le_bytes().to_vec()), (kind, body));
+    assert_eq!("data", std::fs::read_to_string(root.join("new.txt")).unwrap());
+    assert_eq!(73, call(&mut bus, 72, &[U32(0), Str("dir"), U32(0o755), U32(0)]).0);
+    assert!(root.join("dir").is_dir());
+    let (kind, body) = call(&mut bus, 72, &[U32(0), Str("../dir"), U32(0o755), U32(0)]);
+    assert_eq!((7, 22u32.to_le_bytes().to_vec()), (kind, body));
+
+    // directories are listed in order, starting with . and ..
+    call(&mut bus, 110, &[U32(0), U32(5), U16(0)]);
+    call(&mut bus, 12, &[U32(5), U32(0)]);
+    let (kind, body) = call(&mut bus, 40, &[U32(5), U64(0), U32(4096)]);
+    assert_eq!(41, kind);
+    let mut names = Vec::new();
+    let mut entry = &body[4..];
+    while !entry.is_empty() {
+        let len = u16::from_le_bytes([entry[22], entry[23]]) as usize;
+        names.push(String::from_utf8(entry[24..24 + len].to_vec()).unwrap());
+        entry = &entry[24 + len..];
+    }
+    assert_eq!([".", "..", "dir", "escape", "hello.txt", "new.txt", "sub"], names[..]);
+    // later calls continue at the offset of the last entry
+    let (_, body) = call(&mut bus, 40, &[U32(5), U64(6), U32(4096)]);
+    assert_eq!(b"sub", &body[body.len() - 3..]);
+
+    assert_eq!(77, call(&mut bus, 76, &[U32(0), Str("dir"), U32(0x200)]).0);
+    assert!(!root.join("dir").exists());
+    // extended attributes are not supported
+    let (kind, body) = call(&mut bus, 30, &[U32(0), U32(6), Str("user.test")]);
+    assert_eq!((7, 95u32.to_le_bytes().to_vec()), (kind, body));
+
+    // a fid walked through a directory the guest then swaps for a symlink stays in the share
+    let outside = temp_path("virtio_9p-outside");
+    let _ = std::fs::remove_dir_all(&outside);
+    std::fs::create_dir_all(outside.join("etc")).unwrap();
+    std::fs::create_dir_all(root.join("a/etc")).unwrap();
+    let (kind, body) = call(&mut bus, 110, &[U32(0), U32(7), U16(2), Str("a"), Str("etc")]);
+    assert_eq!((111, 2), (kind, body[0]));
+    let rename = [U32(0), Str("a"), U32(0), Str("a_old")];
+    assert_eq!(75, call(&mut bus, 74, &rename).0);
+    let target = outside.to_str().unwrap();
+    assert_eq!(17, call(&mut bus, 16, &[U32(0), Str("a"), Str(target), U32(0)]).0);
+    assert_eq!(7, call(&mut bus, 72, &[U32(7), Str("dir"), U32(0o755), U32(0)]).0);
+    let create = [U32(7), Str("new.txt"), U32(0o101), U32(0o644), U32(0)];
+    assert_eq!(7, call(&mut bus, 14, &create).0);
+    assert_eq!(7, call(&mut bus, 24, &[U32(7), U64(0x7FF)]).0);
+    assert_eq!(0, std::fs::read_dir(outside.join("etc")).unwrap().count());
+    std::fs::remove_dir_all(outside).unwrap();
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn test_goldfish_rtc() {
+    use std::time::Duration;
+
+    use crate::devices::{Device, GoldfishRtc, HartIrqs, Scheduler, WallClock};
+    use crate::memory::Dram;
+
+    const TIME_LOW: u32 = 0x00;
+    const TIME_HIGH: u32 = 0x04;
+    const ALARM_LOW: u32 = 0x08;
+    const ALARM_HIGH: u32 = 0x0C;
+    const IRQ_ENABLED: u32 = 0x10;
+    const ALARM_STATUS: u32 = 0x18;
+    const CLEAR_INTERRUPT: u32 = 0x1C;
+
+    let irqs = HartIrqs::new();
+    let scheduler = Scheduler::new();
+    scheduler.set_wall_clock(WallClock::Virtual(Duration::from_secs(5)));
+    let mut rtc: GoldfishRtc<u32> = GoldfishRtc::new(0x1000, irqs.line(1));
+    let mut bus: Dram<u32> = Dram::with_code(&[], 0);
+    rtc.set_timer(scheduler.timer());
+    assert_eq!(u64::MAX, scheduler.next_event());
+
+    // a virtual clock advances 10ns per instruction, the upper half is latched
+    scheduler.set_now(100);
+    assert_eq!(5_000_001_000, rtc.time());
+    assert!(matches!(rtc.load_u32(TIME_LOW), Ok(0x2A05_F5E8)));
+    scheduler.set_now(1_000_000_000);
+    assert!(matches!(rtc.load_u32(TIME_HIGH), Ok(1)));
+    assert!(matches!(rtc.load_u32(TIME_LOW), Ok(0x7E11_D600)));
+    assert!(matches!(rtc.load_u32(TIME_HIGH), Ok(3)));
+    assert!(matches!(rtc.load_u8(TIME_LOW), Err(CPUError::LoadAccessFault(0))));
+
+    // the time is written upper half first and keeps counting
+    assert!(rtc.store_u32(TIME_HIGH, 0).is_ok());
+    assert!(rtc.store_u32(TIME_LOW, 1000).is_ok());
+    scheduler.set_now(1_000_000_010);
+    assert_eq!(1100, rtc.time());
+
+    // the alarm ticks exactly when it is due, and only interrupts if enabled
+    assert!(rtc.store_u32(ALARM_HIGH, 0).is_ok());
+    assert!(rtc.store_u32(ALARM_LOW, 1125).is_ok());
+    assert!(matches!(rtc.load_u32(ALARM_STATUS), Ok(1)));
+    assert_eq!(1_000_000_013, scheduler.next_event());
+    scheduler.set_now(1_000_000_013);
+    rtc.tick(1_000_000_013, &mut bus);
+    assert!(matches!(rtc.load_u32(ALARM_STATUS), Ok(0)));
+    assert_eq!(0, irqs.pending());
+    assert!(rtc.store_u32(IRQ_ENABLED, 1).is_ok());
+    assert_eq!(0x2, irqs.pending());
+    assert!(rtc.store_u32(CLEAR_INTERRUPT, 1).is_ok());
+    assert_eq!(0, irqs.pending());
+
+    // alarms in the past fire immediately
+    assert!(rtc.store_u32(ALARM_LOW, 0).is_ok());
+    assert_eq!(0x2, irqs.pending());
+
+    scheduler.reset();
+    rtc.reset();
+    assert_eq!(5_000_000_000, rtc.time());
+    assert_eq!(0, irqs.pending());
+}
+
+#[test]
+fn test_framebuffer() {
+    use crate::devices::{Framebuffer, PixelFormat};
+
+    // two by two pixels of red, green, blue and white
+    // empty or larger than the address space
+    assert!(Framebuffer::<u32>::new(0, 2, PixelFormat::Rgb565).is_none());
+    assert!(Framebuffer::<u32>::new(0x1_0000, 0x1_0000, PixelFormat::Rgb565).is_none());
+    assert!(Framebuffer::<u64>::new(usize::MAX, 2, PixelFormat::Rgb565).is_none());
+
+    let mut framebuffer: Framebuffer<u32> = Framebuffer::new(2, 2, PixelFormat::Rgb565).unwrap();
+    let screen = framebuffer.screen();
+    assert_eq!(8, framebuffer.size());
+    assert!(framebuffer.store_u32(0, 0x07E0_F800).is_ok());
+    assert!(framebuffer.store_u16(4, 0x001F).is_ok());
+    assert!(framebuffer.store_u16(6, 0xFFFF).is_ok());
+    assert!(matches!(framebuffer.load_u16(2), Ok(0x07E0)));
+    assert!(matches!(framebuffer.store_u16(8, 0), Err(CPUError::StoreAccessFault(8))));
+
+    let rgb = [255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255];
+    assert_eq!(rgb.to_vec(), screen.to_rgb());
+
+    let mut ppm = Vec::new();
+    assert!(screen.write_ppm(&mut ppm).is_ok());
+    assert_eq!(b"P6\n2 2\n255\n".to_vec(), ppm[..11]);
+    assert_eq!(rgb, ppm[11..]);
+
+    let mut png = Vec::new();
+    assert!(screen.write_png(&mut png).is_ok());
+    assert_eq!(b"\x89PNG\r\n\x1a\n".to_vec(), png[..8]);
+    // 8 bit truecolor with its checksum
+    assert_eq!(
+        [0, 0, 0, 13, b'I', b'H', b'D', b'R', 0, 0, 0, 2, 0, 0, 0, 2, 8, 2, 0, 0, 0],
+        png[8..29]
+    );
+    assert_eq!([0xFD, 0xD4, 0x9A, 0x73], png[29..33]);
+    // the rows in a single stored block, followed by the adler32 of the rows
+    assert_eq!([0, 0, 0, 25, b'I', b'D', b'A', b'T', 0x78, 0x01], png[33..43]);
+    assert_eq!([1, 14, 0, 0xF1, 0xFF, 0], png[43..49]);
+    assert_eq!(rgb[..6], png[49..55]);
+    assert_eq!(0, png[55]);
+    assert_eq!(rgb[6..], png[56..62]);
+    assert_eq!([0x1F, 0xEE, 0x05, 0xFB], png[62..66]);
+    assert_eq!(
+        [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82],
+        png[70..]
+    );
+}
@@ -0,0 +1,63 @@
+use std::ops::Range;
+
+use num_traits::Unsigned;
+
+use crate::cpu::isa::{As, Xlen};
+use crate::cpu::CPUError;
+use crate::memory::{impl_memory, Memory};
+
+/// Written to the finisher to power off with exit code 0.
+pub const FINISHER_PASS: u32 = 0x5555;
+/// Written to the finisher to power off with the exit code in the upper 16 bits.
+pub const FINISHER_FAIL: u32 = 0x3333;
+/// Written to the finisher to reset the machine.
+pub const FINISHER_RESET: u32 = 0x7777;
+
+/// SiFive test finisher, also used as syscon poweroff/reboot device by Linux.
+///
+/// A store to offset 0 with one of the `FINISHER_*` values in the lower 16 bits ends the
+/// running program, every other store is ignored and loads read as zero. Powering off is reported
+/// as [`CPUError::PowerOff`], resetting as [`CPUError::ResetRequested`].
+pub struct TestFinisher<A: Xlen + Unsigned> {
+    size: A,
+}
+
+impl<A: Xlen + Unsigned> TestFinisher<A> {
+    pub fn new(size: A) -> TestFinisher<A> {
+        Self { size }
+    }
+
+    fn write(&mut self, addr: A, value: u32) -> Result<(), CPUError<A>> {
+        if addr != A::zero() {
+            return Ok(());
+        }
+
+        match value & 0xFFFF {
+            FINISHER_PASS => Err(CPUError::PowerOff(0)),
+            FINISHER_FAIL => Err(CPUError::PowerOff(value >> 16)),
+            FINISHER_RESET => Err(CPUError::ResetRequested),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<A: Xlen + Unsigned> Memory<A> for TestFinisher<A> {
+    fn size(&self) -> A {
+        self.size
+    }
+
+    impl_memory!(
+        self,
+        addr,
+        value,
+        {
+            let _ = addr;
+            Ok(0)
+        },
+        { self.write(addr, value as u32) }
+    );
+
+    fn get_data(&self, range: Range<A>) -> Result<Vec<u8>, CPUError<A>> {
+        Ok(vec![0; (range.end - range.start).as_t::<usize>()])
+    }
+}
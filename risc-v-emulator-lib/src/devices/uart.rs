@@ -0,0 +1,349 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use num_traits::Unsigned;
+
+use crate::cpu::isa::{As, Xlen};
+use crate::cpu::CPUError;
+use crate::devices::{Device, IrqLine, Timer};
+use crate::memory::{impl_memory, Memory};
+
+// register offsets, the divisor latch replaces the first two while LCR.DLAB is set
+const RBR_THR_DLL: usize = 0;
+const IER_DLM: usize = 1;
+const IIR_FCR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+const MSR: usize = 6;
+const SCR: usize = 7;
+
+const IER_RDI: u8 = 0x01;
+const IER_THRI: u8 = 0x02;
+
+const IIR_NO_INT: u8 = 0x01;
+const IIR_THRI: u8 = 0x02;
+const IIR_RDI: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xC0;
+
+const FCR_FIFO_ENABLE: u8 = 0x01;
+const FCR_CLEAR_RX: u8 = 0x02;
+
+const LCR_DLAB: u8 = 0x80;
+
+const MCR_LOOP: u8 = 0x10;
+
+const LSR_DR: u8 = 0x01;
+const LSR_THRE: u8 = 0x20;
+const LSR_TEMT: u8 = 0x40;
+
+/// Carrier detect, data set ready and clear to send, i.e. a terminal is attached.
+const MSR_CONNECTED: u8 = 0xB0;
+
+const FIFO_SIZE: usize = 16;
+
+/// How often the input is checked while the receive interrupt is enabled, in virtual time.
+const RX_POLL: Duration = Duration::from_micros(100);
+
+/// Where received characters come from.
+enum Input {
+    Stdin,
+    Channel(Receiver<u8>),
+}
+
+impl Input {
+    fn try_recv(&self) -> Option<u8> {
+        let received = match self {
+            Input::Stdin => stdin().lock().unwrap().try_recv(),
+            Input::Channel(rx) => rx.try_recv(),
+        };
+        received.ok()
+    }
+}
+
+/// Returns the characters read from stdin, which is read by a background thread started on
+/// first use and shared between all uarts.
+fn stdin() -> &'static Mutex<Receiver<u8>> {
+    static STDIN: OnceLock<Mutex<Receiver<u8>>> = OnceLock::new();
+
+    STDIN.get_or_init(|| {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for byte in io::stdin().lock().bytes() {
+                let Ok(byte) = byte else { break };
+                if tx.send(byte).is_err() {
+                    break;
+                }
+            }
+        });
+        Mutex::new(rx)
+    })
+}
+
+struct Registers {
+    rx_fifo: VecDeque<u8>,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+    /// Set when the transmitter becomes empty, cleared by reading IIR
+    thre_pending: bool,
+}
+
+/// NS16550A compatible uart with one byte wide registers, as found on most RISC-V boards.
+///
+/// Transmitted characters are written to the output immediately, so the transmitter is always
+/// empty and the baud rate configured through the divisor latch has no effect. Received
+/// characters are buffered in a 16 byte fifo, which is refilled from the input whenever the
+/// guest looks at the line status or receive buffer, and periodically while the receive
+/// interrupt is enabled. Interrupt identification and enabling
+/// follow the original chip, see [`interrupt_pending`](Self::interrupt_pending).
+pub struct Uart<A: Xlen + Unsigned> {
+    size: A,
+    input: Input,
+    output: RefCell<Box<dyn Write>>,
+    regs: RefCell<Registers>,
+    irq: Option<IrqLine>,
+    timer: Option<Timer>,
+}
+
+impl<A: Xlen + Unsigned> Uart<A> {
+    /// Creates a uart connected to stdin and stdout.
+    ///
+    /// Stdin is only read once the guest polls for input. For interactive use the terminal
+    /// should be switched to raw mode, otherwise characters are only received line by line.
+    pub fn new(size: A) -> Uart<A> {
+        Self::with_io(size, Input::Stdin, Box::new(io::stdout()))
+    }
+
+    /// Creates a uart receiving from `input` and transmitting to `output`.
+    pub fn with_channel(size: A, input: Receiver<u8>, output: impl Write + 'static) -> Uart<A> {
+        Self::with_io(size, Input::Channel(input), Box::new(output))
+    }
+
+    fn with_io(size: A, input: Input, output: Box<dyn Write>) -> Uart<A> {
+        Self {
+            size,
+            input,
+            output: RefCell::new(output),
+            regs: RefCell::new(Registers {
+                rx_fifo: VecDeque::with_capacity(FIFO_SIZE),
+                ier: 0,
+                fcr: 0,
+                lcr: 0,
+                mcr: 0,
+                scr: 0,
+                divisor: 0,
+                thre_pending: false,
+            }),
+            irq: None,
+            timer: None,
+        }
+    }
+
+    /// Connects the interrupt output to `irq`, which follows
+    /// [`interrupt_pending`](Self::interrupt_pending) after every register access and tick.
+    pub fn with_irq(mut self, irq: IrqLine) -> Uart<A> {
+        self.irq = Some(irq);
+        self
+    }
+
+    /// Returns whether an enabled interrupt condition is pending, i.e. the level of the
+    /// interrupt line.
+    pub fn interrupt_pending(&self) -> bool {
+        self.poll_input();
+        self.update_irq()
+    }
+
+    /// Drives the interrupt line from the current state, returns its level.
+    fn update_irq(&self) -> bool {
+        let level = self.interrupt_id(&self.regs.borrow()) != IIR_NO_INT;
+        if let Some(irq) = &self.irq {
+            irq.set(level);
+        }
+
+        level
+    }
+
+    /// Moves received characters into the fifo while there is room, unless in loopback mode.
+    fn poll_input(&self) {
+        let mut regs = self.regs.borrow_mut();
+        if regs.mcr & MCR_LOOP != 0 {
+            return;
+        }
+
+        while regs.rx_fifo.len() < FIFO_SIZE {
+            match self.input.try_recv() {
+                Some(byte) => regs.rx_fifo.push_back(byte),
+                None => break,
+            }
+        }
+    }
+
+    /// Returns the highest priority pending interrupt as encoded in IIR.
+    fn interrupt_id(&self, regs: &Registers) -> u8 {
+        if regs.ier & IER_RDI != 0 && !regs.rx_fifo.is_empty() {
+            IIR_RDI
+        } else if regs.ier & IER_THRI != 0 && regs.thre_pending {
+            IIR_THRI
+        } else {
+            IIR_NO_INT
+        }
+    }
+
+    fn transmit(&self, regs: &mut Registers, byte: u8) {
+        if regs.mcr & MCR_LOOP != 0 {
+            if regs.rx_fifo.len() < FIFO_SIZE {
+                regs.rx_fifo.push_back(byte);
+            }
+        } else {
+            // the output is best effort, the guest can not do anything about errors
+            let mut output = self.output.borrow_mut();
+            let _ = output.write_all(&[byte]);
+            let _ = output.flush();
+        }
+        regs.thre_pending = true;
+    }
+
+    fn read(&self, addr: A) -> u8 {
+        let reg = addr.as_t::<usize>();
+        if matches!(reg, RBR_THR_DLL | LSR) {
+            self.poll_input();
+        }
+
+        let mut regs = self.regs.borrow_mut();
+        let dlab = regs.lcr & LCR_DLAB != 0;
+        match reg {
+            RBR_THR_DLL if dlab => regs.divisor as u8,
+            RBR_THR_DLL => regs.rx_fifo.pop_front().unwrap_or(0),
+            IER_DLM if dlab => (regs.divisor >> 8) as u8,
+            IER_DLM => regs.ier,
+            IIR_FCR => {
+                let id = self.interrupt_id(&regs);
+                // reading the identification acknowledges the transmitter interrupt
+                if id == IIR_THRI {
+                    regs.thre_pending = false;
+                }
+                let fifo = if regs.fcr & FCR_FIFO_ENABLE != 0 {
+                    IIR_FIFO_ENABLED
+                } else {
+                    0
+                };
+                fifo | id
+            }
+            LCR => regs.lcr,
+            MCR => regs.mcr,
+            LSR => {
+                let ready = if regs.rx_fifo.is_empty() { 0 } else { LSR_DR };
+                ready | LSR_THRE | LSR_TEMT
+            }
+            // in loopback mode the modem outputs are connected to the inputs
+            MSR if regs.mcr & MCR_LOOP != 0 => {
+                let mcr = regs.mcr;
+                (mcr & 0x01) << 5 | (mcr & 0x02) << 3 | (mcr & 0x0C) << 4
+            }
+            MSR => MSR_CONNECTED,
+            SCR => regs.scr,
+            _ => 0,
+        }
+    }
+
+    /// Schedules the next check for input while the receive interrupt is enabled.
+    fn schedule_poll(&self, regs: &Registers) {
+        if let Some(timer) = &self.timer {
+            if regs.ier & IER_RDI == 0 {
+                timer.cancel();
+            } else if timer.scheduled().is_none() {
+                timer.schedule_after(RX_POLL);
+            }
+        }
+    }
+
+    fn write(&mut self, addr: A, value: u8) {
+        let regs = &mut *self.regs.borrow_mut();
+        let dlab = regs.lcr & LCR_DLAB != 0;
+        match addr.as_t::<usize>() {
+            RBR_THR_DLL if dlab => regs.divisor = regs.divisor & 0xFF00 | value as u16,
+            RBR_THR_DLL => self.transmit(regs, value),
+            IER_DLM if dlab => regs.divisor = regs.divisor & 0x00FF | (value as u16) << 8,
+            IER_DLM => {
+                // enabling the transmitter interrupt raises it right away, it is always empty
+                if value & IER_THRI != 0 && regs.ier & IER_THRI == 0 {
+                    regs.thre_pending = true;
+                }
+                regs.ier = value & 0x0F;
+                self.schedule_poll(regs);
+            }
+            IIR_FCR => {
+                if value & FCR_CLEAR_RX != 0 {
+                    regs.rx_fifo.clear();
+                }
+                regs.fcr = value;
+            }
+            LCR => regs.lcr = value,
+            MCR => regs.mcr = value & 0x1F,
+            SCR => regs.scr = value,
+            // LSR and MSR are read-only
+            _ => {}
+        }
+    }
+}
+
+impl<A: Xlen + Unsigned> Device<A> for Uart<A> {
+    /// Receives pending input so the guest gets an interrupt without polling. Stdin is left
+    /// alone until the guest enables the receive interrupt.
+    fn tick(&mut self, _now: u64, _bus: &mut dyn Memory<A>) {
+        self.poll_input();
+        self.update_irq();
+        self.schedule_poll(&self.regs.borrow());
+    }
+
+    fn set_timer(&mut self, timer: Timer) {
+        self.timer = Some(timer);
+        self.schedule_poll(&self.regs.borrow());
+    }
+
+    /// Only restarts polling, the registers keep their values.
+    fn reset(&mut self) {
+        self.schedule_poll(&self.regs.borrow());
+    }
+}
+
+impl<A: Xlen + Unsigned> Memory<A> for Uart<A> {
+    fn size(&self) -> A {
+        self.size
+    }
+
+    impl_memory!(
+        self,
+        addr,
+        value,
+        {
+            let value = self.read(addr);
+            self.update_irq();
+            Ok(value as _)
+        },
+        {
+            self.write(addr, value as u8);
+            self.update_irq();
+            Ok(())
+        }
+    );
+
+    fn get_data(&self, range: Range<A>) -> Result<Vec<u8>, CPUError<A>> {
+        // dumping memory must not consume received characters
+        Ok(vec![0; (range.end - range.start).as_t::<usize>()])
+    }
+
+    fn as_device(&mut self) -> Option<&mut dyn Device<A>> {
+        Some(self)
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::cpu::isa::Xlen;
+use crate::cpu::CPUError;
+use crate::devices::virtio::{Chain, Queue, VirtioDevice, DEVICE_ID_BLOCK};
+use crate::memory::Memory;
+
+const SECTOR_SIZE: usize = 512;
+
+// feature bits
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+// request types
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+const T_GET_ID: u32 = 8;
+
+// request status
+const S_OK: u8 = 0;
+const S_IOERR: u8 = 1;
+const S_UNSUPP: u8 = 2;
+
+/// Size of the header of a request: type, reserved and sector.
+const HEADER_SIZE: usize = 16;
+/// Returned for `T_GET_ID`, zero padded to the size of the id.
+const SERIAL: &[u8] = b"risc-v-emulator";
+const ID_SIZE: usize = 20;
+
+/// How writes of the guest reach the image.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DiskMode {
+    /// Writes go to the image.
+    ReadWrite,
+    /// The guest sees a read-only disk.
+    ReadOnly,
+    /// Writes are kept in memory on top of the image, which stays unmodified.
+    CopyOnWrite,
+}
+
+/// Virtio block device backed by a host image file, whose size is rounded down to whole
+/// sectors.
+pub struct VirtioBlk {
+    image: File,
+    mode: DiskMode,
+    /// Number of 512 byte sectors
+    capacity: u64,
+    /// Sectors written in [`DiskMode::CopyOnWrite`]
+    overlay: HashMap<u64, Box<[u8; SECTOR_SIZE]>>,
+}
+
+impl VirtioBlk {
+    /// Opens the image at `path`, which only has to be writable in [`DiskMode::ReadWrite`].
+    pub fn open(path: impl AsRef<Path>, mode: DiskMode) -> io::Result<VirtioBlk> {
+        let image = OpenOptions::new()
+            .read(true)
+            .write(mode == DiskMode::ReadWrite)
+            .open(path)?;
+        let capacity = image.metadata()?.len() / SECTOR_SIZE as u64;
+
+        Ok(Self {
+            image,
+            mode,
+            capacity,
+            overlay: HashMap::new(),
+        })
+    }
+
+    /// Number of 512 byte sectors.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Returns whether `len` bytes at `sector` are within the disk.
+    fn in_range(&self, sector: u64, len: usize) -> bool {
+        let sectors = len.div_ceil(SECTOR_SIZE) as u64;
+        sector
+            .checked_add(sectors)
+            .is_some_and(|end| end <= self.capacity)
+    }
+
+    fn read_sectors(&mut self, sector: u64, data: &mut [u8]) -> io::Result<()> {
+        self.image
+            .seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+        self.image.read_exact(data)?;
+
+        for (i, chunk) in data.chunks_mut(SECTOR_SIZE).enumerate() {
+            if let Some(written) = self.overlay.get(&(sector + i as u64)) {
+                chunk.copy_from_slice(&written[..chunk.len()]);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        match self.mode {
+            DiskMode::ReadWrite => {
+                self.image
+                    .seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+                self.image.write_all(data)
+            }
+            DiskMode::ReadOnly => Err(io::ErrorKind::PermissionDenied.into()),
+            DiskMode::CopyOnWrite => {
+                for (i, chunk) in data.chunks(SECTOR_SIZE).enumerate() {
+                    let sector = sector + i as u64;
+                    // partial sectors keep the rest of their old contents
+                    if chunk.len() < SECTOR_SIZE && !self.overlay.contains_key(&sector) {
+                        let mut old = Box::new([0; SECTOR_SIZE]);
+                        self.read_sectors(sector, &mut old[..])?;
+                        self.overlay.insert(sector, old);
+                    }
+                    let entry = self
+                        .overlay
+                        .entry(sector)
+                        .or_insert_with(|| Box::new([0; SECTOR_SIZE]));
+                    entry[..chunk.len()].copy_from_slice(chunk);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Executes the request in `chain`, returns the number of bytes written to its buffers.
+    ///
+    /// Only the data of the reply and the status are written, the buffers the guest hands in
+    /// may be far larger than the reply.
+    fn handle<A: Xlen>(
+        &mut self,
+        chain: &Chain,
+        mem: &mut dyn Memory<A>,
+    ) -> Result<u32, CPUError<A>> {
+        let request = chain.read(&*mem)?;
+        let writable = chain.writable_len();
+        // without room for the status the request can not be answered
+        let (Some(header), Some(data_len)) = (request.get(..HEADER_SIZE), writable.checked_sub(1))
+        else {
+            return Ok(0);
+        };
+        let kind = u32::from_le_bytes(header[0..4].try_into().expect("4 bytes"));
+        let sector = u64::from_le_bytes(header[8..16].try_into().expect("8 bytes"));
+
+        let mut reply = Vec::new();
+        let status = match kind {
+            T_IN if self.in_range(sector, data_len) => {
+                reply.resize(data_len, 0);
+                match self.read_sectors(sector, &mut reply) {
+                    Ok(()) => S_OK,
+                    Err(_) => S_IOERR,
+                }
+            }
+            T_OUT if self.in_range(sector, request.len() - HEADER_SIZE) => {
+                match self.write_sectors(sector, &request[HEADER_SIZE..]) {
+                    Ok(()) => S_OK,
+                    Err(_) => S_IOERR,
+                }
+            }
+            T_IN | T_OUT => S_IOERR,
+            // nothing is buffered unless the image is written
+            T_FLUSH if self.mode != DiskMode::ReadWrite => S_OK,
+            T_FLUSH => match self.image.sync_data() {
+                Ok(()) => S_OK,
+                Err(_) => S_IOERR,
+            },
+            T_GET_ID => {
+                reply.extend_from_slice(SERIAL);
+                reply.resize(ID_SIZE.min(data_len), 0);
+                S_OK
+            }
+            _ => S_UNSUPP,
+        };
+
+        // the status is the last byte of the writable buffers
+        let written = chain.write(mem, &reply)?;
+        let written = written + chain.write_at(mem, data_len, &[status])?;
+
+        Ok(written)
+    }
+}
+
+impl<A: Xlen> VirtioDevice<A> for VirtioBlk {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID_BLOCK
+    }
+
+    fn features(&self) -> u64 {
+        match self.mode {
+            DiskMode::ReadOnly => F_RO | F_FLUSH,
+            _ => F_FLUSH,
+        }
+    }
+
+    fn queues(&self) -> usize {
+        1
+    }
+
+    /// Only the capacity in sectors, the first field of the configuration.
+    fn read_config(&self, offset: usize) -> u8 {
+        self.capacity
+            .to_le_bytes()
+            .get(offset)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn notify(
+        &mut self,
+        _index: usize,
+        queue: &mut Queue,
+        mem: &mut dyn Memory<A>,
+    ) -> Result<(), CPUError<A>> {
+        while let Some(chain) = queue.pop(&*mem)? {
+            let len = self.handle(&chain, mem)?;
+            queue.push(mem, chain.head, len)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+use crate::cpu::isa::Xlen;
+use crate::cpu::CPUError;
+use crate::devices::virtio::{Queue, VirtioDevice, DEVICE_ID_CONSOLE};
+use crate::memory::Memory;
+
+// feature bits
+const F_MULTIPORT: u64 = 1 << 1;
+
+// queues of the control channel, the ports use the others in pairs of receive and transmit
+const CONTROL_RX: usize = 2;
+const CONTROL_TX: usize = 3;
+
+// control events
+const DEVICE_READY: u16 = 0;
+const DEVICE_ADD: u16 = 1;
+const PORT_READY: u16 = 3;
+const CONSOLE_PORT: u16 = 4;
+const PORT_OPEN: u16 = 6;
+const PORT_NAME: u16 = 7;
+
+/// Same interval as the receiver of the uart.
+const RX_POLL: Duration = Duration::from_micros(100);
+
+struct Port {
+    /// Empty for the console, which is `hvc0` on Linux
+    name: String,
+    input: Receiver<u8>,
+    output: Box<dyn Write>,
+    /// Input taken from the channel while the driver had no buffer for it
+    pending: VecDeque<u8>,
+}
+
+/// Virtio console with any number of character streams, the ports. Port 0 may be a console,
+/// the others are named and show up as `/dev/vport*` on Linux, with their name in sysfs.
+///
+/// Ports are announced to the driver over the control queues of the multiport feature, so
+/// drivers without it only see the first port.
+#[derive(Default)]
+pub struct VirtioConsole {
+    ports: Vec<Port>,
+    /// Control messages for the driver, waiting for a buffer
+    control: VecDeque<Vec<u8>>,
+}
+
+impl VirtioConsole {
+    pub fn new() -> VirtioConsole {
+        Self::default()
+    }
+
+    /// Adds a console receiving from `input` and transmitting to `output`, which has to come
+    /// before any other port.
+    pub fn with_console(self, input: Receiver<u8>, output: impl Write + 'static) -> VirtioConsole {
+        assert!(
+            self.ports.is_empty(),
+            "The console has to be the first port!"
+        );
+        self.with_port(String::new(), input, output)
+    }
+
+    /// Adds a port called `name`.
+    pub fn with_port(
+        mut self,
+        name: impl Into<String>,
+        input: Receiver<u8>,
+        output: impl Write + 'static,
+    ) -> VirtioConsole {
+        self.ports.push(Port {
+            name: name.into(),
+            input,
+            output: Box::new(output),
+            pending: VecDeque::new(),
+        });
+        self
+    }
+
+    /// Returns the port using queue `index`, and whether the queue receives.
+    fn port(index: usize) -> Option<(usize, bool)> {
+        match index {
+            0 | 1 => Some((0, index == 0)),
+            CONTROL_RX | CONTROL_TX => None,
+            _ => Some((index / 2 - 1, index.is_multiple_of(2))),
+        }
+    }
+
+    fn send_control(&mut self, id: usize, event: u16, value: u16, data: &[u8]) {
+        let mut message = Vec::with_capacity(8 + data.len());
+        message.extend((id as u32).to_le_bytes());
+        message.extend(event.to_le_bytes());
+        message.extend(value.to_le_bytes());
+        message.extend(data);
+        self.control.push_back(message);
+    }
+
+    /// Answers the control messages of the driver.
+    fn handle_control(&mut self, message: &[u8]) {
+        let Some(message) = message.get(..8) else {
+            return;
+        };
+        let id = u32::from_le_bytes(message[0..4].try_into().expect("4 bytes")) as usize;
+        let event = u16::from_le_bytes(message[4..6].try_into().expect("2 bytes"));
+        let value = u16::from_le_bytes(message[6..8].try_into().expect("2 bytes"));
+
+        match event {
+            DEVICE_READY if value == 1 => {
+                for id in 0..self.ports.len() {
+                    self.send_control(id, DEVICE_ADD, 0, &[]);
+                }
+            }
+            PORT_READY if value == 1 && id < self.ports.len() => {
+                let name = self.ports[id].name.clone();
+                if name.is_empty() {
+                    self.send_control(id, CONSOLE_PORT, 1, &[]);
+                } else {
+                    self.send_control(id, PORT_NAME, 1, name.as_bytes());
+                }
+                // the host side of every port is always connected
+                self.send_control(id, PORT_OPEN, 1, &[]);
+            }
+            // whether the guest has a port open does not matter, output is written anyway
+            _ => {}
+        }
+    }
+
+    /// Moves control messages to the buffers of the driver, until either runs out.
+    fn flush_control<A: Xlen>(
+        &mut self,
+        queue: &mut Queue,
+        mem: &mut dyn Memory<A>,
+    ) -> Result<(), CPUError<A>> {
+        while !self.control.is_empty() {
+            let Some(chain) = queue.pop(&*mem)? else {
+                break;
+            };
+            let message = self.control.pop_front().expect("not empty");
+            let len = chain.write(mem, &message)?;
+            queue.push(mem, chain.head, len)?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves the input of port `id` to the buffers of its receive queue.
+    fn receive<A: Xlen>(
+        &mut self,
+        id: usize,
+        queue: &mut Queue,
+        mem: &mut dyn Memory<A>,
+    ) -> Result<(), CPUError<A>> {
+        let port = &mut self.ports[id];
+        port.pending.extend(port.input.try_iter());
+        while !port.pending.is_empty() {
+            let Some(chain) = queue.pop(&*mem)? else {
+                break;
+            };
+            let len = chain.writable_len().min(port.pending.len());
+            let data: Vec<_> = port.pending.drain(..len).collect();
+            let len = chain.write(mem, &data)?;
+            queue.push(mem, chain.head, len)?;
+        }
+
+        Ok(())
+    }
+
+    fn transmit<A: Xlen>(
+        &mut self,
+        id: usize,
+        queue: &mut Queue,
+        mem: &mut dyn Memory<A>,
+    ) -> Result<(), CPUError<A>> {
+        while let Some(chain) = queue.pop(&*mem)? {
+            let data = chain.read(&*mem)?;
+            // the output is best effort, the guest can not do anything about errors
+            let output = &mut self.ports[id].output;
+            let _ = output.write_all(&data).and_then(|_| output.flush());
+            queue.push(mem, chain.head, 0)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<A: Xlen> VirtioDevice<A> for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID_CONSOLE
+    }
+
+    fn features(&self) -> u64 {
+        F_MULTIPORT
+    }
+
+    /// The queues of port 0, the control queues and those of the other ports.
+    fn queues(&self) -> usize {
+        2 * (self.ports.len().max(1) + 1)
+    }
+
+    /// The size of the console, which is not used, followed by the number of ports.
+    fn read_config(&self, offset: usize) -> u8 {
+        let ports = (self.ports.len() as u32).to_le_bytes();
+        match offset {
+            4..=7 => ports[offset - 4],
+            _ => 0,
+        }
+    }
+
+    fn notify(
+        &mut self,
+        index: usize,
+        queue: &mut Queue,
+        mem: &mut dyn Memory<A>,
+    ) -> Result<(), CPUError<A>> {
+        match Self::port(index) {
+            Some((id, _)) if id >= self.ports.len() => Ok(()),
+            Some((id, true)) => self.receive(id, queue, mem),
+            Some((id, false)) => self.transmit(id, queue, mem),
+            None if index == CONTROL_TX => {
+                while let Some(chain) = queue.pop(&*mem)? {
+                    let message = chain.read(&*mem)?;
+                    self.handle_control(&message);
+                    queue.push(mem, chain.head, 0)?;
+                }
+                // the answers are sent when the device is polled
+                Ok(())
+            }
+            None => self.flush_control(queue, mem),
+        }
+    }
+
+    fn poll_interval(&self) -> Option<Duration> {
+        Some(RX_POLL)
+    }
+
+    fn poll(&mut self, queues: &mut [Queue], mem: &mut dyn Memory<A>) -> Result<(), CPUError<A>> {
+        self.flush_control(&mut queues[CONTROL_RX], mem)?;
+        for id in 0..self.ports.len() {
+            let index = if id == 0 { 0 } else { 2 * (id + 1) };
+            self.receive(id, &mut queues[index], mem)?;
+        }
+
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.control.clear();
+        for port in &mut self.ports {
+            port.pending.clear();
+        }
+    }
+}
@@ -0,0 +1,320 @@
+use std::ops::Range;
+
+use num_traits::Unsigned;
+
+use crate::cpu::isa::{As, Xlen};
+use crate::cpu::CPUError;
+use crate::devices::virtio::{Queue, VirtioDevice};
+use crate::devices::{Device, IrqLine, Timer};
+use crate::memory::{impl_memory, Access, Memory};
+
+// register offsets, all registers are 32 bits wide
+const MAGIC_VALUE: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const VENDOR_ID: usize = 0x00C;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0A0;
+const QUEUE_DEVICE_HIGH: usize = 0x0A4;
+const CONFIG_GENERATION: usize = 0x0FC;
+const CONFIG: usize = 0x100;
+
+/// "virt" in little endian
+const MAGIC: u32 = 0x7472_6976;
+/// "QEMU", drivers do not care but some match on it
+const VENDOR: u32 = 0x554D_4551;
+
+const QUEUE_SIZE_MAX: u16 = 256;
+
+/// The device is operating in the mode of virtio 1.0 and later, required for version 2 of
+/// the transport.
+const F_VERSION_1: u64 = 1 << 32;
+
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_NEEDS_RESET: u32 = 0x40;
+
+const INTERRUPT_USED_BUFFER: u32 = 1;
+const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+/// Virtio over mmio, version 2 of the transport as found on qemu's virt machine.
+///
+/// Notifications from the driver are handled the next time the bus ticks the device, which is
+/// when the buffers in guest memory are accessed. Devices with host input are polled from the
+/// same tick. If the driver sets up a queue the device can not use, the device stops and
+/// reports that it needs a reset.
+pub struct VirtioMmio<A: Xlen + Unsigned> {
+    size: A,
+    device: Box<dyn VirtioDevice<A>>,
+    irq: IrqLine,
+    timer: Option<Timer>,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    queues: Vec<Queue>,
+    /// Queues the driver has notified since the last tick
+    notified: Vec<bool>,
+    interrupt_status: u32,
+    status: u32,
+}
+
+impl<A: Xlen + Unsigned> VirtioMmio<A> {
+    pub fn new(size: A, device: Box<dyn VirtioDevice<A>>, irq: IrqLine) -> VirtioMmio<A> {
+        let queues = device.queues();
+        Self {
+            size,
+            device,
+            irq,
+            timer: None,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            queues: vec![Queue::default(); queues],
+            notified: vec![false; queues],
+            interrupt_status: 0,
+            status: 0,
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        self.device.features() | F_VERSION_1
+    }
+
+    fn queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn interrupt(&mut self, cause: u32) {
+        self.interrupt_status |= cause;
+        self.irq.raise();
+    }
+
+    fn reset_transport(&mut self) {
+        self.device.reset();
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.queue_sel = 0;
+        self.queues.fill(Queue::default());
+        self.notified.fill(false);
+        self.interrupt_status = 0;
+        self.status = 0;
+        self.irq.lower();
+        if let Some(timer) = &self.timer {
+            timer.cancel();
+        }
+    }
+
+    fn read_register(&self, offset: usize) -> u32 {
+        let half = |value: u64, sel: u32| match sel {
+            0 => value as u32,
+            1 => (value >> 32) as u32,
+            _ => 0,
+        };
+        let queue = self.queues.get(self.queue_sel as usize);
+
+        match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION => 2,
+            DEVICE_ID => self.device.device_id(),
+            VENDOR_ID => VENDOR,
+            DEVICE_FEATURES => half(self.device_features(), self.device_features_sel),
+            QUEUE_NUM_MAX => queue.map_or(0, |_| QUEUE_SIZE_MAX as u32),
+            QUEUE_NUM => queue.map_or(0, |queue| queue.size as u32),
+            QUEUE_READY => queue.map_or(0, |queue| queue.ready as u32),
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            QUEUE_DESC_LOW => queue.map_or(0, |queue| queue.desc as u32),
+            QUEUE_DESC_HIGH => queue.map_or(0, |queue| (queue.desc >> 32) as u32),
+            QUEUE_DRIVER_LOW => queue.map_or(0, |queue| queue.avail as u32),
+            QUEUE_DRIVER_HIGH => queue.map_or(0, |queue| (queue.avail >> 32) as u32),
+            QUEUE_DEVICE_LOW => queue.map_or(0, |queue| queue.used as u32),
+            QUEUE_DEVICE_HIGH => queue.map_or(0, |queue| (queue.used >> 32) as u32),
+            // the configuration of the devices never changes
+            CONFIG_GENERATION => 0,
+            // write-only and reserved registers
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: usize, value: u32) {
+        let set_low = |field: &mut u64| *field = *field & !0xFFFF_FFFF | value as u64;
+        let set_high = |field: &mut u64| *field = *field & 0xFFFF_FFFF | (value as u64) << 32;
+
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            DRIVER_FEATURES => {
+                let shift = 32 * self.driver_features_sel as u64;
+                if shift < 64 {
+                    self.driver_features &= !(0xFFFF_FFFF << shift);
+                    self.driver_features |= (value as u64) << shift & self.device_features();
+                }
+            }
+            DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            QUEUE_SEL => self.queue_sel = value,
+            QUEUE_NUM => {
+                // the split ring requires a power of two
+                if let Some(queue) = self.queue() {
+                    if value.is_power_of_two() && value <= QUEUE_SIZE_MAX as u32 {
+                        queue.size = value as u16;
+                    }
+                }
+            }
+            QUEUE_READY => {
+                if let Some(queue) = self.queue() {
+                    queue.ready = value & 1 != 0;
+                }
+            }
+            QUEUE_NOTIFY => {
+                if let Some(notified) = self.notified.get_mut(value as usize) {
+                    *notified = true;
+                    if let Some(timer) = &self.timer {
+                        timer.schedule_in(0);
+                    }
+                }
+            }
+            INTERRUPT_ACK => {
+                self.interrupt_status &= !value;
+                if self.interrupt_status == 0 {
+                    self.irq.lower();
+                }
+            }
+            STATUS if value == 0 => self.reset_transport(),
+            STATUS => {
+                self.status = value;
+                // starts polling, if the device does
+                if value & STATUS_DRIVER_OK != 0 {
+                    if let Some(timer) = &self.timer {
+                        timer.schedule_in(0);
+                    }
+                }
+            }
+            QUEUE_DESC_LOW => self.queue().into_iter().for_each(|q| set_low(&mut q.desc)),
+            QUEUE_DESC_HIGH => self.queue().into_iter().for_each(|q| set_high(&mut q.desc)),
+            QUEUE_DRIVER_LOW => self.queue().into_iter().for_each(|q| set_low(&mut q.avail)),
+            QUEUE_DRIVER_HIGH => self
+                .queue()
+                .into_iter()
+                .for_each(|q| set_high(&mut q.avail)),
+            QUEUE_DEVICE_LOW => self.queue().into_iter().for_each(|q| set_low(&mut q.used)),
+            QUEUE_DEVICE_HIGH => self.queue().into_iter().for_each(|q| set_high(&mut q.used)),
+            // read-only and reserved registers
+            _ => {}
+        }
+    }
+
+    fn read<T: Access>(&self, addr: A) -> Result<T, CPUError<A>> {
+        let offset = addr.as_t::<usize>();
+        if offset >= CONFIG {
+            let bytes: Vec<_> = (offset - CONFIG..offset - CONFIG + T::SIZE)
+                .map(|offset| self.device.read_config(offset))
+                .collect();
+            return Ok(T::from_le_bytes(&bytes));
+        }
+
+        if T::SIZE != 4 || offset % 4 != 0 {
+            return Err(CPUError::LoadAccessFault(addr));
+        }
+        Ok(T::from_le_bytes(&self.read_register(offset).to_le_bytes()))
+    }
+
+    fn write<T: Access>(&mut self, addr: A, value: T) -> Result<(), CPUError<A>> {
+        let offset = addr.as_t::<usize>();
+        let mut bytes = [0; 16];
+        value.write_le_bytes(&mut bytes[..T::SIZE]);
+        if offset >= CONFIG {
+            for (i, byte) in bytes[..T::SIZE].iter().enumerate() {
+                self.device.write_config(offset - CONFIG + i, *byte);
+            }
+            return Ok(());
+        }
+
+        if T::SIZE != 4 || offset % 4 != 0 {
+            return Err(CPUError::StoreAccessFault(addr));
+        }
+        self.write_register(
+            offset,
+            u32::from_le_bytes(bytes[..4].try_into().expect("4 bytes")),
+        );
+
+        Ok(())
+    }
+}
+
+impl<A: Xlen + Unsigned> Device<A> for VirtioMmio<A> {
+    /// Hands the notified queues to the device and polls it.
+    fn tick(&mut self, _now: u64, bus: &mut dyn Memory<A>) {
+        if self.status & STATUS_DRIVER_OK == 0 || self.status & STATUS_NEEDS_RESET != 0 {
+            return;
+        }
+
+        let mut result = Ok(());
+        for index in 0..self.queues.len() {
+            if std::mem::take(&mut self.notified[index]) {
+                result =
+                    result.and_then(|_| self.device.notify(index, &mut self.queues[index], bus));
+            }
+        }
+        if let Some(interval) = self.device.poll_interval() {
+            result = result.and_then(|_| self.device.poll(&mut self.queues, bus));
+            if let Some(timer) = &self.timer {
+                timer.schedule_after(interval);
+            }
+        }
+
+        if result.is_err() {
+            self.status |= STATUS_NEEDS_RESET;
+            self.interrupt(INTERRUPT_CONFIG_CHANGE);
+            return;
+        }
+        let used = self
+            .queues
+            .iter_mut()
+            .fold(false, |used, queue| queue.take_interrupt() | used);
+        if used {
+            self.interrupt(INTERRUPT_USED_BUFFER);
+        }
+    }
+
+    fn set_timer(&mut self, timer: Timer) {
+        self.timer = Some(timer);
+    }
+
+    fn reset(&mut self) {
+        self.reset_transport();
+    }
+}
+
+impl<A: Xlen + Unsigned> Memory<A> for VirtioMmio<A> {
+    fn size(&self) -> A {
+        self.size
+    }
+
+    impl_memory!(self, addr, value, { self.read(addr) }, {
+        self.write(addr, value)
+    });
+
+    fn get_data(&self, range: Range<A>) -> Result<Vec<u8>, CPUError<A>> {
+        Ok(vec![0; (range.end - range.start).as_t::<usize>()])
+    }
+
+    fn as_device(&mut self) -> Option<&mut dyn Device<A>> {
+        Some(self)
+    }
+}
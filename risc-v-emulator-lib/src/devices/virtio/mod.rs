@@ -0,0 +1,74 @@
+//! Virtio devices on the mmio transport, following version 1.2 of the specification.
+
+use std::time::Duration;
+
+pub use blk::{DiskMode, VirtioBlk};
+pub use console::VirtioConsole;
+pub use mmio::VirtioMmio;
+#[cfg(unix)]
+pub use net::UnixSocket;
+pub use net::{Loopback, NetBackend, Pcap, VirtioNet};
+#[cfg(unix)]
+pub use p9::Virtio9p;
+pub use queue::{Chain, Descriptor, Queue};
+pub use rng::VirtioRng;
+
+use crate::cpu::CPUError;
+use crate::memory::Memory;
+
+mod blk;
+mod console;
+mod mmio;
+mod net;
+#[cfg(unix)]
+mod p9;
+mod queue;
+mod rng;
+
+/// Device types, as reported in the DeviceID register.
+pub const DEVICE_ID_NET: u32 = 1;
+pub const DEVICE_ID_BLOCK: u32 = 2;
+pub const DEVICE_ID_CONSOLE: u32 = 3;
+pub const DEVICE_ID_RNG: u32 = 4;
+pub const DEVICE_ID_9P: u32 = 9;
+
+/// A device behind a [`VirtioMmio`] transport, which handles feature negotiation and the setup
+/// of the queues.
+pub trait VirtioDevice<A> {
+    fn device_id(&self) -> u32;
+
+    /// Device-specific feature bits, the transport adds the ones it implements.
+    fn features(&self) -> u64;
+
+    /// Number of virtqueues.
+    fn queues(&self) -> usize;
+
+    /// Reads a byte of the device-specific configuration space.
+    fn read_config(&self, offset: usize) -> u8;
+
+    /// Writes a byte of the device-specific configuration space, ignored by default.
+    fn write_config(&mut self, _offset: usize, _value: u8) {}
+
+    /// Handles the requests the driver made available on queue `index`. `mem` is the bus the
+    /// transport is mapped on, the buffers are in guest memory.
+    fn notify(
+        &mut self,
+        index: usize,
+        queue: &mut Queue,
+        mem: &mut dyn Memory<A>,
+    ) -> Result<(), CPUError<A>>;
+
+    /// Interval of virtual time between calls to [`poll`](Self::poll) while the driver is
+    /// ready, for devices receiving input from the host. `None`, i.e. no polling, by default.
+    fn poll_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Fills the queues with the input available on the host.
+    fn poll(&mut self, _queues: &mut [Queue], _mem: &mut dyn Memory<A>) -> Result<(), CPUError<A>> {
+        Ok(())
+    }
+
+    /// The driver reset the device.
+    fn reset(&mut self) {}
+}
@@ -0,0 +1,351 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+
+use crate::cpu::isa::Xlen;
+use crate::cpu::CPUError;
+use crate::devices::virtio::{Queue, VirtioDevice, DEVICE_ID_NET};
+use crate::memory::Memory;
+
+// feature bits
+const F_MAC: u64 = 1 << 5;
+const F_STATUS: u64 = 1 << 16;
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+
+/// Size of the header in front of every frame, including the number of buffers.
+const HEADER_SIZE: usize = 12;
+const STATUS_LINK_UP: u16 = 1;
+
+/// Same interval as the receiver of the uart.
+const RX_POLL: Duration = Duration::from_micros(100);
+
+/// Largest frame a backend has to receive, including the header of a VLAN tag.
+const MAX_FRAME_SIZE: usize = 1522;
+
+/// The network a [`VirtioNet`] sends its ethernet frames to, without the virtio header.
+///
+/// Like a real network, backends may drop frames they can not deliver.
+pub trait NetBackend {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()>;
+
+    /// Returns the next received frame without blocking, if any.
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>>;
+}
+
+impl<B: NetBackend + ?Sized> NetBackend for Box<B> {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        (**self).send(frame)
+    }
+
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        (**self).recv()
+    }
+}
+
+#[derive(Default)]
+struct Frames {
+    sent: Vec<Vec<u8>>,
+    received: VecDeque<Vec<u8>>,
+    echo: bool,
+}
+
+/// In-process network for tests, recording the frames the guest sends and delivering the
+/// frames injected by the host. Clones share the same network.
+#[derive(Clone, Default)]
+pub struct Loopback {
+    frames: Rc<RefCell<Frames>>,
+}
+
+impl Loopback {
+    pub fn new() -> Loopback {
+        Self::default()
+    }
+
+    /// Also delivers every frame sent by the guest back to it.
+    pub fn with_echo(self) -> Loopback {
+        self.frames.borrow_mut().echo = true;
+        self
+    }
+
+    /// Queues `frame` to be received by the guest.
+    pub fn inject(&self, frame: &[u8]) {
+        self.frames.borrow_mut().received.push_back(frame.to_vec());
+    }
+
+    /// Returns the frames sent by the guest since the last call.
+    pub fn take_sent(&self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.frames.borrow_mut().sent)
+    }
+}
+
+impl NetBackend for Loopback {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        let mut frames = self.frames.borrow_mut();
+        if frames.echo {
+            frames.received.push_back(frame.to_vec());
+        }
+        frames.sent.push(frame.to_vec());
+        Ok(())
+    }
+
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.frames.borrow_mut().received.pop_front())
+    }
+}
+
+/// Connects two emulators through datagram sockets, one frame per datagram. Each side binds
+/// its own socket and sends to the socket of its peer, so either may be started first.
+#[cfg(unix)]
+pub struct UnixSocket {
+    socket: std::os::unix::net::UnixDatagram,
+    peer: std::path::PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixSocket {
+    /// Binds the socket at `path`, replacing a stale one, and sends to the socket at `peer`.
+    /// Anything else at `path` is left alone and makes binding fail.
+    pub fn bind(
+        path: impl AsRef<std::path::Path>,
+        peer: impl Into<std::path::PathBuf>,
+    ) -> io::Result<UnixSocket> {
+        use std::os::unix::fs::FileTypeExt;
+
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(&path)?,
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "Not replacing a file that is not a socket",
+                ))
+            }
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            Err(_) => {}
+        }
+        let socket = std::os::unix::net::UnixDatagram::bind(path)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            socket,
+            peer: peer.into(),
+        })
+    }
+}
+
+#[cfg(unix)]
+impl NetBackend for UnixSocket {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        match self.socket.send_to(frame, &self.peer) {
+            // the peer is not running (yet) or can not keep up
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound
+                        | io::ErrorKind::ConnectionRefused
+                        | io::ErrorKind::WouldBlock
+                ) =>
+            {
+                Ok(())
+            }
+            result => result.map(|_| ()),
+        }
+    }
+
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut frame = vec![0; MAX_FRAME_SIZE];
+        match self.socket.recv(&mut frame) {
+            Ok(len) => {
+                frame.truncate(len);
+                Ok(Some(frame))
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Writes the frames passing through a backend, in both directions, to a capture in pcap
+/// format, which tools like wireshark and tcpdump read.
+pub struct Pcap<B> {
+    backend: B,
+    capture: Box<dyn Write>,
+}
+
+impl<B: NetBackend> Pcap<B> {
+    /// Writes the header of the capture to `capture`.
+    pub fn new(backend: B, mut capture: impl Write + 'static) -> io::Result<Pcap<B>> {
+        let mut header = Vec::with_capacity(24);
+        header.extend(0xA1B2_C3D4u32.to_le_bytes());
+        // version 2.4
+        header.extend(2u16.to_le_bytes());
+        header.extend(4u16.to_le_bytes());
+        // time zone and accuracy of the timestamps
+        header.extend([0; 8]);
+        header.extend((MAX_FRAME_SIZE as u32).to_le_bytes());
+        // link type ethernet
+        header.extend(1u32.to_le_bytes());
+        capture.write_all(&header)?;
+
+        Ok(Self {
+            backend,
+            capture: Box::new(capture),
+        })
+    }
+
+    fn record(&mut self, frame: &[u8]) -> io::Result<()> {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let captured = frame.len().min(MAX_FRAME_SIZE);
+
+        let mut record = Vec::with_capacity(16 + captured);
+        record.extend((time.as_secs() as u32).to_le_bytes());
+        record.extend(time.subsec_micros().to_le_bytes());
+        record.extend((captured as u32).to_le_bytes());
+        record.extend((frame.len() as u32).to_le_bytes());
+        record.extend(&frame[..captured]);
+        // frames are rare enough to keep the capture complete if the emulator is killed
+        self.capture.write_all(&record)?;
+        self.capture.flush()
+    }
+}
+
+impl<B: NetBackend> NetBackend for Pcap<B> {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.record(frame)?;
+        self.backend.send(frame)
+    }
+
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let frame = self.backend.recv()?;
+        if let Some(frame) = &frame {
+            self.record(frame)?;
+        }
+        Ok(frame)
+    }
+}
+
+/// Virtio network card with a single pair of queues and no offloads, connected to a
+/// [`NetBackend`].
+pub struct VirtioNet {
+    backend: Box<dyn NetBackend>,
+    mac: [u8; 6],
+    /// Frame received while the driver had no buffer for it
+    pending: Option<Vec<u8>>,
+}
+
+impl VirtioNet {
+    pub fn new(backend: impl NetBackend + 'static, mac: [u8; 6]) -> VirtioNet {
+        Self {
+            backend: Box::new(backend),
+            mac,
+            pending: None,
+        }
+    }
+
+    /// Sends the frames the driver made available on the transmit queue.
+    fn transmit<A: Xlen>(
+        &mut self,
+        queue: &mut Queue,
+        mem: &mut dyn Memory<A>,
+    ) -> Result<(), CPUError<A>> {
+        while let Some(chain) = queue.pop(&*mem)? {
+            let data = chain.read(&*mem)?;
+            // failed sends are lost, as on a real network
+            if let Some(frame) = data.get(HEADER_SIZE..) {
+                let _ = self.backend.send(frame);
+            }
+            queue.push(mem, chain.head, 0)?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves received frames to the buffers of the receive queue, until either runs out.
+    fn receive<A: Xlen>(
+        &mut self,
+        queue: &mut Queue,
+        mem: &mut dyn Memory<A>,
+    ) -> Result<(), CPUError<A>> {
+        loop {
+            let frame = match self.pending.take() {
+                Some(frame) => frame,
+                // a broken backend is a disconnected cable
+                None => match self.backend.recv() {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) | Err(_) => return Ok(()),
+                },
+            };
+            let Some(chain) = queue.pop(&*mem)? else {
+                self.pending = Some(frame);
+                return Ok(());
+            };
+
+            // frames that do not fit are dropped, the driver sees an empty buffer
+            let mut data = vec![0; HEADER_SIZE];
+            // the frame takes a single buffer
+            data[10] = 1;
+            data.extend(frame);
+            let len = if data.len() <= chain.writable_len() {
+                chain.write(mem, &data)?
+            } else {
+                0
+            };
+            queue.push(mem, chain.head, len)?;
+        }
+    }
+}
+
+impl<A: Xlen> VirtioDevice<A> for VirtioNet {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID_NET
+    }
+
+    fn features(&self) -> u64 {
+        F_MAC | F_STATUS
+    }
+
+    fn queues(&self) -> usize {
+        2
+    }
+
+    /// The mac address followed by the link status, which is always up.
+    fn read_config(&self, offset: usize) -> u8 {
+        let status = STATUS_LINK_UP.to_le_bytes();
+        self.mac
+            .iter()
+            .chain(&status)
+            .nth(offset)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn notify(
+        &mut self,
+        index: usize,
+        queue: &mut Queue,
+        mem: &mut dyn Memory<A>,
+    ) -> Result<(), CPUError<A>> {
+        match index {
+            RX_QUEUE => self.receive(queue, mem),
+            TX_QUEUE => self.transmit(queue, mem),
+            _ => Ok(()),
+        }
+    }
+
+    fn poll_interval(&self) -> Option<Duration> {
+        Some(RX_POLL)
+    }
+
+    fn poll(&mut self, queues: &mut [Queue], mem: &mut dyn Memory<A>) -> Result<(), CPUError<A>> {
+        self.receive(&mut queues[RX_QUEUE], mem)
+    }
+
+    fn reset(&mut self) {
+        self.pending = None;
+    }
+}
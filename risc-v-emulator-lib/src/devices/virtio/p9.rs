@@ -0,0 +1,863 @@
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, OsStr};
+use std::fs::{File, FileTimes, Permissions};
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::cpu::isa::Xlen;
+use crate::cpu::CPUError;
+use crate::devices::virtio::{Queue, VirtioDevice, DEVICE_ID_9P};
+use crate::memory::Memory;
+
+// feature bits
+const F_MOUNT_TAG: u64 = 1 << 0;
+
+// message types of 9P2000.L, the reply to each request is the next type
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TSYMLINK: u8 = 16;
+const TRENAME: u8 = 20;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TLOCK: u8 = 52;
+const TGETLOCK: u8 = 54;
+const TLINK: u8 = 70;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+// errno values of Linux, which the guest expects
+const ENOENT: u32 = 2;
+const EIO: u32 = 5;
+const EBADF: u32 = 9;
+const ENOTDIR: u32 = 20;
+const EINVAL: u32 = 22;
+const ELOOP: u32 = 40;
+const EPROTO: u32 = 71;
+const EOPNOTSUPP: u32 = 95;
+
+// open flags of Linux
+const O_ACCMODE: u32 = 3;
+const O_RDONLY: u32 = 0;
+const O_WRONLY: u32 = 1;
+const O_RDWR: u32 = 2;
+const O_EXCL: u32 = 0o200;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+
+const AT_REMOVEDIR: u32 = 0x200;
+
+const QT_DIR: u8 = 0x80;
+const QT_SYMLINK: u8 = 0x02;
+const QT_FILE: u8 = 0;
+
+// attributes in Tsetattr
+const SETATTR_MODE: u32 = 1 << 0;
+const SETATTR_SIZE: u32 = 1 << 3;
+const SETATTR_ATIME: u32 = 1 << 4;
+const SETATTR_MTIME: u32 = 1 << 5;
+const SETATTR_ATIME_SET: u32 = 1 << 7;
+const SETATTR_MTIME_SET: u32 = 1 << 8;
+
+/// All attributes of Rgetattr up to the number of blocks, the others are not supported.
+const GETATTR_BASIC: u64 = 0x7FF;
+
+const MAX_MSIZE: u32 = 512 * 1024;
+/// Size of the header of Rread and Rreaddir, which is followed by the data.
+const IO_HEADER_SIZE: u32 = 11;
+
+type Reply = Result<Vec<u8>, u32>;
+
+fn errno(e: io::Error) -> u32 {
+    e.raw_os_error().map_or(EIO, |e| e as u32)
+}
+
+/// Turns the result of a libc call, negative on failure, into the errno for the guest.
+fn check(result: libc::c_int) -> Result<libc::c_int, u32> {
+    if result < 0 {
+        Err(errno(io::Error::last_os_error()))
+    } else {
+        Ok(result)
+    }
+}
+
+fn cstr(name: &OsStr) -> Result<CString, u32> {
+    CString::new(name.as_bytes()).map_err(|_| EINVAL)
+}
+
+fn is_dir(stat: &libc::stat) -> bool {
+    stat.st_mode & libc::S_IFMT == libc::S_IFDIR
+}
+
+// the types of the fields differ between hosts
+#[allow(clippy::unnecessary_cast)]
+fn qid(stat: &libc::stat) -> [u8; 13] {
+    let mut qid = [0; 13];
+    qid[0] = match stat.st_mode & libc::S_IFMT {
+        libc::S_IFDIR => QT_DIR,
+        libc::S_IFLNK => QT_SYMLINK,
+        _ => QT_FILE,
+    };
+    // the version stays zero, so the guest does not cache based on it
+    qid[5..].copy_from_slice(&(stat.st_ino as u64).to_le_bytes());
+    qid
+}
+
+/// Maps the open flags of Linux to those of the host, symlinks are never followed.
+fn open_flags(flags: u32) -> libc::c_int {
+    let mut host = match flags & O_ACCMODE {
+        O_RDONLY => libc::O_RDONLY,
+        O_WRONLY => libc::O_WRONLY,
+        _ => libc::O_RDWR,
+    };
+    for (flag, host_flag) in [
+        (O_EXCL, libc::O_EXCL),
+        (O_TRUNC, libc::O_TRUNC),
+        (O_APPEND, libc::O_APPEND),
+    ] {
+        if flags & flag != 0 {
+            host |= host_flag;
+        }
+    }
+    host | libc::O_NOFOLLOW
+}
+
+// The calls relative to a directory descriptor, the base of resolving paths without following
+// symlinks. Names are single components.
+
+fn open_at(dir: &OwnedFd, name: &CStr, flags: libc::c_int, mode: u32) -> Result<OwnedFd, u32> {
+    // SAFETY: `name` is nul-terminated, the new descriptor is owned by nobody else
+    let fd = check(unsafe {
+        libc::openat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            flags | libc::O_CLOEXEC,
+            mode as libc::c_uint,
+        )
+    })?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn stat_at(dir: &OwnedFd, name: &CStr) -> Result<libc::stat, u32> {
+    let mut stat = MaybeUninit::uninit();
+    // SAFETY: `name` is nul-terminated and `stat` is only read once the call filled it
+    check(unsafe {
+        libc::fstatat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            stat.as_mut_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    })?;
+    Ok(unsafe { stat.assume_init() })
+}
+
+fn stat_fd(file: &File) -> Result<libc::stat, u32> {
+    let mut stat = MaybeUninit::uninit();
+    // SAFETY: `stat` is only read once the call filled it
+    check(unsafe { libc::fstat(file.as_raw_fd(), stat.as_mut_ptr()) })?;
+    Ok(unsafe { stat.assume_init() })
+}
+
+fn mkdir_at(dir: &OwnedFd, name: &CStr, mode: u32) -> Result<(), u32> {
+    // SAFETY: `name` is nul-terminated
+    check(unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), mode as libc::mode_t) })?;
+    Ok(())
+}
+
+fn symlink_at(target: &CStr, dir: &OwnedFd, name: &CStr) -> Result<(), u32> {
+    // SAFETY: both strings are nul-terminated
+    check(unsafe { libc::symlinkat(target.as_ptr(), dir.as_raw_fd(), name.as_ptr()) })?;
+    Ok(())
+}
+
+fn link_at(from: (&OwnedFd, &CStr), to: (&OwnedFd, &CStr)) -> Result<(), u32> {
+    // SAFETY: both names are nul-terminated, a symlink is linked itself
+    check(unsafe {
+        libc::linkat(
+            from.0.as_raw_fd(),
+            from.1.as_ptr(),
+            to.0.as_raw_fd(),
+            to.1.as_ptr(),
+            0,
+        )
+    })?;
+    Ok(())
+}
+
+fn rename_at(from: (&OwnedFd, &CStr), to: (&OwnedFd, &CStr)) -> Result<(), u32> {
+    // SAFETY: both names are nul-terminated
+    check(unsafe {
+        libc::renameat(
+            from.0.as_raw_fd(),
+            from.1.as_ptr(),
+            to.0.as_raw_fd(),
+            to.1.as_ptr(),
+        )
+    })?;
+    Ok(())
+}
+
+fn unlink_at(dir: &OwnedFd, name: &CStr, remove_dir: bool) -> Result<(), u32> {
+    let flags = if remove_dir { libc::AT_REMOVEDIR } else { 0 };
+    // SAFETY: `name` is nul-terminated
+    check(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), flags) })?;
+    Ok(())
+}
+
+fn read_link_at(dir: &OwnedFd, name: &CStr) -> Result<Vec<u8>, u32> {
+    let mut target = vec![0; libc::PATH_MAX as usize];
+    // SAFETY: `name` is nul-terminated and at most `target.len()` bytes are written to it
+    let len = unsafe {
+        libc::readlinkat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            target.as_mut_ptr().cast(),
+            target.len(),
+        )
+    };
+    if len < 0 {
+        return Err(errno(io::Error::last_os_error()));
+    }
+    target.truncate(len as usize);
+    Ok(target)
+}
+
+/// Returns the names in the directory `dir`, without `.` and `..`.
+fn list_dir(dir: OwnedFd) -> Result<Vec<CString>, u32> {
+    let fd = dir.into_raw_fd();
+    // SAFETY: the stream takes ownership of the descriptor and is closed below
+    let stream = unsafe { libc::fdopendir(fd) };
+    if stream.is_null() {
+        let e = errno(io::Error::last_os_error());
+        drop(unsafe { OwnedFd::from_raw_fd(fd) });
+        return Err(e);
+    }
+
+    let mut names = Vec::new();
+    loop {
+        // SAFETY: the entry stays valid until the next call on the stream
+        let entry = unsafe { libc::readdir(stream) };
+        if entry.is_null() {
+            break;
+        }
+        let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+        if name != c"." && name != c".." {
+            names.push(name.to_owned());
+        }
+    }
+    unsafe { libc::closedir(stream) };
+
+    Ok(names)
+}
+
+fn put_str(reply: &mut Vec<u8>, s: &str) {
+    reply.extend((s.len() as u16).to_le_bytes());
+    reply.extend(s.as_bytes());
+}
+
+/// Reads the fields of a request in order.
+struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], u32> {
+        if self.data.len() < len {
+            return Err(EPROTO);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, u32> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, u32> {
+        Ok(u16::from_le_bytes(
+            self.bytes(2)?.try_into().expect("2 bytes"),
+        ))
+    }
+
+    fn u32(&mut self) -> Result<u32, u32> {
+        Ok(u32::from_le_bytes(
+            self.bytes(4)?.try_into().expect("4 bytes"),
+        ))
+    }
+
+    fn u64(&mut self) -> Result<u64, u32> {
+        Ok(u64::from_le_bytes(
+            self.bytes(8)?.try_into().expect("8 bytes"),
+        ))
+    }
+
+    fn string(&mut self) -> Result<&'a str, u32> {
+        let len = self.u16()? as usize;
+        std::str::from_utf8(self.bytes(len)?).map_err(|_| EINVAL)
+    }
+}
+
+struct Fid {
+    /// Relative to the root of the share, resolved again on every access
+    path: PathBuf,
+    file: Option<File>,
+    /// Entries of an opened directory: name, qid and type, listed on the first Treaddir
+    entries: Option<Vec<(String, [u8; 13], u8)>>,
+}
+
+impl Fid {
+    fn new(path: PathBuf) -> Fid {
+        Self {
+            path,
+            file: None,
+            entries: None,
+        }
+    }
+}
+
+/// Shares a host directory with the guest over 9P2000.L, which Linux mounts with
+/// `mount -t 9p -o trans=virtio,version=9p2000.L <tag> <dir>`.
+///
+/// Files are accessed with the permissions of the emulator, ownership can not be changed and
+/// locks are local to the guest. The guest can not leave the directory: walks stop at its root
+/// and symlinks are resolved by the guest, never followed on the host. Paths are resolved from
+/// a descriptor of the root one component at a time, so a directory the guest swaps for a
+/// symlink does not lead fids walked through it out of the share.
+pub struct Virtio9p {
+    root: OwnedFd,
+    tag: String,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl Virtio9p {
+    /// Shares the directory `root` under the mount tag `tag`.
+    pub fn new(root: impl Into<PathBuf>, tag: impl Into<String>) -> io::Result<Virtio9p> {
+        let root = File::open(root.into())?;
+        if !root.metadata()?.is_dir() {
+            return Err(io::ErrorKind::NotADirectory.into());
+        }
+
+        Ok(Self {
+            root: root.into(),
+            tag: tag.into(),
+            msize: MAX_MSIZE,
+            fids: HashMap::new(),
+        })
+    }
+
+    /// Opens the directory `path` of the share without following any symlink.
+    fn open_dir(&self, path: &Path) -> Result<OwnedFd, u32> {
+        let mut dir = self.root.try_clone().map_err(errno)?;
+        for name in path {
+            let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW;
+            dir = open_at(&dir, &cstr(name)?, flags, 0)?;
+        }
+        Ok(dir)
+    }
+
+    /// Returns the directory containing `path` and the name of `path` in it, `.` for the root.
+    fn at(&self, path: &Path) -> Result<(OwnedFd, CString), u32> {
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => Ok((self.open_dir(parent)?, cstr(name)?)),
+            _ => Ok((self.root.try_clone().map_err(errno)?, c".".to_owned())),
+        }
+    }
+
+    fn metadata(&self, path: &Path) -> Result<libc::stat, u32> {
+        let (dir, name) = self.at(path)?;
+        stat_at(&dir, &name)
+    }
+
+    /// Opens `path` with the host open `flags`, fails if it is a symlink.
+    fn open(&self, path: &Path, flags: libc::c_int, mode: u32) -> Result<File, u32> {
+        let (dir, name) = self.at(path)?;
+        Ok(open_at(&dir, &name, flags | libc::O_NOFOLLOW, mode)?.into())
+    }
+
+    fn fid(&mut self, fid: u32) -> Result<&mut Fid, u32> {
+        self.fids.get_mut(&fid).ok_or(EBADF)
+    }
+
+    /// Returns the path of `name` in the directory of `fid`.
+    fn child(&mut self, fid: u32, name: &str) -> Result<PathBuf, u32> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(EINVAL);
+        }
+        let dir = self.fid(fid)?.path.clone();
+        if !is_dir(&self.metadata(&dir)?) {
+            return Err(ENOTDIR);
+        }
+
+        Ok(dir.join(name))
+    }
+
+    /// Handles `request`, returns the reply.
+    fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+        let mut decoder = Decoder { data: request };
+        let (Ok(_size), Ok(kind), Ok(tag)) = (decoder.u32(), decoder.u8(), decoder.u16()) else {
+            return Vec::new();
+        };
+
+        let (kind, body) = match self.dispatch(kind, &mut decoder) {
+            Ok(body) => (kind + 1, body),
+            Err(e) => (RLERROR, e.to_le_bytes().to_vec()),
+        };
+        let mut reply = Vec::with_capacity(7 + body.len());
+        reply.extend((7 + body.len() as u32).to_le_bytes());
+        reply.push(kind);
+        reply.extend(tag.to_le_bytes());
+        reply.extend(body);
+        reply
+    }
+
+    fn dispatch(&mut self, kind: u8, request: &mut Decoder) -> Reply {
+        match kind {
+            TVERSION => self.version(request),
+            TATTACH => self.attach(request),
+            TWALK => self.walk(request),
+            TLOPEN => self.lopen(request),
+            TLCREATE => self.lcreate(request),
+            TREAD => self.read(request),
+            TWRITE => self.write(request),
+            TREADDIR => self.readdir(request),
+            TGETATTR => self.getattr(request),
+            TSETATTR => self.setattr(request),
+            TCLUNK => {
+                self.fids.remove(&request.u32()?).ok_or(EBADF)?;
+                Ok(Vec::new())
+            }
+            TREMOVE => self.remove(request),
+            TMKDIR => {
+                let (dir, name, mode) = (request.u32()?, request.string()?, request.u32()?);
+                let path = self.child(dir, name)?;
+                let (dir, name) = self.at(&path)?;
+                mkdir_at(&dir, &name, mode & 0o7777)?;
+                Ok(qid(&self.metadata(&path)?).to_vec())
+            }
+            TSYMLINK => {
+                let (dir, name, target) = (request.u32()?, request.string()?, request.string()?);
+                let path = self.child(dir, name)?;
+                let (dir, name) = self.at(&path)?;
+                symlink_at(&cstr(target.as_ref())?, &dir, &name)?;
+                Ok(qid(&self.metadata(&path)?).to_vec())
+            }
+            TLINK => {
+                let (dir, fid, name) = (request.u32()?, request.u32()?, request.string()?);
+                let target = self.fid(fid)?.path.clone();
+                let path = self.child(dir, name)?;
+                let (target_dir, target) = self.at(&target)?;
+                let (dir, name) = self.at(&path)?;
+                link_at((&target_dir, &target), (&dir, &name))?;
+                Ok(Vec::new())
+            }
+            TREADLINK => {
+                let path = self.fid(request.u32()?)?.path.clone();
+                let (dir, name) = self.at(&path)?;
+                let target = read_link_at(&dir, &name)?;
+                let mut reply = Vec::new();
+                put_str(&mut reply, &String::from_utf8_lossy(&target));
+                Ok(reply)
+            }
+            TRENAME => {
+                let (fid, dir, name) = (request.u32()?, request.u32()?, request.string()?);
+                let from = self.fid(fid)?.path.clone();
+                let to = self.child(dir, name)?;
+                let (from_dir, from_name) = self.at(&from)?;
+                let (to_dir, to_name) = self.at(&to)?;
+                rename_at((&from_dir, &from_name), (&to_dir, &to_name))?;
+                self.fid(fid)?.path = to;
+                Ok(Vec::new())
+            }
+            TRENAMEAT => {
+                let (old_dir, old_name) = (request.u32()?, request.string()?);
+                let (new_dir, new_name) = (request.u32()?, request.string()?);
+                let from = self.child(old_dir, old_name)?;
+                let to = self.child(new_dir, new_name)?;
+                let (from_dir, from) = self.at(&from)?;
+                let (to_dir, to) = self.at(&to)?;
+                rename_at((&from_dir, &from), (&to_dir, &to))?;
+                Ok(Vec::new())
+            }
+            TUNLINKAT => {
+                let (dir, name, flags) = (request.u32()?, request.string()?, request.u32()?);
+                let path = self.child(dir, name)?;
+                let (dir, name) = self.at(&path)?;
+                unlink_at(&dir, &name, flags & AT_REMOVEDIR != 0)?;
+                Ok(Vec::new())
+            }
+            TFSYNC => {
+                let (fid, datasync) = (request.u32()?, request.u32()?);
+                if let Some(file) = &self.fid(fid)?.file {
+                    match datasync {
+                        0 => file.sync_all().map_err(errno)?,
+                        _ => file.sync_data().map_err(errno)?,
+                    }
+                }
+                Ok(Vec::new())
+            }
+            TSTATFS => {
+                self.fid(request.u32()?)?;
+                // the statistics of the host need statvfs, report an empty file system
+                let mut reply = Vec::new();
+                reply.extend(0x0102_1997u32.to_le_bytes());
+                reply.extend(4096u32.to_le_bytes());
+                reply.extend([0; 48]);
+                reply.extend(255u32.to_le_bytes());
+                Ok(reply)
+            }
+            // locks are granted, other guests and the host do not see them
+            TLOCK => Ok(vec![0]),
+            TGETLOCK => {
+                let (_fid, _kind) = (request.u32()?, request.u8()?);
+                // F_UNLCK, the rest of the request is returned as is
+                let mut reply = vec![2];
+                reply.extend(request.data);
+                Ok(reply)
+            }
+            // requests complete before the next one is read
+            TFLUSH => Ok(Vec::new()),
+            _ => Err(EOPNOTSUPP),
+        }
+    }
+
+    fn version(&mut self, request: &mut Decoder) -> Reply {
+        let (msize, version) = (request.u32()?, request.string()?);
+        self.msize = msize.min(MAX_MSIZE);
+        self.fids.clear();
+
+        let mut reply = self.msize.to_le_bytes().to_vec();
+        put_str(
+            &mut reply,
+            if version.starts_with("9P2000.L") {
+                "9P2000.L"
+            } else {
+                "unknown"
+            },
+        );
+        Ok(reply)
+    }
+
+    fn attach(&mut self, request: &mut Decoder) -> Reply {
+        let fid = request.u32()?;
+        let meta = self.metadata(Path::new(""))?;
+        self.fids.insert(fid, Fid::new(PathBuf::new()));
+        Ok(qid(&meta).to_vec())
+    }
+
+    fn walk(&mut self, request: &mut Decoder) -> Reply {
+        let (fid, new_fid, names) = (request.u32()?, request.u32()?, request.u16()?);
+        let mut path = self.fid(fid)?.path.clone();
+
+        let mut qids = Vec::new();
+        for _ in 0..names {
+            let name = request.string()?;
+            // symlinks are only walked to, not through
+            let step = match self.metadata(&path) {
+                Ok(stat) if !is_dir(&stat) => Err(ENOTDIR),
+                Ok(_) => match name {
+                    ".." => Ok(path.parent().map(Path::to_path_buf).unwrap_or_default()),
+                    "." => Ok(path.clone()),
+                    _ if name.is_empty() || name.contains('/') => Err(EINVAL),
+                    _ => Ok(path.join(name)),
+                },
+                Err(e) => Err(e),
+            }
+            .and_then(|next| Ok((self.metadata(&next)?, next)));
+
+            match step {
+                Ok((meta, next)) => {
+                    qids.push(qid(&meta));
+                    path = next;
+                }
+                // only the first step failing is an error
+                Err(e) if qids.is_empty() => return Err(e),
+                Err(_) => break,
+            }
+        }
+
+        if qids.len() == names as usize {
+            self.fids.insert(new_fid, Fid::new(path));
+        }
+        let mut reply = (qids.len() as u16).to_le_bytes().to_vec();
+        reply.extend(qids.concat());
+        Ok(reply)
+    }
+
+    fn lopen(&mut self, request: &mut Decoder) -> Reply {
+        let (fid, flags) = (request.u32()?, request.u32()?);
+        let path = self.fid(fid)?.path.clone();
+        let stat = self.metadata(&path)?;
+        if stat.st_mode & libc::S_IFMT == libc::S_IFLNK {
+            return Err(ELOOP);
+        }
+
+        if !is_dir(&stat) {
+            let file = self.open(&path, open_flags(flags), 0)?;
+            self.fid(fid)?.file = Some(file);
+        }
+
+        let mut reply = qid(&stat).to_vec();
+        // the iounit, i.e. limited by msize
+        reply.extend(0u32.to_le_bytes());
+        Ok(reply)
+    }
+
+    fn lcreate(&mut self, request: &mut Decoder) -> Reply {
+        let (fid, name) = (request.u32()?, request.string()?);
+        let (flags, mode) = (request.u32()?, request.u32()?);
+        let path = self.child(fid, name)?;
+
+        // the new file is always writable
+        let flags = match flags & O_ACCMODE {
+            O_RDONLY => flags | O_RDWR,
+            _ => flags,
+        };
+        let file = self.open(&path, open_flags(flags) | libc::O_CREAT, mode & 0o7777)?;
+        let stat = stat_fd(&file)?;
+        // the fid now stands for the new file
+        let fid = self.fid(fid)?;
+        fid.path = path;
+        fid.file = Some(file);
+
+        let mut reply = qid(&stat).to_vec();
+        reply.extend(0u32.to_le_bytes());
+        Ok(reply)
+    }
+
+    fn read(&mut self, request: &mut Decoder) -> Reply {
+        let (fid, offset, count) = (request.u32()?, request.u64()?, request.u32()?);
+        let count = count.min(self.msize.saturating_sub(IO_HEADER_SIZE)) as usize;
+        let file = self.fid(fid)?.file.as_ref().ok_or(EBADF)?;
+
+        let mut data = vec![0; count];
+        let mut len = 0;
+        while len < count {
+            match file.read_at(&mut data[len..], offset + len as u64) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(errno(e)),
+            }
+        }
+
+        let mut reply = (len as u32).to_le_bytes().to_vec();
+        reply.extend(&data[..len]);
+        Ok(reply)
+    }
+
+    fn write(&mut self, request: &mut Decoder) -> Reply {
+        let (fid, offset, count) = (request.u32()?, request.u64()?, request.u32()?);
+        let data = request.bytes(count as usize)?;
+        let file = self.fid(fid)?.file.as_ref().ok_or(EBADF)?;
+
+        let written = file.write_at(data, offset).map_err(errno)?;
+        Ok((written as u32).to_le_bytes().to_vec())
+    }
+
+    fn readdir(&mut self, request: &mut Decoder) -> Reply {
+        let (fid, offset, count) = (request.u32()?, request.u64()?, request.u32()?);
+        let count = count.min(self.msize.saturating_sub(IO_HEADER_SIZE)) as usize;
+        let path = self.fid(fid)?.path.clone();
+
+        // the listing is taken when the guest starts reading, offsets index into it
+        if offset == 0 || self.fid(fid)?.entries.is_none() {
+            let parent = path.parent().map(Path::to_path_buf).unwrap_or_default();
+            let mut entries = vec![
+                (".".to_string(), qid(&self.metadata(&path)?), 4),
+                ("..".to_string(), qid(&self.metadata(&parent)?), 4),
+            ];
+            let dir = self.open_dir(&path)?;
+            let mut names: Vec<_> = list_dir(dir.try_clone().map_err(errno)?)?
+                .into_iter()
+                .filter_map(|name| name.into_string().ok())
+                .collect();
+            names.sort();
+            for name in names {
+                // entries removed since they were listed are skipped
+                let Ok(stat) = stat_at(&dir, &cstr(name.as_ref())?) else {
+                    continue;
+                };
+                let kind = match stat.st_mode & libc::S_IFMT {
+                    libc::S_IFDIR => 4,
+                    libc::S_IFLNK => 10,
+                    libc::S_IFREG => 8,
+                    _ => 0,
+                };
+                entries.push((name, qid(&stat), kind));
+            }
+            self.fid(fid)?.entries = Some(entries);
+        }
+
+        let mut data = Vec::new();
+        let entries = self.fid(fid)?.entries.as_ref().expect("listed");
+        for (index, (name, qid, kind)) in entries.iter().enumerate().skip(offset as usize) {
+            if data.len() + 24 + name.len() > count {
+                break;
+            }
+            data.extend(qid);
+            data.extend((index as u64 + 1).to_le_bytes());
+            data.push(*kind);
+            put_str(&mut data, name);
+        }
+
+        let mut reply = (data.len() as u32).to_le_bytes().to_vec();
+        reply.extend(data);
+        Ok(reply)
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    fn getattr(&mut self, request: &mut Decoder) -> Reply {
+        let path = self.fid(request.u32()?)?.path.clone();
+        let stat = self.metadata(&path)?;
+
+        let mut reply = GETATTR_BASIC.to_le_bytes().to_vec();
+        reply.extend(qid(&stat));
+        reply.extend((stat.st_mode as u32).to_le_bytes());
+        reply.extend((stat.st_uid as u32).to_le_bytes());
+        reply.extend((stat.st_gid as u32).to_le_bytes());
+        for value in [
+            stat.st_nlink as u64,
+            stat.st_rdev as u64,
+            stat.st_size as u64,
+            stat.st_blksize as u64,
+            stat.st_blocks as u64,
+        ] {
+            reply.extend(value.to_le_bytes());
+        }
+        for (secs, nsecs) in [
+            (stat.st_atime as i64, stat.st_atime_nsec as i64),
+            (stat.st_mtime as i64, stat.st_mtime_nsec as i64),
+            (stat.st_ctime as i64, stat.st_ctime_nsec as i64),
+        ] {
+            reply.extend(secs.to_le_bytes());
+            reply.extend(nsecs.to_le_bytes());
+        }
+        // birth time, generation and data version are not supported
+        reply.extend([0; 32]);
+        Ok(reply)
+    }
+
+    fn setattr(&mut self, request: &mut Decoder) -> Reply {
+        let (fid, valid, mode) = (request.u32()?, request.u32()?, request.u32()?);
+        let (_uid, _gid, size) = (request.u32()?, request.u32()?, request.u64()?);
+        let atime = (request.u64()?, request.u64()?);
+        let mtime = (request.u64()?, request.u64()?);
+        let path = self.fid(fid)?.path.clone();
+
+        // the changes are made through a descriptor, which can not be opened for a symlink
+        let changes = SETATTR_MODE | SETATTR_SIZE | SETATTR_ATIME | SETATTR_MTIME;
+        if valid & changes == 0 {
+            return Ok(Vec::new());
+        }
+        let access = match valid & SETATTR_SIZE {
+            0 => libc::O_RDONLY,
+            _ => libc::O_WRONLY,
+        };
+        let file = self.open(&path, access, 0)?;
+
+        if valid & SETATTR_MODE != 0 {
+            file.set_permissions(Permissions::from_mode(mode & 0o7777))
+                .map_err(errno)?;
+        }
+        if valid & SETATTR_SIZE != 0 {
+            file.set_len(size).map_err(errno)?;
+        }
+        if valid & (SETATTR_ATIME | SETATTR_MTIME) != 0 {
+            let time = |set, (secs, nsecs): (u64, u64)| match valid & set {
+                0 => Ok(SystemTime::now()),
+                _ => SystemTime::UNIX_EPOCH
+                    .checked_add(Duration::from_secs(secs))
+                    .and_then(|time| time.checked_add(Duration::from_nanos(nsecs)))
+                    .ok_or(EINVAL),
+            };
+            let mut times = FileTimes::new();
+            if valid & SETATTR_ATIME != 0 {
+                times = times.set_accessed(time(SETATTR_ATIME_SET, atime)?);
+            }
+            if valid & SETATTR_MTIME != 0 {
+                times = times.set_modified(time(SETATTR_MTIME_SET, mtime)?);
+            }
+            file.set_times(times).map_err(errno)?;
+        }
+
+        Ok(Vec::new())
+    }
+
+    fn remove(&mut self, request: &mut Decoder) -> Reply {
+        // the fid is clunked even if the removal fails
+        let fid = self.fids.remove(&request.u32()?).ok_or(EBADF)?;
+        if fid.path.as_os_str().is_empty() {
+            return Err(ENOENT);
+        }
+
+        let (dir, name) = self.at(&fid.path)?;
+        unlink_at(&dir, &name, is_dir(&stat_at(&dir, &name)?))?;
+        Ok(Vec::new())
+    }
+}
+
+impl<A: Xlen> VirtioDevice<A> for Virtio9p {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID_9P
+    }
+
+    fn features(&self) -> u64 {
+        F_MOUNT_TAG
+    }
+
+    fn queues(&self) -> usize {
+        1
+    }
+
+    /// The length of the mount tag followed by the tag.
+    fn read_config(&self, offset: usize) -> u8 {
+        let len = (self.tag.len() as u16).to_le_bytes();
+        len.iter()
+            .chain(self.tag.as_bytes())
+            .nth(offset)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn notify(
+        &mut self,
+        _index: usize,
+        queue: &mut Queue,
+        mem: &mut dyn Memory<A>,
+    ) -> Result<(), CPUError<A>> {
+        while let Some(chain) = queue.pop(&*mem)? {
+            let request = chain.read(&*mem)?;
+            let reply = self.handle(&request);
+            let len = chain.write(mem, &reply)?;
+            queue.push(mem, chain.head, len)?;
+        }
+
+        Ok(())
+    }
+
+    /// The driver starts a new session with Tversion anyway.
+    fn reset(&mut self) {
+        self.fids.clear();
+    }
+}
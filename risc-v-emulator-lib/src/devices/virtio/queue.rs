@@ -0,0 +1,224 @@
+use num_traits::NumCast;
+
+use crate::cpu::isa::Xlen;
+use crate::cpu::CPUError;
+use crate::memory::Memory;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+/// Set by the driver in the available ring if it does not want to be interrupted.
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+/// Converts a guest physical address, which may be wider than xlen, to a bus address.
+pub(crate) fn guest_addr<A: Xlen>(addr: u64) -> Result<A, CPUError<A>> {
+    NumCast::from(addr).ok_or(CPUError::AddressNotMapped(A::max_value()))
+}
+
+/// Converts the guest physical address `offset` bytes past `base` to a bus address, both are
+/// chosen by the driver and may overflow.
+fn guest_offset<A: Xlen>(base: u64, offset: u64) -> Result<A, CPUError<A>> {
+    guest_addr(
+        base.checked_add(offset)
+            .ok_or(CPUError::AddressNotMapped(A::max_value()))?,
+    )
+}
+
+/// Reads `len` bytes of guest memory at `addr`.
+pub(crate) fn read_guest<A: Xlen>(
+    mem: &dyn Memory<A>,
+    addr: u64,
+    len: usize,
+) -> Result<Vec<u8>, CPUError<A>> {
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+
+    let end = addr
+        .checked_add(len as u64)
+        .ok_or(CPUError::AddressNotMapped(A::max_value()))?;
+    mem.get_data(guest_addr(addr)?..guest_addr(end)?)
+}
+
+/// Writes `data` to guest memory at `addr`, in double words where they are aligned.
+pub(crate) fn write_guest<A: Xlen>(
+    mem: &mut dyn Memory<A>,
+    addr: u64,
+    data: &[u8],
+) -> Result<(), CPUError<A>> {
+    let mut offset = 0;
+    while offset < data.len() {
+        let at = addr
+            .checked_add(offset as u64)
+            .ok_or(CPUError::AddressNotMapped(A::max_value()))?;
+        match data.get(offset..offset + 8) {
+            Some(word) if at.is_multiple_of(8) => {
+                let word = u64::from_le_bytes(word.try_into().expect("8 bytes"));
+                mem.store_u64(guest_addr(at)?, word)?;
+                offset += 8;
+            }
+            _ => {
+                mem.store_u8(guest_addr(at)?, data[offset])?;
+                offset += 1;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// A buffer in guest memory, part of a [`Chain`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Descriptor {
+    pub addr: u64,
+    pub len: u32,
+    /// Written by the device, otherwise read by the device
+    pub writable: bool,
+}
+
+/// A request made available by the driver, the device-readable buffers come first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chain {
+    /// Index of the first descriptor, identifying the request in the used ring
+    pub head: u16,
+    pub descriptors: Vec<Descriptor>,
+}
+
+impl Chain {
+    /// Returns the contents of all device-readable buffers.
+    pub fn read<A: Xlen>(&self, mem: &dyn Memory<A>) -> Result<Vec<u8>, CPUError<A>> {
+        let mut data = Vec::new();
+        for desc in self.descriptors.iter().filter(|desc| !desc.writable) {
+            data.extend(read_guest(mem, desc.addr, desc.len as usize)?);
+        }
+
+        Ok(data)
+    }
+
+    /// Total size of the device-writable buffers.
+    pub fn writable_len(&self) -> usize {
+        self.descriptors
+            .iter()
+            .filter(|desc| desc.writable)
+            .map(|desc| desc.len as usize)
+            .sum()
+    }
+
+    /// Fills the device-writable buffers with `data`, returns the number of bytes written.
+    /// Data that does not fit is dropped.
+    pub fn write<A: Xlen>(&self, mem: &mut dyn Memory<A>, data: &[u8]) -> Result<u32, CPUError<A>> {
+        self.write_at(mem, 0, data)
+    }
+
+    /// Like [`write`](Self::write), but starts `offset` bytes into the device-writable buffers.
+    pub fn write_at<A: Xlen>(
+        &self,
+        mem: &mut dyn Memory<A>,
+        mut offset: usize,
+        data: &[u8],
+    ) -> Result<u32, CPUError<A>> {
+        let mut written = 0;
+        for desc in self.descriptors.iter().filter(|desc| desc.writable) {
+            let desc_len = desc.len as usize;
+            if offset >= desc_len {
+                offset -= desc_len;
+                continue;
+            }
+
+            let addr = desc
+                .addr
+                .checked_add(offset as u64)
+                .ok_or(CPUError::AddressNotMapped(A::max_value()))?;
+            let len = (desc_len - offset).min(data.len() - written);
+            write_guest(mem, addr, &data[written..written + len])?;
+            written += len;
+            offset = 0;
+        }
+
+        Ok(written as u32)
+    }
+}
+
+/// Split virtqueue, whose rings the driver allocates in guest memory.
+#[derive(Debug, Clone, Default)]
+pub struct Queue {
+    /// Number of descriptors chosen by the driver
+    pub(crate) size: u16,
+    pub(crate) ready: bool,
+    /// Guest physical addresses of the descriptor table and the available and used rings
+    pub(crate) desc: u64,
+    pub(crate) avail: u64,
+    pub(crate) used: u64,
+    /// Index in the available ring of the next request to take
+    next_avail: u16,
+    next_used: u16,
+    /// Requests have been completed and the driver asked to be interrupted
+    interrupt: bool,
+}
+
+impl Queue {
+    /// Takes the next request the driver made available, if any.
+    pub fn pop<A: Xlen>(&mut self, mem: &dyn Memory<A>) -> Result<Option<Chain>, CPUError<A>> {
+        if !self.ready || self.size == 0 {
+            return Ok(None);
+        }
+
+        let avail_idx = mem.load_u16(guest_offset(self.avail, 2)?)?;
+        if avail_idx == self.next_avail {
+            return Ok(None);
+        }
+
+        let slot = 4 + 2 * (self.next_avail % self.size) as u64;
+        let head = mem.load_u16(guest_offset(self.avail, slot)?)?;
+        self.next_avail = self.next_avail.wrapping_add(1);
+
+        // a chain can not be longer than the table, which also catches loops
+        let mut descriptors = Vec::new();
+        let mut index = head;
+        loop {
+            if index >= self.size || descriptors.len() == self.size as usize {
+                return Err(CPUError::AddressNotMapped(guest_addr(self.desc)?));
+            }
+
+            let entry = 16 * index as u64;
+            let flags = mem.load_u16(guest_offset(self.desc, entry + 12)?)?;
+            descriptors.push(Descriptor {
+                addr: mem.load_u64(guest_offset(self.desc, entry)?)?,
+                len: mem.load_u32(guest_offset(self.desc, entry + 8)?)?,
+                writable: flags & DESC_F_WRITE != 0,
+            });
+
+            if flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            index = mem.load_u16(guest_offset(self.desc, entry + 14)?)?;
+        }
+
+        Ok(Some(Chain { head, descriptors }))
+    }
+
+    /// Returns the request starting at descriptor `head` to the driver, `len` bytes have been
+    /// written to its buffers.
+    pub fn push<A: Xlen>(
+        &mut self,
+        mem: &mut dyn Memory<A>,
+        head: u16,
+        len: u32,
+    ) -> Result<(), CPUError<A>> {
+        let slot = 4 + 8 * (self.next_used % self.size) as u64;
+        mem.store_u32(guest_offset(self.used, slot)?, head as u32)?;
+        mem.store_u32(guest_offset(self.used, slot + 4)?, len)?;
+        self.next_used = self.next_used.wrapping_add(1);
+        mem.store_u16(guest_offset(self.used, 2)?, self.next_used)?;
+
+        let flags = mem.load_u16(guest_addr(self.avail)?)?;
+        self.interrupt |= flags & AVAIL_F_NO_INTERRUPT == 0;
+
+        Ok(())
+    }
+
+    /// Returns whether the driver has to be interrupted for completed requests, and clears it.
+    pub(crate) fn take_interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.interrupt)
+    }
+}
@@ -0,0 +1,76 @@
+use crate::cpu::isa::Xlen;
+use crate::cpu::CPUError;
+use crate::devices::virtio::{Queue, VirtioDevice, DEVICE_ID_RNG};
+use crate::memory::Memory;
+
+/// Most bytes handed out per request, the driver may offer far larger buffers.
+const MAX_REQUEST: usize = 4096;
+
+/// Entropy source filling the buffers of the driver with pseudo-random bytes.
+///
+/// The bytes only depend on the seed and on the sizes of the buffers, so runs with the same
+/// seed see the same "entropy". Not suitable for anything that needs real randomness.
+pub struct VirtioRng {
+    seed: u64,
+    state: u64,
+}
+
+impl VirtioRng {
+    pub fn new(seed: u64) -> VirtioRng {
+        Self { seed, state: seed }
+    }
+
+    /// Next output of splitmix64.
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl<A: Xlen> VirtioDevice<A> for VirtioRng {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID_RNG
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn queues(&self) -> usize {
+        1
+    }
+
+    /// There is no configuration.
+    fn read_config(&self, _offset: usize) -> u8 {
+        0
+    }
+
+    fn notify(
+        &mut self,
+        _index: usize,
+        queue: &mut Queue,
+        mem: &mut dyn Memory<A>,
+    ) -> Result<(), CPUError<A>> {
+        while let Some(chain) = queue.pop(&*mem)? {
+            // buffers may be filled partly, the driver asks again for more
+            let len = chain.writable_len().min(MAX_REQUEST);
+            let mut data = Vec::with_capacity(len + 8);
+            while data.len() < len {
+                data.extend(self.next().to_le_bytes());
+            }
+            data.truncate(len);
+            let len = chain.write(mem, &data)?;
+            queue.push(mem, chain.head, len)?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts over, so a rebooted guest sees the same bytes again.
+    fn reset(&mut self) {
+        self.state = self.seed;
+    }
+}
@@ -0,0 +1,235 @@
+//! Minimal loader for statically linked little-endian RISC-V ELF executables.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+const MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const CLASS_32: u8 = 1;
+const CLASS_64: u8 = 2;
+const DATA_LE: u8 = 1;
+const MACHINE_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+
+#[derive(Debug)]
+pub enum ElfError {
+    NotAnElf,
+    Unsupported(&'static str),
+    /// A header or section points past the end of the file
+    Truncated,
+    /// A segment could not be written to the given address
+    SegmentNotMapped(u64),
+}
+
+impl Display for ElfError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ElfError::NotAnElf => write!(f, "Not an ELF file!"),
+            ElfError::Unsupported(what) => write!(f, "Unsupported ELF file: {what}!"),
+            ElfError::Truncated => write!(f, "ELF file is truncated!"),
+            ElfError::SegmentNotMapped(address) => {
+                write!(f, "Can not load segment to address {address:#018X}!")
+            }
+        }
+    }
+}
+
+impl Error for ElfError {}
+
+/// A loadable segment, `data` is followed by `mem_size - data.len()` zero bytes in memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u64,
+    pub data: Vec<u8>,
+    pub mem_size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Elf {
+    /// Register width in bits
+    pub xlen: u32,
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+    symbols: HashMap<String, u64>,
+}
+
+impl Elf {
+    pub fn is_elf(data: &[u8]) -> bool {
+        data.starts_with(&MAGIC)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Elf, ElfError> {
+        if !Self::is_elf(data) {
+            return Err(ElfError::NotAnElf);
+        }
+
+        let wide = match data.get(4) {
+            Some(&CLASS_32) => false,
+            Some(&CLASS_64) => true,
+            _ => return Err(ElfError::Unsupported("unknown class")),
+        };
+        let file = File { data, wide };
+
+        if data.get(5) != Some(&DATA_LE) {
+            return Err(ElfError::Unsupported("big endian"));
+        }
+        if file.u16(0x12)? != MACHINE_RISCV {
+            return Err(ElfError::Unsupported("not a RISC-V executable"));
+        }
+
+        // the header fields after the entry point move with the width of addresses
+        let (ph_off, sh_off, sizes) = if wide {
+            (0x20, 0x28, 0x36)
+        } else {
+            (0x1C, 0x20, 0x2A)
+        };
+
+        let entry = file.word(0x18)?;
+        let ph_off = file.word(ph_off)?;
+        let sh_off = file.word(sh_off)?;
+        let (ph_entsize, ph_num) = (file.u16(sizes)?, file.u16(sizes + 2)?);
+        let (sh_entsize, sh_num) = (file.u16(sizes + 4)?, file.u16(sizes + 6)?);
+
+        let mut segments = Vec::new();
+        for i in 0..ph_num as u64 {
+            let ph = file.at(ph_off.saturating_add(i * ph_entsize as u64))?;
+            if ph.u32(0)? != PT_LOAD {
+                continue;
+            }
+
+            let (offset, address, file_size, mem_size) = if wide {
+                (ph.u64(0x08)?, ph.u64(0x18)?, ph.u64(0x20)?, ph.u64(0x28)?)
+            } else {
+                (
+                    ph.u32(0x04)? as u64,
+                    ph.u32(0x0C)? as u64,
+                    ph.u32(0x10)? as u64,
+                    ph.u32(0x14)? as u64,
+                )
+            };
+
+            segments.push(Segment {
+                address,
+                data: file.bytes(offset, file_size)?.to_vec(),
+                mem_size: mem_size.max(file_size),
+            });
+        }
+
+        let mut symbols = HashMap::new();
+        for i in 0..sh_num as u64 {
+            let sh = file.at(sh_off.saturating_add(i * sh_entsize as u64))?;
+            if sh.u32(0x04)? != SHT_SYMTAB {
+                continue;
+            }
+
+            let (offset, size, link, entsize) = if wide {
+                (sh.u64(0x18)?, sh.u64(0x20)?, sh.u32(0x28)?, sh.u64(0x38)?)
+            } else {
+                (
+                    sh.u32(0x10)? as u64,
+                    sh.u32(0x14)? as u64,
+                    sh.u32(0x18)?,
+                    sh.u32(0x24)? as u64,
+                )
+            };
+            if entsize == 0 {
+                return Err(ElfError::Unsupported("symbol table without entry size"));
+            }
+
+            // the string table holding the symbol names
+            let strtab = file.at(sh_off.saturating_add(link as u64 * sh_entsize as u64))?;
+            let strtab = if wide {
+                file.bytes(strtab.u64(0x18)?, strtab.u64(0x20)?)?
+            } else {
+                file.bytes(strtab.u32(0x10)? as u64, strtab.u32(0x14)? as u64)?
+            };
+
+            for sym in (offset..offset.saturating_add(size)).step_by(entsize as usize) {
+                let sym = file.at(sym)?;
+                let name = sym.u32(0)? as usize;
+                let value = if wide {
+                    sym.u64(0x08)?
+                } else {
+                    sym.u32(0x04)? as u64
+                };
+
+                let name = strtab.get(name..).ok_or(ElfError::Truncated)?;
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                if !name.is_empty() {
+                    symbols.insert(String::from_utf8_lossy(name).into_owned(), value);
+                }
+            }
+        }
+
+        Ok(Elf {
+            xlen: if wide { 64 } else { 32 },
+            entry,
+            segments,
+            symbols,
+        })
+    }
+
+    /// Returns the address of the symbol `name`.
+    pub fn symbol(&self, name: &str) -> Option<u64> {
+        self.symbols.get(name).copied()
+    }
+}
+
+/// Bounds checked little-endian reads from the file.
+struct File<'a> {
+    data: &'a [u8],
+    wide: bool,
+}
+
+impl<'a> File<'a> {
+    /// Returns the part of the file starting at `offset`, e.g. a single header.
+    fn at(&self, offset: u64) -> Result<File<'a>, ElfError> {
+        let data = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| self.data.get(offset..))
+            .ok_or(ElfError::Truncated)?;
+
+        Ok(File {
+            data,
+            wide: self.wide,
+        })
+    }
+
+    fn bytes(&self, offset: u64, len: u64) -> Result<&'a [u8], ElfError> {
+        let start = usize::try_from(offset).map_err(|_| ElfError::Truncated)?;
+        let len = usize::try_from(len).map_err(|_| ElfError::Truncated)?;
+        start
+            .checked_add(len)
+            .and_then(|end| self.data.get(start..end))
+            .ok_or(ElfError::Truncated)
+    }
+
+    fn u16(&self, offset: u64) -> Result<u16, ElfError> {
+        Ok(u16::from_le_bytes(
+            self.bytes(offset, 2)?.try_into().unwrap(),
+        ))
+    }
+
+    fn u32(&self, offset: u64) -> Result<u32, ElfError> {
+        Ok(u32::from_le_bytes(
+            self.bytes(offset, 4)?.try_into().unwrap(),
+        ))
+    }
+
+    fn u64(&self, offset: u64) -> Result<u64, ElfError> {
+        Ok(u64::from_le_bytes(
+            self.bytes(offset, 8)?.try_into().unwrap(),
+        ))
+    }
+
+    /// Reads an address or offset, whose width depends on the class of the file.
+    fn word(&self, offset: u64) -> Result<u64, ElfError> {
+        if self.wide {
+            self.u64(offset)
+        } else {
+            Ok(self.u32(offset)? as u64)
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Writer for flattened devicetrees, the binary form in which firmware and kernels are handed
+//! the description of the machine.
+
+use std::collections::HashMap;
+
+const MAGIC: u32 = 0xD00D_FEED;
+const VERSION: u32 = 17;
+/// Oldest version readers of the output have to understand.
+const LAST_COMPATIBLE_VERSION: u32 = 16;
+const HEADER_SIZE: usize = 40;
+/// The memory reservation map, which only holds its terminating empty entry.
+const RESERVATION_MAP_SIZE: usize = 16;
+
+const BEGIN_NODE: u32 = 1;
+const END_NODE: u32 = 2;
+const PROP: u32 = 3;
+const END: u32 = 9;
+
+/// Builds a devicetree blob in document order.
+///
+/// Nodes are opened with [`begin_node`](Self::begin_node) and closed with
+/// [`end_node`](Self::end_node), properties are added to the innermost open node. The first node
+/// is the root, whose name is empty.
+#[derive(Default)]
+pub struct Fdt {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    /// Offsets of the property names in `strings`, each name is stored once
+    names: HashMap<String, u32>,
+    depth: usize,
+}
+
+impl Fdt {
+    pub fn new() -> Fdt {
+        Self::default()
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.token(BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        assert!(self.depth > 0, "no node to end");
+
+        self.token(END_NODE);
+        self.depth -= 1;
+    }
+
+    /// Adds a property with the raw `value`, empty for properties that are only flags.
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        assert!(self.depth > 0, "properties belong to a node");
+
+        let offset = match self.names.get(name) {
+            Some(&offset) => offset,
+            None => {
+                let offset = self.strings.len() as u32;
+                self.strings.extend_from_slice(name.as_bytes());
+                self.strings.push(0);
+                self.names.insert(name.to_owned(), offset);
+                offset
+            }
+        };
+
+        self.token(PROP);
+        self.token(value.len() as u32);
+        self.token(offset);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    /// Adds a property made of 32 bit cells.
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_u64(&mut self, name: &str, value: u64) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    /// Adds a property holding a list of strings, e.g. `compatible`.
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let value: Vec<u8> = values
+            .iter()
+            .flat_map(|value| value.bytes().chain([0]))
+            .collect();
+        self.property(name, &value);
+    }
+
+    /// Returns the blob, all nodes have to be ended.
+    pub fn finish(mut self) -> Vec<u8> {
+        assert_eq!(0, self.depth, "nodes are still open");
+        self.token(END);
+
+        let structure_offset = HEADER_SIZE + RESERVATION_MAP_SIZE;
+        let strings_offset = structure_offset + self.structure.len();
+        let size = strings_offset + self.strings.len();
+
+        let mut blob = Vec::with_capacity(size);
+        for field in [
+            MAGIC,
+            size as u32,
+            structure_offset as u32,
+            strings_offset as u32,
+            HEADER_SIZE as u32,
+            VERSION,
+            LAST_COMPATIBLE_VERSION,
+            // physical id of the boot cpu
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ] {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        blob.resize(structure_offset, 0);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+
+        blob
+    }
+
+    fn token(&mut self, token: u32) {
+        self.structure.extend_from_slice(&token.to_be_bytes());
+    }
+
+    /// Pads the structure block to the next token.
+    fn align(&mut self) {
+        self.structure
+            .resize(self.structure.len().next_multiple_of(4), 0);
+    }
+}
@@ -1,2 +1,7 @@
 pub mod cpu;
+pub mod devices;
+pub mod elf;
+pub mod fdt;
 pub mod memory;
+#[cfg(test)]
+mod test_util;
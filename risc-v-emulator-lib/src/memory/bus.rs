@@ -1,46 +1,407 @@
+use std::cell::Cell;
 use std::ops::Range;
 
 use num_traits::Unsigned;
 
-use crate::cpu::isa::Xlen;
+use crate::cpu::isa::{As, Xlen};
 use crate::cpu::CPUError;
-use crate::memory::{impl_memory_map, Memory};
+use crate::devices::{Scheduler, WallClock};
+use crate::memory::{impl_memory, Access, AccessType, HostMemory, Memory, Permissions};
+
+/// How the bus handles accesses whose address is not a multiple of the access size.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum MisalignedAccess {
+    /// Raise an address-misaligned exception.
+    Trap,
+    /// Perform the access as a whole, which still faults if it crosses a region boundary.
+    #[default]
+    Emulate,
+    /// Split the access into byte accesses, which may span multiple regions.
+    Split,
+}
+
+type PermissionedMapping<A> = (Range<A>, Box<dyn Memory<A>>, Permissions);
+
+struct Region<A: Xlen + Unsigned> {
+    mapping: Range<A>,
+    mem: Box<dyn Memory<A>>,
+    /// Fast path for plain ram, bypassing `mem` entirely
+    host: Option<HostMemory>,
+    permissions: Permissions,
+}
 
 pub struct Bus<A: Xlen + Unsigned> {
-    mem_map: Vec<(Range<A>, Box<dyn Memory<A>>)>,
+    /// Regions sorted by address
+    mem_map: Vec<Region<A>>,
+    /// Index of the region hit by the last access
+    last_hit: Cell<usize>,
+    misaligned_access: MisalignedAccess,
+    misaligned_count: Cell<u64>,
+    /// Indices of the regions that are devices, in the order of their timers
+    devices: Vec<usize>,
+    scheduler: Scheduler,
+    /// Whether a device is being ticked, whose stores are recorded in `device_writes`
+    ticking: bool,
+    /// Address and length of the stores of devices, adjacent ones are merged
+    device_writes: Vec<(A, usize)>,
+}
+
+/// Stands in for a device while it is ticked, so the device can access the rest of the bus.
+struct Detached;
+
+impl<A: Xlen + Unsigned> Memory<A> for Detached {
+    fn size(&self) -> A {
+        A::zero()
+    }
+
+    impl_memory!(
+        self,
+        addr,
+        value,
+        { Err(CPUError::AddressNotMapped(addr)) },
+        {
+            let _ = value;
+            Err(CPUError::AddressNotMapped(addr))
+        }
+    );
+
+    fn get_data(&self, range: Range<A>) -> Result<Vec<u8>, CPUError<A>> {
+        Err(CPUError::AddressNotMapped(range.start))
+    }
 }
 
 impl<A: Xlen + Unsigned> Bus<A> {
+    /// Creates a bus where every region is readable, writable and executable.
     pub fn new(mem_map: Vec<(Range<A>, Box<dyn Memory<A>>)>) -> Self {
+        Self::with_permissions(
+            mem_map
+                .into_iter()
+                .map(|(mapping, mem)| (mapping, mem, Permissions::RWX))
+                .collect(),
+        )
+    }
+
+    pub fn with_permissions(mem_map: Vec<PermissionedMapping<A>>) -> Self {
         let mut end_prev = A::zero();
-        for (Range { start, end }, mem) in &mem_map {
+        for (Range { start, end }, mem, _) in &mem_map {
             assert!(start < end);
             assert!(start >= &end_prev);
             assert!(mem.size() >= *end - *start);
             end_prev = *end;
         }
 
-        Self { mem_map }
+        let mut mem_map: Vec<_> = mem_map
+            .into_iter()
+            .map(|(mapping, mut mem, permissions)| Region {
+                mapping,
+                host: mem.host_memory(),
+                mem,
+                permissions,
+            })
+            .collect();
+        let scheduler = Scheduler::new();
+        let devices = mem_map
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, region)| {
+                let device = region.mem.as_device()?;
+                device.set_timer(scheduler.timer());
+                Some(i)
+            })
+            .collect();
+
+        Self {
+            mem_map,
+            last_hit: Cell::new(0),
+            misaligned_access: MisalignedAccess::default(),
+            misaligned_count: Cell::new(0),
+            devices,
+            scheduler,
+            ticking: false,
+            device_writes: Vec::new(),
+        }
+    }
+
+    /// Maps `mem` at `mapping`, which must not overlap any mapped region.
+    pub fn insert(
+        &mut self,
+        mapping: Range<A>,
+        mut mem: Box<dyn Memory<A>>,
+        permissions: Permissions,
+    ) {
+        assert!(mapping.start < mapping.end);
+        assert!(mem.size() >= mapping.end - mapping.start);
+        let index = self
+            .mem_map
+            .partition_point(|region| region.mapping.start < mapping.start);
+        assert!(index == 0 || self.mem_map[index - 1].mapping.end <= mapping.start);
+        assert!(index == self.mem_map.len() || mapping.end <= self.mem_map[index].mapping.start);
+
+        for device in &mut self.devices {
+            if *device >= index {
+                *device += 1;
+            }
+        }
+        if let Some(device) = mem.as_device() {
+            device.set_timer(self.scheduler.timer());
+            self.devices.push(index);
+        }
+
+        self.mem_map.insert(
+            index,
+            Region {
+                mapping,
+                host: mem.host_memory(),
+                mem,
+                permissions,
+            },
+        );
+        self.last_hit.set(0);
+    }
+
+    /// Advances time to `now` and ticks the devices whose event is due, see
+    /// [`Device::tick`](crate::devices::Device::tick).
+    #[inline]
+    pub fn tick(&mut self, now: u64) {
+        self.scheduler.set_now(now);
+        if now >= self.scheduler.next_event() {
+            self.run_events(now);
+        }
+    }
+
+    fn run_events(&mut self, now: u64) {
+        let mut mem: Box<dyn Memory<A>> = Box::new(Detached);
+        self.ticking = true;
+        for timer in 0..self.devices.len() {
+            if !self.scheduler.take_due(timer, now) {
+                continue;
+            }
+
+            let index = self.devices[timer];
+            std::mem::swap(&mut self.mem_map[index].mem, &mut mem);
+            if let Some(device) = mem.as_device() {
+                device.tick(now, self);
+            }
+            std::mem::swap(&mut self.mem_map[index].mem, &mut mem);
+        }
+        self.ticking = false;
+        self.scheduler.update_next();
+    }
+
+    /// Returns the address and length of the memory devices have written to since the last
+    /// call, e.g. by dma. Code the cpu translated from it is stale.
+    pub fn take_device_writes(&mut self) -> Vec<(A, usize)> {
+        std::mem::take(&mut self.device_writes)
+    }
+
+    fn record_device_write(&mut self, addr: A, size: usize) {
+        match self.device_writes.last_mut() {
+            Some(&mut (start, ref mut len))
+                if start.as_t::<usize>().wrapping_add(*len) == addr.as_t() =>
+            {
+                *len += size;
+            }
+            _ => self.device_writes.push((addr, size)),
+        }
+    }
+
+    /// Time of the next event scheduled by a device, `u64::MAX` if there is none.
+    #[inline]
+    pub fn next_event(&self) -> u64 {
+        self.scheduler.next_event()
+    }
+
+    /// Drops all pending events and resets every device on the bus, see
+    /// [`Device::reset`](crate::devices::Device::reset).
+    pub fn reset_devices(&mut self) {
+        self.scheduler.reset();
+        for &index in &self.devices {
+            if let Some(device) = self.mem_map[index].mem.as_device() {
+                device.reset();
+            }
+        }
+    }
+
+    /// Sets the time of day seen by the devices, see [`Timer::wall_time`](crate::devices::Timer::wall_time).
+    pub fn set_wall_clock(&mut self, clock: WallClock) {
+        self.scheduler.set_wall_clock(clock);
+    }
+
+    pub fn misaligned_access(&self) -> MisalignedAccess {
+        self.misaligned_access
+    }
+
+    pub fn set_misaligned_access(&mut self, policy: MisalignedAccess) {
+        self.misaligned_access = policy;
+    }
+
+    /// Number of misaligned accesses seen so far, including trapped ones.
+    pub fn misaligned_accesses(&self) -> u64 {
+        self.misaligned_count.get()
+    }
+
+    pub fn fetch_u32(&self, addr: A) -> Result<u32, CPUError<A>> {
+        self.read(addr, AccessType::Fetch)
     }
 }
 
 impl<A: Xlen + Unsigned> Bus<A> {
-    fn map(&self, addr: A) -> Result<(&dyn Memory<A>, &Range<A>), CPUError<A>> {
-        for (mapping, mem) in &self.mem_map {
-            if mapping.contains(&addr) {
-                return Ok((mem.as_ref(), mapping));
+    /// Returns the index of the region containing all `size` bytes starting at `addr`.
+    ///
+    /// Accesses starting outside of every region are reported as [`CPUError::AddressNotMapped`],
+    /// accesses crossing the end of the region they start in or violating its permissions are
+    /// reported as access faults.
+    fn lookup(&self, addr: A, size: usize, access: AccessType) -> Result<usize, CPUError<A>> {
+        let last_hit = self.last_hit.get();
+        let index = if self
+            .mem_map
+            .get(last_hit)
+            .is_some_and(|region| region.mapping.contains(&addr))
+        {
+            last_hit
+        } else {
+            let index = self
+                .mem_map
+                .partition_point(|region| region.mapping.end <= addr);
+            if !self
+                .mem_map
+                .get(index)
+                .is_some_and(|region| region.mapping.contains(&addr))
+            {
+                return Err(CPUError::AddressNotMapped(addr));
+            }
+            self.last_hit.set(index);
+            index
+        };
+
+        let region = &self.mem_map[index];
+        let last = A::from(size - 1)
+            .and_then(|offset| addr.checked_add(&offset))
+            .ok_or(access.access_fault(addr))?;
+
+        if last < region.mapping.end && region.permissions.allows(access) {
+            Ok(index)
+        } else {
+            Err(access.access_fault(addr))
+        }
+    }
+
+    fn map(&self, addr: A, size: usize, access: AccessType) -> Result<&Region<A>, CPUError<A>> {
+        Ok(&self.mem_map[self.lookup(addr, size, access)?])
+    }
+
+    fn map_mut(
+        &mut self,
+        addr: A,
+        size: usize,
+        access: AccessType,
+    ) -> Result<&mut Region<A>, CPUError<A>> {
+        let index = self.lookup(addr, size, access)?;
+        Ok(&mut self.mem_map[index])
+    }
+
+    /// Applies the misaligned access policy, returns whether the access has to be split.
+    fn check_alignment(
+        &self,
+        addr: A,
+        size: usize,
+        access: AccessType,
+    ) -> Result<bool, CPUError<A>> {
+        if addr.as_t::<usize>() & (size - 1) == 0 {
+            return Ok(false);
+        }
+
+        self.misaligned_count.set(self.misaligned_count.get() + 1);
+
+        match self.misaligned_access {
+            MisalignedAccess::Trap => Err(access.misaligned(addr)),
+            MisalignedAccess::Emulate => Ok(false),
+            MisalignedAccess::Split => Ok(true),
+        }
+    }
+
+    fn read<T: Access>(&self, addr: A, access: AccessType) -> Result<T, CPUError<A>> {
+        if self.check_alignment(addr, T::SIZE, access)? {
+            let mut bytes = [0; 16];
+            let mut byte_addr = addr;
+            for byte in &mut bytes[..T::SIZE] {
+                *byte = self.map(byte_addr, 1, access)?.load(byte_addr)?;
+                byte_addr = byte_addr.wrapping_add(&A::one());
             }
+
+            return Ok(T::from_le_bytes(&bytes[..T::SIZE]));
         }
-        Err(CPUError::AddressNotMapped(addr))
+
+        self.map(addr, T::SIZE, access)?.load(addr)
     }
 
-    fn map_mut(&mut self, addr: A) -> Result<(&mut dyn Memory<A>, &Range<A>), CPUError<A>> {
-        for (mapping, mem) in &mut self.mem_map {
-            if mapping.contains(&addr) {
-                return Ok((mem.as_mut(), mapping));
+    fn write<T: Access>(&mut self, addr: A, value: T) -> Result<(), CPUError<A>> {
+        if self.ticking {
+            self.record_device_write(addr, T::SIZE);
+        }
+
+        if self.check_alignment(addr, T::SIZE, AccessType::Store)? {
+            let mut bytes = [0; 16];
+            value.write_le_bytes(&mut bytes[..T::SIZE]);
+
+            let mut byte_addr = addr;
+            for byte in &bytes[..T::SIZE] {
+                self.map_mut(byte_addr, 1, AccessType::Store)?
+                    .store(byte_addr, *byte)?;
+                byte_addr = byte_addr.wrapping_add(&A::one());
             }
+
+            return Ok(());
+        }
+
+        self.map_mut(addr, T::SIZE, AccessType::Store)?
+            .store(addr, value)
+    }
+}
+
+impl<A: Xlen + Unsigned> Region<A> {
+    /// Loads from bus address `addr`, which has to lie within this region.
+    #[inline]
+    fn load<T: Access>(&self, addr: A) -> Result<T, CPUError<A>> {
+        let offset = addr - self.mapping.start;
+        match self
+            .host
+            .as_ref()
+            .and_then(|host| host.get(offset.as_t(), T::SIZE))
+        {
+            Some(bytes) => Ok(T::from_le_bytes(bytes)),
+            None => T::load(self.mem.as_ref(), offset),
+        }
+    }
+
+    /// Stores to bus address `addr`, which has to lie within this region.
+    #[inline]
+    fn store<T: Access>(&mut self, addr: A, value: T) -> Result<(), CPUError<A>> {
+        let offset = addr - self.mapping.start;
+        match self
+            .host
+            .as_mut()
+            .and_then(|host| host.get_mut(offset.as_t(), T::SIZE))
+        {
+            Some(bytes) => {
+                value.write_le_bytes(bytes);
+                Ok(())
+            }
+            None => value.store(self.mem.as_mut(), offset),
+        }
+    }
+
+    fn get_data(&self, range: Range<A>) -> Result<Vec<u8>, CPUError<A>> {
+        let start = range.start - self.mapping.start;
+        let end = range.end - self.mapping.start;
+        match self
+            .host
+            .as_ref()
+            .and_then(|host| host.get(start.as_t(), (end - start).as_t()))
+        {
+            Some(bytes) => Ok(bytes.to_vec()),
+            None => self.mem.get_data(start..end),
         }
-        Err(CPUError::AddressNotMapped(addr))
     }
 }
 
@@ -49,10 +410,15 @@ impl<A: Xlen + Unsigned> Memory<A> for Bus<A> {
         A::max_value()
     }
 
-    impl_memory_map!(self, addr, { self.map(addr)? }, { self.map_mut(addr)? });
+    impl_memory!(self, addr, value, { self.read(addr, AccessType::Load) }, {
+        self.write(addr, value)
+    });
 
     fn get_data(&self, range: Range<A>) -> Result<Vec<u8>, CPUError<A>> {
-        let (mem, mapping) = self.map(range.start)?;
-        mem.get_data(range.start - mapping.start..range.end - mapping.start)
+        let region = self.map(range.start, 1, AccessType::Load)?;
+        if range.end < range.start || range.end > region.mapping.end {
+            return Err(CPUError::AddressNotMapped(range.end));
+        }
+        region.get_data(range)
     }
 }
@@ -1,11 +1,10 @@
-use std::mem;
 use std::ops::Range;
 
-use num_traits::{FromBytes, ToBytes, Unsigned};
+use num_traits::Unsigned;
 
 use crate::cpu::isa::{As, Xlen};
 use crate::cpu::CPUError;
-use crate::memory::{impl_memory, Memory};
+use crate::memory::{impl_memory, load_from_slice, store_to_slice, Access, HostMemory, Memory};
 
 pub struct Dram<A: Xlen + Unsigned> {
     dram: Vec<u8>,
@@ -18,35 +17,19 @@ impl<A: Xlen + Unsigned> Dram<A> {
 
         // write code at start of new dram
         let mut dram = vec![0; size.as_t::<usize>()];
-        dram.splice(..code.len(), code.iter().copied());
+        dram[..code.len()].copy_from_slice(code);
 
         Self { dram, size }
     }
 }
 
 impl<A: Xlen + Unsigned> Dram<A> {
-    fn load<T: FromBytes>(&self, addr: A) -> Result<T, CPUError<A>>
-    where
-        for<'a> <T as FromBytes>::Bytes: TryFrom<&'a [u8]>,
-    {
-        let bytes =
-            self.dram[addr.as_t::<usize>()..addr.as_t::<usize>() + mem::size_of::<T>()].as_ref();
-
-        if let Ok(bytes) = &bytes.try_into() {
-            Ok(T::from_le_bytes(bytes))
-        } else {
-            panic!("Error in {}", line!());
-        }
+    pub(super) fn load<T: Access>(&self, addr: A) -> Result<T, CPUError<A>> {
+        load_from_slice(&self.dram, addr)
     }
 
-    fn store<T: ToBytes>(&mut self, addr: A, value: T) -> Result<(), CPUError<A>> {
-        let bytes = value.to_le_bytes().as_ref().to_owned();
-        self.dram.splice(
-            addr.as_t::<usize>()..addr.as_t::<usize>() + bytes.len(),
-            bytes,
-        );
-
-        Ok(())
+    fn store<T: Access>(&mut self, addr: A, value: T) -> Result<(), CPUError<A>> {
+        store_to_slice(&mut self.dram, addr, value)
     }
 }
 
@@ -60,10 +43,15 @@ impl<A: Xlen + Unsigned> Memory<A> for Dram<A> {
     });
 
     fn get_data(&self, range: Range<A>) -> Result<Vec<u8>, CPUError<A>> {
-        if range.end <= self.size {
+        if range.start <= range.end && range.end <= self.size {
             Ok(self.dram[range.start.as_t()..range.end.as_t()].to_vec())
         } else {
             Err(CPUError::AddressNotMapped(range.end))
         }
     }
+
+    fn host_memory(&mut self) -> Option<HostMemory> {
+        // SAFETY: the dram is never resized, so its buffer does not move
+        Some(unsafe { HostMemory::new(&mut self.dram) })
+    }
 }
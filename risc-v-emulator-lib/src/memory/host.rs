@@ -0,0 +1,104 @@
+use std::ptr::NonNull;
+use std::slice;
+
+use crate::memory::sparse_dram::{Page, PAGE_SIZE};
+#[cfg(doc)]
+use crate::memory::{Bus, Memory};
+
+/// Host buffer backing a [`Memory`], either contiguous or made of pages.
+///
+/// The type is public, as [`Memory::host_memory`] returns it, but lives in a private module, so
+/// memories outside of this crate can not hand a buffer they still own to the [`Bus`].
+///
+/// The [`Bus`] reads and writes it directly instead of dispatching to the load and store methods
+/// of the memory. Accesses it can not serve, e.g. to pages that are not allocated yet, are left
+/// to the memory.
+pub struct HostMemory {
+    backing: Backing,
+    len: usize,
+}
+
+enum Backing {
+    Contiguous(NonNull<u8>),
+    /// Table of pages of `PAGE_SIZE` bytes, only the allocated ones are accessed directly
+    Paged(NonNull<Option<Page>>),
+}
+
+impl HostMemory {
+    /// # Safety
+    ///
+    /// `buf` must stay valid at the same address for as long as the memory handing it out
+    /// exists, and must not be accessed through any other path once it is mapped on a [`Bus`].
+    pub(crate) unsafe fn new(buf: &mut [u8]) -> Self {
+        Self {
+            len: buf.len(),
+            backing: Backing::Contiguous(NonNull::from(buf).cast()),
+        }
+    }
+
+    /// Backs `len` bytes, which must fit in `pages`.
+    ///
+    /// # Safety
+    ///
+    /// `pages` must stay valid at the same address for as long as the memory handing it out
+    /// exists. The memory may allocate pages in it, but must neither free nor move them.
+    pub(crate) unsafe fn paged(pages: &mut [Option<Page>], len: usize) -> Self {
+        assert!(len <= pages.len() * PAGE_SIZE);
+
+        Self {
+            len,
+            backing: Backing::Paged(NonNull::from(pages).cast()),
+        }
+    }
+
+    /// Returns the `len` bytes at `offset`, or `None` if they are out of bounds or not backed by
+    /// host memory.
+    pub(super) fn get(&self, offset: usize, len: usize) -> Option<&[u8]> {
+        if offset.checked_add(len)? > self.len {
+            return None;
+        }
+
+        match self.backing {
+            // SAFETY: in bounds of the buffer, which is valid as long as `self` is (see `new`)
+            Backing::Contiguous(ptr) => {
+                Some(unsafe { slice::from_raw_parts(ptr.as_ptr().add(offset), len) })
+            }
+            Backing::Paged(pages) => {
+                let (page, offset) = within_page(offset, len)?;
+                // SAFETY: in bounds of the table, which is valid as long as `self` is (see
+                // `paged`)
+                let page = unsafe { &*pages.as_ptr().add(page) }.as_ref()?;
+                Some(&page[offset..offset + len])
+            }
+        }
+    }
+
+    /// Returns the `len` bytes at `offset` mutably, or `None` if they are out of bounds or not
+    /// backed by host memory.
+    pub(super) fn get_mut(&mut self, offset: usize, len: usize) -> Option<&mut [u8]> {
+        if offset.checked_add(len)? > self.len {
+            return None;
+        }
+
+        match self.backing {
+            // SAFETY: in bounds of the buffer, which is valid as long as `self` is (see `new`)
+            Backing::Contiguous(ptr) => {
+                Some(unsafe { slice::from_raw_parts_mut(ptr.as_ptr().add(offset), len) })
+            }
+            Backing::Paged(pages) => {
+                let (page, offset) = within_page(offset, len)?;
+                // SAFETY: in bounds of the table, which is valid as long as `self` is (see
+                // `paged`)
+                let page = unsafe { &mut *pages.as_ptr().add(page) }.as_mut()?;
+                Some(&mut page[offset..offset + len])
+            }
+        }
+    }
+}
+
+/// Returns the page and the offset in it of the `len` bytes at `offset`, or `None` if they
+/// cross a page boundary.
+fn within_page(offset: usize, len: usize) -> Option<(usize, usize)> {
+    let page_offset = offset % PAGE_SIZE;
+    (page_offset + len <= PAGE_SIZE).then_some((offset / PAGE_SIZE, page_offset))
+}
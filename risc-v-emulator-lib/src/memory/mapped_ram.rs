@@ -0,0 +1,105 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+use memmap2::{MmapMut, MmapOptions};
+use num_traits::Unsigned;
+
+use crate::cpu::isa::{As, Xlen};
+use crate::cpu::CPUError;
+use crate::memory::{impl_memory, load_from_slice, store_to_slice, HostMemory, Memory};
+
+/// How stores to a [`MappedRam`] reach the backing file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MapMode {
+    /// Copy-on-write: the guest sees its own stores, the file is never modified.
+    Private,
+    /// Write-through: stores are written back to the file.
+    Shared,
+}
+
+/// Ram backed by a memory-mapped host file.
+pub struct MappedRam<A: Xlen + Unsigned> {
+    map: MmapMut,
+    size: A,
+    _file: File,
+}
+
+impl<A: Xlen + Unsigned> MappedRam<A> {
+    /// Maps the first `size` bytes of the file at `path`.
+    ///
+    /// In [`MapMode::Shared`] the file is created and grown to `size` if necessary,
+    /// in [`MapMode::Private`] it has to exist and be at least `size` bytes long.
+    pub fn open(path: impl AsRef<Path>, size: A, mode: MapMode) -> io::Result<MappedRam<A>> {
+        let len = size
+            .to_usize()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "size exceeds host"))?;
+
+        let file = match mode {
+            MapMode::Private => File::open(path)?,
+            MapMode::Shared => OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?,
+        };
+
+        if file.metadata()?.len() < len as u64 {
+            match mode {
+                MapMode::Private => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "file is smaller than the mapped region",
+                    ))
+                }
+                MapMode::Shared => file.set_len(len as u64)?,
+            }
+        }
+
+        // SAFETY: the mapping is only accessed through bounds checked slices. Concurrent
+        // modification of the file by other processes is the user's responsibility, the same
+        // as for any other memory shared with the outside world.
+        let map = unsafe {
+            match mode {
+                MapMode::Private => MmapOptions::new().len(len).map_copy(&file)?,
+                MapMode::Shared => MmapOptions::new().len(len).map_mut(&file)?,
+            }
+        };
+
+        Ok(Self {
+            map,
+            size,
+            _file: file,
+        })
+    }
+
+    /// Writes outstanding changes of a [`MapMode::Shared`] mapping back to the file.
+    pub fn flush(&self) -> io::Result<()> {
+        self.map.flush()
+    }
+}
+
+impl<A: Xlen + Unsigned> Memory<A> for MappedRam<A> {
+    fn size(&self) -> A {
+        self.size
+    }
+
+    impl_memory!(self, addr, value, { load_from_slice(&self.map, addr) }, {
+        store_to_slice(&mut self.map, addr, value)
+    });
+
+    fn get_data(&self, range: Range<A>) -> Result<Vec<u8>, CPUError<A>> {
+        if range.start <= range.end && range.end <= self.size {
+            Ok(self.map[range.start.as_t()..range.end.as_t()].to_vec())
+        } else {
+            Err(CPUError::AddressNotMapped(range.end))
+        }
+    }
+
+    fn host_memory(&mut self) -> Option<HostMemory> {
+        // SAFETY: the mapping is neither moved nor remapped while the ram exists
+        Some(unsafe { HostMemory::new(&mut self.map) })
+    }
+}
@@ -1,12 +1,24 @@
 use std::ops::Range;
 
-pub use bus::Bus;
+pub use bus::{Bus, MisalignedAccess};
 pub use dram::Dram;
+pub(crate) use host::HostMemory;
+pub use mapped_ram::{MapMode, MappedRam};
+pub use rom::Rom;
+pub use sparse_dram::SparseDram;
 
+use crate::cpu::isa::Xlen;
 use crate::cpu::CPUError;
+use crate::devices::Device;
 
 mod bus;
 mod dram;
+mod host;
+mod mapped_ram;
+mod rom;
+mod sparse_dram;
+#[cfg(test)]
+mod test;
 
 pub trait Memory<A> {
     fn size(&self) -> A;
@@ -36,8 +48,152 @@ pub trait Memory<A> {
     fn store_i128(&mut self, addr: A, value: i128) -> Result<(), CPUError<A>>;
 
     fn get_data(&self, range: Range<A>) -> Result<Vec<u8>, CPUError<A>>;
+
+    /// Returns the host buffer backing this memory if it is plain ram, see `HostMemory`. Only
+    /// the memories of this crate can provide one, the type can not be named outside of it.
+    fn host_memory(&mut self) -> Option<HostMemory> {
+        None
+    }
+
+    /// Returns this memory as a [`Device`] if it has to be ticked.
+    fn as_device(&mut self) -> Option<&mut dyn Device<A>> {
+        None
+    }
+}
+
+/// The kind of memory access, used to report the matching exception.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AccessType {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl AccessType {
+    pub(crate) fn access_fault<A>(self, addr: A) -> CPUError<A> {
+        match self {
+            AccessType::Fetch => CPUError::InstructionAccessFault(addr),
+            AccessType::Load => CPUError::LoadAccessFault(addr),
+            AccessType::Store => CPUError::StoreAccessFault(addr),
+        }
+    }
+
+    pub(crate) fn misaligned<A>(self, addr: A) -> CPUError<A> {
+        match self {
+            AccessType::Fetch => CPUError::InstructionAddressMisaligned(addr),
+            AccessType::Load => CPUError::LoadAddressMisaligned(addr),
+            AccessType::Store => CPUError::StoreAddressMisaligned(addr),
+        }
+    }
+}
+
+/// Access permissions of a region mapped on the [`Bus`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    /// Regular memory, e.g. dram.
+    pub const RWX: Permissions = Permissions::new(true, true, true);
+    /// Code that must not be modified, e.g. a boot rom or flash.
+    pub const RX: Permissions = Permissions::new(true, false, true);
+    /// Execute-never memory, e.g. mmio registers of devices.
+    pub const RW: Permissions = Permissions::new(true, true, false);
+    /// Read-only data.
+    pub const R: Permissions = Permissions::new(true, false, false);
+
+    pub const fn new(read: bool, write: bool, execute: bool) -> Self {
+        Self {
+            read,
+            write,
+            execute,
+        }
+    }
+
+    pub fn allows(&self, access: AccessType) -> bool {
+        match access {
+            AccessType::Fetch => self.execute,
+            AccessType::Load => self.read,
+            AccessType::Store => self.write,
+        }
+    }
+}
+
+/// Loads a `T` from `addr` in a host buffer, faulting if the access runs past its end.
+pub(crate) fn load_from_slice<A: Xlen, T: Access>(mem: &[u8], addr: A) -> Result<T, CPUError<A>> {
+    addr.to_usize()
+        .and_then(|start| mem.get(start..start.checked_add(T::SIZE)?))
+        .map(T::from_le_bytes)
+        .ok_or(CPUError::LoadAccessFault(addr))
+}
+
+/// Stores `value` at `addr` in a host buffer, faulting if the access runs past its end.
+pub(crate) fn store_to_slice<A: Xlen, T: Access>(
+    mem: &mut [u8],
+    addr: A,
+    value: T,
+) -> Result<(), CPUError<A>> {
+    addr.to_usize()
+        .and_then(|start| mem.get_mut(start..start.checked_add(T::SIZE)?))
+        .map(|bytes| value.write_le_bytes(bytes))
+        .ok_or(CPUError::StoreAccessFault(addr))
 }
 
+/// A value that can be transferred over a [`Memory`] in a single access.
+pub(crate) trait Access: Copy {
+    const SIZE: usize;
+
+    fn load<A>(mem: &(impl Memory<A> + ?Sized), addr: A) -> Result<Self, CPUError<A>>;
+    fn store<A>(self, mem: &mut (impl Memory<A> + ?Sized), addr: A) -> Result<(), CPUError<A>>;
+
+    fn from_le_bytes(bytes: &[u8]) -> Self;
+    fn write_le_bytes(self, bytes: &mut [u8]);
+}
+
+macro_rules! impl_access {
+    ($($t:ty, $load:ident, $store:ident);*) => {
+        $(
+            impl Access for $t {
+                const SIZE: usize = std::mem::size_of::<$t>();
+
+                fn load<A>(mem: &(impl Memory<A> + ?Sized), addr: A) -> Result<Self, CPUError<A>> {
+                    mem.$load(addr)
+                }
+
+                fn store<A>(self, mem: &mut (impl Memory<A> + ?Sized), addr: A) -> Result<(), CPUError<A>> {
+                    mem.$store(addr, self)
+                }
+
+                fn from_le_bytes(bytes: &[u8]) -> Self {
+                    let mut buf = [0; std::mem::size_of::<$t>()];
+                    buf.copy_from_slice(bytes);
+                    <$t>::from_le_bytes(buf)
+                }
+
+                fn write_le_bytes(self, bytes: &mut [u8]) {
+                    bytes.copy_from_slice(&<$t>::to_le_bytes(self));
+                }
+            }
+        )*
+    };
+}
+
+impl_access!(
+    u8, load_u8, store_u8;
+    u16, load_u16, store_u16;
+    u32, load_u32, store_u32;
+    u64, load_u64, store_u64;
+    u128, load_u128, store_u128;
+    i8, load_i8, store_i8;
+    i16, load_i16, store_i16;
+    i32, load_i32, store_i32;
+    i64, load_i64, store_i64;
+    i128, load_i128, store_i128
+);
+
 macro_rules! impl_memory {
     ($self:ident, $addr:ident, $value:ident, $load:block, $store:block) => {
         fn load_u8(&$self, $addr: A) -> Result<u8, CPUError<A>> $load
@@ -67,33 +223,3 @@ macro_rules! impl_memory {
 }
 
 pub(crate) use impl_memory;
-
-macro_rules! impl_memory_map {
-    ($self:ident, $addr:ident, $map:block, $map_mut:block) => {
-        fn load_u8(&$self, $addr: A) -> Result<u8, CPUError<A>> { let (mem, mapping) = $map; mem.load_u8($addr - mapping.start) }
-        fn load_u16(&$self, $addr: A) -> Result<u16, CPUError<A>> { let (mem, mapping) = $map; mem.load_u16($addr - mapping.start) }
-        fn load_u32(&$self, $addr: A) -> Result<u32, CPUError<A>> { let (mem, mapping) = $map; mem.load_u32($addr - mapping.start) }
-        fn load_u64(&$self, $addr: A) -> Result<u64, CPUError<A>> { let (mem, mapping) = $map; mem.load_u64($addr - mapping.start) }
-        fn load_u128(&$self, $addr: A) -> Result<u128, CPUError<A>> { let (mem, mapping) = $map; mem.load_u128($addr - mapping.start) }
-
-        fn load_i8(&$self, $addr: A) -> Result<i8, CPUError<A>> { let (mem, mapping) = $map; mem.load_i8($addr - mapping.start) }
-        fn load_i16(&$self, $addr: A) -> Result<i16, CPUError<A>> { let (mem, mapping) = $map; mem.load_i16($addr - mapping.start) }
-        fn load_i32(&$self, $addr: A) -> Result<i32, CPUError<A>> { let (mem, mapping) = $map; mem.load_i32($addr - mapping.start) }
-        fn load_i64(&$self, $addr: A) -> Result<i64, CPUError<A>> { let (mem, mapping) = $map; mem.load_i64($addr - mapping.start) }
-        fn load_i128(&$self, $addr: A) -> Result<i128, CPUError<A>> { let (mem, mapping) = $map; mem.load_i128($addr - mapping.start) }
-
-        fn store_u8(&mut $self, $addr: A, value: u8) -> Result<(), CPUError<A>> {let (mem, mapping) = $map_mut; mem.store_u8($addr - mapping.start, value)}
-        fn store_u16(&mut $self, $addr: A, value: u16) -> Result<(), CPUError<A>> {let (mem, mapping) = $map_mut; mem.store_u16($addr - mapping.start, value)}
-        fn store_u32(&mut $self, $addr: A, value: u32) -> Result<(), CPUError<A>> {let (mem, mapping) = $map_mut; mem.store_u32($addr - mapping.start, value)}
-        fn store_u64(&mut $self, $addr: A, value: u64) -> Result<(), CPUError<A>> {let (mem, mapping) = $map_mut; mem.store_u64($addr - mapping.start, value)}
-        fn store_u128(&mut $self, $addr: A, value: u128) -> Result<(), CPUError<A>> {let (mem, mapping) = $map_mut; mem.store_u128($addr - mapping.start, value)}
-
-        fn store_i8(&mut $self, $addr: A, value: i8) -> Result<(), CPUError<A>> {let (mem, mapping) = $map_mut; mem.store_i8($addr - mapping.start, value)}
-        fn store_i16(&mut $self, $addr: A, value: i16) -> Result<(), CPUError<A>> {let (mem, mapping) = $map_mut; mem.store_i16($addr - mapping.start, value)}
-        fn store_i32(&mut $self, $addr: A, value: i32) -> Result<(), CPUError<A>> {let (mem, mapping) = $map_mut; mem.store_i32($addr - mapping.start, value)}
-        fn store_i64(&mut $self, $addr: A, value: i64) -> Result<(), CPUError<A>> {let (mem, mapping) = $map_mut; mem.store_i64($addr - mapping.start, value)}
-        fn store_i128(&mut $self, $addr: A, value: i128) -> Result<(), CPUError<A>> {let (mem, mapping) = $map_mut; mem.store_i128($addr - mapping.start, value)}
-    };
-}
-
-pub(crate) use impl_memory_map;
@@ -0,0 +1,35 @@
+use std::ops::Range;
+
+use num_traits::Unsigned;
+
+use crate::cpu::isa::Xlen;
+use crate::cpu::CPUError;
+use crate::memory::{impl_memory, Dram, Memory};
+
+/// Read-only memory, e.g. a boot rom or flash. Every store raises an access fault.
+pub struct Rom<A: Xlen + Unsigned> {
+    rom: Dram<A>,
+}
+
+impl<A: Xlen + Unsigned> Rom<A> {
+    pub fn with_data(data: &[u8], size: A) -> Rom<A> {
+        Self {
+            rom: Dram::with_code(data, size),
+        }
+    }
+}
+
+impl<A: Xlen + Unsigned> Memory<A> for Rom<A> {
+    fn size(&self) -> A {
+        self.rom.size()
+    }
+
+    impl_memory!(self, addr, value, { self.rom.load(addr) }, {
+        let _ = value;
+        Err(CPUError::StoreAccessFault(addr))
+    });
+
+    fn get_data(&self, range: Range<A>) -> Result<Vec<u8>, CPUError<A>> {
+        self.rom.get_data(range)
+    }
+}
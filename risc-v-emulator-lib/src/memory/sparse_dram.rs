@@ -0,0 +1,147 @@
+use std::ops::Range;
+
+use num_traits::Unsigned;
+
+use crate::cpu::isa::{As, Xlen};
+use crate::cpu::CPUError;
+use crate::memory::{impl_memory, Access, HostMemory, Memory};
+
+pub const PAGE_SIZE: usize = 4096;
+
+pub(crate) type Page = Box<[u8; PAGE_SIZE]>;
+
+/// Dram which only allocates host memory for pages that have been written to.
+/// Reads from untouched pages return zeros.
+///
+/// On a [`Bus`](crate::memory::Bus), accesses within allocated pages take the same fast path as
+/// [`Dram`](crate::memory::Dram), the others go through the load and store methods.
+pub struct SparseDram<A: Xlen + Unsigned> {
+    /// Never resized, the bus keeps a pointer to it
+    pages: Box<[Option<Page>]>,
+    size: A,
+}
+
+impl<A: Xlen + Unsigned> SparseDram<A> {
+    pub fn new(size: A) -> SparseDram<A> {
+        assert!(size.to_usize().is_some());
+
+        let pages = (0..size.as_t::<usize>().div_ceil(PAGE_SIZE))
+            .map(|_| None)
+            .collect();
+
+        Self { pages, size }
+    }
+
+    pub fn with_code(code: &[u8], size: A) -> SparseDram<A> {
+        let mut dram = Self::new(size);
+        assert!(dram.in_bounds(0, code.len()));
+        dram.write(0, code);
+
+        dram
+    }
+
+    /// Number of pages currently backed by host memory.
+    pub fn allocated_pages(&self) -> usize {
+        self.pages.iter().filter(|page| page.is_some()).count()
+    }
+
+    fn in_bounds(&self, addr: usize, len: usize) -> bool {
+        addr.checked_add(len)
+            .is_some_and(|end| end <= self.size.as_t::<usize>())
+    }
+
+    /// Splits `len` bytes starting at `addr` into (page, offset in page, range in data) chunks
+    /// that do not cross a page boundary.
+    fn chunks(addr: usize, len: usize) -> impl Iterator<Item = (usize, usize, Range<usize>)> {
+        let mut done = 0;
+        std::iter::from_fn(move || {
+            if done == len {
+                return None;
+            }
+
+            let addr = addr + done;
+            let offset = addr % PAGE_SIZE;
+            let n = (PAGE_SIZE - offset).min(len - done);
+            let chunk = (addr / PAGE_SIZE, offset, done..done + n);
+            done += n;
+
+            Some(chunk)
+        })
+    }
+
+    fn read(&self, addr: usize, buf: &mut [u8]) {
+        for (page, offset, range) in Self::chunks(addr, buf.len()) {
+            let len = range.len();
+            match &self.pages[page] {
+                Some(page) => buf[range].copy_from_slice(&page[offset..offset + len]),
+                None => buf[range].fill(0),
+            }
+        }
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) {
+        for (page, offset, range) in Self::chunks(addr, data.len()) {
+            let len = range.len();
+            let page = self.pages[page].get_or_insert_with(|| Box::new([0; PAGE_SIZE]));
+            page[offset..offset + len].copy_from_slice(&data[range]);
+        }
+    }
+
+    fn load<T: Access>(&self, addr: A) -> Result<T, CPUError<A>> {
+        let offset = addr.as_t::<usize>();
+        if addr.to_usize().is_none() || !self.in_bounds(offset, T::SIZE) {
+            return Err(CPUError::LoadAccessFault(addr));
+        }
+
+        let page_offset = offset % PAGE_SIZE;
+        if page_offset + T::SIZE <= PAGE_SIZE {
+            return Ok(match &self.pages[offset / PAGE_SIZE] {
+                Some(page) => T::from_le_bytes(&page[page_offset..page_offset + T::SIZE]),
+                None => T::from_le_bytes(&[0; 16][..T::SIZE]),
+            });
+        }
+
+        let mut bytes = [0; 16];
+        self.read(offset, &mut bytes[..T::SIZE]);
+
+        Ok(T::from_le_bytes(&bytes[..T::SIZE]))
+    }
+
+    fn store<T: Access>(&mut self, addr: A, value: T) -> Result<(), CPUError<A>> {
+        let offset = addr.as_t::<usize>();
+        if addr.to_usize().is_none() || !self.in_bounds(offset, T::SIZE) {
+            return Err(CPUError::StoreAccessFault(addr));
+        }
+
+        let mut bytes = [0; 16];
+        value.write_le_bytes(&mut bytes[..T::SIZE]);
+        self.write(offset, &bytes[..T::SIZE]);
+
+        Ok(())
+    }
+}
+
+impl<A: Xlen + Unsigned> Memory<A> for SparseDram<A> {
+    fn size(&self) -> A {
+        self.size
+    }
+
+    impl_memory!(self, addr, value, { self.load(addr) }, {
+        self.store(addr, value)
+    });
+
+    fn get_data(&self, range: Range<A>) -> Result<Vec<u8>, CPUError<A>> {
+        if range.start <= range.end && range.end <= self.size {
+            let mut data = vec![0; (range.end - range.start).as_t()];
+            self.read(range.start.as_t(), &mut data);
+            Ok(data)
+        } else {
+            Err(CPUError::AddressNotMapped(range.end))
+        }
+    }
+
+    fn host_memory(&mut self) -> Option<HostMemory> {
+        // SAFETY: the page table is never resized and pages are never freed
+        Some(unsafe { HostMemory::paged(&mut self.pages, self.size.as_t()) })
+    }
+}
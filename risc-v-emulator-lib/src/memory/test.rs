@@ -0,0 +1,375 @@
+use std::cell::Cell;
+use std::ops::Range;
+use std::rc::Rc;
+
+use num_traits::Unsigned;
+
+use crate::cpu::isa::{As, Xlen};
+use crate::cpu::CPUError;
+use crate::devices::{Device, Timer};
+use crate::memory::{
+    impl_memory, Bus, Dram, MapMode, MappedRam, Memory, MisalignedAccess, Permissions, Rom,
+    SparseDram,
+};
+use crate::test_util::temp_file;
+
+fn bus() -> Bus<u32> {
+    Bus::new(vec![
+        (0x1000..0x1010, Box::new(Dram::with_code(&[], 0x10))),
+        (0x1010..0x1020, Box::new(Dram::with_code(&[], 0x10))),
+    ])
+}
+
+#[test]
+fn test_dram_access_past_end() {
+    let mut dram: Dram<u32> = Dram::with_code(&[], 0x10);
+
+    assert!(matches!(dram.load_u32(0xC), Ok(0)));
+    assert!(matches!(
+        dram.load_u32(0xD),
+        Err(CPUError::LoadAccessFault(0xD))
+    ));
+    assert!(matches!(
+        dram.load_u8(0x10),
+        Err(CPUError::LoadAccessFault(0x10))
+    ));
+    assert!(matches!(
+        dram.load_u8(u32::MAX),
+        Err(CPUError::LoadAccessFault(u32::MAX))
+    ));
+
+    assert!(dram.store_u32(0xC, 0xDEAD_BEEF).is_ok());
+    assert!(matches!(
+        dram.store_u64(0xC, 0),
+        Err(CPUError::StoreAccessFault(0xC))
+    ));
+    assert!(matches!(dram.load_u32(0xC), Ok(0xDEAD_BEEF)));
+}
+
+#[test]
+fn test_bus_access_crossing_regions() {
+    let mut bus = bus();
+
+    assert!(bus.store_u32(0x100C, 0x1234_5678).is_ok());
+    assert!(matches!(bus.load_u32(0x100C), Ok(0x1234_5678)));
+    assert!(matches!(
+        bus.load_u32(0x100E),
+        Err(CPUError::LoadAccessFault(0x100E))
+    ));
+    assert!(matches!(
+        bus.store_u16(0x100F, 0),
+        Err(CPUError::StoreAccessFault(0x100F))
+    ));
+    assert!(matches!(
+        bus.load_u32(0x101E),
+        Err(CPUError::LoadAccessFault(0x101E))
+    ));
+}
+
+#[test]
+fn test_bus_access_unmapped() {
+    let mut bus = bus();
+
+    assert!(matches!(
+        bus.load_u8(0x0FFF),
+        Err(CPUError::AddressNotMapped(0x0FFF))
+    ));
+    assert!(matches!(
+        bus.store_u8(0x1020, 0),
+        Err(CPUError::AddressNotMapped(0x1020))
+    ));
+    assert!(matches!(
+        bus.get_data(0x1008..0x1018),
+        Err(CPUError::AddressNotMapped(0x1018))
+    ));
+}
+
+#[test]
+fn test_bus_misaligned_trap() {
+    let mut bus = bus();
+    bus.set_misaligned_access(MisalignedAccess::Trap);
+
+    assert!(matches!(
+        bus.load_u32(0x1002),
+        Err(CPUError::LoadAddressMisaligned(0x1002))
+    ));
+    assert!(matches!(
+        bus.store_u16(0x1001, 0),
+        Err(CPUError::StoreAddressMisaligned(0x1001))
+    ));
+    assert!(matches!(
+        bus.fetch_u32(0x1006),
+        Err(CPUError::InstructionAddressMisaligned(0x1006))
+    ));
+    assert!(bus.load_u32(0x1004).is_ok());
+    assert_eq!(3, bus.misaligned_accesses());
+}
+
+#[test]
+fn test_bus_misaligned_emulate() {
+    let mut bus = bus();
+    bus.set_misaligned_access(MisalignedAccess::Emulate);
+
+    assert!(bus.store_u32(0x1002, 0xAABB_CCDD).is_ok());
+    assert!(matches!(bus.load_u16(0x1003), Ok(0xBBCC)));
+    assert!(matches!(
+        bus.load_u32(0x100E),
+        Err(CPUError::LoadAccessFault(0x100E))
+    ));
+    assert_eq!(3, bus.misaligned_accesses());
+}
+
+#[test]
+fn test_bus_misaligned_split() {
+    let mut bus = bus();
+    bus.set_misaligned_access(MisalignedAccess::Split);
+
+    assert!(bus.store_u32(0x100E, 0xAABB_CCDD).is_ok());
+    assert!(matches!(bus.load_u32(0x100E), Ok(0xAABB_CCDD)));
+    assert!(matches!(bus.load_i16(0x100F), Ok(-0x4434)));
+    assert!(matches!(bus.load_u16(0x1010), Ok(0xAABB)));
+    assert!(matches!(
+        bus.load_u32(0x101E),
+        Err(CPUError::AddressNotMapped(0x1020))
+    ));
+    assert_eq!(4, bus.misaligned_accesses());
+}
+
+#[test]
+fn test_rom_rejects_stores() {
+    let mut rom: Rom<u32> = Rom::with_data(&[0x13, 0x37], 0x10);
+
+    assert!(matches!(rom.load_u16(0), Ok(0x3713)));
+    assert!(matches!(
+        rom.store_u8(0, 0),
+        Err(CPUError::StoreAccessFault(0))
+    ));
+    assert!(matches!(rom.load_u16(0), Ok(0x3713)));
+}
+
+#[test]
+fn test_bus_permissions() {
+    let mut bus: Bus<u32> = Bus::with_permissions(vec![
+        (
+            0x1000..0x1010,
+            Box::new(Rom::with_data(&[1], 0x10)),
+            Permissions::RX,
+        ),
+        (
+            0x1010..0x1020,
+            Box::new(Dram::with_code(&[], 0x10)),
+            Permissions::RW,
+        ),
+        (
+            0x1020..0x1030,
+            Box::new(Dram::with_code(&[], 0x10)),
+            Permissions::R,
+        ),
+    ]);
+
+    assert!(matches!(bus.fetch_u32(0x1000), Ok(1)));
+    assert!(matches!(
+        bus.store_u32(0x1000, 0),
+        Err(CPUError::StoreAccessFault(0x1000))
+    ));
+
+    assert!(bus.store_u32(0x1010, 2).is_ok());
+    assert!(matches!(bus.load_u32(0x1010), Ok(2)));
+    assert!(matches!(
+        bus.fetch_u32(0x1010),
+        Err(CPUError::InstructionAccessFault(0x1010))
+    ));
+
+    assert!(matches!(
+        bus.store_u8(0x1020, 0),
+        Err(CPUError::StoreAccessFault(0x1020))
+    ));
+    assert!(matches!(
+        bus.fetch_u32(0x1020),
+        Err(CPUError::InstructionAccessFault(0x1020))
+    ));
+}
+
+/// Copies the time to the start of the bus every 10 instructions and counts resets.
+struct Ticker {
+    timer: Option<Timer>,
+    resets: Rc<Cell<u32>>,
+}
+
+// generic since `impl_memory!` names the address type `A`
+impl<A: Xlen + Unsigned> Memory<A> for Ticker {
+    fn size(&self) -> A {
+        A::from(0x10).unwrap()
+    }
+
+    impl_memory!(
+        self,
+        addr,
+        value,
+        { Err(CPUError::LoadAccessFault(addr)) },
+        {
+            let _ = value;
+            Err(CPUError::StoreAccessFault(addr))
+        }
+    );
+
+    fn get_data(&self, range: Range<A>) -> Result<Vec<u8>, CPUError<A>> {
+        Ok(vec![0; (range.end - range.start).as_t::<usize>()])
+    }
+
+    fn as_device(&mut self) -> Option<&mut dyn Device<A>> {
+        Some(self)
+    }
+}
+
+impl<A: Xlen + Unsigned> Device<A> for Ticker {
+    fn tick(&mut self, now: u64, bus: &mut dyn Memory<A>) {
+        // the device itself is not reachable while it is ticked
+        assert!(matches!(
+            bus.load_u8(A::from(0x2000).unwrap()),
+            Err(CPUError::AddressNotMapped(_))
+        ));
+        bus.store_u64(A::from(0x1000).unwrap(), now).unwrap();
+        self.timer.as_ref().unwrap().schedule_in(10);
+    }
+
+    fn set_timer(&mut self, timer: Timer) {
+        timer.schedule_at(10);
+        self.timer = Some(timer);
+    }
+
+    fn reset(&mut self) {
+        self.resets.set(self.resets.get() + 1);
+    }
+}
+
+#[test]
+fn test_bus_tick_devices() {
+    let resets = Rc::new(Cell::new(0));
+    let mut bus: Bus<u32> = Bus::new(vec![
+        (0x1000..0x1010, Box::new(Dram::with_code(&[], 0x10))),
+        (
+            0x2000..0x2010,
+            Box::new(Ticker {
+                timer: None,
+                resets: resets.clone(),
+            }),
+        ),
+    ]);
+    assert_eq!(10, bus.next_event());
+
+    bus.tick(5);
+    assert!(matches!(bus.load_u64(0x1000), Ok(0)));
+    // late ticks are rescheduled from the time they happen
+    bus.tick(12);
+    assert!(matches!(bus.load_u64(0x1000), Ok(12)));
+    assert_eq!(22, bus.next_event());
+    bus.tick(21);
+    assert!(matches!(bus.load_u64(0x1000), Ok(12)));
+    // the device is mapped again afterwards
+    assert!(matches!(
+        bus.load_u8(0x2000),
+        Err(CPUError::LoadAccessFault(_))
+    ));
+
+    // pending events are dropped on reset
+    bus.reset_devices();
+    assert_eq!(1, resets.get());
+    assert_eq!(u64::MAX, bus.next_event());
+}
+
+#[test]
+fn test_sparse_dram_allocates_on_write() {
+    let mut dram: SparseDram<u32> = SparseDram::new(0x4000);
+    assert_eq!(0, dram.allocated_pages());
+
+    assert!(matches!(dram.load_u64(0x1000), Ok(0)));
+    assert_eq!(0, dram.allocated_pages());
+
+    assert!(dram.store_u32(0x1FFE, 0xAABB_CCDD).is_ok());
+    assert_eq!(2, dram.allocated_pages());
+    assert!(matches!(dram.load_u32(0x1FFE), Ok(0xAABB_CCDD)));
+    assert!(matches!(dram.load_u8(0x2000), Ok(0xBB)));
+
+    assert!(matches!(
+        dram.load_u32(0x3FFE),
+        Err(CPUError::LoadAccessFault(0x3FFE))
+    ));
+    assert!(matches!(
+        dram.store_u8(0x4000, 0),
+        Err(CPUError::StoreAccessFault(0x4000))
+    ));
+    assert!(matches!(dram.get_data(0x1FFF..0x2001), Ok(data) if data == [0xCC, 0xBB]));
+}
+
+#[test]
+fn test_sparse_dram_host_memory() {
+    let mut dram: SparseDram<u32> = SparseDram::new(0x2800);
+    let mut host = dram.host_memory().unwrap();
+
+    // pages that are not allocated yet are left to the dram
+    assert_eq!(None, host.get(0x1000, 4));
+    assert!(dram.store_u8(0x1000, 1).is_ok());
+    assert_eq!(Some(&[1, 0, 0, 0][..]), host.get(0x1000, 4));
+    host.get_mut(0x1FFC, 4).unwrap().copy_from_slice(&[1, 2, 3, 4]);
+    assert!(matches!(dram.load_u32(0x1FFC), Ok(0x0403_0201)));
+
+    // so are accesses crossing pages or the end of the dram
+    assert!(dram.store_u8(0x0FFF, 1).is_ok());
+    assert_eq!(None, host.get(0x0FFE, 4));
+    assert!(dram.store_u8(0x27FF, 1).is_ok());
+    assert!(host.get_mut(0x27FC, 4).is_some());
+    assert_eq!(None, host.get(0x27FE, 4));
+}
+
+#[test]
+fn test_sparse_dram_4gib() {
+    const SIZE: u64 = 4 * 1024 * 1024 * 1024;
+
+    let mut bus: Bus<u64> = Bus::new(vec![(
+        0x8000_0000..0x8000_0000 + SIZE,
+        Box::new(SparseDram::with_code(&[1, 2, 3, 4], SIZE)),
+    )]);
+
+    assert!(bus.store_u64(0x8000_0000 + SIZE - 8, u64::MAX).is_ok());
+    assert!(matches!(bus.load_u32(0x8000_0000), Ok(0x0403_0201)));
+    assert!(matches!(bus.load_u64(0x8000_0000 + SIZE - 8), Ok(u64::MAX)));
+}
+
+#[test]
+fn test_mapped_ram_shared() {
+    let path = temp_file("mapped_ram_shared", &[1, 2, 3, 4]);
+
+    let mut ram: MappedRam<u32> = MappedRam::open(&path, 0x1000, MapMode::Shared).unwrap();
+    assert!(matches!(ram.load_u32(0), Ok(0x0403_0201)));
+    assert!(ram.store_u16(0x10, 0xBEEF).is_ok());
+    assert!(matches!(
+        ram.store_u16(0xFFF, 0),
+        Err(CPUError::StoreAccessFault(0xFFF))
+    ));
+    ram.flush().unwrap();
+
+    let data = std::fs::read(&path).unwrap();
+    assert_eq!(0x1000, data.len());
+    assert_eq!([0xEF, 0xBE], data[0x10..0x12]);
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_mapped_ram_private() {
+    let path = temp_file("mapped_ram_private", &[0; 0x1000]);
+
+    let mut bus: Bus<u32> = Bus::new(vec![(
+        0x1000..0x2000,
+        Box::new(MappedRam::open(&path, 0x1000, MapMode::Private).unwrap()),
+    )]);
+    assert!(bus.store_u32(0x1000, 0x1234_5678).is_ok());
+    assert!(matches!(bus.load_u32(0x1000), Ok(0x1234_5678)));
+    drop(bus);
+
+    assert_eq!(vec![0; 0x1000], std::fs::read(&path).unwrap());
+    assert!(MappedRam::<u32>::open(&path, 0x2000, MapMode::Private).is_err());
+
+    std::fs::remove_file(path).unwrap();
+}
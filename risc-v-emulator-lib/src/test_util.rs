@@ -0,0 +1,43 @@
+//! Helpers shared by the tests of all modules.
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// Writer keeping everything written to it, e.g. the output of a console. Clones share the
+/// same buffer.
+#[derive(Clone, Default)]
+pub(crate) struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Capture {
+    /// Returns everything written so far.
+    pub(crate) fn contents(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+}
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns a path in the temporary directory, unique to `name` and this test run.
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "risc-v-emulator-test-{}-{name}",
+        std::process::id()
+    ))
+}
+
+/// Creates a temporary file holding `data`, see [`temp_path`].
+pub(crate) fn temp_file(name: &str, data: &[u8]) -> PathBuf {
+    let path = temp_path(name);
+    std::fs::write(&path, data).expect("Could not create temporary file!");
+    path
+}
@@ -5,16 +5,34 @@ use risc_v_emulator_lib::cpu::Cpu;
 fn test_rv32i() {
     let cpu_rv32i: Cpu<RV32I, 32> = Cpu::with_code(&[]);
     assert_eq!("RV32I", cpu_rv32i.get_isa_id());
+
+    let info = cpu_rv32i.isa_info();
+    assert_eq!(
+        ("RV32I", 32, 4, 32),
+        (info.id, info.xlen, info.insn_size, info.reg_count)
+    );
 }
 
 #[test]
 fn test_rv32e() {
     let cpu_rv32e: Cpu<RV32E, 16> = Cpu::with_code(&[]);
     assert_eq!("RV32E", cpu_rv32e.get_isa_id());
+
+    let info = cpu_rv32e.isa_info();
+    assert_eq!(
+        ("RV32E", 32, 4, 16),
+        (info.id, info.xlen, info.insn_size, info.reg_count)
+    );
 }
 
 #[test]
 fn test_rv64i() {
     let cpu_rv64i: Cpu<RV64I, 32> = Cpu::with_code(&[]);
     assert_eq!("RV64I", cpu_rv64i.get_isa_id());
+
+    let info = cpu_rv64i.isa_info();
+    assert_eq!(
+        ("RV64I", 64, 4, 32),
+        (info.id, info.xlen, info.insn_size, info.reg_count)
+    );
 }
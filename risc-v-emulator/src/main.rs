@@ -1,38 +1,146 @@
 use std::error::Error;
 use std::fs::File;
-use std::io::Read;
-use std::time::Instant;
+use std::io::{self, IsTerminal, Read};
+use std::process::{Command, ExitCode, Stdio};
+use std::time::{Duration, Instant};
 use std::{env, fs};
 
 use risc_v_emulator_lib::cpu::isa::RV32I;
-use risc_v_emulator_lib::cpu::Cpu;
+use risc_v_emulator_lib::cpu::{Cpu, StopReason};
+use risc_v_emulator_lib::devices::virtio::{
+    DiskMode, NetBackend, Pcap, UnixSocket, Virtio9p, VirtioBlk, VirtioNet, VirtioRng,
+};
+use risc_v_emulator_lib::devices::{Framebuffer, PixelFormat, WallClock};
+use risc_v_emulator_lib::elf::Elf;
 
-fn main() -> Result<(), Box<dyn Error>> {
-    env::set_var("RUST_BACKTRACE", "1");
+const USAGE: &str = "Usage: risc-v-emulator [--drive <image> [--snapshot]] \
+    [--net <socket>,<peer socket> [--mac <address>] [--pcap <file>]] [--share <dir>] \
+    [--rng <seed>] [--virtual-clock <unix time>] \
+    [--framebuffer <width>x<height>[,<format>] [--screenshot <file>]] \
+    [--kernel <image> [--initrd <file>] [--append <cmdline>]] <filename>";
+
+/// Mount tag of the directory shared with --share.
+const SHARE_TAG: &str = "host";
 
-    let args: Vec<String> = env::args().collect();
+/// Address of the network card unless --mac is given, the default of qemu.
+const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
 
-    assert_eq!(args.len(), 2, "Usage: risc-v-emulator <filename>");
+fn main() -> Result<ExitCode, Box<dyn Error>> {
+    env::set_var("RUST_BACKTRACE", "1");
+
+    // the disk image is attached as a virtio block device, with --snapshot writes are discarded
+    let (mut program, mut drive, mut mode) = (None, None, DiskMode::ReadWrite);
+    // the network card talks to the emulator whose socket is the peer socket
+    let (mut net, mut mac, mut pcap) = (None, DEFAULT_MAC, None);
+    // the shared directory is mounted with the tag "host", the seed makes runs reproducible
+    let (mut share, mut seed) = (None, None);
+    // the time of day starts at the given second and follows the retired instructions
+    let mut wall_clock = WallClock::Host;
+    // the last frame is written to the screenshot, as ppm if the extension says so, else png
+    let (mut framebuffer, mut screenshot) = (None, None);
+    // with a kernel the program is the firmware and the machine boots like qemu's virt machine
+    let (mut kernel, mut initrd, mut append) = (None, None, String::new());
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--drive" => drive = Some(args.next().ok_or(USAGE)?),
+            "--snapshot" => mode = DiskMode::CopyOnWrite,
+            "--net" => net = Some(args.next().ok_or(USAGE)?),
+            "--mac" => mac = parse_mac(&args.next().ok_or(USAGE)?).ok_or("Invalid mac address")?,
+            "--pcap" => pcap = Some(args.next().ok_or(USAGE)?),
+            "--share" => share = Some(args.next().ok_or(USAGE)?),
+            "--rng" => seed = Some(args.next().ok_or(USAGE)?.parse()?),
+            "--virtual-clock" => {
+                let start = Duration::from_secs(args.next().ok_or(USAGE)?.parse()?);
+                wall_clock = WallClock::Virtual(start);
+            }
+            "--framebuffer" => {
+                let mode = args.next().ok_or(USAGE)?;
+                framebuffer = Some(parse_framebuffer(&mode).ok_or("Invalid framebuffer mode")?);
+            }
+            "--screenshot" => screenshot = Some(args.next().ok_or(USAGE)?),
+            "--kernel" => kernel = Some(args.next().ok_or(USAGE)?),
+            "--initrd" => initrd = Some(args.next().ok_or(USAGE)?),
+            "--append" => append = args.next().ok_or(USAGE)?,
+            _ if program.is_none() => program = Some(arg),
+            _ => return Err(USAGE.into()),
+        }
+    }
 
-    let mut file = File::open(&args[1])?;
+    let mut file = File::open(program.ok_or(USAGE)?)?;
     let mut code = Vec::new();
     file.read_to_end(&mut code)?;
 
-    let mut cpu: Cpu<RV32I, 32> = Cpu::with_code(&code);
+    let mut cpu: Cpu<RV32I, 32> = if let Some(kernel) = kernel {
+        let initrd = initrd.map(fs::read).transpose()?;
+        Cpu::virt(&code, &fs::read(kernel)?, initrd.as_deref(), &append)
+            .ok_or("The kernel and initrd do not fit in memory")?
+    } else if Elf::is_elf(&code) {
+        Cpu::with_elf(&code)?
+    } else {
+        Cpu::with_code(&code)
+    };
+    cpu.set_wall_clock(wall_clock);
+    if let Some(drive) = drive {
+        cpu.attach_virtio(VirtioBlk::open(drive, mode)?)
+            .ok_or("No free virtio slot for the drive")?;
+    }
+    if let Some(net) = net {
+        let (socket, peer) = net.rsplit_once(',').ok_or(USAGE)?;
+        let mut backend: Box<dyn NetBackend> = Box::new(UnixSocket::bind(socket, peer)?);
+        if let Some(pcap) = pcap {
+            backend = Box::new(Pcap::new(backend, File::create(pcap)?)?);
+        }
+        cpu.attach_virtio(VirtioNet::new(backend, mac))
+            .ok_or("No free virtio slot for the network card")?;
+    }
+    if let Some(share) = share {
+        cpu.attach_virtio(Virtio9p::new(share, SHARE_TAG)?)
+            .ok_or("No free virtio slot for the shared directory")?;
+    }
+    if let Some(seed) = seed {
+        cpu.attach_virtio(VirtioRng::new(seed))
+            .ok_or("No free virtio slot for the entropy source")?;
+    }
+
+    let screen = match framebuffer {
+        Some((width, height, format)) => {
+            let framebuffer = Framebuffer::new(width, height, format)
+                .ok_or("The framebuffer does not fit in the address space")?;
+            let screen = framebuffer.screen();
+            cpu.attach_framebuffer(framebuffer)
+                .ok_or("The framebuffer does not fit in its slot")?;
+            Some(screen)
+        }
+        None => None,
+    };
+
+    // the uart hands every key press to the guest, until the terminal is restored on drop
+    let terminal = RawTerminal::enable();
 
-    let mut cycles = 0;
     let t_start = Instant::now();
 
-    // start execution
-    loop {
-        cycles += 1;
-        if let Err(e) = cpu.cycle() {
+    // start execution, the guest's exit code becomes ours if it powers off
+    let reason = cpu.run();
+    drop(terminal);
+
+    let exit_code = match reason {
+        StopReason::Error(e) => {
             eprintln!("Error: {e} Dumping registers:\n{:?}", cpu.dump_registers());
-            break;
+            ExitCode::FAILURE
         }
-    }
+        StopReason::PowerOff(code) => {
+            println!("Powered off with exit code {code}");
+            ExitCode::from(u8::try_from(code).unwrap_or(u8::MAX))
+        }
+        reason => {
+            println!("{reason}");
+            ExitCode::SUCCESS
+        }
+    };
 
     let elapsed = t_start.elapsed().as_nanos();
+    let cycles = cpu.instructions_retired().max(1) as u128;
     let ns_per_cycle = elapsed / cycles;
     let freq = 1. / ns_per_cycle as f32;
 
@@ -42,11 +150,77 @@ fn main() -> Result<(), Box<dyn Error>> {
         human_time(ns_per_cycle),
         freq
     );
+    if let (Some(screen), Some(screenshot)) = (screen, screenshot) {
+        println!("Writing screenshot...");
+        screen.save(screenshot)?;
+    }
     println!("Writing memory dump...");
 
     fs::write("mem.dump", cpu.dump_memory()).expect("Could not write memory dump!");
 
-    Ok(())
+    Ok(exit_code)
+}
+
+/// Parses a mac address written as six hexadecimal bytes separated by colons.
+fn parse_mac(text: &str) -> Option<[u8; 6]> {
+    let mut mac = [0; 6];
+    let mut bytes = text.split(':');
+    for byte in &mut mac {
+        *byte = u8::from_str_radix(bytes.next()?, 16).ok()?;
+    }
+
+    bytes.next().is_none().then_some(mac)
+}
+
+/// Parses a framebuffer mode like `640x480,r5g6b5`, the pixel format defaults to `x8r8g8b8`.
+fn parse_framebuffer(text: &str) -> Option<(usize, usize, PixelFormat)> {
+    let (size, format) = match text.split_once(',') {
+        Some((size, format)) => (size, PixelFormat::from_name(format)?),
+        None => (text, PixelFormat::Xrgb8888),
+    };
+    let (width, height) = size.split_once('x')?;
+    let (width, height) = (width.parse().ok()?, height.parse().ok()?);
+
+    (width > 0 && height > 0).then_some((width, height, format))
+}
+
+/// Puts the terminal on stdin into raw mode through `stty`, keeping Ctrl-C working, and
+/// restores the previous settings when dropped.
+struct RawTerminal {
+    saved: Option<String>,
+}
+
+impl RawTerminal {
+    fn enable() -> RawTerminal {
+        if !io::stdin().is_terminal() {
+            return RawTerminal { saved: None };
+        }
+
+        let saved = stty(&["-g"]).filter(|_| stty(&["raw", "-echo", "isig"]).is_some());
+        RawTerminal { saved }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        if let Some(saved) = &self.saved {
+            stty(&[saved.trim()]);
+        }
+    }
+}
+
+/// Runs `stty` on the terminal of stdin, returning its output on success.
+fn stty(args: &[&str]) -> Option<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .output()
+        .ok()?;
+
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
 }
 
 fn human_time(mut d: u128) -> String {